@@ -20,9 +20,9 @@ mod imp {
 
     use rocksdb::DB;
     use prometheus::{self, Encoder, TextEncoder};
-    use profiling;
 
     use tikv::raftstore::store::Engines;
+    use tikv::util::profiling;
 
     const ROCKSDB_DB_STATS_KEY: &'static str = "rocksdb.dbstats";
     const ROCKSDB_CF_STATS_KEY: &'static str = "rocksdb.cfstats";
@@ -58,16 +58,21 @@ mod imp {
     }
 
     // TODO: remove backup_path from configuration
-    pub fn handle_signal(engines: Engines, _: &str) {
+    pub fn handle_signal(engines: Engines, _: &str, on_reload: &mut FnMut()) {
         use signal::trap::Trap;
         use nix::sys::signal::{SIGUSR1, SIGUSR2, SIGHUP, SIGINT, SIGTERM};
         let trap = Trap::trap(&[SIGTERM, SIGINT, SIGHUP, SIGUSR1, SIGUSR2]);
         for sig in trap {
             match sig {
-                SIGTERM | SIGINT | SIGHUP => {
+                SIGTERM | SIGINT => {
                     info!("receive signal {}, stopping server...", sig);
                     break;
                 }
+                SIGHUP => {
+                    // Use SIGHUP to reload the config file.
+                    info!("receive signal {}, reloading config...", sig);
+                    on_reload();
+                }
                 SIGUSR1 => {
                     // Use SIGUSR1 to log metrics.
                     let mut buffer = vec![];
@@ -80,7 +85,9 @@ mod imp {
                     print_rocksdb_stats(&engines.raft_engine);
                     print_malloc_stats();
                 }
-                SIGUSR2 => profiling::dump_prof(None),
+                SIGUSR2 => if let Err(e) = profiling::dump_heap_profile(None) {
+                    error!("{}", e);
+                },
                 // TODO: handle more signal
                 _ => unreachable!(),
             }
@@ -118,11 +125,9 @@ mod imp {
 
 #[cfg(not(unix))]
 mod imp {
-    use std::sync::Arc;
-
-    use rocksdb::DB;
+    use tikv::raftstore::store::Engines;
 
-    pub fn handle_signal(_: Arc<DB>, _: &str) {}
+    pub fn handle_signal(_: Engines, _: &str, _: &mut FnMut()) {}
 }
 
 pub use self::imp::handle_signal;
@@ -23,22 +23,45 @@ extern crate kvproto;
 extern crate rocksdb;
 extern crate tempdir;
 extern crate rustc_serialize;
+extern crate grpcio as grpc;
+extern crate futures;
+#[macro_use]
+extern crate serde_json;
 
-use std::{str, u64};
-use clap::{App, Arg, SubCommand};
+use std::{process, str, u64};
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::Arc;
+use clap::{App, Arg, ArgMatches, SubCommand, Values};
+use futures::{Future, Stream};
+use grpc::{ChannelBuilder, EnvBuilder, Error as GrpcError, RpcStatusCode};
 use rustc_serialize::hex::{FromHex, ToHex};
 use protobuf::Message;
-use kvproto::raft_cmdpb::RaftCmdRequest;
+use kvproto::raft_cmdpb::{CmdType, RaftCmdRequest};
 use kvproto::raft_serverpb::{PeerState, RaftApplyState, RaftLocalState, RegionLocalState};
-use kvproto::eraftpb::Entry;
+use kvproto::eraftpb::{ConfChange, Entry, EntryType};
+use kvproto::metapb::{Peer, Region};
+use kvproto::kvrpcpb::MvccInfo;
+use kvproto::debugpb::{BackupRequest, CompactRequest, DB as DBType, GetRegionPropertiesRequest,
+                       GetRequest, RaftLogRequest, RegionInfoRequest, RegionSizeRequest,
+                       RestoreRequest, ScanMvccRequest};
+use kvproto::debugpb_grpc::DebugClient;
 use rocksdb::{ReadOptions, SeekKey, DB};
 use tikv::util::{self, escape, unescape};
 use tikv::util::codec::bytes::encode_bytes;
-use tikv::raftstore::store::keys;
+use tikv::util::codec::number::NumberEncoder;
+use tikv::util::security::{Config as SecurityConfig, SecurityManager};
+use tikv::raftstore::store::{keys, Engines, RaftLogEngine, RaftLogEngineConfig};
+use tikv::server::backup;
+use tikv::server::debug::{Debugger, RaftLogs, RegionDumpManifest};
+use tikv::pd::{PdClient, RpcClient};
 use tikv::raftstore::store::engine::{IterOption, Iterable, Peekable};
 use tikv::storage::{CfName, ALL_CFS, CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE};
 use tikv::storage::mvcc::{Lock, Write};
 use tikv::storage::types::Key;
+use tikv::coprocessor::codec::table;
+
+const MAX_GRPC_RECV_MSG_LEN: usize = 64 * 1024 * 1024;
 
 fn main() {
     let mut app = App::new("TiKV Ctl")
@@ -58,6 +81,43 @@ fn main() {
                 .takes_value(true)
                 .help("set raft rocksdb path"),
         )
+        .arg(
+            Arg::with_name("raftlog")
+                .long("raftlog")
+                .takes_value(true)
+                .help("set raft log engine path, if raft logs are kept in the raft log engine"),
+        )
+        .arg(
+            Arg::with_name("host")
+                .long("host")
+                .takes_value(true)
+                .help("set the address of a running tikv-server, instead of opening the dbs"),
+        )
+        .arg(
+            Arg::with_name("ca-path")
+                .long("ca-path")
+                .takes_value(true)
+                .help("set the CA certificate path for TLS connection"),
+        )
+        .arg(
+            Arg::with_name("cert-path")
+                .long("cert-path")
+                .takes_value(true)
+                .help("set the certificate path for TLS connection"),
+        )
+        .arg(
+            Arg::with_name("key-path")
+                .long("key-path")
+                .takes_value(true)
+                .help("set the private key path for TLS connection"),
+        )
+        .arg(
+            Arg::with_name("decode")
+                .long("decode")
+                .takes_value(true)
+                .possible_values(&["tidb"])
+                .help("decode the keys and values printed by print and scan in the given format"),
+        )
         .arg(
             Arg::with_name("hex-to-escaped")
                 .short("h")
@@ -75,7 +135,10 @@ fn main() {
                 .about("print raft log entry")
                 .subcommand(
                     SubCommand::with_name("log")
-                        .about("print the raft log entry info")
+                        .about(
+                            "print the raft log entry info, or check the entries of a region \
+                             in a range if no index is given",
+                        )
                         .arg(
                             Arg::with_name("region")
                                 .short("r")
@@ -93,6 +156,25 @@ fn main() {
                                 .short("k")
                                 .takes_value(true)
                                 .help("set the raw key"),
+                        )
+                        .arg(
+                            Arg::with_name("from")
+                                .long("from")
+                                .takes_value(true)
+                                .conflicts_with_all(&["index", "key"])
+                                .help("set the first index, the truncated index + 1 by default"),
+                        )
+                        .arg(
+                            Arg::with_name("to")
+                                .long("to")
+                                .takes_value(true)
+                                .conflicts_with_all(&["index", "key"])
+                                .help("set the last index, the last index by default"),
+                        )
+                        .arg(
+                            Arg::with_name("json")
+                                .long("json")
+                                .help("print the entries in a range in JSON"),
                         ),
                 )
                 .subcommand(
@@ -216,6 +298,281 @@ fn main() {
                         .help("set commit_ts as filter"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("unsafe-recover")
+                .about("unsafely recover the cluster when the majority replicas are failed")
+                .subcommand(
+                    SubCommand::with_name("remove-fail-stores")
+                        .about(
+                            "remove the failed stores from the peer lists of the regions, \
+                             the store must be stopped",
+                        )
+                        .arg(
+                            Arg::with_name("stores")
+                                .short("s")
+                                .takes_value(true)
+                                .required(true)
+                                .multiple(true)
+                                .use_delimiter(true)
+                                .help("the failed store ids, separated by commas"),
+                        )
+                        .arg(
+                            Arg::with_name("regions")
+                                .long("regions")
+                                .takes_value(true)
+                                .multiple(true)
+                                .use_delimiter(true)
+                                .help(
+                                    "the region ids to recover, separated by commas, \
+                                     all regions by default",
+                                ),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("tombstone")
+                .about(
+                    "set the local peer of a region to tombstone, the store must be stopped",
+                )
+                .arg(
+                    Arg::with_name("region")
+                        .short("r")
+                        .takes_value(true)
+                        .required(true)
+                        .help("specify region id"),
+                )
+                .arg(
+                    Arg::with_name("pd")
+                        .short("p")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .help(
+                            "set the PD endpoints to check whether the peer is removed, \
+                             separated by commas",
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("recreate-region")
+                .about(
+                    "recreate an empty region for the key range of a region with a new id, \
+                     the store must be stopped",
+                )
+                .arg(
+                    Arg::with_name("region")
+                        .short("r")
+                        .takes_value(true)
+                        .required(true)
+                        .help("specify the region id whose key range is used"),
+                )
+                .arg(
+                    Arg::with_name("pd")
+                        .short("p")
+                        .takes_value(true)
+                        .required(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .help("set the PD endpoints, separated by commas"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("bad-regions")
+                .about("check the raft states and ranges of all regions and report the bad ones")
+                .arg(
+                    Arg::with_name("print-ids")
+                        .long("print-ids")
+                        .help("print the bad region ids in one line, separated by commas"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compact")
+                .about("compact a column family in a specified range")
+                .arg(
+                    Arg::with_name("db")
+                        .long("db")
+                        .takes_value(true)
+                        .possible_values(&["kv", "raft"])
+                        .default_value("kv")
+                        .help("the db to compact"),
+                )
+                .arg(
+                    Arg::with_name("cf")
+                        .short("c")
+                        .long("cf")
+                        .takes_value(true)
+                        .default_value(CF_DEFAULT)
+                        .help("the column family to compact"),
+                )
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .takes_value(true)
+                        .help("set the start raw key, in escaped form, unbounded by default"),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .takes_value(true)
+                        .help("set the end raw key, in escaped form, unbounded by default"),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .long("threads")
+                        .takes_value(true)
+                        .default_value("1")
+                        .help("the number of threads to compact"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("region-properties")
+                .about("show the properties of the tables of a region")
+                .arg(
+                    Arg::with_name("region")
+                        .short("r")
+                        .takes_value(true)
+                        .required(true)
+                        .help("specify region id"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("dump-region")
+                .about("dump the data of a region into SST files with a manifest")
+                .arg(
+                    Arg::with_name("region")
+                        .short("r")
+                        .takes_value(true)
+                        .required(true)
+                        .help("specify region id"),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .takes_value(true)
+                        .required(true)
+                        .help("set the directory to write the files"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("load-region")
+                .about("ingest a region dump into a region with the same range")
+                .arg(
+                    Arg::with_name("region")
+                        .short("r")
+                        .takes_value(true)
+                        .required(true)
+                        .help("specify region id"),
+                )
+                .arg(
+                    Arg::with_name("in")
+                        .long("in")
+                        .takes_value(true)
+                        .required(true)
+                        .help("set the directory of the dump"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("back up the leader regions of the store at a timestamp")
+                .arg(
+                    Arg::with_name("ts")
+                        .long("ts")
+                        .takes_value(true)
+                        .required(true)
+                        .help("set the timestamp to back up at"),
+                )
+                .arg(
+                    Arg::with_name("start")
+                        .long("start")
+                        .takes_value(true)
+                        .default_value("")
+                        .help("set the start raw key, in escaped form"),
+                )
+                .arg(
+                    Arg::with_name("end")
+                        .long("end")
+                        .takes_value(true)
+                        .default_value("")
+                        .help("set the end raw key, in escaped form"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .long("path")
+                        .takes_value(true)
+                        .required(true)
+                        .help("set the backup directory on the store"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check-backup")
+                .about("check that the backups of all stores cover the range and are intact")
+                .arg(
+                    Arg::with_name("path")
+                        .long("path")
+                        .takes_value(true)
+                        .required(true)
+                        .help("set the backup directory holding the files of all stores"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("restore a backup into the leader regions of the store")
+                .arg(
+                    Arg::with_name("path")
+                        .long("path")
+                        .takes_value(true)
+                        .required(true)
+                        .help("set the backup directory holding the files of all stores"),
+                )
+                .arg(
+                    Arg::with_name("old-prefix")
+                        .long("old-prefix")
+                        .takes_value(true)
+                        .default_value("")
+                        .help("set the raw key prefix to rewrite, in escaped form"),
+                )
+                .arg(
+                    Arg::with_name("new-prefix")
+                        .long("new-prefix")
+                        .takes_value(true)
+                        .default_value("")
+                        .help("set the raw key prefix to rewrite to, in escaped form"),
+                )
+                .arg(
+                    Arg::with_name("ts")
+                        .long("ts")
+                        .takes_value(true)
+                        .default_value("0")
+                        .help(
+                            "set the commit timestamp to rewrite all data to, \
+                             0 keeps the original commit timestamps",
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("encode-key")
+                .about("encode a TiDB table key to the raw key in the kv db, in escaped form")
+                .arg(
+                    Arg::with_name("table")
+                        .long("table")
+                        .takes_value(true)
+                        .required(true)
+                        .help("set the table id"),
+                )
+                .arg(
+                    Arg::with_name("handle")
+                        .long("handle")
+                        .takes_value(true)
+                        .conflicts_with("index")
+                        .help("set the row handle, the start of the records by default"),
+                )
+                .arg(
+                    Arg::with_name("index")
+                        .long("index")
+                        .takes_value(true)
+                        .help("set the index id to encode the start of the index"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("diff")
                 .about("diff two region keys")
@@ -248,6 +605,30 @@ fn main() {
             return;
         }
     };
+    if let Some(matches) = matches.subcommand_matches("encode-key") {
+        let table_id = matches.value_of("table").unwrap().parse().unwrap();
+        let handle = matches.value_of("handle").map(|h| h.parse().unwrap());
+        let index_id = matches.value_of("index").map(|i| i.parse().unwrap());
+        println!("{}", escape(&encode_tidb_key(table_id, handle, index_id)));
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("check-backup") {
+        check_backup(Path::new(matches.value_of("path").unwrap()));
+        return;
+    }
+    for cmd in &["backup", "restore"] {
+        if matches.subcommand_matches(cmd).is_some() && matches.value_of("host").is_none() {
+            perror_and_exit(cmd, "the store must be running and specified by --host");
+        }
+    }
+    let decode = matches.value_of("decode").is_some();
+    if let Some(host) = matches.value_of("host") {
+        let client = new_debug_client(host, &matches);
+        if !run_remote(&client, &matches) {
+            let _ = app.print_help();
+        }
+        return;
+    }
     let db_path = matches.value_of("db").unwrap();
     let db = util::rocksdb::open(db_path, ALL_CFS).unwrap();
     let raft_db = if let Some(raftdb_path) = matches.value_of("raftdb") {
@@ -256,13 +637,30 @@ fn main() {
         let raftdb_path = db_path.to_owned() + "../raft";
         util::rocksdb::open(&raftdb_path, &[CF_DEFAULT]).unwrap()
     };
+    let raft_log_path = matches.value_of("raftlog").map(String::from).or_else(|| {
+        let path = db_path.to_owned() + "../raft-log";
+        if Path::new(&path).exists() {
+            Some(path)
+        } else {
+            None
+        }
+    });
+    let raft_log_path = raft_log_path.as_ref().map(String::as_str);
 
     if let Some(matches) = matches.subcommand_matches("print") {
         let cf_name = matches.value_of("cf").unwrap_or(CF_DEFAULT);
         let key = String::from(matches.value_of("key").unwrap());
-        dump_raw_value(db, cf_name, key);
+        dump_raw_value(db, cf_name, key, decode);
     } else if let Some(matches) = matches.subcommand_matches("raft") {
         if let Some(matches) = matches.subcommand_matches("log") {
+            if let Some((region_id, from, to)) = parse_raft_log_range(matches) {
+                let engines = new_engines(db, raft_db, raft_log_path);
+                let logs = Debugger::new(engines)
+                    .raft_logs(region_id, from, to)
+                    .unwrap_or_else(|e| perror_and_exit("failed to get raft logs", e));
+                dump_raft_logs(region_id, &logs, matches.is_present("json"));
+                return;
+            }
             let key = match matches.value_of("key") {
                 None => {
                     let region = String::from(matches.value_of("region").unwrap());
@@ -271,7 +669,8 @@ fn main() {
                 }
                 Some(k) => unescape(k),
             };
-            dump_raft_log_entry(raft_db, &key);
+            let engines = new_engines(db, raft_db, raft_log_path);
+            dump_raft_log_entry(Debugger::new(engines), &key);
         } else if let Some(matches) = matches.subcommand_matches("region") {
             let skip_tombstone = matches.is_present("skip-tombstone");
             match matches.value_of("region") {
@@ -305,7 +704,7 @@ fn main() {
                 panic!("The region's start pos must greater than the end pos.")
             }
         }
-        dump_range(db, from, to, limit, cf_name, start_ts, commit_ts);
+        dump_range(db, from, to, limit, cf_name, start_ts, commit_ts, decode);
     } else if let Some(matches) = matches.subcommand_matches("mvcc") {
         let cf_name = matches.value_of("cf").unwrap_or(CF_DEFAULT);
         let key = matches.value_of("key").unwrap();
@@ -333,6 +732,60 @@ fn main() {
                 let _ = app.print_help();
             }
         }
+    } else if let Some(matches) = matches.subcommand_matches("unsafe-recover") {
+        if let Some(matches) = matches.subcommand_matches("remove-fail-stores") {
+            let store_ids = parse_u64_values(matches.values_of("stores").unwrap());
+            let region_ids = matches.values_of("regions").map(parse_u64_values);
+            let engines = new_engines(db, raft_db, raft_log_path);
+            remove_fail_stores(Debugger::new(engines), store_ids, region_ids);
+        } else {
+            let _ = app.print_help();
+        }
+    } else if let Some(sub_matches) = matches.subcommand_matches("tombstone") {
+        let region_id = sub_matches.value_of("region").unwrap().parse().unwrap();
+        let pd_client = sub_matches
+            .values_of("pd")
+            .map(|endpoints| new_pd_client(endpoints, &matches));
+        let engines = new_engines(db, raft_db, raft_log_path);
+        set_region_tombstone(Debugger::new(engines), region_id, pd_client);
+    } else if let Some(sub_matches) = matches.subcommand_matches("recreate-region") {
+        let region_id = sub_matches.value_of("region").unwrap().parse().unwrap();
+        let pd_client = new_pd_client(sub_matches.values_of("pd").unwrap(), &matches);
+        let engines = new_engines(db, raft_db, raft_log_path);
+        recreate_region(Debugger::new(engines), region_id, pd_client);
+    } else if let Some(matches) = matches.subcommand_matches("bad-regions") {
+        let engines = new_engines(db, raft_db, raft_log_path);
+        print_bad_regions(Debugger::new(engines), matches.is_present("print-ids"));
+    } else if let Some(matches) = matches.subcommand_matches("compact") {
+        let (db_type, cf, from, to, threads) = parse_compact_args(matches);
+        let engines = new_engines(db, raft_db, raft_log_path);
+        if let Err(e) = Debugger::new(engines).compact(db_type, cf, &from, &to, threads) {
+            perror_and_exit("failed to compact", e);
+        }
+        println!("compaction of cf {} is finished", cf);
+    } else if let Some(matches) = matches.subcommand_matches("region-properties") {
+        let region_id = matches.value_of("region").unwrap().parse().unwrap();
+        let engines = new_engines(db, raft_db, raft_log_path);
+        match Debugger::new(engines).region_properties(region_id) {
+            Ok(props) => print_properties(props),
+            Err(e) => perror_and_exit("failed to get region properties", e),
+        }
+    } else if let Some(matches) = matches.subcommand_matches("dump-region") {
+        let region_id = matches.value_of("region").unwrap().parse().unwrap();
+        let dir = Path::new(matches.value_of("out").unwrap());
+        let engines = new_engines(db, raft_db, raft_log_path);
+        match Debugger::new(engines).dump_region(region_id, dir) {
+            Ok(manifest) => print_region_dump(&manifest),
+            Err(e) => perror_and_exit("failed to dump region", e),
+        }
+    } else if let Some(matches) = matches.subcommand_matches("load-region") {
+        let region_id = matches.value_of("region").unwrap().parse().unwrap();
+        let dir = Path::new(matches.value_of("in").unwrap());
+        let engines = new_engines(db, raft_db, raft_log_path);
+        match Debugger::new(engines).load_region(region_id, dir) {
+            Ok(manifest) => print_region_dump(&manifest),
+            Err(e) => perror_and_exit("failed to load region", e),
+        }
     } else if let Some(matches) = matches.subcommand_matches("diff") {
         let region_id: u64 = matches.value_of("region").unwrap().parse().unwrap();
         let db_path2 = matches.value_of("to").unwrap();
@@ -467,18 +920,794 @@ fn dump_mvcc_write(
     }
 }
 
-fn dump_raw_value(db: DB, cf: &str, key: String) {
+fn perror_and_exit<E: Debug>(prefix: &str, e: E) -> ! {
+    eprintln!("{}: {:?}", prefix, e);
+    process::exit(-1);
+}
+
+fn new_security_mgr(matches: &ArgMatches) -> SecurityManager {
+    let path = |name| matches.value_of(name).unwrap_or("").to_owned();
+    let cfg = SecurityConfig {
+        ca_path: path("ca-path"),
+        cert_path: path("cert-path"),
+        key_path: path("key-path"),
+        cert_allowed_cn: vec![],
+    };
+    if let Err(e) = cfg.validate() {
+        perror_and_exit("invalid security config", e);
+    }
+    SecurityManager::new(&cfg).unwrap_or_else(|e| perror_and_exit("failed to load certificates", e))
+}
+
+fn new_pd_client(endpoints: Values, matches: &ArgMatches) -> RpcClient {
+    let endpoints: Vec<String> = endpoints.map(|e| e.trim().to_owned()).collect();
+    let security_mgr = Arc::new(new_security_mgr(matches));
+    RpcClient::new(&endpoints, security_mgr)
+        .unwrap_or_else(|e| perror_and_exit("failed to connect to PD", e))
+}
+
+// Checks that the store belongs to the cluster of the PD, returns the store id.
+fn check_cluster(debugger: &Debugger, pd_client: &RpcClient) -> u64 {
+    let ident = debugger
+        .get_store_ident()
+        .unwrap_or_else(|e| perror_and_exit("failed to get the store ident", e));
+    let cluster_id = pd_client
+        .get_cluster_id()
+        .unwrap_or_else(|e| perror_and_exit("failed to get the cluster id", e));
+    if ident.get_cluster_id() != cluster_id {
+        perror_and_exit(
+            "cluster mismatch",
+            format!("store in {}, PD in {}", ident.get_cluster_id(), cluster_id),
+        );
+    }
+    ident.get_store_id()
+}
+
+fn new_debug_client(host: &str, matches: &ArgMatches) -> DebugClient {
+    let security_mgr = new_security_mgr(matches);
+    let env = Arc::new(
+        EnvBuilder::new()
+            .cq_count(1)
+            .name_prefix("tikv-ctl-debug")
+            .build(),
+    );
+    let cb = ChannelBuilder::new(env).max_receive_message_len(MAX_GRPC_RECV_MSG_LEN);
+    DebugClient::new(security_mgr.connect(cb, host))
+}
+
+fn is_not_found(e: &GrpcError) -> bool {
+    match *e {
+        GrpcError::RpcFailure(ref status) => status.status == RpcStatusCode::NotFound,
+        _ => false,
+    }
+}
+
+// Runs the subcommand with the debug service of a running tikv-server, returns false if
+// there is no subcommand to run.
+fn run_remote(client: &DebugClient, matches: &ArgMatches) -> bool {
+    let decode = matches.value_of("decode").is_some();
+    if let Some(matches) = matches.subcommand_matches("print") {
+        let cf_name = matches.value_of("cf").unwrap_or(CF_DEFAULT);
+        let key = unescape(matches.value_of("key").unwrap());
+        remote_dump_raw_value(client, cf_name, key, decode);
+    } else if let Some(matches) = matches.subcommand_matches("raft") {
+        if let Some(matches) = matches.subcommand_matches("log") {
+            if let Some((region_id, from, to)) = parse_raft_log_range(matches) {
+                let logs = remote_raft_logs(client, region_id, from, to);
+                dump_raft_logs(region_id, &logs, matches.is_present("json"));
+                return true;
+            }
+            let (region_id, index) = match matches.value_of("key") {
+                None => (
+                    matches.value_of("region").unwrap().parse().unwrap(),
+                    matches.value_of("index").unwrap().parse().unwrap(),
+                ),
+                Some(k) => keys::decode_raft_log_key(&unescape(k)).unwrap(),
+            };
+            remote_dump_raft_log_entry(client, region_id, index);
+        } else if let Some(matches) = matches.subcommand_matches("region") {
+            let region_id = match matches.value_of("region") {
+                Some(id) => id.parse().unwrap(),
+                None => perror_and_exit("raft region", "region id is required with --host"),
+            };
+            remote_dump_region_info(client, region_id, matches.is_present("skip-tombstone"));
+        } else {
+            panic!("Currently only support raft log entry and scan.")
+        }
+    } else if let Some(matches) = matches.subcommand_matches("size") {
+        let region_id = match matches.value_of("region") {
+            Some(id) => id.parse().unwrap(),
+            None => perror_and_exit("size", "region id is required with --host"),
+        };
+        remote_dump_region_size(client, region_id, matches.value_of("cf"));
+    } else if let Some(matches) = matches.subcommand_matches("scan") {
+        // The keys are the same as the local mode, with the data prefix.
+        let origin = |k: Vec<u8>| if keys::validate_data_key(&k) {
+            keys::origin_key(&k).to_vec()
+        } else {
+            k
+        };
+        let from = origin(unescape(matches.value_of("from").unwrap()));
+        let to = matches.value_of("to").map_or_else(Vec::new, |k| origin(unescape(k)));
+        let limit = matches.value_of("limit").map_or(0, |s| s.parse().unwrap());
+        let mut req = ScanMvccRequest::new();
+        req.set_from_key(from);
+        req.set_to_key(to);
+        req.set_limit(limit);
+        for (key, info) in remote_scan_mvcc(client, req) {
+            println!("key: {}", escape(&key));
+            println!("{:?}", info);
+            if decode {
+                println!("decoded key: {}", decode_tidb_key(&key));
+                for value in info.get_values() {
+                    let row = decode_tidb_row(value.get_value());
+                    println!("decoded value at {}: {}", value.get_ts(), row);
+                }
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("mvcc") {
+        let cf_name = matches.value_of("cf").unwrap_or(CF_DEFAULT);
+        let key = matches.value_of("key").unwrap();
+        let encoded_key = if matches.is_present("encoded") {
+            unescape(key)
+        } else {
+            encode_bytes(&unescape(key))
+        };
+        let start_ts = matches.value_of("start_ts").map(|s| s.parse().unwrap());
+        let commit_ts = matches.value_of("commit_ts").map(|s| s.parse().unwrap());
+        println!("You are searching Key {}: ", key);
+        let mut req = ScanMvccRequest::new();
+        req.set_from_key(encoded_key.clone());
+        let infos = remote_scan_mvcc(client, req)
+            .take_while(|&(ref k, _)| k.starts_with(&encoded_key));
+        for (key, info) in infos {
+            remote_dump_mvcc_info(&key, &info, cf_name, start_ts, commit_ts);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("compact") {
+        let (db_type, cf, from, to, threads) = parse_compact_args(matches);
+        let mut req = CompactRequest::new();
+        req.set_db(db_type);
+        req.set_cf(cf.to_owned());
+        req.set_from_key(from);
+        req.set_to_key(to);
+        req.set_threads(threads);
+        if let Err(e) = client.compact(&req) {
+            perror_and_exit("DebugClient::compact", e);
+        }
+        println!("compaction of cf {} is finished", cf);
+    } else if let Some(matches) = matches.subcommand_matches("region-properties") {
+        let mut req = GetRegionPropertiesRequest::new();
+        req.set_region_id(matches.value_of("region").unwrap().parse().unwrap());
+        let mut resp = client
+            .get_region_properties(&req)
+            .unwrap_or_else(|e| perror_and_exit("DebugClient::get_region_properties", e));
+        let props = resp
+            .take_props()
+            .into_iter()
+            .map(|mut p| (p.take_name(), p.take_value()))
+            .collect();
+        print_properties(props);
+    } else if matches.subcommand_matches("unsafe-recover").is_some() ||
+        matches.subcommand_matches("tombstone").is_some() ||
+        matches.subcommand_matches("recreate-region").is_some() ||
+        matches.subcommand_matches("bad-regions").is_some() ||
+        matches.subcommand_matches("dump-region").is_some() ||
+        matches.subcommand_matches("load-region").is_some()
+    {
+        perror_and_exit("--host", "the store must be stopped and opened locally");
+    } else if let Some(matches) = matches.subcommand_matches("backup") {
+        let mut req = BackupRequest::new();
+        req.set_backup_ts(matches.value_of("ts").unwrap().parse().unwrap());
+        req.set_start_key(unescape(matches.value_of("start").unwrap()));
+        req.set_end_key(unescape(matches.value_of("end").unwrap()));
+        req.set_path(matches.value_of("path").unwrap().to_owned());
+        remote_backup(client, req);
+    } else if let Some(matches) = matches.subcommand_matches("restore") {
+        let mut req = RestoreRequest::new();
+        req.set_path(matches.value_of("path").unwrap().to_owned());
+        req.set_old_prefix(unescape(matches.value_of("old-prefix").unwrap()));
+        req.set_new_prefix(unescape(matches.value_of("new-prefix").unwrap()));
+        req.set_restore_ts(matches.value_of("ts").unwrap().parse().unwrap());
+        remote_restore(client, req);
+    } else if matches.subcommand_matches("diff").is_some() {
+        perror_and_exit("diff", "diff is not supported with --host");
+    } else {
+        return false;
+    }
+    true
+}
+
+fn remote_dump_raw_value(client: &DebugClient, cf: &str, key: Vec<u8>, decode: bool) {
+    let mut req = GetRequest::new();
+    req.set_db(DBType::KV);
+    req.set_cf(cf.to_owned());
+    req.set_key(key);
+    match client.get(&req) {
+        Ok(mut resp) => {
+            let value = resp.take_value();
+            println!("value: {}", escape(&value));
+            if decode {
+                println!("decoded key: {}", decode_tidb_key(req.get_key()));
+                println!("decoded value: {}", decode_tidb_value(cf, &value));
+            }
+        }
+        Err(ref e) if is_not_found(e) => println!("value: None"),
+        Err(e) => perror_and_exit("DebugClient::get", e),
+    }
+}
+
+fn remote_dump_raft_log_entry(client: &DebugClient, region_id: u64, index: u64) {
+    let idx_key = keys::raft_log_key(region_id, index);
+    println!("idx_key: {}", escape(&idx_key));
+    println!("region: {}", region_id);
+    println!("log index: {}", index);
+    let mut req = RaftLogRequest::new();
+    req.set_region_id(region_id);
+    req.set_log_index(index);
+    let mut ent = client
+        .raft_log(&req)
+        .unwrap_or_else(|e| perror_and_exit("DebugClient::raft_log", e))
+        .take_entry();
+    let data = ent.take_data();
+    println!("entry {:?}", ent);
+    let mut msg = RaftCmdRequest::new();
+    msg.merge_from_bytes(&data).unwrap();
+    println!("msg len: {}", data.len());
+    println!("{:?}", msg);
+}
+
+fn remote_raft_logs(client: &DebugClient, region_id: u64, from: u64, to: u64) -> RaftLogs {
+    let mut req = RegionInfoRequest::new();
+    req.set_region_id(region_id);
+    let resp = client
+        .region_info(&req)
+        .unwrap_or_else(|e| perror_and_exit("DebugClient::region_info", e));
+    if !resp.has_raft_local_state() || !resp.has_raft_apply_state() {
+        perror_and_exit("raft log", "raft states are not found");
+    }
+    let mut logs = RaftLogs::new(
+        resp.get_raft_local_state(),
+        resp.get_raft_apply_state(),
+        from,
+        to,
+    ).unwrap_or_else(|e| perror_and_exit("raft log", e));
+    for index in logs.from..logs.to + 1 {
+        let mut req = RaftLogRequest::new();
+        req.set_region_id(region_id);
+        req.set_log_index(index);
+        match client.raft_log(&req) {
+            Ok(mut resp) => logs.entries.push(resp.take_entry()),
+            Err(ref e) if is_not_found(e) => {}
+            Err(e) => perror_and_exit("DebugClient::raft_log", e),
+        }
+    }
+    logs
+}
+
+fn remote_dump_region_info(client: &DebugClient, region_id: u64, skip_tombstone: bool) {
+    let mut req = RegionInfoRequest::new();
+    req.set_region_id(region_id);
+    let resp = match client.region_info(&req) {
+        Ok(resp) => resp,
+        Err(ref e) if is_not_found(e) => {
+            println!("region {} not found", region_id);
+            return;
+        }
+        Err(e) => perror_and_exit("DebugClient::region_info", e),
+    };
+    let region_state = if resp.has_region_local_state() {
+        Some(resp.get_region_local_state())
+    } else {
+        None
+    };
+    if skip_tombstone && region_state.map_or(false, |s| s.get_state() == PeerState::Tombstone) {
+        return;
+    }
+    println!("region state key: {}", escape(&keys::region_state_key(region_id)));
+    println!("region state: {:?}", region_state);
+    println!("raft state key: {}", escape(&keys::raft_state_key(region_id)));
+    if resp.has_raft_local_state() {
+        println!("raft state: {:?}", Some(resp.get_raft_local_state()));
+    } else {
+        println!("raft state: None");
+    }
+    println!("apply state key: {}", escape(&keys::apply_state_key(region_id)));
+    if resp.has_raft_apply_state() {
+        println!("apply state: {:?}", Some(resp.get_raft_apply_state()));
+    } else {
+        println!("apply state: None");
+    }
+}
+
+fn remote_dump_region_size(client: &DebugClient, region_id: u64, cf: Option<&str>) {
+    println!("region id: {}", region_id);
+    if let Some(cf_name) = cf {
+        println!("cf_name: {}", cf_name);
+    }
+    let cfs = match cf {
+        Some(s) => vec![s.to_owned()],
+        None => vec![CF_DEFAULT.to_owned(), CF_WRITE.to_owned(), CF_LOCK.to_owned()],
+    };
+    let mut req = RegionSizeRequest::new();
+    req.set_region_id(region_id);
+    req.set_cfs(cfs.into());
+    let resp = client
+        .region_size(&req)
+        .unwrap_or_else(|e| perror_and_exit("DebugClient::region_size", e));
+    let size = resp.get_entries().iter().map(|e| e.get_size()).sum();
+    println!("region size: {}", convert_gbmb(size));
+}
+
+fn remote_backup(client: &DebugClient, req: BackupRequest) {
+    let resps = client.backup(&req).wait();
+    for resp in resps {
+        let resp = resp.unwrap_or_else(|e| perror_and_exit("DebugClient::backup", e));
+        if !resp.get_error().is_empty() {
+            perror_and_exit("backup", resp.get_error());
+        }
+        if resp.get_not_leader() {
+            println!(
+                "[{}/{}] region: {} is skipped, it's backed up by its leader",
+                resp.get_done(),
+                resp.get_total(),
+                resp.get_region_id()
+            );
+            continue;
+        }
+        println!(
+            "[{}/{}] region: {}, range: [{}, {}), kvs: {}, size: {}",
+            resp.get_done(),
+            resp.get_total(),
+            resp.get_region_id(),
+            escape(resp.get_start_key()),
+            escape(resp.get_end_key()),
+            resp.get_kvs(),
+            resp.get_size()
+        );
+    }
+    println!("backup at {} is finished", req.get_backup_ts());
+}
+
+fn remote_restore(client: &DebugClient, req: RestoreRequest) {
+    let resps = client.restore(&req).wait();
+    for resp in resps {
+        let resp = resp.unwrap_or_else(|e| perror_and_exit("DebugClient::restore", e));
+        if !resp.get_error().is_empty() {
+            perror_and_exit("restore", resp.get_error());
+        }
+        println!(
+            "[{}/{}] region: {}, kvs: {}, size: {}",
+            resp.get_done(),
+            resp.get_total(),
+            resp.get_region_id(),
+            resp.get_kvs(),
+            resp.get_size()
+        );
+    }
+    println!("restore from {} is finished", req.get_path());
+}
+
+fn check_backup(dir: &Path) {
+    let errors = backup::check_backup(dir).unwrap_or_else(|e| perror_and_exit("check-backup", e));
+    if errors.is_empty() {
+        println!("backup in {} is complete", dir.display());
+        return;
+    }
+    for e in &errors {
+        println!("{}", e);
+    }
+    process::exit(1);
+}
+
+fn remote_scan_mvcc(
+    client: &DebugClient,
+    req: ScanMvccRequest,
+) -> Box<Iterator<Item = (Vec<u8>, MvccInfo)>> {
+    let stream = client
+        .scan_mvcc(&req)
+        .map(|mut resp| (resp.take_key(), resp.take_info()));
+    Box::new(stream.wait().map(|res| {
+        res.unwrap_or_else(|e| perror_and_exit("DebugClient::scan_mvcc", e))
+    }))
+}
+
+fn remote_dump_mvcc_info(
+    key: &[u8],
+    info: &MvccInfo,
+    cf: &str,
+    start_ts: Option<u64>,
+    commit_ts: Option<u64>,
+) {
+    let all = cf == "all";
+    if all || cf == CF_DEFAULT {
+        for value in info.get_values() {
+            if start_ts.is_none() || start_ts.unwrap() == value.get_ts() {
+                println!("Key: {:?}", escape(key));
+                println!("Value: {:?}", escape(value.get_value()));
+                println!("Start_ts: {:?}", value.get_ts());
+                println!("");
+            }
+        }
+    }
+    if (all || cf == CF_LOCK) && info.has_lock() {
+        let lock = info.get_lock();
+        if start_ts.is_none() || start_ts.unwrap() == lock.get_lock_version() {
+            println!("Key: {:?}", escape(key));
+            println!("Primary: {:?}", escape(lock.get_primary_lock()));
+            println!("Start_ts: {:?}", lock.get_lock_version());
+            println!("");
+        }
+    }
+    if all || cf == CF_WRITE {
+        for write in info.get_writes() {
+            if (start_ts.is_none() || start_ts.unwrap() == write.get_start_ts()) &&
+                (commit_ts.is_none() || commit_ts.unwrap() == write.get_commit_ts())
+            {
+                println!("Key: {:?}", escape(key));
+                println!("Type: {:?}", write.get_field_type());
+                println!("Start_ts: {:?}", write.get_start_ts());
+                println!("Commit_ts: {:?}", write.get_commit_ts());
+                println!("");
+            }
+        }
+    }
+}
+
+fn parse_u64_values(values: Values) -> Vec<u64> {
+    values
+        .map(|v| {
+            v.trim()
+                .parse()
+                .unwrap_or_else(|e| perror_and_exit(&format!("invalid id {:?}", v), e))
+        })
+        .collect()
+}
+
+fn remove_fail_stores(debugger: Debugger, store_ids: Vec<u64>, region_ids: Option<Vec<u64>>) {
+    println!("removing stores {:?} from the regions", store_ids);
+    let changes = debugger
+        .remove_failed_stores(store_ids, region_ids)
+        .unwrap_or_else(|e| perror_and_exit("remove failed stores", e));
+    for &(ref before, ref after) in &changes {
+        let stores = |r: &Region| -> Vec<u64> {
+            r.get_peers().iter().map(|p| p.get_store_id()).collect()
+        };
+        println!("region {}:", before.get_id());
+        println!(
+            "  before: stores {:?}, conf version {}",
+            stores(before),
+            before.get_region_epoch().get_conf_ver()
+        );
+        println!(
+            "  after: stores {:?}, conf version {}",
+            stores(after),
+            after.get_region_epoch().get_conf_ver()
+        );
+    }
+    println!("{} regions are changed", changes.len());
+}
+
+fn set_region_tombstone(debugger: Debugger, region_id: u64, pd_client: Option<RpcClient>) {
+    let pd_region = pd_client.map(|pd_client| {
+        check_cluster(&debugger, &pd_client);
+        match pd_client.get_region_by_id(region_id).wait() {
+            Ok(Some(region)) => region,
+            Ok(None) => perror_and_exit("tombstone", "region is not found on PD"),
+            Err(e) => perror_and_exit("failed to get the region from PD", e),
+        }
+    });
+    match debugger.set_region_tombstone(region_id, pd_region) {
+        Ok(region) => println!("region {:?} is set to tombstone", region),
+        Err(e) => perror_and_exit("failed to set tombstone", e),
+    }
+}
+
+fn recreate_region(debugger: Debugger, region_id: u64, pd_client: RpcClient) {
+    let store_id = check_cluster(&debugger, &pd_client);
+    let mut region = match pd_client.get_region_by_id(region_id).wait() {
+        Ok(Some(region)) => region,
+        Ok(None) => perror_and_exit("recreate-region", "region is not found on PD"),
+        Err(e) => perror_and_exit("failed to get the region from PD", e),
+    };
+    let alloc_id = || {
+        pd_client
+            .alloc_id()
+            .unwrap_or_else(|e| perror_and_exit("failed to allocate id", e))
+    };
+    let new_region_id = alloc_id();
+    let new_peer_id = alloc_id();
+    // A newer version makes PD take the new region for the range.
+    let version = region.get_region_epoch().get_version() + 1;
+    region.set_id(new_region_id);
+    region.mut_region_epoch().set_version(version);
+    region.mut_region_epoch().set_conf_ver(1);
+    region.mut_peers().clear();
+    let mut peer = Peer::new();
+    peer.set_id(new_peer_id);
+    peer.set_store_id(store_id);
+    region.mut_peers().push(peer);
+    println!("recreating region {:?}", region);
+    match debugger.recreate_region(region) {
+        Ok(()) => println!("region {} is recreated", new_region_id),
+        Err(e) => perror_and_exit("failed to recreate region", e),
+    }
+}
+
+fn print_bad_regions(debugger: Debugger, print_ids: bool) {
+    let bad_regions = debugger
+        .bad_regions()
+        .unwrap_or_else(|e| perror_and_exit("failed to check regions", e));
+    for &(region_id, ref reason) in &bad_regions {
+        println!("region {}: {}", region_id, reason);
+    }
+    // The reasons are ordered by region id.
+    let mut ids: Vec<_> = bad_regions.iter().map(|&(id, _)| id.to_string()).collect();
+    ids.dedup();
+    println!("{} bad regions are found", ids.len());
+    if print_ids {
+        println!("{}", ids.join(","));
+    }
+}
+
+fn parse_compact_args<'a>(matches: &'a ArgMatches) -> (DBType, &'a str, Vec<u8>, Vec<u8>, u32) {
+    let db_type = if matches.value_of("db").unwrap() == "raft" {
+        DBType::RAFT
+    } else {
+        DBType::KV
+    };
+    let cf = matches.value_of("cf").unwrap();
+    let from = matches.value_of("from").map_or_else(Vec::new, unescape);
+    let to = matches.value_of("to").map_or_else(Vec::new, unescape);
+    let threads = matches.value_of("threads").unwrap().parse().unwrap();
+    (db_type, cf, from, to, threads)
+}
+
+fn print_region_dump(manifest: &RegionDumpManifest) {
+    println!(
+        "region: {}, range: [{}, {}), conf_ver: {}, version: {}",
+        manifest.region_id,
+        manifest.start_key,
+        manifest.end_key,
+        manifest.conf_ver,
+        manifest.version
+    );
+    for f in &manifest.files {
+        println!(
+            "cf: {}, file: {}, kvs: {}, keys: [{}, {}], size: {}, checksum: {}",
+            f.cf,
+            f.name,
+            f.kvs,
+            f.smallest_key,
+            f.largest_key,
+            f.size,
+            f.checksum
+        );
+    }
+}
+
+fn print_properties(props: Vec<(String, String)>) {
+    for (name, value) in props {
+        println!("{}: {}", name, value);
+    }
+}
+
+// Encodes a record key, or the start of the records or the index, as a key in the kv db.
+fn encode_tidb_key(table_id: i64, handle: Option<i64>, index_id: Option<i64>) -> Vec<u8> {
+    let key = match (handle, index_id) {
+        (_, Some(index_id)) => table::encode_index_seek_key(table_id, index_id, &[]),
+        (Some(handle), None) => {
+            let mut encoded = vec![];
+            encoded.encode_i64(handle).unwrap();
+            table::encode_row_key(table_id, &encoded)
+        }
+        (None, None) => table::encode_row_key(table_id, &[]),
+    };
+    keys::data_key(Key::from_raw(&key).encoded())
+}
+
+// Decodes a key in the kv db as a TiDB table key. The data prefix and the timestamp are
+// optional, and the key may be not encoded.
+fn decode_tidb_key(key: &[u8]) -> String {
+    let key = if keys::validate_data_key(key) {
+        keys::origin_key(key)
+    } else {
+        key
+    };
+    let raw_key = Key::from_encoded(key.to_vec())
+        .raw()
+        .unwrap_or_else(|_| key.to_vec());
+    match table::decode_table_key(&raw_key) {
+        Ok(table_key) => table_key.to_string(),
+        Err(e) => format!("not a table key: {:?}", e),
+    }
+}
+
+fn decode_tidb_row(value: &[u8]) -> String {
+    match table::decode_row(value) {
+        Ok(row) => {
+            let cols: Vec<_> = row.iter()
+                .map(|&(id, ref datum)| format!("{}: {}", id, datum))
+                .collect();
+            format!("{{{}}}", cols.join(", "))
+        }
+        Err(e) => format!("not a row: {:?}", e),
+    }
+}
+
+// Decodes a value in the column family as a TiDB row.
+fn decode_tidb_value(cf: &str, value: &[u8]) -> String {
+    match cf {
+        CF_DEFAULT => decode_tidb_row(value),
+        CF_WRITE => match Write::parse(value) {
+            Ok(Write {
+                short_value: Some(ref v),
+                ..
+            }) => decode_tidb_row(v),
+            Ok(write) => format!("{:?} without short value", write.write_type),
+            Err(e) => format!("not a write: {:?}", e),
+        },
+        CF_LOCK => match Lock::parse(value) {
+            Ok(lock) => format!("{:?}", lock),
+            Err(e) => format!("not a lock: {:?}", e),
+        },
+        _ => "not a row".to_owned(),
+    }
+}
+
+// Returns the region and the range of raft log to check, `None` if an entry is specified.
+fn parse_raft_log_range(matches: &ArgMatches) -> Option<(u64, u64, u64)> {
+    if matches.is_present("index") || matches.is_present("key") {
+        return None;
+    }
+    let region_id = match matches.value_of("region") {
+        Some(id) => id.parse().unwrap(),
+        None => perror_and_exit("raft log", "region id is required"),
+    };
+    let from = matches.value_of("from").map_or(0, |s| s.parse().unwrap());
+    let to = matches.value_of("to").map_or(0, |s| s.parse().unwrap());
+    Some((region_id, from, to))
+}
+
+// Describes the commands in a raft log entry.
+fn describe_entry(entry: &Entry) -> Vec<String> {
+    let data = entry.get_data();
+    match entry.get_entry_type() {
+        EntryType::EntryNormal if data.is_empty() => vec!["empty entry".to_owned()],
+        EntryType::EntryNormal => {
+            let mut msg = RaftCmdRequest::new();
+            if let Err(e) = msg.merge_from_bytes(data) {
+                return vec![format!("invalid command: {:?}", e)];
+            }
+            if msg.has_admin_request() {
+                return vec![format!("admin {:?}", msg.get_admin_request())];
+            }
+            msg.get_requests()
+                .iter()
+                .map(|req| match req.get_cmd_type() {
+                    CmdType::Put => {
+                        let put = req.get_put();
+                        format!(
+                            "put cf: {:?}, key: {}, value: {}",
+                            put.get_cf(),
+                            escape(put.get_key()),
+                            escape(put.get_value())
+                        )
+                    }
+                    CmdType::Delete => {
+                        let delete = req.get_delete();
+                        format!(
+                            "delete cf: {:?}, key: {}",
+                            delete.get_cf(),
+                            escape(delete.get_key())
+                        )
+                    }
+                    _ => format!("{:?}", req),
+                })
+                .collect()
+        }
+        EntryType::EntryConfChange => {
+            let mut cc = ConfChange::new();
+            if let Err(e) = cc.merge_from_bytes(data) {
+                return vec![format!("invalid conf change: {:?}", e)];
+            }
+            vec![
+                format!(
+                    "conf change {:?}, node: {}",
+                    cc.get_change_type(),
+                    cc.get_node_id()
+                ),
+            ]
+        }
+    }
+}
+
+fn dump_raft_logs(region_id: u64, logs: &RaftLogs, json: bool) {
+    let errors = logs.check();
+    if json {
+        let entries: Vec<_> = logs.entries
+            .iter()
+            .map(|e| {
+                json!({
+                    "index": e.get_index(),
+                    "term": e.get_term(),
+                    "type": format!("{:?}", e.get_entry_type()),
+                    "commands": describe_entry(e),
+                })
+            })
+            .collect();
+        let res = json!({
+            "region_id": region_id,
+            "truncated_index": logs.truncated_index,
+            "last_index": logs.last_index,
+            "entries": entries,
+            "errors": errors,
+        });
+        println!("{}", serde_json::to_string_pretty(&res).unwrap());
+        return;
+    }
+    println!(
+        "region: {}, truncated index: {}, last index: {}",
+        region_id,
+        logs.truncated_index,
+        logs.last_index
+    );
+    for e in &logs.entries {
+        println!(
+            "index: {}, term: {}, type: {:?}",
+            e.get_index(),
+            e.get_term(),
+            e.get_entry_type()
+        );
+        for cmd in describe_entry(e) {
+            println!("  {}", cmd);
+        }
+    }
+    if errors.is_empty() {
+        println!("raft log in [{}, {}] is consistent", logs.from, logs.to);
+    }
+    for e in &errors {
+        println!("error: {}", e);
+    }
+}
+
+fn dump_raw_value(db: DB, cf: &str, key: String, decode: bool) {
     let key = unescape(&key);
     let value = db.get_value_cf(cf, &key).unwrap();
-    println!("value: {}", value.map_or("None".to_owned(), |v| escape(&v)));
+    println!("value: {}", value.as_ref().map_or("None".to_owned(), |v| escape(v)));
+    if decode {
+        println!("decoded key: {}", decode_tidb_key(&key));
+        if let Some(v) = value {
+            println!("decoded value: {}", decode_tidb_value(cf, &v));
+        }
+    }
+}
+
+// Opens the raft log engine in `raft_log_path` if it's given, so that raft logs are
+// read from it instead of raftdb.
+fn new_engines(db: DB, raft_db: DB, raft_log_path: Option<&str>) -> Engines {
+    let engines = Engines::new(Arc::new(db), Arc::new(raft_db));
+    let path = match raft_log_path {
+        Some(path) => path,
+        None => return engines,
+    };
+    let cfg = RaftLogEngineConfig {
+        enabled: true,
+        dir: path.to_owned(),
+        ..RaftLogEngineConfig::default()
+    };
+    let raft_log_engine = RaftLogEngine::open(cfg)
+        .unwrap_or_else(|e| perror_and_exit("failed to open raft log engine", e));
+    engines.with_raft_log_engine(Arc::new(raft_log_engine))
 }
 
-fn dump_raft_log_entry(raft_db: DB, idx_key: &[u8]) {
+fn dump_raft_log_entry(debugger: Debugger, idx_key: &[u8]) {
     let (region_id, idx) = keys::decode_raft_log_key(idx_key).unwrap();
     println!("idx_key: {}", escape(idx_key));
     println!("region: {}", region_id);
     println!("log index: {}", idx);
-    let mut ent: Entry = raft_db.get_msg(idx_key).unwrap().unwrap();
+    let mut ent = debugger
+        .raft_log(region_id, idx)
+        .unwrap_or_else(|e| perror_and_exit("failed to get raft log", e));
     let data = ent.take_data();
     println!("entry {:?}", ent);
     let mut msg = RaftCmdRequest::new();
@@ -705,6 +1934,7 @@ fn dump_range(
     cf: &str,
     start_ts: Option<u64>,
     commit_ts: Option<u64>,
+    decode: bool,
 ) {
     let from = unescape(&from);
     let to = to.map_or_else(|| vec![0xff], |s| unescape(&s));
@@ -737,6 +1967,10 @@ fn dump_range(
         if right_key {
             println!("key: {}, value len: {}", escape(k), v.len());
             println!("{}", escape(v));
+            if decode {
+                println!("decoded key: {}", decode_tidb_key(k));
+                println!("decoded value: {}", decode_tidb_value(cf, v));
+            }
             cnt += 1;
         }
         Ok(cnt < limit)
@@ -869,4 +2103,19 @@ mod tests {
         }
         assert_eq!(test_iter.len(), 0);
     }
+
+    #[test]
+    fn test_tidb_key_codec() {
+        let key = encode_tidb_key(1, Some(-10), None);
+        assert!(keys::validate_data_key(&key));
+        assert_eq!(decode_tidb_key(&key), "table_id: 1, handle: -10");
+        let key_with_ts = Key::from_encoded(keys::origin_key(&key).to_vec()).append_ts(5);
+        assert_eq!(
+            decode_tidb_key(key_with_ts.encoded()),
+            "table_id: 1, handle: -10"
+        );
+        assert!(encode_tidb_key(1, None, None) < key);
+        assert!(encode_tidb_key(1, None, Some(2)) < encode_tidb_key(1, None, None));
+        assert!(decode_tidb_key(b"zmeta").starts_with("not a table key"));
+    }
 }
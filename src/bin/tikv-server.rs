@@ -46,8 +46,6 @@ extern crate tempdir;
 extern crate grpcio as grpc;
 
 mod signal_handler;
-#[cfg(unix)]
-mod profiling;
 
 use std::error::Error;
 use std::process;
@@ -62,18 +60,24 @@ use std::time::Duration;
 use clap::{App, Arg, ArgMatches};
 use fs2::FileExt;
 
-use tikv::config::{MetricConfig, TiKvConfig};
+use tikv::config::{ConfigManager, CoprocessorConfigHandler, DbConfigHandler, MetricConfig,
+                   RaftstoreConfigHandler, ResourceGroupConfigHandler, StorageConfigHandler,
+                   TiKvConfig};
 use tikv::util::{self, panic_hook, rocksdb as rocksdb_util};
 use tikv::util::collections::HashMap;
-use tikv::util::logger::{self, StderrLogger};
+use tikv::util::logger::{self, LogWriter, StderrLogger};
 use tikv::util::file_log::RotatingFileLogger;
+use tikv::util::io_limiter;
+use tikv::util::resource_group::ResourceGroupManager;
+use tikv::util::security::SecurityManager;
 use tikv::util::transport::SendCh;
 use tikv::storage::DEFAULT_ROCKSDB_SUB_DIR;
-use tikv::server::{create_raft_storage, Node, Server, DEFAULT_CLUSTER_ID};
+use tikv::server::{create_raft_storage, Node, Server, StatusServer, DEFAULT_CLUSTER_ID};
 use tikv::server::transport::ServerRaftStoreRouter;
 use tikv::server::resolve;
-use tikv::raftstore::store::{self, Engines, SnapManager};
+use tikv::raftstore::store::{self, Engines, RaftLogEngine, SnapManagerBuilder};
 use tikv::pd::{PdClient, RpcClient};
+use tikv::import::SSTImporter;
 use tikv::util::time::Monitor;
 use tikv::util::rocksdb::metrics_flusher::{MetricsFlusher, DEFAULT_FLUSER_INTERVAL};
 
@@ -86,26 +90,34 @@ macro_rules! fatal {
     })
 }
 
+fn new_file_logger(path: &str, config: &TiKvConfig) -> RotatingFileLogger {
+    RotatingFileLogger::with_rotation(path, config.log_rotation()).unwrap_or_else(|e| {
+        eprintln!("failed to initial log with file {:?}: {:?}", path, e);
+        process::exit(-1);
+    })
+}
+
 fn init_log(config: &TiKvConfig) {
-    if config.log_file.is_empty() {
-        logger::init_log(StderrLogger, config.log_level).unwrap_or_else(|e| {
-            eprintln!("failed to initial log: {:?}", e);
-            process::exit(-1);
-        });
+    let slow_writer: Option<Box<LogWriter + Sync + Send>> = if config.slow_log_file.is_empty() {
+        None
     } else {
-        let w = RotatingFileLogger::new(&config.log_file).unwrap_or_else(|e| {
-            eprintln!(
-                "failed to initial log with file {:?}: {:?}",
-                config.log_file,
-                e
-            );
-            process::exit(-1);
-        });
-        logger::init_log(w, config.log_level).unwrap_or_else(|e| {
-            eprintln!("failed to initial log: {:?}", e);
-            process::exit(-1);
-        });
-    }
+        Some(Box::new(new_file_logger(&config.slow_log_file, config)))
+    };
+    let res = if config.log_file.is_empty() {
+        logger::init_log_with_slow_writer(
+            StderrLogger,
+            slow_writer,
+            config.log_level,
+            config.log_format,
+        )
+    } else {
+        let w = new_file_logger(&config.log_file, config);
+        logger::init_log_with_slow_writer(w, slow_writer, config.log_level, config.log_format)
+    };
+    res.unwrap_or_else(|e| {
+        eprintln!("failed to initial log: {:?}", e);
+        process::exit(-1);
+    });
 }
 
 fn initial_metric(cfg: &MetricConfig, node_id: Option<u64>) {
@@ -143,11 +155,17 @@ fn check_system_config(config: &TiKvConfig) {
     }
 }
 
-fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig) {
+fn run_raft_server(
+    pd_client: RpcClient,
+    cfg: &TiKvConfig,
+    security_mgr: Arc<SecurityManager>,
+    reload_config: &Fn() -> Result<TiKvConfig, Box<Error>>,
+) {
     let store_path = Path::new(&cfg.storage.data_dir);
     let lock_path = store_path.join(Path::new("LOCK"));
     let db_path = store_path.join(Path::new(DEFAULT_ROCKSDB_SUB_DIR));
     let snap_path = store_path.join(Path::new("snap"));
+    let import_path = store_path.join(Path::new("import"));
     let raft_db_path = Path::new(&cfg.raft_store.raftdb_path);
 
     let f = File::create(lock_path.as_path()).unwrap_or_else(|e| {
@@ -160,6 +178,8 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig) {
         );
     }
 
+    io_limiter::set_background_io_rate_limit(cfg.rocksdb.background_io_rate_limit.0);
+
     // Initialize raftstore channels.
     let mut event_loop = store::create_event_loop(&cfg.raft_store)
         .unwrap_or_else(|e| fatal!("failed to create event loop: {:?}", e));
@@ -176,15 +196,46 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig) {
     );
     let mut storage = create_raft_storage(raft_router.clone(), kv_engine.clone(), &cfg.storage)
         .unwrap_or_else(|e| fatal!("failed to create raft stroage: {:?}", e));
+    // Set before the storage is cloned, the coprocessor shares the groups with it.
+    let resource_groups = ResourceGroupManager::new(&cfg.resource_group);
+    storage.set_resource_groups(resource_groups.clone());
 
     // Create pd client, snapshot manager, server.
     let pd_client = Arc::new(pd_client);
     let (mut worker, resolver) = resolve::new_resolver(pd_client.clone())
         .unwrap_or_else(|e| fatal!("failed to start address resolver: {:?}", e));
-    let snap_mgr = SnapManager::new(
-        snap_path.as_path().to_str().unwrap().to_owned(),
-        Some(store_sendch),
+    let snap_mgr = SnapManagerBuilder::default()
+        .max_send_bytes_per_sec(cfg.server.snap_max_send_bytes_per_sec.0)
+        .max_recv_bytes_per_sec(cfg.server.snap_max_recv_bytes_per_sec.0)
+        .max_concurrent_send(cfg.server.concurrent_send_snap_limit)
+        .max_concurrent_recv(cfg.server.concurrent_recv_snap_limit)
+        .compress_plain_file(cfg.server.snap_compression)
+        .sst_lock_cf(cfg.server.snap_sst_lock_cf)
+        .build(
+            snap_path.as_path().to_str().unwrap().to_owned(),
+            Some(store_sendch),
+        );
+
+    // Create raft engine.
+    let raft_db_opts = cfg.raftdb.build_opt();
+    let raft_db_cf_opts = cfg.raftdb.build_cf_opts();
+    let raft_engine = Arc::new(
+        rocksdb_util::new_engine_opt(
+            raft_db_path.to_str().unwrap(),
+            raft_db_opts,
+            raft_db_cf_opts,
+        ).unwrap_or_else(|s| fatal!("failed to create raft engine: {:?}", s)),
     );
+    let mut engines = Engines::new(kv_engine.clone(), raft_engine.clone());
+    if cfg.raft_log_engine.enabled {
+        let raft_log_engine = RaftLogEngine::open(cfg.raft_log_engine.clone())
+            .unwrap_or_else(|e| fatal!("failed to open raft log engine: {:?}", e));
+        engines = engines.with_raft_log_engine(Arc::new(raft_log_engine));
+    }
+    let importer = SSTImporter::new(&import_path)
+        .unwrap_or_else(|e| fatal!("failed to create sst importer: {:?}", e));
+    engines = engines.with_sst_importer(Arc::new(importer));
+
     let mut server = Server::new(
         &cfg.server,
         cfg.raft_store.region_split_size.0 as usize,
@@ -193,22 +244,25 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig) {
         snap_status_sender,
         resolver,
         snap_mgr.clone(),
+        security_mgr,
+        Some(engines.clone()),
     ).unwrap_or_else(|e| fatal!("failed to create server: {:?}", e));
     let trans = server.transport();
 
-    // Create raft engine.
-    let raft_db_opts = cfg.raftdb.build_opt();
-    let raft_db_cf_opts = cfg.raftdb.build_cf_opts();
-    let raft_engine = Arc::new(
-        rocksdb_util::new_engine_opt(
-            raft_db_path.to_str().unwrap(),
-            raft_db_opts,
-            raft_db_cf_opts,
-        ).unwrap_or_else(|s| fatal!("failed to create raft engine: {:?}", s)),
-    );
+    // Start the status server before the node, so it reports the store as not
+    // ready during bootstrap.
+    let mut status_server = StatusServer::new();
+    if !cfg.server.status_addr.is_empty() {
+        status_server
+            .set_config(cfg)
+            .unwrap_or_else(|e| fatal!("failed to dump config: {:?}", e));
+        status_server
+            .start(&cfg.server.status_addr)
+            .unwrap_or_else(|e| fatal!("failed to start status server: {:?}", e));
+    }
+
     // Create node.
     let mut node = Node::new(&mut event_loop, &cfg.server, &cfg.raft_store, pd_client);
-    let engines = Engines::new(kv_engine.clone(), raft_engine.clone());
     node.start(
         event_loop,
         engines.clone(),
@@ -217,6 +271,7 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig) {
         snap_status_receiver,
     ).unwrap_or_else(|e| fatal!("failed to start node: {:?}", e));
     initial_metric(&cfg.metric, Some(node.id()));
+    status_server.set_store_id(node.id());
 
     // Start storage.
     info!("start storage");
@@ -238,7 +293,30 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig) {
     server
         .start(&cfg.server)
         .unwrap_or_else(|e| fatal!("failed to start server: {:?}", e));
-    signal_handler::handle_signal(engines, &cfg.rocksdb.backup_dir);
+
+    let mut cfg_manager = ConfigManager::new(cfg.clone());
+    cfg_manager.register(Box::new(RaftstoreConfigHandler::new(node.get_sendch())));
+    cfg_manager.register(Box::new(StorageConfigHandler::new(storage.clone())));
+    cfg_manager.register(Box::new(
+        CoprocessorConfigHandler::new(server.end_point_scheduler()),
+    ));
+    cfg_manager.register(Box::new(DbConfigHandler::new(kv_engine)));
+    cfg_manager.register(Box::new(ResourceGroupConfigHandler::new(resource_groups)));
+    {
+        let mut on_reload = || {
+            let res = reload_config().and_then(|c| cfg_manager.reload(c));
+            match res {
+                Ok(changed) => {
+                    info!("config reloaded, changed fields: {:?}", changed);
+                    if let Err(e) = status_server.set_config(cfg_manager.config()) {
+                        error!("failed to dump config: {:?}", e);
+                    }
+                }
+                Err(e) => error!("failed to reload config: {:?}", e),
+            }
+        };
+        signal_handler::handle_signal(engines, &cfg.rocksdb.backup_dir, &mut on_reload);
+    }
 
     // Stop.
     server
@@ -246,6 +324,7 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig) {
         .unwrap_or_else(|e| fatal!("failed to stop server: {:?}", e));
 
     metrics_flusher.stop();
+    status_server.stop();
 
     node.stop()
         .unwrap_or_else(|e| fatal!("failed to stop node: {:?}", e));
@@ -254,6 +333,14 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig) {
     }
 }
 
+fn load_config_file(path: &str) -> Result<TiKvConfig, Box<Error>> {
+    let mut f = try!(File::open(path));
+    let mut s = String::new();
+    try!(f.read_to_string(&mut s));
+    let c = try!(toml::from_str(&s));
+    Ok(c)
+}
+
 fn overwrite_config_with_cmd_args(config: &mut TiKvConfig, matches: &ArgMatches) {
     if let Some(level) = matches.value_of("log-level") {
         config.log_level = logger::get_level_by_string(level);
@@ -271,6 +358,10 @@ fn overwrite_config_with_cmd_args(config: &mut TiKvConfig, matches: &ArgMatches)
         config.server.advertise_addr = advertise_addr.to_owned();
     }
 
+    if let Some(status_addr) = matches.value_of("status-addr") {
+        config.server.status_addr = status_addr.to_owned();
+    }
+
     if let Some(data_dir) = matches.value_of("data-dir") {
         config.storage.data_dir = data_dir.to_owned();
     }
@@ -356,6 +447,13 @@ fn main() {
                 .value_name("IP:PORT")
                 .help("Sets advertise listening address for client communication"),
         )
+        .arg(
+            Arg::with_name("status-addr")
+                .long("status-addr")
+                .takes_value(true)
+                .value_name("IP:PORT")
+                .help("Sets HTTP listening address for the status server"),
+        )
         .arg(
             Arg::with_name("log-level")
                 .short("L")
@@ -437,18 +535,10 @@ fn main() {
     let mut config = matches.value_of("config").map_or_else(
         TiKvConfig::default,
         |path| {
-            File::open(&path)
-                .map_err::<Box<Error>, _>(|e| Box::new(e))
-                .and_then(|mut f| {
-                    let mut s = String::new();
-                    try!(f.read_to_string(&mut s));
-                    let c = try!(toml::from_str(&s));
-                    Ok(c)
-                })
-                .unwrap_or_else(|e| {
-                    eprintln!("invalid configuration file {:?}: {}", path, e);
-                    process::exit(-1);
-                })
+            load_config_file(path).unwrap_or_else(|e| {
+                eprintln!("invalid configuration file {:?}: {}", path, e);
+                process::exit(-1);
+            })
         },
     );
 
@@ -474,7 +564,11 @@ fn main() {
     // Before any startup, check system configuration.
     check_system_config(&config);
 
-    let pd_client = RpcClient::new(&config.pd.endpoints)
+    let security_mgr = Arc::new(
+        SecurityManager::new(&config.security)
+            .unwrap_or_else(|e| fatal!("failed to create security manager: {:?}", e)),
+    );
+    let pd_client = RpcClient::new(&config.pd.endpoints, security_mgr.clone())
         .unwrap_or_else(|e| fatal!("failed to create rpc client: {:?}", e));
     let cluster_id = pd_client
         .get_cluster_id()
@@ -485,6 +579,17 @@ fn main() {
     config.server.cluster_id = cluster_id;
     info!("connect to PD cluster {}", cluster_id);
 
+    // Used to reload the config file on SIGHUP.
+    let reload_config = || -> Result<TiKvConfig, Box<Error>> {
+        let path = match matches.value_of("config") {
+            Some(path) => path,
+            None => return Err("no configuration file is specified".into()),
+        };
+        let mut config = try!(load_config_file(path));
+        overwrite_config_with_cmd_args(&mut config, &matches);
+        Ok(config)
+    };
+
     let _m = Monitor::default();
-    run_raft_server(pd_client, &config);
+    run_raft_server(pd_client, &config, security_mgr, &reload_config);
 }
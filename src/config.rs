@@ -11,24 +11,37 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::path::Path;
+use std::sync::Arc;
 use std::usize;
 
 use log::LogLevelFilter;
 use rocksdb::{BlockBasedOptions, ColumnFamilyOptions, CompactionPriority, DBCompressionType,
-              DBOptions, DBRecoveryMode};
+              DBOptions, DBRecoveryMode, DB};
+use serde_json::{self, Value};
 use sys_info;
 
+use coprocessor::EndPointTask;
 use server::Config as ServerConfig;
 use raftstore::store::Config as RaftstoreConfig;
+use raftstore::store::Msg as StoreMsg;
+use raftstore::store::RaftLogEngineConfig;
 use raftstore::store::keys::region_raft_prefix_len;
-use storage::{Config as StorageConfig, CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE, DEFAULT_DATA_DIR,
-              DEFAULT_ROCKSDB_SUB_DIR};
+use storage::{Config as StorageConfig, Storage, CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE,
+              DEFAULT_DATA_DIR, DEFAULT_ROCKSDB_SUB_DIR};
 use util::config::{self, compression_type_level_serde, ReadableDuration, ReadableSize, GB, KB, MB};
+use util::file_log::RotationConfig;
+use util::io_limiter;
+use util::logger::LogFormat;
+use util::resource_group::{Config as ResourceGroupConfig, ResourceGroupManager};
+use util::security::Config as SecurityConfig;
 use util::properties::{MvccPropertiesCollectorFactory, SizePropertiesCollectorFactory};
-use util::rocksdb::{db_exist, CFOptions, EventListener, FixedPrefixSliceTransform,
-                    FixedSuffixSliceTransform, NoopSliceTransform};
+use util::rocksdb::{db_exist, get_cf_handle, CFOptions, EventListener,
+                    FixedPrefixSliceTransform, FixedSuffixSliceTransform, NoopSliceTransform};
+use util::transport::SendCh;
+use util::worker::Scheduler;
 
 const LOCKCF_MIN_MEM: usize = 256 * MB as usize;
 const LOCKCF_MAX_MEM: usize = GB as usize;
@@ -308,6 +321,9 @@ pub struct DbConfig {
     pub info_log_roll_time: ReadableDuration,
     pub info_log_dir: String,
     pub rate_bytes_per_sec: ReadableSize,
+    // The rate limit of the process-wide IO limiter shared by snapshots, GC, manual
+    // compaction, import and backup, see `util::io_limiter`.
+    pub background_io_rate_limit: ReadableSize,
     pub max_sub_compactions: u32,
     pub writable_file_max_buffer_size: ReadableSize,
     pub use_direct_io_for_flush_and_compaction: bool,
@@ -338,6 +354,7 @@ impl Default for DbConfig {
             info_log_roll_time: ReadableDuration::secs(0),
             info_log_dir: "".to_owned(),
             rate_bytes_per_sec: ReadableSize::kb(0),
+            background_io_rate_limit: ReadableSize::kb(0),
             max_sub_compactions: 1,
             writable_file_max_buffer_size: ReadableSize::mb(1),
             use_direct_io_for_flush_and_compaction: false,
@@ -620,7 +637,14 @@ pub enum LogLevel {
 pub struct TiKvConfig {
     #[serde(with = "LogLevel")]
     pub log_level: LogLevelFilter,
+    pub log_format: LogFormat,
     pub log_file: String,
+    pub log_rotation_timespan: ReadableDuration,
+    pub log_rotation_size: ReadableSize,
+    pub log_max_backups: usize,
+    pub log_max_days: u64,
+    pub log_compress: bool,
+    pub slow_log_file: String,
     pub server: ServerConfig,
     pub storage: StorageConfig,
     pub pd: PdConfig,
@@ -629,26 +653,65 @@ pub struct TiKvConfig {
     pub raft_store: RaftstoreConfig,
     pub rocksdb: DbConfig,
     pub raftdb: RaftDbConfig,
+    pub raft_log_engine: RaftLogEngineConfig,
+    pub resource_group: ResourceGroupConfig,
+    pub security: SecurityConfig,
 }
 
 impl Default for TiKvConfig {
     fn default() -> TiKvConfig {
         TiKvConfig {
             log_level: LogLevelFilter::Info,
+            log_format: LogFormat::Text,
             log_file: "".to_owned(),
+            log_rotation_timespan: ReadableDuration::hours(24),
+            log_rotation_size: ReadableSize::kb(0),
+            log_max_backups: 0,
+            log_max_days: 0,
+            log_compress: false,
+            slow_log_file: "".to_owned(),
             server: ServerConfig::default(),
             metric: MetricConfig::default(),
             raft_store: RaftstoreConfig::default(),
             pd: PdConfig::default(),
             rocksdb: DbConfig::default(),
             raftdb: RaftDbConfig::default(),
+            raft_log_engine: RaftLogEngineConfig::default(),
+            resource_group: ResourceGroupConfig::default(),
+            security: SecurityConfig::default(),
             storage: StorageConfig::default(),
         }
     }
 }
 
 impl TiKvConfig {
+    pub fn log_rotation(&self) -> RotationConfig {
+        RotationConfig {
+            timespan: self.log_rotation_timespan.0,
+            max_size: self.log_rotation_size.0,
+            max_backups: self.log_max_backups,
+            max_days: self.log_max_days,
+            compress: self.log_compress,
+        }
+    }
+
     pub fn validate(&mut self) -> Result<(), Box<Error>> {
+        if !self.slow_log_file.is_empty() && !self.log_file.is_empty() &&
+            (self.slow_log_file == self.log_file ||
+                self.slow_log_file.starts_with(&format!("{}.", self.log_file)))
+        {
+            // Files named `log-file.*` are treated as rotated logs of `log-file`.
+            return Err(format!(
+                "slow-log-file {:?} conflicts with log-file {:?}",
+                self.slow_log_file,
+                self.log_file
+            ).into());
+        }
+        // The status server only speaks plain HTTP, it must not bypass TLS.
+        if self.security.is_tls_enabled() && !self.server.status_addr.is_empty() {
+            return Err("server.status-addr can't be set when TLS is enabled".into());
+        }
+
         try!(self.storage.validate());
         if self.rocksdb.backup_dir.is_empty() && self.storage.data_dir != DEFAULT_DATA_DIR {
             self.rocksdb.backup_dir = format!(
@@ -683,10 +746,559 @@ impl TiKvConfig {
             return Err("default rocksdb not exist, buf raftdb exist".into());
         }
 
+        let default_raft_log_dir = Path::new(&self.storage.data_dir).join("raft-log");
+        if self.raft_log_engine.enabled {
+            self.raft_log_engine.dir = if self.raft_log_engine.dir.is_empty() {
+                try!(config::canonicalize_path(
+                    default_raft_log_dir.to_str().unwrap()
+                ))
+            } else {
+                try!(config::canonicalize_path(&self.raft_log_engine.dir))
+            };
+            // Raft logs can't be migrated between raftdb and raft log engine.
+            if db_exist(&self.raft_store.raftdb_path) && !db_exist(&self.raft_log_engine.dir) {
+                return Err(
+                    "raftdb exist, but raft log engine is enabled on an existing store".into(),
+                );
+            }
+        } else if db_exist(default_raft_log_dir.to_str().unwrap()) ||
+            (!self.raft_log_engine.dir.is_empty() && db_exist(&self.raft_log_engine.dir))
+        {
+            return Err("raft log engine data exist, but raft log engine is disabled".into());
+        }
+
         try!(self.rocksdb.validate());
         try!(self.server.validate());
         try!(self.raft_store.validate());
+        try!(self.raft_log_engine.validate());
         try!(self.pd.validate());
+        try!(self.resource_group.validate());
+        try!(self.security.validate());
+        Ok(())
+    }
+}
+
+/// Config fields that can be changed without restarting, as `section.field`
+/// paths of the serialized config. `*` matches any single segment.
+const MUTABLE_CONFIGS: &'static [&'static str] = &[
+    "server.end-point-concurrency",
+    "server.end-point-max-tasks",
+    "server.end-point-slow-log-threshold",
+    "storage.gc-ratio-threshold",
+    "storage.scheduler-too-busy-threshold",
+    "storage.scheduler-worker-pool-size",
+    "storage.scheduler-slow-log-threshold",
+    "storage.flow-control-enable",
+    "storage.flow-control-soft-l0-files",
+    "storage.flow-control-hard-l0-files",
+    "storage.flow-control-soft-pending-compaction-bytes",
+    "storage.flow-control-hard-pending-compaction-bytes",
+    "raftstore.sync-log",
+    "raftstore.raft-entry-max-size",
+    "raftstore.raft-log-gc-tick-interval",
+    "raftstore.raft-log-gc-threshold",
+    "raftstore.raft-log-gc-count-limit",
+    "raftstore.raft-log-gc-size-limit",
+    "raftstore.split-region-check-tick-interval",
+    "raftstore.region-max-size",
+    "raftstore.region-split-size",
+    "raftstore.region-split-check-diff",
+    "raftstore.region-compact-check-interval",
+    "raftstore.region-compact-delete-keys-count",
+    "raftstore.pd-heartbeat-tick-interval",
+    "raftstore.pd-store-heartbeat-tick-interval",
+    "raftstore.max-peer-down-duration",
+    "raftstore.max-leader-missing-duration",
+    "raftstore.allow-remove-leader",
+    "rocksdb.max-background-jobs",
+    "rocksdb.background-io-rate-limit",
+    "rocksdb.*.write-buffer-size",
+    "rocksdb.*.max-write-buffer-number",
+    "rocksdb.*.max-bytes-for-level-base",
+    "rocksdb.*.target-file-size-base",
+    "rocksdb.*.level0-file-num-compaction-trigger",
+    "rocksdb.*.level0-slowdown-writes-trigger",
+    "rocksdb.*.level0-stop-writes-trigger",
+    "rocksdb.*.max-compaction-bytes",
+    "resource-group.groups",
+];
+
+fn is_mutable(field: &str) -> bool {
+    MUTABLE_CONFIGS.iter().any(|pattern| {
+        let mut patterns = pattern.split('.');
+        let mut segments = field.split('.');
+        loop {
+            match (patterns.next(), segments.next()) {
+                (None, None) => return true,
+                (Some(p), Some(s)) if p == "*" || p == s => continue,
+                _ => return false,
+            }
+        }
+    })
+}
+
+fn flatten_config(prefix: &str, value: Value, fields: &mut BTreeMap<String, Value>) {
+    match value {
+        Value::Object(map) => for (k, v) in map {
+            let key = if prefix.is_empty() {
+                k
+            } else {
+                format!("{}.{}", prefix, k)
+            };
+            flatten_config(&key, v, fields);
+        },
+        v => {
+            fields.insert(prefix.to_owned(), v);
+        }
+    }
+}
+
+/// Returns the paths of all fields that differ between the two configs.
+pub fn diff_config(old: &TiKvConfig, new: &TiKvConfig) -> Result<Vec<String>, Box<Error>> {
+    let mut old_fields = BTreeMap::new();
+    flatten_config("", try!(serde_json::to_value(old)), &mut old_fields);
+    let mut new_fields = BTreeMap::new();
+    flatten_config("", try!(serde_json::to_value(new)), &mut new_fields);
+
+    let mut changed: Vec<_> = new_fields
+        .iter()
+        .filter(|&(k, v)| old_fields.get(k) != Some(v))
+        .map(|(k, _)| k.clone())
+        .collect();
+    changed.extend(
+        old_fields
+            .keys()
+            .filter(|k| !new_fields.contains_key(*k))
+            .cloned(),
+    );
+    changed.sort();
+    Ok(changed)
+}
+
+fn section_changed(changed: &[String], section: &str) -> bool {
+    changed
+        .iter()
+        .any(|f| f.split('.').next() == Some(section))
+}
+
+/// `ConfigHandler` applies a reloaded config to a running component.
+pub trait ConfigHandler {
+    /// `changed` contains the paths of the changed fields, all of which are
+    /// mutable.
+    fn on_config_changed(&mut self, changed: &[String], cfg: &TiKvConfig)
+        -> Result<(), Box<Error>>;
+}
+
+/// `ConfigManager` holds the config the server is running with, and pushes
+/// the diffs of reloaded configs to the registered components.
+pub struct ConfigManager {
+    config: TiKvConfig,
+    handlers: Vec<Box<ConfigHandler>>,
+}
+
+impl ConfigManager {
+    pub fn new(config: TiKvConfig) -> ConfigManager {
+        ConfigManager {
+            config: config,
+            handlers: vec![],
+        }
+    }
+
+    pub fn register(&mut self, handler: Box<ConfigHandler>) {
+        self.handlers.push(handler);
+    }
+
+    pub fn config(&self) -> &TiKvConfig {
+        &self.config
+    }
+
+    /// Validates the new config and applies it if only mutable fields are
+    /// changed. Returns the paths of the changed fields.
+    ///
+    /// If a handler fails, the handlers that have been called are given the
+    /// running config again, so components don't run with a mix of both.
+    pub fn reload(&mut self, mut config: TiKvConfig) -> Result<Vec<String>, Box<Error>> {
+        config.server.cluster_id = self.config.server.cluster_id;
+        try!(config.validate());
+        let changed = try!(diff_config(&self.config, &config));
+        let immutable: Vec<_> = changed.iter().filter(|f| !is_mutable(f)).collect();
+        if !immutable.is_empty() {
+            return Err(format!("can't change {:?} without restart", immutable).into());
+        }
+        if changed.is_empty() {
+            return Ok(changed);
+        }
+        for i in 0..self.handlers.len() {
+            if let Err(e) = self.handlers[i].on_config_changed(&changed, &config) {
+                // The failed handler may have applied a part of the config too.
+                for handler in self.handlers[..i + 1].iter_mut().rev() {
+                    if let Err(e) = handler.on_config_changed(&changed, &self.config) {
+                        error!("failed to roll back config {:?}: {:?}", changed, e);
+                    }
+                }
+                return Err(e);
+            }
+        }
+        self.config = config;
+        Ok(changed)
+    }
+}
+
+pub struct RaftstoreConfigHandler {
+    ch: SendCh<StoreMsg>,
+}
+
+impl RaftstoreConfigHandler {
+    pub fn new(ch: SendCh<StoreMsg>) -> RaftstoreConfigHandler {
+        RaftstoreConfigHandler { ch: ch }
+    }
+}
+
+impl ConfigHandler for RaftstoreConfigHandler {
+    fn on_config_changed(
+        &mut self,
+        changed: &[String],
+        cfg: &TiKvConfig,
+    ) -> Result<(), Box<Error>> {
+        if section_changed(changed, "raftstore") {
+            try!(self.ch.send(StoreMsg::ChangeConfig(cfg.raft_store.clone())));
+        }
+        Ok(())
+    }
+}
+
+pub struct StorageConfigHandler {
+    storage: Storage,
+}
+
+impl StorageConfigHandler {
+    pub fn new(storage: Storage) -> StorageConfigHandler {
+        StorageConfigHandler { storage: storage }
+    }
+}
+
+impl ConfigHandler for StorageConfigHandler {
+    fn on_config_changed(
+        &mut self,
+        changed: &[String],
+        cfg: &TiKvConfig,
+    ) -> Result<(), Box<Error>> {
+        if section_changed(changed, "storage") {
+            try!(self.storage.change_config(&cfg.storage));
+        }
+        Ok(())
+    }
+}
+
+pub struct CoprocessorConfigHandler {
+    scheduler: Scheduler<EndPointTask>,
+}
+
+impl CoprocessorConfigHandler {
+    pub fn new(scheduler: Scheduler<EndPointTask>) -> CoprocessorConfigHandler {
+        CoprocessorConfigHandler {
+            scheduler: scheduler,
+        }
+    }
+}
+
+impl ConfigHandler for CoprocessorConfigHandler {
+    fn on_config_changed(
+        &mut self,
+        changed: &[String],
+        cfg: &TiKvConfig,
+    ) -> Result<(), Box<Error>> {
+        if section_changed(changed, "server") {
+            let task = EndPointTask::ChangeConfig(cfg.server.clone());
+            try!(
+                self.scheduler
+                    .schedule(task)
+                    .map_err(|e| format!("failed to change coprocessor config: {}", e))
+            );
+        }
+        Ok(())
+    }
+}
+
+pub struct ResourceGroupConfigHandler {
+    mgr: ResourceGroupManager,
+}
+
+impl ResourceGroupConfigHandler {
+    pub fn new(mgr: ResourceGroupManager) -> ResourceGroupConfigHandler {
+        ResourceGroupConfigHandler { mgr: mgr }
+    }
+}
+
+impl ConfigHandler for ResourceGroupConfigHandler {
+    fn on_config_changed(
+        &mut self,
+        changed: &[String],
+        cfg: &TiKvConfig,
+    ) -> Result<(), Box<Error>> {
+        if section_changed(changed, "resource-group") {
+            self.mgr.set_config(&cfg.resource_group);
+        }
+        Ok(())
+    }
+}
+
+macro_rules! cf_mutable_option {
+    ($cf:expr, $field:expr) => {
+        match $field {
+            "write-buffer-size" => Some($cf.write_buffer_size.0.to_string()),
+            "max-write-buffer-number" => Some($cf.max_write_buffer_number.to_string()),
+            "max-bytes-for-level-base" => Some($cf.max_bytes_for_level_base.0.to_string()),
+            "target-file-size-base" => Some($cf.target_file_size_base.0.to_string()),
+            "level0-file-num-compaction-trigger" => {
+                Some($cf.level0_file_num_compaction_trigger.to_string())
+            }
+            "level0-slowdown-writes-trigger" => {
+                Some($cf.level0_slowdown_writes_trigger.to_string())
+            }
+            "level0-stop-writes-trigger" => Some($cf.level0_stop_writes_trigger.to_string()),
+            "max-compaction-bytes" => Some($cf.max_compaction_bytes.0.to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// Applies the changed options of the kv rocksdb with `SetOptions`.
+pub struct DbConfigHandler {
+    db: Arc<DB>,
+}
+
+impl DbConfigHandler {
+    pub fn new(db: Arc<DB>) -> DbConfigHandler {
+        DbConfigHandler { db: db }
+    }
+}
+
+impl ConfigHandler for DbConfigHandler {
+    fn on_config_changed(
+        &mut self,
+        changed: &[String],
+        cfg: &TiKvConfig,
+    ) -> Result<(), Box<Error>> {
+        for field in changed {
+            let segments: Vec<_> = field.split('.').collect();
+            match segments.as_slice() {
+                &["rocksdb", "max-background-jobs"] => {
+                    let value = cfg.rocksdb.max_background_jobs.to_string();
+                    try!(
+                        self.db
+                            .set_db_options(&[("max_background_jobs", value.as_str())])
+                    );
+                }
+                &["rocksdb", "background-io-rate-limit"] => {
+                    io_limiter::set_background_io_rate_limit(
+                        cfg.rocksdb.background_io_rate_limit.0,
+                    );
+                }
+                &["rocksdb", cf_config, option] => {
+                    let value = match cf_config {
+                        "defaultcf" => cf_mutable_option!(cfg.rocksdb.defaultcf, option),
+                        "writecf" => cf_mutable_option!(cfg.rocksdb.writecf, option),
+                        "lockcf" => cf_mutable_option!(cfg.rocksdb.lockcf, option),
+                        "raftcf" => cf_mutable_option!(cfg.rocksdb.raftcf, option),
+                        _ => None,
+                    };
+                    let value = match value {
+                        Some(v) => v,
+                        None => continue,
+                    };
+                    // Section `defaultcf` configures column family `default`, and so on.
+                    let cf = &cf_config[..cf_config.len() - 2];
+                    let handle = try!(get_cf_handle(&self.db, cf));
+                    let name = option.replace('-', "_");
+                    try!(
+                        self.db
+                            .set_options_cf(handle, &[(name.as_str(), value.as_str())])
+                    );
+                }
+                _ => continue,
+            }
+            info!("config {} of kv rocksdb changed", field);
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::error::Error;
+    use std::rc::Rc;
+
+    use tempdir::TempDir;
+
+    use util::config::ReadableSize;
+    use super::*;
+
+    #[test]
+    fn test_diff_config() {
+        let old = TiKvConfig::default();
+        let mut new = old.clone();
+        assert!(diff_config(&old, &new).unwrap().is_empty());
+
+        new.storage.scheduler_too_busy_threshold += 1;
+        new.rocksdb.writecf.write_buffer_size = ReadableSize::mb(1);
+        new.server.addr = "127.0.0.1:0".to_owned();
+        let changed = diff_config(&old, &new).unwrap();
+        assert_eq!(
+            changed,
+            vec![
+                "rocksdb.writecf.write-buffer-size".to_owned(),
+                "server.addr".to_owned(),
+                "storage.scheduler-too-busy-threshold".to_owned(),
+            ]
+        );
+        assert!(is_mutable(&changed[0]));
+        assert!(!is_mutable(&changed[1]));
+        assert!(is_mutable(&changed[2]));
+        assert!(!is_mutable("rocksdb.writecf"));
+        assert!(!is_mutable("rocksdb.writecf.write-buffer-size.x"));
+
+        let mut new = old.clone();
+        new.resource_group.groups.push(Default::default());
+        let changed = diff_config(&old, &new).unwrap();
+        assert_eq!(changed, vec!["resource-group.groups".to_owned()]);
+        assert!(is_mutable(&changed[0]));
+    }
+
+    #[test]
+    fn test_validate_slow_log_file() {
+        let mut cfg = TiKvConfig::default();
+        cfg.log_file = "tikv.log".to_owned();
+        cfg.slow_log_file = "tikv.log".to_owned();
+        assert!(cfg.validate().is_err());
+        cfg.slow_log_file = "tikv.log.slow".to_owned();
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_status_addr_with_tls() {
+        let mut cfg = TiKvConfig::default();
+        cfg.server.status_addr = "127.0.0.1:20180".to_owned();
+        cfg.security.ca_path = "ca.pem".to_owned();
+        let err = cfg.validate().unwrap_err();
+        assert!(format!("{}", err).contains("status-addr"), "{}", err);
+    }
+
+    struct RecordHandler {
+        changed: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl ConfigHandler for RecordHandler {
+        fn on_config_changed(
+            &mut self,
+            changed: &[String],
+            _: &TiKvConfig,
+        ) -> Result<(), Box<Error>> {
+            self.changed.borrow_mut().extend_from_slice(changed);
+            Ok(())
+        }
+    }
+
+    // Records the applied `end_point_max_tasks`, and fails to apply `fail_tasks`.
+    struct MaxTasksHandler {
+        applied: Rc<RefCell<Vec<usize>>>,
+        fail_tasks: usize,
+    }
+
+    impl ConfigHandler for MaxTasksHandler {
+        fn on_config_changed(
+            &mut self,
+            _: &[String],
+            cfg: &TiKvConfig,
+        ) -> Result<(), Box<Error>> {
+            if cfg.server.end_point_max_tasks == self.fail_tasks {
+                return Err("injected failure".into());
+            }
+            self.applied
+                .borrow_mut()
+                .push(cfg.server.end_point_max_tasks);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_config_manager_reload() {
+        let path = TempDir::new("test-config-manager").unwrap();
+        let mut cfg = TiKvConfig::default();
+        cfg.storage.data_dir = path.path().to_str().unwrap().to_owned();
+        cfg.validate().unwrap();
+
+        let changed = Rc::new(RefCell::new(vec![]));
+        let mut manager = ConfigManager::new(cfg.clone());
+        manager.register(box RecordHandler {
+            changed: changed.clone(),
+        });
+
+        // Nothing changed.
+        assert!(manager.reload(cfg.clone()).unwrap().is_empty());
+        assert!(changed.borrow().is_empty());
+
+        // Immutable fields can't be changed.
+        let mut new_cfg = cfg.clone();
+        new_cfg.server.end_point_max_tasks += 1;
+        new_cfg.server.grpc_concurrency += 1;
+        assert!(manager.reload(new_cfg).is_err());
+        assert!(changed.borrow().is_empty());
+        assert_eq!(
+            manager.config().server.end_point_max_tasks,
+            cfg.server.end_point_max_tasks
+        );
+
+        // Invalid configs are rejected.
+        let mut new_cfg = cfg.clone();
+        new_cfg.server.end_point_concurrency = 0;
+        assert!(manager.reload(new_cfg).is_err());
+
+        let mut new_cfg = cfg.clone();
+        new_cfg.server.end_point_max_tasks += 1;
+        new_cfg.storage.gc_ratio_threshold += 1.0;
+        assert_eq!(manager.reload(new_cfg).unwrap().len(), 2);
+        assert_eq!(
+            *changed.borrow(),
+            vec![
+                "server.end-point-max-tasks".to_owned(),
+                "storage.gc-ratio-threshold".to_owned(),
+            ]
+        );
+        assert_eq!(
+            manager.config().server.end_point_max_tasks,
+            cfg.server.end_point_max_tasks + 1
+        );
+    }
+
+    #[test]
+    fn test_config_manager_rollback() {
+        let path = TempDir::new("test-config-manager-rollback").unwrap();
+        let mut cfg = TiKvConfig::default();
+        cfg.storage.data_dir = path.path().to_str().unwrap().to_owned();
+        cfg.validate().unwrap();
+        let old_tasks = cfg.server.end_point_max_tasks;
+        let new_tasks = old_tasks + 1;
+
+        let mut manager = ConfigManager::new(cfg.clone());
+        let applied = Rc::new(RefCell::new(vec![]));
+        manager.register(box MaxTasksHandler {
+            applied: applied.clone(),
+            fail_tasks: 0,
+        });
+        let failed = Rc::new(RefCell::new(vec![]));
+        manager.register(box MaxTasksHandler {
+            applied: failed.clone(),
+            fail_tasks: new_tasks,
+        });
+
+        // The first handler gets the running config again after the second fails.
+        let mut new_cfg = cfg.clone();
+        new_cfg.server.end_point_max_tasks = new_tasks;
+        assert!(manager.reload(new_cfg).is_err());
+        assert_eq!(*applied.borrow(), vec![new_tasks, old_tasks]);
+        assert_eq!(*failed.borrow(), vec![old_tasks]);
+        assert_eq!(manager.config().server.end_point_max_tasks, old_tasks);
+    }
+}
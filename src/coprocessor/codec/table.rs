@@ -14,6 +14,7 @@
 
 use std::io::Write;
 use std::{cmp, u8};
+use std::fmt::{self, Display, Formatter};
 use tipb::schema::ColumnInfo;
 
 use coprocessor::select::xeval::EvalContext;
@@ -139,6 +140,90 @@ pub fn encode_index_seek_key(table_id: i64, idx_id: i64, encoded: &[u8]) -> Vec<
     key
 }
 
+/// The parts of a table key, without the schema of the table.
+#[derive(Debug, PartialEq)]
+pub enum TableKey {
+    Record { table_id: i64, handle: i64 },
+    // The values include the handle for a non-unique index.
+    Index {
+        table_id: i64,
+        index_id: i64,
+        values: Vec<Datum>,
+    },
+}
+
+impl Display for TableKey {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            TableKey::Record { table_id, handle } => {
+                write!(f, "table_id: {}, handle: {}", table_id, handle)
+            }
+            TableKey::Index {
+                table_id,
+                index_id,
+                ref values,
+            } => write!(
+                f,
+                "table_id: {}, index_id: {}, index_values: {:?}",
+                table_id,
+                index_id,
+                values
+            ),
+        }
+    }
+}
+
+/// `decode_table_key` decodes a record key or an index key. The datums of an index key
+/// are decoded as they are flattened, since the column types are unknown.
+pub fn decode_table_key(key: &[u8]) -> Result<TableKey> {
+    if !key.starts_with(TABLE_PREFIX) {
+        return Err(invalid_type!("table key expected, but got {}", escape(key)));
+    }
+    let mut remaining = &key[TABLE_PREFIX.len()..];
+    let table_id = try!(remaining.decode_i64());
+    if remaining.starts_with(RECORD_PREFIX_SEP) {
+        remaining = &remaining[RECORD_PREFIX_SEP.len()..];
+        let handle = try!(remaining.decode_i64());
+        return Ok(TableKey::Record {
+            table_id: table_id,
+            handle: handle,
+        });
+    }
+    if !remaining.starts_with(INDEX_PREFIX_SEP) {
+        return Err(invalid_type!("table key expected, but got {}", escape(key)));
+    }
+    remaining = &remaining[INDEX_PREFIX_SEP.len()..];
+    let index_id = try!(remaining.decode_i64());
+    let values = try!(remaining.decode());
+    Ok(TableKey::Index {
+        table_id: table_id,
+        index_id: index_id,
+        values: values,
+    })
+}
+
+/// `decode_row` decodes a row value encoded by `encode_row` to column ids and the
+/// flattened column values.
+pub fn decode_row(data: &[u8]) -> Result<Vec<(i64, Datum)>> {
+    let mut buf = data;
+    let datums = try!(buf.decode());
+    if datums.len() == 1 && datums[0] == Datum::Null {
+        return Ok(vec![]);
+    }
+    if datums.len() % 2 != 0 {
+        return Err(invalid_type!("{} is not a valid row", escape(data)));
+    }
+    let mut row = Vec::with_capacity(datums.len() / 2);
+    let mut iter = datums.into_iter();
+    while let (Some(id), Some(value)) = (iter.next(), iter.next()) {
+        match id {
+            Datum::I64(id) => row.push((id, value)),
+            _ => return Err(invalid_type!("column id expected, but got {}", id)),
+        }
+    }
+    Ok(row)
+}
+
 // `decode_index_key` decodes datums from an index key.
 pub fn decode_index_key(
     ctx: &EvalContext,
@@ -389,6 +474,49 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_decode_table_key() {
+        let mut handle = vec![];
+        handle.encode_i64(-3).unwrap();
+        let key = encode_row_key(1, &handle);
+        let expect = TableKey::Record {
+            table_id: 1,
+            handle: -3,
+        };
+        assert_eq!(decode_table_key(&key).unwrap(), expect);
+        assert_eq!(format!("{}", expect), "table_id: 1, handle: -3");
+        let key = encode_column_key(1, -3, 4);
+        assert_eq!(decode_table_key(&key).unwrap(), expect);
+
+        let values = vec![Datum::Bytes(b"abc".to_vec()), Datum::I64(10)];
+        let key = encode_index_seek_key(2, 5, &datum::encode_key(&values).unwrap());
+        let expect = TableKey::Index {
+            table_id: 2,
+            index_id: 5,
+            values: values,
+        };
+        assert_eq!(decode_table_key(&key).unwrap(), expect);
+
+        assert!(decode_table_key(b"m_abc").is_err());
+        let mut key = encode_row_key(1, &[]);
+        key.truncate(PREFIX_LEN - SEP_LEN);
+        key.extend_from_slice(b"_x");
+        assert!(decode_table_key(&key).is_err());
+    }
+
+    #[test]
+    fn test_decode_row() {
+        let row = vec![Datum::I64(100), Datum::Bytes(b"abc".to_vec()), Datum::Null];
+        let col_ids = vec![1, 3, 5];
+        let data = encode_row(row.clone(), &col_ids).unwrap();
+        let expect: Vec<_> = col_ids.into_iter().zip(row).collect();
+        assert_eq!(decode_row(&data).unwrap(), expect);
+        let data = encode_row(vec![], &[]).unwrap();
+        assert!(decode_row(&data).unwrap().is_empty());
+        let data = datum::encode_value(&[Datum::I64(1)]).unwrap();
+        assert!(decode_row(&data).is_err());
+    }
+
     fn new_col_info(tp: u8) -> ColumnInfo {
         let mut col_info = ColumnInfo::new();
         col_info.set_tp(tp as i32);
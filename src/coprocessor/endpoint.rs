@@ -17,15 +17,20 @@ use std::rc::Rc;
 use std::fmt::{self, Debug, Display, Formatter};
 
 use tipb::select::{self, Chunk, DAGRequest, SelectRequest};
+use tipb::executor::ExecType;
 use tipb::schema::ColumnInfo;
 use protobuf::Message as PbMsg;
 use kvproto::coprocessor::{KeyRange, Request, Response};
 use kvproto::errorpb::{self, ServerIsBusy};
 use kvproto::kvrpcpb::CommandPri;
 
+use util::escape;
 use util::time::duration_to_sec;
+use util::logger::SLOW_LOG_TARGET;
 use util::worker::{BatchRunnable, Scheduler};
 use util::collections::HashMap;
+use util::metrics::RESOURCE_GROUP_WAIT_HISTOGRAM_VEC;
+use util::resource_group::{self, ResourceGroupManager};
 use util::threadpool::{Context, ContextFactory, ThreadPool, DEFAULT_TASKS_PER_TICK};
 use server::{Config, OnResponse};
 use storage::{self, engine, Engine, Snapshot, SnapshotStore, Statistics};
@@ -47,11 +52,12 @@ pub const BATCH_ROW_COUNT: usize = 64;
 // If a request has been handled for more than 60 seconds, the client should
 // be timeout already, so it can be safely aborted.
 const REQUEST_MAX_HANDLE_SECS: u64 = 60;
-// If handle time is larger than the lower bound, the query is considered as slow query.
-const SLOW_QUERY_LOWER_BOUND: f64 = 1.0; // 1 second.
 
 const DEFAULT_ERROR_CODE: i32 = 1;
 
+// Used when the task isn't dispatched by a `Host`, see `end-point-slow-log-threshold`.
+const DEFAULT_SLOW_LOG_THRESHOLD_SECS: u64 = 1;
+
 pub const SINGLE_GROUP: &'static [u8] = b"SingleGroup";
 
 const OUTDATED_ERROR_MSG: &'static str = "request outdated.";
@@ -67,6 +73,8 @@ pub struct Host {
     low_priority_pool: ThreadPool<CopContext>,
     high_priority_pool: ThreadPool<CopContext>,
     max_running_task_count: usize,
+    slow_log_threshold: Duration,
+    resource_groups: ResourceGroupManager,
 }
 
 struct CopContext {
@@ -133,13 +141,20 @@ impl ContextFactory<CopContext> for CopContextFactory {
 }
 
 impl Host {
-    pub fn new(engine: Box<Engine>, scheduler: Scheduler<Task>, cfg: &Config) -> Host {
+    pub fn new(
+        engine: Box<Engine>,
+        scheduler: Scheduler<Task>,
+        cfg: &Config,
+        resource_groups: ResourceGroupManager,
+    ) -> Host {
         Host {
             engine: engine,
             sched: scheduler,
             reqs: HashMap::default(),
             last_req_id: 0,
             max_running_task_count: cfg.end_point_max_tasks,
+            slow_log_threshold: cfg.end_point_slow_log_threshold.0,
+            resource_groups: resource_groups,
             pool: ThreadPool::new(
                 thd_name!("endpoint-normal-pool"),
                 cfg.end_point_concurrency,
@@ -161,6 +176,24 @@ impl Host {
         }
     }
 
+    fn change_config(&mut self, cfg: &Config) {
+        info!(
+            "end point config changed, concurrency {} -> {}, max tasks {} -> {}, \
+             slow log threshold {:?} -> {:?}",
+            self.pool.get_num_threads(),
+            cfg.end_point_concurrency,
+            self.max_running_task_count,
+            cfg.end_point_max_tasks,
+            self.slow_log_threshold,
+            cfg.end_point_slow_log_threshold.0
+        );
+        self.max_running_task_count = cfg.end_point_max_tasks;
+        self.slow_log_threshold = cfg.end_point_slow_log_threshold.0;
+        self.pool.set_num_threads(cfg.end_point_concurrency);
+        self.low_priority_pool.set_num_threads(cfg.end_point_concurrency);
+        self.high_priority_pool.set_num_threads(cfg.end_point_concurrency);
+    }
+
     fn running_task_count(&self) -> usize {
         self.pool.get_task_count() + self.low_priority_pool.get_task_count() +
             self.high_priority_pool.get_task_count()
@@ -183,6 +216,11 @@ impl Host {
 
 
         for req in reqs {
+            let group = self.resource_groups.get(req.req.get_context().get_resource_group_name());
+            if group.is_read_throttled() {
+                on_error(Error::Throttled(group.name().to_owned()), req);
+                continue;
+            }
             let pri = req.priority();
             let pri_str = get_req_pri_str(pri);
             let type_str = get_req_type_str(req.req.get_tp());
@@ -196,8 +234,13 @@ impl Host {
                 CommandPri::High => &mut self.high_priority_pool,
                 CommandPri::Normal => &mut self.pool,
             };
-            pool.execute(move |ctx: &mut CopContext| {
+            let wait_timer = RESOURCE_GROUP_WAIT_HISTOGRAM_VEC
+                .with_label_values(&[group.name(), "coprocessor"])
+                .start_coarse_timer();
+            pool.execute_with_group(group.id(), group.weight(), move |ctx: &mut CopContext| {
+                wait_timer.observe_duration();
                 let stats = end_point.handle_request(req);
+                group.consume_read(resource_group::read_request_units(stats.total_op_count()));
                 ctx.task_count += 1;
                 ctx.add_statistics(type_str, &stats);
                 COPR_PENDING_REQS
@@ -213,6 +256,7 @@ pub enum Task {
     SnapRes(u64, engine::Result<Box<Snapshot>>),
     BatchSnapRes(Vec<(u64, engine::Result<Box<Snapshot>>)>),
     RetryRequests(Vec<u64>),
+    ChangeConfig(Config),
 }
 
 impl Display for Task {
@@ -222,6 +266,7 @@ impl Display for Task {
             Task::SnapRes(req_id, _) => write!(f, "snapres [{}]", req_id),
             Task::BatchSnapRes(_) => write!(f, "batch snapres"),
             Task::RetryRequests(ref retry) => write!(f, "retry on task ids: {:?}", retry),
+            Task::ChangeConfig(_) => write!(f, "change config"),
         }
     }
 }
@@ -241,6 +286,11 @@ pub struct RequestTask {
     statistics: Statistics,
     on_resp: OnResponse,
     cop_req: Option<Result<CopRequest>>,
+    // The executors of a DAG request, only used by the slow log.
+    executors: Option<Vec<ExecType>>,
+    // The address of the client, only used by the slow log.
+    client_addr: Option<String>,
+    slow_log_threshold: Duration,
 }
 
 impl RequestTask {
@@ -248,6 +298,7 @@ impl RequestTask {
         let timer = Instant::now();
         let deadline = timer + Duration::from_secs(REQUEST_MAX_HANDLE_SECS);
         let mut start_ts = None;
+        let mut executors = None;
         let tp = req.get_tp();
         let cop_req = match tp {
             REQ_TYPE_SELECT | REQ_TYPE_INDEX => {
@@ -265,6 +316,7 @@ impl RequestTask {
                     Err(box_err!(e))
                 } else {
                     start_ts = Some(dag.get_start_ts());
+                    executors = Some(dag.get_executors().iter().map(|e| e.get_tp()).collect());
                     Ok(CopRequest::DAG(dag))
                 }
             }
@@ -279,9 +331,16 @@ impl RequestTask {
             statistics: Default::default(),
             on_resp: on_resp,
             cop_req: Some(cop_req),
+            executors: executors,
+            client_addr: None,
+            slow_log_threshold: Duration::from_secs(DEFAULT_SLOW_LOG_THRESHOLD_SECS),
         }
     }
 
+    pub fn set_client_addr(&mut self, addr: String) {
+        self.client_addr = Some(addr);
+    }
+
     #[inline]
     fn check_outdated(&self) -> Result<()> {
         check_if_outdated(self.deadline, self.req.get_tp())
@@ -317,23 +376,45 @@ impl RequestTask {
             .observe(self.statistics.total_op_count() as f64);
 
 
-        if handle_time > SLOW_QUERY_LOWER_BOUND {
-            info!(
-                "[region {}] handle {:?} [{}] takes {:?} [waiting: {:?}, keys: {}, hit: {}, \
-                 ranges: {} ({:?})]",
-                self.req.get_context().get_region_id(),
-                self.start_ts,
-                type_str,
-                handle_time,
-                wait_time,
-                self.statistics.total_op_count(),
-                self.statistics.total_processed(),
-                self.req.get_ranges().len(),
-                self.req.get_ranges().get(0)
-            );
+        if handle_time >= duration_to_sec(self.slow_log_threshold) {
+            self.log_slow_query(handle_time, wait_time);
         }
     }
 
+    fn log_slow_query(&self, handle_time: f64, wait_time: f64) {
+        let ranges = self.req.get_ranges();
+        let key_range = match (ranges.first(), ranges.last()) {
+            (Some(first), Some(last)) => format!(
+                "[{}, {})",
+                escape(first.get_start()),
+                escape(last.get_end())
+            ),
+            _ => "none".to_owned(),
+        };
+        let tag = match self.executors {
+            Some(ref execs) => format!("{} {:?}", get_req_type_str(self.req.get_tp()), execs),
+            None => get_req_type_str(self.req.get_tp()).to_owned(),
+        };
+        info!(
+            target: SLOW_LOG_TARGET,
+            "[region {}] slow query: {}, start_ts: {:?}, takes {:?}s [wait: {:?}s, \
+             process: {:?}s, ranges: {} {}, processed keys: {}, total ops: {}, seeks: {}, \
+             client: {}]",
+            self.req.get_context().get_region_id(),
+            tag,
+            self.start_ts,
+            handle_time,
+            wait_time,
+            handle_time - wait_time,
+            ranges.len(),
+            key_range,
+            self.statistics.total_processed(),
+            self.statistics.total_op_count(),
+            self.statistics.total_seek_count(),
+            self.client_addr.as_ref().map_or("unknown", |a| a.as_str())
+        );
+    }
+
     pub fn priority(&self) -> CommandPri {
         self.req.get_context().get_priority()
     }
@@ -359,7 +440,8 @@ impl BatchRunnable<Task> for Host {
         let mut grouped_reqs = map![];
         for task in tasks.drain(..) {
             match task {
-                Task::Request(req) => {
+                Task::Request(mut req) => {
+                    req.slow_log_threshold = self.slow_log_threshold;
                     if let Err(e) = req.check_outdated() {
                         on_error(e, req);
                         continue;
@@ -393,6 +475,7 @@ impl BatchRunnable<Task> for Host {
                         self.reqs.insert(id, reqs);
                     }
                 },
+                Task::ChangeConfig(cfg) => self.change_config(&cfg),
             }
         }
 
@@ -491,6 +574,15 @@ fn err_resp(e: Error) -> Response {
             errorpb.set_server_is_busy(server_is_busy_err);
             resp.set_region_error(errorpb);
         }
+        Error::Throttled(group) => {
+            COPR_REQ_ERROR.with_label_values(&["throttled"]).inc();
+            let mut errorpb = errorpb::Error::new();
+            errorpb.set_message(format!("resource group {} is throttled", group));
+            let mut server_is_busy_err = ServerIsBusy::new();
+            server_is_busy_err.set_reason(ENDPOINT_IS_BUSY.to_owned());
+            errorpb.set_server_is_busy(server_is_busy_err);
+            resp.set_region_error(errorpb);
+        }
         Error::Other(_) => {
             resp.set_other_error(format!("{}", e));
             COPR_REQ_ERROR.with_label_values(&["other"]).inc();
@@ -694,7 +786,12 @@ mod tests {
         let engine = engine::new_local_engine(TEMP_DIR, &[]).unwrap();
         let mut cfg = Config::default();
         cfg.end_point_concurrency = 1;
-        let end_point = Host::new(engine, worker.scheduler(), &cfg);
+        let end_point = Host::new(
+            engine,
+            worker.scheduler(),
+            &cfg,
+            ResourceGroupManager::default(),
+        );
         worker.start_batch(end_point, 30).unwrap();
         let (tx, rx) = mpsc::channel();
         let mut task = RequestTask::new(Request::new(), box move |msg| { tx.send(msg).unwrap(); });
@@ -705,13 +802,40 @@ mod tests {
         assert_eq!(resp.get_other_error(), super::OUTDATED_ERROR_MSG);
     }
 
+    #[test]
+    fn test_change_config() {
+        let worker = Worker::new("test-endpoint");
+        let engine = engine::new_local_engine(TEMP_DIR, &[]).unwrap();
+        let mut cfg = Config::default();
+        cfg.end_point_concurrency = 1;
+        let mut end_point = Host::new(
+            engine,
+            worker.scheduler(),
+            &cfg,
+            ResourceGroupManager::default(),
+        );
+
+        cfg.end_point_concurrency = 3;
+        cfg.end_point_max_tasks = 10;
+        end_point.run_batch(&mut vec![Task::ChangeConfig(cfg.clone())]);
+        assert_eq!(end_point.max_running_task_count, 10);
+        assert_eq!(end_point.pool.get_num_threads(), 3);
+        assert_eq!(end_point.low_priority_pool.get_num_threads(), 3);
+        assert_eq!(end_point.high_priority_pool.get_num_threads(), 3);
+    }
+
     #[test]
     fn test_too_many_reqs() {
         let mut worker = Worker::new("test-endpoint");
         let engine = engine::new_local_engine(TEMP_DIR, &[]).unwrap();
         let mut cfg = Config::default();
         cfg.end_point_concurrency = 1;
-        let mut end_point = Host::new(engine, worker.scheduler(), &cfg);
+        let mut end_point = Host::new(
+            engine,
+            worker.scheduler(),
+            &cfg,
+            ResourceGroupManager::default(),
+        );
         end_point.max_running_task_count = 3;
         worker.start_batch(end_point, 30).unwrap();
         let (tx, rx) = mpsc::channel();
@@ -741,4 +865,29 @@ mod tests {
         }
         panic!("suppose to get ServerIsBusy error.");
     }
+
+    #[test]
+    fn test_resource_group_throttled() {
+        let mut worker = Worker::new("test-endpoint");
+        let engine = engine::new_local_engine(TEMP_DIR, &[]).unwrap();
+        let cfg = Config::default();
+        let mut group_cfg = resource_group::Config::default();
+        group_cfg.groups.push(resource_group::GroupConfig {
+            name: "batch".to_owned(),
+            read_quota: 10,
+            ..Default::default()
+        });
+        let groups = ResourceGroupManager::new(&group_cfg);
+        groups.get("batch").consume_read(100);
+        let end_point = Host::new(engine, worker.scheduler(), &cfg, groups);
+        worker.start_batch(end_point, 30).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let mut req = Request::new();
+        req.mut_context().set_resource_group_name("batch".to_owned());
+        let task = RequestTask::new(req, box move |msg| { tx.send(msg).unwrap(); });
+        worker.schedule(Task::Request(task)).unwrap();
+        let resp = rx.recv_timeout(Duration::from_secs(3)).unwrap();
+        assert!(resp.get_region_error().has_server_is_busy());
+    }
 }
@@ -43,6 +43,10 @@ quick_error! {
         Full(allow: usize) {
             description("running queue is full")
         }
+        Throttled(group: String) {
+            description("resource group is throttled")
+            display("resource group {} is throttled", group)
+        }
         Other(err: Box<error::Error + Send + Sync>) {
             from()
             cause(err.as_ref())
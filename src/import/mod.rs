@@ -0,0 +1,24 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Imports SST files into regions. The files are uploaded to every store holding a
+//! peer of the region first, then a raft command carrying only their meta ingests
+//! them on every peer.
+
+mod errors;
+mod sst_importer;
+mod sst_service;
+
+pub use self::errors::{Error, Result};
+pub use self::sst_importer::{ImportFile, SSTImporter};
+pub use self::sst_service::ImportSSTService;
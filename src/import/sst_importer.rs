@@ -0,0 +1,334 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crc::crc32::{self, Digest, Hasher32};
+use kvproto::import_sstpb::SSTMeta;
+use rocksdb::{IngestExternalFileOptions, DB};
+
+use raftstore::store::keys;
+use storage::DATA_CFS;
+use util::escape;
+use util::file::{calc_crc32, get_file_size};
+use util::rocksdb::{get_cf_handle, get_sst_key_range};
+use super::{Error, Result};
+
+// Files are written to the temporary directory while they are uploaded, and moved to
+// the import directory once they are complete.
+const TEMP_DIR: &'static str = ".temp";
+
+/// `SSTImporter` keeps the SST files uploaded to the store until they are ingested.
+///
+/// A file is named after the meta it's uploaded with, so every store holding a peer
+/// of the region finds it by the meta in the ingest command. A file is only complete
+/// in the import directory after its length, checksum and key range are checked
+/// against the meta.
+pub struct SSTImporter {
+    root: PathBuf,
+    temp: PathBuf,
+}
+
+impl SSTImporter {
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<SSTImporter> {
+        let root = root.as_ref().to_path_buf();
+        let temp = root.join(TEMP_DIR);
+        // Files left in the temporary directory are uploads interrupted by a restart.
+        if temp.exists() {
+            try!(fs::remove_dir_all(&temp));
+        }
+        try!(fs::create_dir_all(&temp));
+        Ok(SSTImporter {
+            root: root,
+            temp: temp,
+        })
+    }
+
+    /// Creates the file to upload the data of `meta` to. It fails if the file is being
+    /// uploaded or has been uploaded.
+    pub fn create(&self, meta: &SSTMeta) -> Result<ImportFile> {
+        let name = try!(file_name(meta));
+        let path = self.root.join(&name);
+        if path.exists() {
+            return Err(Error::FileExists(path));
+        }
+        let temp_path = self.temp.join(&name);
+        let file = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+        {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {
+                return Err(Error::FileExists(temp_path))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(ImportFile {
+            meta: meta.clone(),
+            path: path,
+            temp_path: temp_path,
+            file: Some(file),
+            digest: Digest::new(crc32::IEEE),
+        })
+    }
+
+    pub fn exists(&self, meta: &SSTMeta) -> bool {
+        file_name(meta)
+            .map(|name| self.root.join(name).exists())
+            .unwrap_or(false)
+    }
+
+    /// Deletes the file of `meta`, it's fine if the file doesn't exist.
+    pub fn delete(&self, meta: &SSTMeta) -> Result<()> {
+        let path = self.root.join(try!(file_name(meta)));
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Ingests the file of `meta` into the cf of `db` after checking its length and
+    /// checksum. The file is copied into `db` and kept, so the ingestion can be
+    /// replayed until the file is deleted.
+    pub fn ingest(&self, meta: &SSTMeta, db: &DB) -> Result<()> {
+        let path = self.root.join(try!(file_name(meta)));
+        let length = try!(get_file_size(&path));
+        if length != meta.get_length() {
+            let reason = format!("length {}, expect {}", length, meta.get_length());
+            return Err(Error::FileCorrupted(path, reason));
+        }
+        let crc32 = try!(calc_crc32(&path));
+        if crc32 != meta.get_crc32() {
+            let reason = format!("crc32 {}, expect {}", crc32, meta.get_crc32());
+            return Err(Error::FileCorrupted(path, reason));
+        }
+        let handle = try!(get_cf_handle(db, meta.get_cf_name()).map_err(Error::RocksDB));
+        let opts = IngestExternalFileOptions::new();
+        try!(
+            db.ingest_external_file_cf(handle, &opts, &[path.to_str().unwrap()])
+                .map_err(Error::RocksDB)
+        );
+        Ok(())
+    }
+}
+
+/// `ImportFile` is a file being uploaded. The file is removed if it's dropped before
+/// it's finished.
+pub struct ImportFile {
+    meta: SSTMeta,
+    path: PathBuf,
+    temp_path: PathBuf,
+    file: Option<File>,
+    digest: Digest,
+}
+
+impl ImportFile {
+    pub fn append(&mut self, data: &[u8]) -> Result<()> {
+        try!(self.file.as_mut().unwrap().write_all(data));
+        self.digest.write(data);
+        Ok(())
+    }
+
+    /// Checks the file against its meta and moves it to the import directory.
+    pub fn finish(&mut self) -> Result<()> {
+        try!(self.file.take().unwrap().sync_all());
+        try!(self.validate());
+        if self.path.exists() {
+            return Err(Error::FileExists(self.path.clone()));
+        }
+        try!(fs::rename(&self.temp_path, &self.path));
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        let crc32 = self.digest.sum32();
+        if crc32 != self.meta.get_crc32() {
+            let reason = format!("crc32 {}, expect {}", crc32, self.meta.get_crc32());
+            return Err(Error::FileCorrupted(self.temp_path.clone(), reason));
+        }
+        let length = try!(get_file_size(&self.temp_path));
+        if length != self.meta.get_length() {
+            let reason = format!("length {}, expect {}", length, self.meta.get_length());
+            return Err(Error::FileCorrupted(self.temp_path.clone(), reason));
+        }
+        // The keys in the file are data keys, and the range of the meta is in origin
+        // keys, both ends are inclusive.
+        let range = self.meta.get_range();
+        let (smallest, largest) = match get_sst_key_range(self.temp_path.to_str().unwrap()) {
+            Ok(Some(range)) => range,
+            Ok(None) => {
+                let reason = "no keys".to_owned();
+                return Err(Error::FileCorrupted(self.temp_path.clone(), reason));
+            }
+            Err(e) => return Err(Error::RocksDB(e)),
+        };
+        if smallest < keys::data_key(range.get_start()) ||
+            largest > keys::data_key(range.get_end())
+        {
+            let reason = format!(
+                "key range [{}, {}] is out of [{}, {}]",
+                escape(&smallest),
+                escape(&largest),
+                escape(range.get_start()),
+                escape(range.get_end())
+            );
+            return Err(Error::FileCorrupted(self.temp_path.clone(), reason));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ImportFile {
+    fn drop(&mut self) {
+        // The temporary file is gone once the file is finished.
+        if let Err(e) = fs::remove_file(&self.temp_path) {
+            if e.kind() != ErrorKind::NotFound {
+                warn!("failed to remove {}: {:?}", self.temp_path.display(), e);
+            }
+        }
+    }
+}
+
+// The name of the file is unique for the uuid, and tells the region and the cf the
+// file is uploaded for.
+fn file_name(meta: &SSTMeta) -> Result<String> {
+    if meta.get_uuid().is_empty() {
+        return Err(box_err!("empty uuid in {:?}", meta));
+    }
+    // The cf is a part of the path, only the known ones are allowed.
+    let cf = meta.get_cf_name();
+    if DATA_CFS.iter().find(|x| **x == cf).is_none() {
+        return Err(box_err!("invalid cf {:?} in {:?}", cf, meta));
+    }
+    let mut uuid = String::with_capacity(meta.get_uuid().len() * 2);
+    for b in meta.get_uuid() {
+        write!(uuid, "{:02x}", b).unwrap();
+    }
+    let epoch = meta.get_region_epoch();
+    Ok(format!(
+        "{}_{}_{}_{}_{}.sst",
+        uuid,
+        meta.get_region_id(),
+        epoch.get_conf_ver(),
+        epoch.get_version(),
+        cf
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use rocksdb::{ColumnFamilyOptions, EnvOptions, SstFileWriter};
+    use tempdir::TempDir;
+
+    use raftstore::store::engine::Peekable;
+    use storage::{ALL_CFS, CF_DEFAULT};
+    use util::rocksdb::new_engine;
+    use super::*;
+
+    // Writes an SST file of the data keys of `keys`, returns its content and meta.
+    fn new_sst(dir: &Path, uuid: u8, keys: &[&[u8]]) -> (Vec<u8>, SSTMeta) {
+        let path = dir.join(format!("{}.sst", uuid));
+        let mut writer = SstFileWriter::new(EnvOptions::new(), ColumnFamilyOptions::new());
+        writer.open(path.to_str().unwrap()).unwrap();
+        for key in keys {
+            writer.put(&keys::data_key(key), b"v").unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut meta = SSTMeta::new();
+        meta.set_uuid(vec![uuid; 16]);
+        meta.set_cf_name(CF_DEFAULT.to_owned());
+        meta.set_region_id(1);
+        meta.mut_range().set_start(keys[0].to_vec());
+        meta.mut_range().set_end(keys[keys.len() - 1].to_vec());
+        meta.set_length(get_file_size(&path).unwrap());
+        meta.set_crc32(calc_crc32(&path).unwrap());
+        let mut data = vec![];
+        File::open(&path)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        (data, meta)
+    }
+
+    fn upload(importer: &SSTImporter, meta: &SSTMeta, data: &[u8]) -> Result<()> {
+        let mut file = try!(importer.create(meta));
+        for chunk in data.chunks(64) {
+            try!(file.append(chunk));
+        }
+        file.finish()
+    }
+
+    #[test]
+    fn test_import_and_ingest() {
+        let dir = TempDir::new("test_import_and_ingest").unwrap();
+        let importer = SSTImporter::new(dir.path().join("import")).unwrap();
+        let (data, meta) = new_sst(dir.path(), 1, &[b"k1", b"k2"]);
+
+        assert!(!importer.exists(&meta));
+        upload(&importer, &meta, &data).unwrap();
+        assert!(importer.exists(&meta));
+        // A file can't be uploaded twice.
+        match importer.create(&meta) {
+            Err(Error::FileExists(_)) => {}
+            res => panic!("expect file exists, got {:?}", res.map(|_| ())),
+        }
+
+        let db_dir = TempDir::new("test_import_and_ingest_db").unwrap();
+        let db = new_engine(db_dir.path().to_str().unwrap(), ALL_CFS).unwrap();
+        importer.ingest(&meta, &db).unwrap();
+        assert_eq!(&*db.get_value(&keys::data_key(b"k2")).unwrap().unwrap(), b"v");
+        // The file is kept until it's deleted.
+        assert!(importer.exists(&meta));
+        importer.delete(&meta).unwrap();
+        assert!(!importer.exists(&meta));
+        importer.delete(&meta).unwrap();
+        assert!(importer.ingest(&meta, &db).is_err());
+    }
+
+    #[test]
+    fn test_import_invalid_file() {
+        let dir = TempDir::new("test_import_invalid_file").unwrap();
+        let importer = SSTImporter::new(dir.path().join("import")).unwrap();
+        let (data, meta) = new_sst(dir.path(), 1, &[b"k1", b"k2"]);
+
+        let mut bad = meta.clone();
+        bad.set_crc32(meta.get_crc32() + 1);
+        assert!(upload(&importer, &bad, &data).is_err());
+        let mut bad = meta.clone();
+        bad.set_length(meta.get_length() + 1);
+        assert!(upload(&importer, &bad, &data).is_err());
+        let mut bad = meta.clone();
+        bad.mut_range().set_end(b"k1".to_vec());
+        assert!(upload(&importer, &bad, &data).is_err());
+        let mut bad = meta.clone();
+        bad.set_cf_name("../default".to_owned());
+        assert!(importer.create(&bad).is_err());
+        bad.set_cf_name(CF_DEFAULT.to_owned());
+        bad.clear_uuid();
+        assert!(importer.create(&bad).is_err());
+
+        // Nothing is left by the failed uploads.
+        assert!(!importer.exists(&meta));
+        assert_eq!(fs::read_dir(dir.path().join("import").join(TEMP_DIR)).unwrap().count(), 0);
+        upload(&importer, &meta, &data).unwrap();
+        assert!(importer.exists(&meta));
+    }
+}
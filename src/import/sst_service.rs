@@ -0,0 +1,157 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use futures::{Future, Stream};
+use futures::sync::oneshot;
+use futures_cpupool::{Builder, CpuPool};
+use grpc::{ClientStreamingSink, RequestStream, RpcContext, RpcStatus, RpcStatusCode, UnarySink};
+use kvproto::import_sstpb::*;
+use kvproto::import_sstpb_grpc;
+use kvproto::raft_cmdpb::{CmdType, IngestSSTRequest, RaftCmdRequest, Request};
+
+use server::transport::RaftStoreRouter;
+use super::{Error, SSTImporter};
+
+// Uploaded files are written in a separate pool to not block grpc threads.
+const IMPORT_POOL_SIZE: usize = 4;
+
+fn error_to_status(e: Error) -> RpcStatus {
+    let code = match e {
+        Error::FileExists(_) => RpcStatusCode::AlreadyExists,
+        Error::FileCorrupted(..) | Error::InvalidChunk => RpcStatusCode::InvalidArgument,
+        _ => RpcStatusCode::Unknown,
+    };
+    RpcStatus::new(code, Some(format!("{:?}", e)))
+}
+
+/// `ImportSSTService` handles the RPC messages of the `ImportSst` service. A file is
+/// uploaded to every store holding a peer of its region, then the leader proposes
+/// the ingestion of the files, see `SSTImporter`.
+#[derive(Clone)]
+pub struct ImportSSTService<T: RaftStoreRouter + 'static> {
+    router: T,
+    pool: CpuPool,
+    importer: Arc<SSTImporter>,
+}
+
+impl<T: RaftStoreRouter + 'static> ImportSSTService<T> {
+    pub fn new(router: T, importer: Arc<SSTImporter>) -> ImportSSTService<T> {
+        let pool = Builder::new()
+            .name_prefix(thd_name!("sst-importer"))
+            .pool_size(IMPORT_POOL_SIZE)
+            .create();
+        ImportSSTService {
+            router: router,
+            pool: pool,
+            importer: importer,
+        }
+    }
+}
+
+impl<T: RaftStoreRouter + 'static> import_sstpb_grpc::ImportSst for ImportSSTService<T> {
+    fn upload(
+        &self,
+        ctx: RpcContext,
+        stream: RequestStream<UploadRequest>,
+        sink: ClientStreamingSink<UploadResponse>,
+    ) {
+        let importer = self.importer.clone();
+        let pool = self.pool.clone();
+        // The first chunk is the meta of the file, the others are its data.
+        let f = stream
+            .map_err(Error::from)
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(move |(chunk, stream)| {
+                let file = match chunk {
+                    Some(ref chunk) if chunk.has_meta() => try!(importer.create(chunk.get_meta())),
+                    _ => return Err(Error::InvalidChunk),
+                };
+                Ok((file, stream))
+            })
+            .and_then(move |(file, stream)| {
+                let pool1 = pool.clone();
+                stream
+                    .map_err(Error::from)
+                    .fold(file, move |mut file, chunk| {
+                        pool.spawn_fn(move || {
+                            if !chunk.has_data() {
+                                return Err(Error::InvalidChunk);
+                            }
+                            try!(file.append(chunk.get_data()));
+                            Ok(file)
+                        })
+                    })
+                    .and_then(move |mut file| pool1.spawn_fn(move || file.finish()))
+            })
+            .then(|res| match res {
+                Ok(()) => sink.success(UploadResponse::new()),
+                Err(e) => {
+                    error!("failed to upload sst: {:?}", e);
+                    sink.fail(error_to_status(e))
+                }
+            });
+        ctx.spawn(f.map_err(|e| error!("failed to send upload response: {:?}", e)));
+    }
+
+    fn ingest(&self, ctx: RpcContext, mut req: IngestRequest, sink: UnarySink<IngestResponse>) {
+        let mut cmd = RaftCmdRequest::new();
+        {
+            let context = req.mut_context();
+            let header = cmd.mut_header();
+            header.set_region_id(context.get_region_id());
+            header.set_region_epoch(context.take_region_epoch());
+            header.set_peer(context.take_peer());
+        }
+        for meta in req.take_ssts().into_iter() {
+            // The proposer must have the files, a peer missing a file when applying the
+            // command can't ingest it.
+            if !self.importer.exists(&meta) {
+                let status = RpcStatus::new(
+                    RpcStatusCode::NotFound,
+                    Some(format!("{:?} is not uploaded", meta)),
+                );
+                ctx.spawn(sink.fail(status).map_err(|_| ()));
+                return;
+            }
+            let mut ingest = IngestSSTRequest::new();
+            ingest.set_sst(meta);
+            let mut r = Request::new();
+            r.set_cmd_type(CmdType::IngestSST);
+            r.set_ingest_sst(ingest);
+            cmd.mut_requests().push(r);
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let mut resp = IngestResponse::new();
+        if let Err(e) = self.router.send_command(cmd, box move |resp| {
+            let _ = tx.send(resp);
+        }) {
+            resp.set_error(e.into());
+            ctx.spawn(sink.success(resp).map_err(|_| ()));
+            return;
+        }
+        let f = rx.map_err(Error::from).then(move |res| match res {
+            Ok(mut cmd_resp) => {
+                if cmd_resp.get_header().has_error() {
+                    resp.set_error(cmd_resp.mut_header().take_error());
+                }
+                sink.success(resp)
+            }
+            Err(e) => sink.fail(error_to_status(e)),
+        });
+        ctx.spawn(f.map_err(|e| error!("failed to send ingest response: {:?}", e)));
+    }
+}
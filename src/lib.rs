@@ -67,6 +67,7 @@ extern crate fnv;
 extern crate ordermap;
 extern crate flat_map;
 extern crate futures;
+extern crate futures_cpupool;
 extern crate tokio_core;
 extern crate tokio_timer;
 extern crate serde_json;
@@ -75,6 +76,10 @@ extern crate serde;
 extern crate serde_derive;
 extern crate toml;
 extern crate sys_info;
+extern crate flate2;
+extern crate hyper;
+#[cfg(feature = "mem-profiling")]
+extern crate jemallocator;
 
 #[macro_use]
 pub mod util;
@@ -85,5 +90,6 @@ pub mod raftstore;
 pub mod pd;
 pub mod server;
 pub mod coprocessor;
+pub mod import;
 
 pub use storage::Storage;
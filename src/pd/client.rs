@@ -23,6 +23,7 @@ use kvproto::metapb;
 use kvproto::pdpb::{self, Member};
 
 use util::{Either, HandyRwLock};
+use util::security::SecurityManager;
 use pd::PdFuture;
 use super::{Error, PdClient, RegionStat, Result, REQUEST_TIMEOUT};
 use super::util::{check_resp_header, sync_request, validate_endpoints, Inner, LeaderClient};
@@ -36,18 +37,18 @@ pub struct RpcClient {
 }
 
 impl RpcClient {
-    pub fn new(endpoints: &[String]) -> Result<RpcClient> {
+    pub fn new(endpoints: &[String], security_mgr: Arc<SecurityManager>) -> Result<RpcClient> {
         let env = Arc::new(
             EnvBuilder::new()
                 .cq_count(CQ_COUNT)
                 .name_prefix(thd_name!(CLIENT_PREFIX))
                 .build(),
         );
-        let (client, members) = try!(validate_endpoints(env.clone(), endpoints));
+        let (client, members) = try!(validate_endpoints(env.clone(), &security_mgr, endpoints));
 
         Ok(RpcClient {
             cluster_id: members.get_header().get_cluster_id(),
-            leader_client: LeaderClient::new(env, client, members),
+            leader_client: LeaderClient::new(env, security_mgr, client, members),
         })
     }
 
@@ -351,4 +352,28 @@ impl PdClient for RpcClient {
             .request(req, executor, LEADER_CHANGE_RETRY)
             .execute()
     }
+
+    fn scatter_region(&self, region: metapb::Region, leader: metapb::Peer) -> PdFuture<()> {
+        let mut req = pdpb::ScatterRegionRequest::new();
+        req.set_header(self.header());
+        req.set_region_id(region.get_id());
+        req.set_region(region);
+        req.set_leader(leader);
+
+        let executor = |client: &RwLock<Inner>, req: pdpb::ScatterRegionRequest| {
+            let option = CallOption::default().timeout(Duration::from_secs(REQUEST_TIMEOUT));
+            let handler = client.rl().client.scatter_region_async_opt(req, option);
+            handler
+                .map_err(Error::Grpc)
+                .and_then(|resp| {
+                    try!(check_resp_header(resp.get_header()));
+                    Ok(())
+                })
+                .boxed()
+        };
+
+        self.leader_client
+            .request(req, executor, LEADER_CHANGE_RETRY)
+            .execute()
+    }
 }
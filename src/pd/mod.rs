@@ -136,6 +136,9 @@ pub trait PdClient: Send + Sync {
 
     // Report pd the split region.
     fn report_split(&self, left: metapb::Region, right: metapb::Region) -> PdFuture<()>;
+
+    // Ask pd to scatter the peers and the leader of the region over the stores.
+    fn scatter_region(&self, region: metapb::Region, leader: metapb::Peer) -> PdFuture<()>;
 }
 
 const REQUEST_TIMEOUT: u64 = 2; // 2s
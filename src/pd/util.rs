@@ -31,11 +31,13 @@ use kvproto::pdpb_grpc::PdClient;
 use prometheus::HistogramTimer;
 
 use util::{Either, HandyRwLock};
+use util::security::SecurityManager;
 use super::{Error, PdFuture, Result, REQUEST_TIMEOUT};
 use super::metrics::PD_SEND_MSG_HISTOGRAM;
 
 pub struct Inner {
     env: Arc<Environment>,
+    security_mgr: Arc<SecurityManager>,
     pub hb_sender: Either<
         Option<ClientDuplexSender<RegionHeartbeatRequest>>,
         UnboundedSender<RegionHeartbeatRequest>,
@@ -93,6 +95,7 @@ pub struct LeaderClient {
 impl LeaderClient {
     pub fn new(
         env: Arc<Environment>,
+        security_mgr: Arc<SecurityManager>,
         client: PdClient,
         members: GetMembersResponse,
     ) -> LeaderClient {
@@ -101,6 +104,7 @@ impl LeaderClient {
             timer: Timer::default(),
             inner: Arc::new(RwLock::new(Inner {
                 env: env,
+                security_mgr: security_mgr,
                 hb_sender: Either::Left(Some(tx)),
                 hb_receiver: Either::Left(Some(rx)),
                 client: client,
@@ -160,7 +164,11 @@ impl LeaderClient {
 
             let start = Instant::now();
             (
-                try!(try_connect_leader(inner.env.clone(), &inner.members)),
+                try!(try_connect_leader(
+                    inner.env.clone(),
+                    &inner.security_mgr,
+                    &inner.members
+                )),
                 start,
             )
         };
@@ -317,6 +325,7 @@ where
 
 pub fn validate_endpoints(
     env: Arc<Environment>,
+    security_mgr: &SecurityManager,
     endpoints: &[String],
 ) -> Result<(PdClient, GetMembersResponse)> {
     if endpoints.is_empty() {
@@ -333,7 +342,7 @@ pub fn validate_endpoints(
             return Err(box_err!("duplicate PD endpoint {}", ep));
         }
 
-        let (_, resp) = match connect(env.clone(), ep) {
+        let (_, resp) = match connect(env.clone(), security_mgr, ep) {
             Ok(resp) => resp,
             // Ignore failed PD node.
             Err(e) => {
@@ -364,7 +373,7 @@ pub fn validate_endpoints(
 
     match members {
         Some(members) => {
-            let (client, members) = try!(try_connect_leader(env.clone(), &members));
+            let (client, members) = try!(try_connect_leader(env.clone(), security_mgr, &members));
             info!("All PD endpoints are consistent: {:?}", endpoints);
             Ok((client, members))
         }
@@ -372,10 +381,14 @@ pub fn validate_endpoints(
     }
 }
 
-fn connect(env: Arc<Environment>, addr: &str) -> Result<(PdClient, GetMembersResponse)> {
+fn connect(
+    env: Arc<Environment>,
+    security_mgr: &SecurityManager,
+    addr: &str,
+) -> Result<(PdClient, GetMembersResponse)> {
     debug!("connect to PD endpoint: {:?}", addr);
-    let addr = addr.trim_left_matches("http://");
-    let channel = ChannelBuilder::new(env).connect(addr);
+    let addr = addr.trim_left_matches("http://").trim_left_matches("https://");
+    let channel = security_mgr.connect(ChannelBuilder::new(env), addr);
     let client = PdClient::new(channel);
     let option = CallOption::default().timeout(Duration::from_secs(REQUEST_TIMEOUT));
     match client.get_members_opt(GetMembersRequest::new(), option) {
@@ -386,6 +399,7 @@ fn connect(env: Arc<Environment>, addr: &str) -> Result<(PdClient, GetMembersRes
 
 pub fn try_connect_leader(
     env: Arc<Environment>,
+    security_mgr: &SecurityManager,
     previous: &GetMembersResponse,
 ) -> Result<(PdClient, GetMembersResponse)> {
     let previous_leader = previous.get_leader();
@@ -399,7 +413,7 @@ pub fn try_connect_leader(
         .chain(&[previous_leader.clone()])
     {
         for ep in m.get_client_urls() {
-            match connect(env.clone(), ep.as_str()) {
+            match connect(env.clone(), security_mgr, ep.as_str()) {
                 Ok((_, r)) => {
                     let new_cluster_id = r.get_header().get_cluster_id();
                     if new_cluster_id == cluster_id {
@@ -426,7 +440,7 @@ pub fn try_connect_leader(
     if let Some(resp) = resp {
         let leader = resp.get_leader().clone();
         for ep in leader.get_client_urls() {
-            if let Ok((client, _)) = connect(env.clone(), ep.as_str()) {
+            if let Ok((client, _)) = connect(env.clone(), security_mgr, ep.as_str()) {
                 info!("connect to PD leader {:?}", ep);
                 return Ok((client, resp));
             }
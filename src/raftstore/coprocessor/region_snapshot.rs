@@ -332,6 +332,7 @@ mod tests {
         PeerStorage::new(
             engine,
             raft_engine,
+            None,
             r,
             worker::dummy_scheduler(),
             "".to_owned(),
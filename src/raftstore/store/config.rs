@@ -96,6 +96,14 @@ pub struct Config {
     pub right_derive_when_split: bool,
 
     pub allow_remove_leader: bool,
+
+    /// When enabled, a region stops ticking and heartbeating after its leader
+    /// finds all followers are fully replicated and nothing is in flight.
+    pub hibernate_regions: bool,
+    /// A hibernated follower wakes up at this interval to check whether its
+    /// leader is still alive. It also wakes up as soon as the store of its leader
+    /// is found unreachable.
+    pub hibernate_leader_check_interval: ReadableDuration,
 }
 
 impl Default for Config {
@@ -141,6 +149,8 @@ impl Default for Config {
             raft_store_max_leader_lease: ReadableDuration::secs(9),
             right_derive_when_split: true,
             allow_remove_leader: false,
+            hibernate_regions: false,
+            hibernate_leader_check_interval: ReadableDuration::secs(10),
         }
     }
 }
@@ -202,6 +212,16 @@ impl Config {
             ));
         }
 
+        if self.hibernate_regions &&
+            self.hibernate_leader_check_interval.as_millis() < election_timeout
+        {
+            return Err(box_err!(
+                "hibernate leader check interval {} ms is less than election timeout {} ms",
+                self.hibernate_leader_check_interval.as_millis(),
+                election_timeout
+            ));
+        }
+
         Ok(())
     }
 }
@@ -246,5 +266,11 @@ mod tests {
         cfg.raft_election_timeout_ticks = 10;
         cfg.raft_store_max_leader_lease = ReadableDuration::secs(20);
         assert!(cfg.validate().is_err());
+
+        cfg = Config::new();
+        cfg.hibernate_leader_check_interval = ReadableDuration::secs(5);
+        assert!(cfg.validate().is_ok());
+        cfg.hibernate_regions = true;
+        assert!(cfg.validate().is_err());
     }
 }
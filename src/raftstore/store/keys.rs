@@ -134,6 +134,16 @@ pub fn decode_raft_log_key(key: &[u8]) -> Result<(u64, u64)> {
     Ok((region_id, index))
 }
 
+/// Get the region id and suffix from raft state key or apply state key.
+pub fn decode_region_raft_key(key: &[u8]) -> Result<(u64, u8)> {
+    let suffix_idx = REGION_RAFT_PREFIX_KEY.len() + mem::size_of::<u64>();
+    if key.len() != suffix_idx + mem::size_of::<u8>() || !key.starts_with(REGION_RAFT_PREFIX_KEY) {
+        return Err(box_err!("key {} is not a valid region raft key", escape(key)));
+    }
+    let region_id = BigEndian::read_u64(&key[REGION_RAFT_PREFIX_KEY.len()..suffix_idx]);
+    Ok((region_id, key[suffix_idx]))
+}
+
 pub fn raft_log_prefix(region_id: u64) -> Vec<u8> {
     make_region_id_key(region_id, RAFT_LOG_SUFFIX, 0)
 }
@@ -241,6 +251,19 @@ mod tests {
             assert!(raft_log_key(region_id, 1).starts_with(&prefix));
             assert!(raft_state_key(region_id).starts_with(&prefix));
             assert!(apply_state_key(region_id).starts_with(&prefix));
+
+            let key = apply_state_key(region_id);
+            assert_eq!(
+                decode_region_raft_key(&key).unwrap(),
+                (region_id, APPLY_STATE_SUFFIX)
+            );
+            let key = raft_state_key(region_id);
+            assert_eq!(
+                decode_region_raft_key(&key).unwrap(),
+                (region_id, RAFT_STATE_SUFFIX)
+            );
+            assert!(decode_region_raft_key(&raft_log_key(region_id, 1)).is_err());
+            assert!(decode_region_raft_key(&region_state_key(region_id)).is_err());
         }
 
         // test sort.
@@ -166,6 +166,7 @@ pub struct RaftProposeMetrics {
     pub all: u64,
     pub local_read: u64,
     pub read_index: u64,
+    pub follower_read: u64,
     pub normal: u64,
     pub transfer_leader: u64,
     pub conf_change: u64,
@@ -178,6 +179,7 @@ impl Default for RaftProposeMetrics {
             all: 0,
             local_read: 0,
             read_index: 0,
+            follower_read: 0,
             normal: 0,
             transfer_leader: 0,
             conf_change: 0,
@@ -211,6 +213,13 @@ impl RaftProposeMetrics {
                 .unwrap();
             self.read_index = 0;
         }
+        if self.follower_read > 0 {
+            PEER_PROPOSAL_COUNTER_VEC
+                .with_label_values(&["follower_read"])
+                .inc_by(self.follower_read as f64)
+                .unwrap();
+            self.follower_read = 0;
+        }
         if self.normal > 0 {
             PEER_PROPOSAL_COUNTER_VEC
                 .with_label_values(&["normal"])
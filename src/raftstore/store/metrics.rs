@@ -136,6 +136,13 @@ lazy_static! {
             exponential_buckets(0.0005, 2.0, 20).unwrap()
         ).unwrap();
 
+    pub static ref FOLLOWER_READ_WAIT_HISTOGRAM: Histogram =
+        register_histogram!(
+            "tikv_raftstore_follower_read_wait_duration_secs",
+            "Bucketed histogram of follower read waiting for read index and apply duration",
+            exponential_buckets(0.0005, 2.0, 20).unwrap()
+        ).unwrap();
+
     pub static ref PEER_GC_RAFT_LOG_COUNTER: Counter =
         register_counter!(
             "tikv_raftstore_gc_raft_log_total",
@@ -193,4 +200,23 @@ lazy_static! {
             vec![1.0, 2.0, 4.0, 6.0, 8.0, 10.0, 12.0, 14.0, 16.0, 18.0,
                  20.0, 24.0, 32.0, 64.0, 128.0, 256.0]
         ).unwrap();
+
+    pub static ref RAFT_LOG_ENGINE_WRITE_HISTOGRAM: Histogram =
+        register_histogram!(
+            "tikv_raftstore_raft_log_engine_write_duration_seconds",
+            "Bucketed histogram of raft log engine write duration",
+            exponential_buckets(0.0005, 2.0, 20).unwrap()
+        ).unwrap();
+
+    pub static ref RAFT_LOG_ENGINE_FILES_GAUGE: Gauge =
+        register_gauge!(
+            "tikv_raftstore_raft_log_engine_files",
+            "Number of raft log engine files."
+        ).unwrap();
+
+    pub static ref RAFT_LOG_ENGINE_PURGED_FILES_COUNTER: Counter =
+        register_counter!(
+            "tikv_raftstore_raft_log_engine_purged_files_total",
+            "Total number of purged raft log engine files."
+        ).unwrap();
 }
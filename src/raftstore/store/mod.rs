@@ -19,6 +19,7 @@ pub mod transport;
 pub mod bootstrap;
 pub mod cmd_resp;
 pub mod util;
+pub mod raft_log_engine;
 
 mod store;
 mod peer;
@@ -33,10 +34,14 @@ pub use self::store::{create_event_loop, Engines, Store, StoreChannel};
 pub use self::config::Config;
 pub use self::transport::Transport;
 pub use self::peer::Peer;
+pub use self::raft_log_engine::{Config as RaftLogEngineConfig, LogBatch, RaftLogEngine};
 pub use self::bootstrap::{bootstrap_store, clear_prepare_bootstrap, clear_prepare_bootstrap_state,
                           prepare_bootstrap, write_prepare_bootstrap};
 pub use self::engine::{Iterable, Mutable, Peekable};
-pub use self::peer_storage::{do_snapshot, CacheQueryStats, PeerStorage, SnapState,
+pub use self::peer_storage::{do_snapshot, write_initial_apply_state, write_initial_raft_state,
+                             write_peer_state, CacheQueryStats, PeerStorage, SnapState,
                              RAFT_INIT_LOG_INDEX, RAFT_INIT_LOG_TERM};
 pub use self::snap::{check_abort, copy_snapshot, ApplyOptions, SnapEntry, SnapKey, SnapManager,
-                     Snapshot, SnapshotDeleter, SnapshotStatistics};
+                     SnapManagerBuilder, Snapshot, SnapshotDeleter, SnapshotStatistics,
+                     TransferSlot};
+pub use self::worker::compact_range;
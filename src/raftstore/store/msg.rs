@@ -22,6 +22,8 @@ use raft::SnapshotStatus;
 
 use util::escape;
 
+use super::Config;
+
 pub type Callback = Box<FnBox(RaftCmdResponse) + Send>;
 pub type BatchCallback = Box<FnBox(Vec<Option<RaftCmdResponse>>) + Send>;
 
@@ -72,6 +74,9 @@ pub enum Msg {
 
     ReportUnreachable { region_id: u64, to_peer_id: u64 },
 
+    // Asks PD to scatter the region if it's still the leader at the epoch.
+    ScatterRegion { region_id: u64, epoch: RegionEpoch },
+
     // For snapshot stats.
     SnapshotStats,
 
@@ -81,6 +86,9 @@ pub enum Msg {
         index: u64,
         hash: Vec<u8>,
     },
+
+    // For online config reload.
+    ChangeConfig(Config),
 }
 
 impl fmt::Debug for Msg {
@@ -100,6 +108,7 @@ impl fmt::Debug for Msg {
                 to_peer_id,
                 region_id
             ),
+            Msg::ScatterRegion { region_id, .. } => write!(fmt, "scatter region {}", region_id),
             Msg::SnapshotStats => write!(fmt, "Snapshot stats"),
             Msg::ComputeHashResult {
                 region_id,
@@ -112,6 +121,7 @@ impl fmt::Debug for Msg {
                 index,
                 escape(hash)
             ),
+            Msg::ChangeConfig(_) => write!(fmt, "Change Config"),
         }
     }
 }
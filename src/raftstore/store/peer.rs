@@ -38,7 +38,7 @@ use raftstore::store::worker::apply::ExecResult;
 use util::worker::{FutureWorker, Scheduler};
 use raftstore::store::worker::{Apply, ApplyRes, ApplyTask};
 use util::Either;
-use util::time::monotonic_raw_now;
+use util::time::{duration_to_sec, monotonic_raw_now};
 use util::collections::{FlatMap, FlatMapValues as Values, HashSet};
 
 use pd::INVALID_ID;
@@ -55,11 +55,18 @@ use super::local_metrics::{RaftMessageMetrics, RaftMetrics, RaftProposeMetrics,
 
 const TRANSFER_LEADER_ALLOW_LOG_LAG: u64 = 10;
 const DEFAULT_APPEND_WB_SIZE: usize = 4 * 1024;
+// How many times a follower sends read index again if the leader doesn't answer in time.
+const FOLLOWER_READ_RETRY_LIMIT: usize = 3;
 
 struct ReadIndexRequest {
     id: u64,
     cmds: Vec<(RaftCmdRequest, Callback)>,
+    // For follower reads it's the time when the read index is sent to the leader.
     renew_lease_time: Timespec,
+    // The index returned by read index, `None` if it's not confirmed yet.
+    read_index: Option<u64>,
+    // How many times the read index is sent again, only used by follower reads.
+    retry: usize,
 }
 
 impl ReadIndexRequest {
@@ -251,6 +258,12 @@ pub struct Peer {
     //      locally.
     leader_lease_expired_time: Option<Either<Timespec, Timespec>>,
 
+    // A hibernated peer doesn't tick until it's woken up by a message or proposal.
+    hibernated: bool,
+    // For a leader, it's the count of continuous ticks the region stays idle;
+    // for a hibernated follower, it's the ticks since it hibernates.
+    idle_ticks: usize,
+
     pub peer_stat: PeerStat,
 }
 
@@ -306,6 +319,7 @@ impl Peer {
         let ps = try!(PeerStorage::new(
             store.kv_engine(),
             store.raft_engine(),
+            store.raft_log_engine(),
             region,
             sched,
             tag.clone(),
@@ -360,6 +374,8 @@ impl Peer {
             raft_entry_max_size: cfg.raft_entry_max_size.0,
             cfg: cfg,
             leader_lease_expired_time: None,
+            hibernated: false,
+            idle_ticks: 0,
             peer_stat: PeerStat::default(),
         };
 
@@ -402,6 +418,9 @@ impl Peer {
         // write kv rocksdb first in case of restart happen between two write
         try!(self.kv_engine.write(kv_wb));
         try!(self.raft_engine.write(raft_wb));
+        if let Some(engine) = self.get_store().get_raft_log_engine() {
+            try!(engine.clean_region(region.get_id()));
+        }
 
         if self.get_store().is_initialized() {
             // If we meet panic when deleting data and raft log, the dirty data
@@ -531,6 +550,12 @@ impl Peer {
     }
 
     pub fn step(&mut self, m: eraftpb::Message) -> Result<()> {
+        log_fields!(region_id = self.region_id, peer_id = self.peer_id());
+        if util::is_hibernate_msg(&m) && self.maybe_hibernate(&m) {
+            return Ok(());
+        }
+        self.wake_up();
+
         if self.is_leader() && m.get_from() != INVALID_ID {
             self.peer_heartbeats.insert(m.get_from(), Instant::now());
         }
@@ -538,6 +563,156 @@ impl Peer {
         Ok(())
     }
 
+    pub fn set_config(&mut self, cfg: Rc<Config>) {
+        self.raft_entry_max_size = cfg.raft_entry_max_size.0;
+        self.cfg = cfg;
+    }
+
+    #[inline]
+    pub fn is_hibernated(&self) -> bool {
+        self.hibernated
+    }
+
+    /// Let the peer tick again if it's hibernated.
+    pub fn wake_up(&mut self) {
+        if !self.hibernated {
+            return;
+        }
+        debug!("{} wakes up", self.tag);
+        self.hibernated = false;
+        self.idle_ticks = 0;
+        if self.is_leader() {
+            // No message is received during hibernation, don't report followers as down.
+            let now = Instant::now();
+            let peer_ids: Vec<_> = self.region()
+                .get_peers()
+                .iter()
+                .map(|p| p.get_id())
+                .collect();
+            for peer_id in peer_ids {
+                self.peer_heartbeats.insert(peer_id, now);
+            }
+        }
+    }
+
+    // A leader region is idle when all the entries are replicated to all followers
+    // and applied, and there is nothing in flight.
+    fn is_idle(&self) -> bool {
+        let raft = &self.raft_group.raft;
+        let last_index = raft.raft_log.last_index();
+        raft.raft_log.committed == last_index && !raft.pending_conf &&
+            raft.lead_transferee.is_none() &&
+            self.get_store().applied_index() == last_index &&
+            self.pending_reads.reads.is_empty() &&
+            self.apply_proposals.is_empty() &&
+            raft.prs.values().all(|pr| pr.matched == last_index)
+    }
+
+    /// Check whether the leader can hibernate, called on every raft base tick.
+    /// Return true if the leader hibernates.
+    pub fn check_hibernate<T: Transport>(&mut self, trans: &T) -> bool {
+        if !self.cfg.hibernate_regions || !self.is_leader() || self.pending_remove ||
+            !self.is_idle()
+        {
+            self.idle_ticks = 0;
+            return false;
+        }
+        // Wait for an election timeout, so followers have learned the latest
+        // committed index from heartbeats.
+        self.idle_ticks += 1;
+        if self.idle_ticks < self.cfg.raft_election_timeout_ticks {
+            return false;
+        }
+
+        let term = self.term();
+        let commit = self.raft_group.raft.raft_log.committed;
+        let from = self.peer_id();
+        let peers = self.region().get_peers().to_vec();
+        for peer in peers {
+            if peer.get_id() == from {
+                continue;
+            }
+            let mut msg = eraftpb::Message::new();
+            msg.set_msg_type(MessageType::MsgHeartbeat);
+            msg.set_from(from);
+            msg.set_to(peer.get_id());
+            msg.set_term(term);
+            msg.set_commit(commit);
+            util::set_hibernate_msg(&mut msg);
+            if let Err(e) = self.send_raft_message(msg, trans) {
+                warn!("{} failed to send hibernate msg to {:?}: {:?}", self.tag, peer, e);
+            }
+        }
+
+        debug!("{} hibernates at term {} commit {}", self.tag, term, commit);
+        self.hibernated = true;
+        self.idle_ticks = 0;
+        true
+    }
+
+    // A follower hibernates on the leader's request only if it has everything the
+    // leader has. Otherwise the message is stepped as a normal heartbeat, whose
+    // response wakes the leader up again.
+    fn maybe_hibernate(&mut self, m: &eraftpb::Message) -> bool {
+        {
+            let raft = &self.raft_group.raft;
+            if raft.state != StateRole::Follower || m.get_term() != raft.term ||
+                m.get_from() != raft.leader_id ||
+                raft.raft_log.committed != m.get_commit() ||
+                raft.raft_log.last_index() != m.get_commit()
+            {
+                return false;
+            }
+        }
+        if self.pending_remove || self.is_applying_snapshot() || self.has_pending_snapshot() ||
+            !self.pending_reads.reads.is_empty()
+        {
+            return false;
+        }
+
+        debug!("{} hibernates with leader {}", self.tag, m.get_from());
+        self.hibernated = true;
+        self.idle_ticks = 0;
+        true
+    }
+
+    /// Tick a hibernated peer. A follower wakes up periodically to check whether
+    /// the leader is alive, see `wake_up_follower`.
+    pub fn tick_hibernated<T: Transport>(&mut self, trans: &T) {
+        if self.is_leader() {
+            return;
+        }
+        self.idle_ticks += 1;
+        let check_ticks = self.cfg.hibernate_leader_check_interval.as_millis() /
+            cmp::max(self.cfg.raft_base_tick_interval.as_millis(), 1);
+        if (self.idle_ticks as u64) < check_ticks {
+            return;
+        }
+        self.wake_up_follower(trans);
+    }
+
+    /// Wake up a hibernated follower and ping the leader. The leader wakes up and
+    /// heartbeats again if it's alive, otherwise the election timeout will fire and
+    /// a new leader is elected.
+    pub fn wake_up_follower<T: Transport>(&mut self, trans: &T) {
+        if !self.hibernated || self.is_leader() {
+            return;
+        }
+        self.wake_up();
+        let leader_id = self.leader_id();
+        if leader_id == INVALID_ID {
+            return;
+        }
+        let mut msg = eraftpb::Message::new();
+        msg.set_msg_type(MessageType::MsgHeartbeatResponse);
+        msg.set_from(self.peer_id());
+        msg.set_to(leader_id);
+        msg.set_term(self.term());
+        if let Err(e) = self.send_raft_message(msg, trans) {
+            warn!("{} failed to ping leader {}: {:?}", self.tag, leader_id, e);
+        }
+    }
+
     pub fn check_peers(&mut self) {
         if !self.is_leader() {
             self.peer_heartbeats.clear();
@@ -677,6 +852,31 @@ impl Peer {
         self.get_store().applied_index_term == self.term()
     }
 
+    #[inline]
+    pub fn ready_to_handle_follower_read(&self, read_index: u64) -> bool {
+        // All entries committed when the leader got the read index must be applied,
+        // otherwise the follower may return stale values.
+        !self.is_applying_snapshot() && self.get_store().applied_index() >= read_index
+    }
+
+    /// Check whether the request can be served by this peer as a follower. Only
+    /// reads that opt in follower read are allowed, and the leader must be known
+    /// to confirm the read index.
+    pub fn can_serve_follower_read(&self, req: &RaftCmdRequest) -> bool {
+        if req.has_admin_request() || req.get_requests().is_empty() ||
+            self.leader_id() == INVALID_ID ||
+            !req.get_header().get_follower_read()
+        {
+            return false;
+        }
+        req.get_requests()
+            .iter()
+            .all(|r| match r.get_cmd_type() {
+                CmdType::Get | CmdType::Snap => true,
+                _ => false,
+            })
+    }
+
     pub fn take_apply_proposals(&mut self) -> Option<RegionProposal> {
         if self.apply_proposals.is_empty() {
             return None;
@@ -692,6 +892,7 @@ impl Peer {
         ctx: &mut ReadyContext<T>,
         worker: &FutureWorker<PdTask>,
     ) {
+        log_fields!(region_id = self.region_id, peer_id = self.peer_id());
         self.marked_to_be_checked = false;
         if self.pending_remove {
             return;
@@ -761,6 +962,7 @@ impl Peer {
         ready: &mut Ready,
         invoke_ctx: InvokeContext,
     ) -> Option<ApplySnapResult> {
+        log_fields!(region_id = self.region_id, peer_id = self.peer_id());
         if invoke_ctx.has_snapshot() {
             // When apply snapshot, there is no log applied and not compacted yet.
             self.raft_log_size_hint = 0;
@@ -784,6 +986,7 @@ impl Peer {
     }
 
     pub fn handle_raft_ready_apply(&mut self, mut ready: Ready, apply_tasks: &mut Vec<Apply>) {
+        log_fields!(region_id = self.region_id, peer_id = self.peer_id());
         // Call `handle_raft_committed_entries` directly here may lead to inconsistency.
         // In some cases, there will be some pending committed entries when applying a
         // snapshot. If we call `handle_raft_committed_entries` directly, these updates
@@ -828,27 +1031,40 @@ impl Peer {
     }
 
     fn apply_reads(&mut self, ready: &Ready) {
+        let is_leader = self.is_leader();
+        let term = self.term();
         let mut propose_time = None;
-        if self.ready_to_handle_read() {
-            for state in &ready.read_states {
-                let mut read = self.pending_reads.reads.pop_front().unwrap();
-                assert_eq!(state.request_ctx.as_slice(), read.binary_id());
-                for (req, cb) in read.cmds.drain(..) {
-                    // TODO: we should add test case that a split happens before pending
-                    // read-index is handled. To do this we need to control async-apply
-                    // procedure precisely.
-                    cb(self.handle_read(req));
+        for state in &ready.read_states {
+            let ready_cnt = self.pending_reads.ready_cnt;
+            let pos = self.pending_reads
+                .reads
+                .iter()
+                .skip(ready_cnt)
+                .position(|read| state.request_ctx.as_slice() == read.binary_id());
+            let pos = match pos {
+                Some(pos) => ready_cnt + pos,
+                None => {
+                    // The request may have been dropped already, e.g. a follower read
+                    // that is not answered in time.
+                    debug!("{} ignore read state {:?}", self.tag, state);
+                    continue;
+                }
+            };
+            // The leader answers read index in order, so earlier requests that are
+            // still unanswered must have been dropped silently.
+            for mut read in self.pending_reads.reads.drain(ready_cnt..pos) {
+                for (_, cb) in read.cmds.drain(..) {
+                    apply::notify_stale_req(term, cb);
                 }
-                propose_time = Some(read.renew_lease_time);
             }
-        } else {
-            for state in &ready.read_states {
-                let read = &self.pending_reads.reads[self.pending_reads.ready_cnt];
-                assert_eq!(state.request_ctx.as_slice(), read.binary_id());
-                self.pending_reads.ready_cnt += 1;
+            let read = &mut self.pending_reads.reads[ready_cnt];
+            read.read_index = Some(state.index);
+            self.pending_reads.ready_cnt += 1;
+            if is_leader {
                 propose_time = Some(read.renew_lease_time);
             }
         }
+        self.handle_ready_reads();
 
         // Note that only after handle read_states can we identify what requests are
         // actually stale.
@@ -897,14 +1113,86 @@ impl Peer {
             self.mark_to_be_checked(groups);
         }
 
-        if self.pending_reads.ready_cnt > 0 && self.ready_to_handle_read() {
-            for _ in 0..self.pending_reads.ready_cnt {
-                let mut read = self.pending_reads.reads.pop_front().unwrap();
-                for (req, cb) in read.cmds.drain(..) {
-                    cb(self.handle_read(req));
+        self.handle_ready_reads();
+    }
+
+    /// Handle the reads whose read index has been confirmed, in order.
+    fn handle_ready_reads(&mut self) {
+        let is_leader = self.is_leader();
+        while self.pending_reads.ready_cnt > 0 {
+            if is_leader {
+                if !self.ready_to_handle_read() {
+                    return;
+                }
+            } else {
+                let read_index = self.pending_reads.reads[0].read_index.unwrap();
+                if !self.ready_to_handle_follower_read(read_index) {
+                    return;
+                }
+            }
+
+            let mut read = self.pending_reads.reads.pop_front().unwrap();
+            self.pending_reads.ready_cnt -= 1;
+            for (req, cb) in read.cmds.drain(..) {
+                // TODO: we should add test case that a split happens before pending
+                // read-index is handled. To do this we need to control async-apply
+                // procedure precisely.
+                cb(self.handle_read(req));
+            }
+            if !is_leader {
+                if let Ok(elapsed) = (monotonic_raw_now() - read.renew_lease_time).to_std() {
+                    FOLLOWER_READ_WAIT_HISTOGRAM.observe(duration_to_sec(elapsed));
                 }
             }
-            self.pending_reads.ready_cnt = 0;
+        }
+    }
+
+    /// Handle the follower reads that are ready and send read index again for the ones
+    /// that are not answered by the leader in time. The leader may reject read index
+    /// silently, e.g. when it hasn't committed an entry in its term yet.
+    pub fn check_follower_reads(&mut self) {
+        if self.is_leader() || self.pending_reads.reads.is_empty() {
+            return;
+        }
+        self.handle_ready_reads();
+
+        let now = monotonic_raw_now();
+        let deadline = now - self.cfg.raft_store_max_leader_lease();
+        let ready_cnt = self.pending_reads.ready_cnt;
+        let expired = self.pending_reads
+            .reads
+            .iter()
+            .skip(ready_cnt)
+            .take_while(|read| read.renew_lease_time <= deadline)
+            .count();
+        if expired == 0 {
+            return;
+        }
+        info!(
+            "{} {} follower reads are not answered by leader {} in time",
+            self.tag,
+            expired,
+            self.leader_id()
+        );
+        let term = self.term();
+        let reads: Vec<_> = self.pending_reads
+            .reads
+            .drain(ready_cnt..ready_cnt + expired)
+            .collect();
+        for mut read in reads {
+            if self.leader_id() == INVALID_ID || read.retry >= FOLLOWER_READ_RETRY_LIMIT {
+                for (_, cb) in read.cmds.drain(..) {
+                    apply::notify_stale_req(term, cb);
+                }
+                continue;
+            }
+            // The leader answers read index in order, so the retried read gets a new id
+            // and is queued after the reads that are still waiting for the leader.
+            read.id = self.pending_reads.next_id();
+            read.renew_lease_time = now;
+            read.retry += 1;
+            self.raft_group.read_index(read.binary_id().to_vec());
+            self.pending_reads.reads.push_back(read);
         }
     }
 
@@ -1000,9 +1288,11 @@ impl Peer {
         mut err_resp: RaftCmdResponse,
         metrics: &mut RaftProposeMetrics,
     ) -> bool {
+        log_fields!(region_id = self.region_id, peer_id = self.peer_id());
         if self.pending_remove {
             return false;
         }
+        self.wake_up();
 
         metrics.all += 1;
 
@@ -1056,6 +1346,7 @@ impl Peer {
             cmd_resp::bind_error(&mut resp, box_err!("peer is pending remove"));
             return Some(resp);
         }
+        self.wake_up();
         metrics.all += 1;
 
         // TODO: deny non-snapshot request.
@@ -1087,6 +1378,15 @@ impl Peer {
     }
 
     fn get_handle_policy(&mut self, req: &RaftCmdRequest) -> Result<RequestPolicy> {
+        if !self.is_leader() {
+            if !self.can_serve_follower_read(req) {
+                let leader = self.get_peer_from_cache(self.leader_id());
+                return Err(Error::NotLeader(self.region_id, leader));
+            }
+            // A follower always needs to ask the leader for the read index.
+            return Ok(RequestPolicy::ReadIndex);
+        }
+
         if req.has_admin_request() {
             if apply::get_change_peer_cmd(req).is_some() {
                 return Ok(RequestPolicy::ProposeConfChange);
@@ -1102,7 +1402,9 @@ impl Peer {
         for r in req.get_requests() {
             match r.get_cmd_type() {
                 CmdType::Get | CmdType::Snap => is_read = true,
-                CmdType::Delete | CmdType::Put | CmdType::DeleteRange => is_write = true,
+                CmdType::Delete | CmdType::Put | CmdType::DeleteRange | CmdType::IngestSST => {
+                    is_write = true
+                }
                 CmdType::Prewrite | CmdType::Invalid => {
                     return Err(box_err!(
                         "invalid cmd type {:?}, message maybe currupted",
@@ -1282,6 +1584,10 @@ impl Peer {
         cb: Callback,
         metrics: &mut RaftProposeMetrics,
     ) -> bool {
+        if !self.is_leader() {
+            return self.follower_read_index(req, cb, metrics);
+        }
+
         metrics.read_index += 1;
 
         let renew_lease_time = monotonic_raw_now();
@@ -1315,6 +1621,8 @@ impl Peer {
             id: id,
             cmds: vec![(req, cb)],
             renew_lease_time: renew_lease_time,
+            read_index: None,
+            retry: 0,
         });
 
         match self.leader_lease_expired_time {
@@ -1338,6 +1646,31 @@ impl Peer {
         true
     }
 
+    fn follower_read_index(
+        &mut self,
+        req: RaftCmdRequest,
+        cb: Callback,
+        metrics: &mut RaftProposeMetrics,
+    ) -> bool {
+        metrics.follower_read += 1;
+
+        // Unlike the leader, a follower can't batch the request into a pending read,
+        // whose read index may be confirmed before the request arrives. If the leader
+        // drops the read index message, the read is retried in `check_follower_reads`.
+        let id = self.pending_reads.next_id();
+        let ctx: [u8; 8] = unsafe { mem::transmute(id) };
+        self.raft_group.read_index(ctx.to_vec());
+
+        self.pending_reads.reads.push_back(ReadIndexRequest {
+            id: id,
+            cmds: vec![(req, cb)],
+            renew_lease_time: monotonic_raw_now(),
+            read_index: None,
+            retry: 0,
+        });
+        true
+    }
+
     fn propose_normal(
         &mut self,
         mut req: RaftCmdRequest,
@@ -1669,6 +2002,7 @@ impl Peer {
                 CmdType::Put |
                 CmdType::Delete |
                 CmdType::DeleteRange |
+                CmdType::IngestSST |
                 CmdType::Invalid => unreachable!(),
             };
 
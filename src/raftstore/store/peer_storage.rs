@@ -20,7 +20,7 @@ use std::{cmp, error, u64};
 use std::time::Instant;
 use std::collections::VecDeque;
 
-use rocksdb::{Writable, WriteBatch, DB};
+use rocksdb::{Writable, WriteBatch, WriteOptions, DB};
 use protobuf::Message;
 
 use kvproto::metapb::{self, Region};
@@ -35,6 +35,7 @@ use super::worker::RegionTask;
 use super::keys::{self, enc_end_key, enc_start_key};
 use super::engine::{Iterable, Mutable, Peekable, Snapshot as DbSnapshot};
 use super::peer::ReadyContext;
+use super::raft_log_engine::{LogBatch, RaftLogEngine};
 use super::metrics::*;
 use super::{SnapEntry, SnapKey, SnapManager, SnapshotStatistics};
 use storage::CF_RAFT;
@@ -259,6 +260,8 @@ impl CacheQueryStats {
 pub struct PeerStorage {
     pub kv_engine: Arc<DB>,
     pub raft_engine: Arc<DB>,
+    // If set, raft log entries are stored in it instead of `raft_engine`.
+    pub raft_log_engine: Option<Arc<RaftLogEngine>>,
 
     pub region: metapb::Region,
     pub raft_state: RaftLocalState,
@@ -307,6 +310,8 @@ pub struct InvokeContext {
     pub apply_state: RaftApplyState,
     last_term: u64,
     pub snap_region: Option<Region>,
+    // Entries to be written to the raft log engine, if it's enabled.
+    pub log_batch: LogBatch,
 }
 
 impl InvokeContext {
@@ -317,6 +322,7 @@ impl InvokeContext {
             apply_state: store.apply_state.clone(),
             last_term: store.last_term,
             snap_region: None,
+            log_batch: LogBatch::new(),
         }
     }
 
@@ -365,7 +371,12 @@ impl InvokeContext {
     }
 }
 
-pub fn recover_from_applying_state(kv_engine: &DB, raft_engine: &DB, region_id: u64) -> Result<()> {
+pub fn recover_from_applying_state(
+    kv_engine: &DB,
+    raft_engine: &DB,
+    raft_log_engine: Option<&RaftLogEngine>,
+    region_id: u64,
+) -> Result<()> {
     let snapshot_raft_state_key = keys::snapshot_raft_state_key(region_id);
     let snapshot_raft_state: RaftLocalState =
         match box_try!(kv_engine.get_msg_cf(CF_RAFT, &snapshot_raft_state_key)) {
@@ -380,7 +391,7 @@ pub fn recover_from_applying_state(kv_engine: &DB, raft_engine: &DB, region_id:
         };
 
     let raft_state_key = keys::raft_state_key(region_id);
-    let raft_state: RaftLocalState = match box_try!(raft_engine.get_msg(&raft_state_key)) {
+    let mut raft_state: RaftLocalState = match box_try!(raft_engine.get_msg(&raft_state_key)) {
         Some(state) => state,
         None => RaftLocalState::new(),
     };
@@ -393,6 +404,38 @@ pub fn recover_from_applying_state(kv_engine: &DB, raft_engine: &DB, region_id:
     // after restart, we need check last_index.
     if last_index(&snapshot_raft_state) > last_index(&raft_state) {
         try!(raft_engine.put_msg(&raft_state_key, &snapshot_raft_state));
+        raft_state = snapshot_raft_state.clone();
+    }
+
+    // with the raft log engine, the old entries are cleaned up together with the entries
+    // received after the snapshot, and they are written after raft_local_state. the
+    // raft_local_state written with the snapshot commits the snapshot index, if the entries
+    // it points to are not written yet, it falls back to snapshot_raft_state. the missing
+    // entries are never acknowledged, as messages are only sent after all writes.
+    let engine = match raft_log_engine {
+        Some(engine) => engine,
+        None => return Ok(()),
+    };
+    let snapshot_index = last_index(&snapshot_raft_state);
+    if raft_state.get_hard_state().get_commit() < snapshot_index {
+        // the old raft_local_state, its entries are not cleaned up yet.
+        return Ok(());
+    }
+    let last = last_index(&raft_state);
+    let written = last == snapshot_index ||
+        (engine.first_index(region_id) == Some(snapshot_index + 1) &&
+            engine.last_index(region_id).map_or(false, |l| l >= last));
+    if !written {
+        let wb = WriteBatch::new();
+        try!(wb.put_msg(&raft_state_key, &snapshot_raft_state));
+        let mut write_opts = WriteOptions::new();
+        write_opts.set_sync(true);
+        try!(raft_engine.write_opt(wb, &write_opts));
+        raft_state = snapshot_raft_state;
+    }
+    if last_index(&raft_state) == snapshot_index {
+        try!(engine.clean_region(region_id));
+        try!(engine.sync());
     }
     Ok(())
 }
@@ -435,6 +478,7 @@ fn init_apply_state(kv_engine: &DB, region: &Region) -> Result<RaftApplyState> {
 
 fn init_last_term(
     raft_engine: &DB,
+    raft_log_engine: Option<&RaftLogEngine>,
     region: &Region,
     raft_state: &RaftLocalState,
     apply_state: &RaftApplyState,
@@ -449,8 +493,12 @@ fn init_last_term(
     } else {
         assert!(last_idx > RAFT_INIT_LOG_INDEX);
     }
-    let last_log_key = keys::raft_log_key(region.get_id(), last_idx);
-    Ok(match try!(raft_engine.get_msg::<Entry>(&last_log_key)) {
+    Ok(match try!(get_raft_entry(
+        raft_engine,
+        raft_log_engine,
+        region.get_id(),
+        last_idx
+    )) {
         None => {
             return Err(box_err!(
                 "[region {}] entry at {} doesn't exist, may lose data.",
@@ -462,10 +510,24 @@ fn init_last_term(
     })
 }
 
+/// Get the raft log entry from the raft log engine if it's enabled, otherwise from raftdb.
+pub fn get_raft_entry(
+    raft_engine: &DB,
+    raft_log_engine: Option<&RaftLogEngine>,
+    region_id: u64,
+    idx: u64,
+) -> Result<Option<Entry>> {
+    match raft_log_engine {
+        Some(engine) => engine.get_entry(region_id, idx),
+        None => raft_engine.get_msg(&keys::raft_log_key(region_id, idx)),
+    }
+}
+
 impl PeerStorage {
     pub fn new(
         kv_engine: Arc<DB>,
         raft_engine: Arc<DB>,
+        raft_log_engine: Option<Arc<RaftLogEngine>>,
         region: &metapb::Region,
         region_sched: Scheduler<RegionTask>,
         tag: String,
@@ -484,6 +546,7 @@ impl PeerStorage {
         }
         let last_term = try!(init_last_term(
             &raft_engine,
+            raft_log_engine.as_ref().map(|e| e.as_ref()),
             region,
             &raft_state,
             &apply_state
@@ -492,6 +555,7 @@ impl PeerStorage {
         Ok(PeerStorage {
             kv_engine: kv_engine,
             raft_engine: raft_engine,
+            raft_log_engine: raft_log_engine,
             region: region.clone(),
             raft_state: raft_state,
             apply_state: apply_state,
@@ -594,6 +658,20 @@ impl PeerStorage {
         max_size: u64,
         buf: &mut Vec<Entry>,
     ) -> raft::Result<u64> {
+        if let Some(ref engine) = self.raft_log_engine {
+            let total_size = try!(engine.fetch_entries_to(
+                self.get_region_id(),
+                low,
+                high,
+                max_size,
+                buf
+            ));
+            if buf.len() == (high - low) as usize || total_size > max_size {
+                return Ok(total_size);
+            }
+            return Err(RaftError::Store(StorageError::Unavailable));
+        }
+
         let mut total_size: u64 = 0;
         let mut next_index = low;
         let mut exceeded_max_size = false;
@@ -843,16 +921,22 @@ impl PeerStorage {
             (e.get_index(), e.get_term())
         };
 
-        for entry in entries {
-            try!(raft_wb.put_msg(
-                &keys::raft_log_key(self.get_region_id(), entry.get_index()),
-                entry
-            ));
-        }
+        if self.raft_log_engine.is_some() {
+            // Previously appended entries which never committed are replaced
+            // by the raft log engine automatically.
+            ctx.log_batch.add_entries(self.get_region_id(), entries);
+        } else {
+            for entry in entries {
+                try!(raft_wb.put_msg(
+                    &keys::raft_log_key(self.get_region_id(), entry.get_index()),
+                    entry
+                ));
+            }
 
-        // Delete any previously appended log entries which never committed.
-        for i in (last_index + 1)..(prev_last_index + 1) {
-            try!(raft_wb.delete(&keys::raft_log_key(self.get_region_id(), i)));
+            // Delete any previously appended log entries which never committed.
+            for i in (last_index + 1)..(prev_last_index + 1) {
+                try!(raft_wb.delete(&keys::raft_log_key(self.get_region_id(), i)));
+            }
         }
 
         ctx.raft_state.set_last_index(last_index);
@@ -894,6 +978,12 @@ impl PeerStorage {
         if self.is_initialized() {
             // we can only delete the old data when the peer is initialized.
             try!(self.clear_meta(kv_wb, raft_wb));
+            if self.raft_log_engine.is_some() {
+                // The old entries are not covered by `clear_meta`. The log batch of a
+                // snapshot is written after the raft state, so the old raft state never
+                // points to cleaned entries, see `recover_from_applying_state`.
+                ctx.log_batch.clean_region(region_id);
+            }
         }
 
         try!(write_peer_state(
@@ -933,6 +1023,7 @@ impl PeerStorage {
         try!(clear_meta(
             &self.kv_engine,
             &self.raft_engine,
+            self.raft_log_engine.is_some(),
             kv_wb,
             raft_wb,
             region_id,
@@ -984,6 +1075,10 @@ impl PeerStorage {
         self.raft_engine.clone()
     }
 
+    pub fn get_raft_log_engine(&self) -> Option<Arc<RaftLogEngine>> {
+        self.raft_log_engine.clone()
+    }
+
     /// Check whether the storage has finished applying snapshot.
     #[inline]
     pub fn is_applying_snapshot(&self) -> bool {
@@ -1184,9 +1279,13 @@ impl PeerStorage {
 }
 
 /// Delete all meta belong to the region. Results are stored in `wb`.
+///
+/// If `use_raft_log_engine` is true, raft logs are kept in the raft log engine, and it's
+/// caller's duty to clean them up after the raft state is removed.
 pub fn clear_meta(
     kv_engine: &DB,
     raft_engine: &DB,
+    use_raft_log_engine: bool,
     kv_wb: &WriteBatch,
     raft_wb: &WriteBatch,
     region_id: u64,
@@ -1197,6 +1296,16 @@ pub fn clear_meta(
     try!(kv_wb.delete_cf(handle, &keys::region_state_key(region_id)));
     try!(kv_wb.delete_cf(handle, &keys::apply_state_key(region_id)));
 
+    if use_raft_log_engine {
+        try!(raft_wb.delete(&keys::raft_state_key(region_id)));
+        info!(
+            "[region {}] clear peer 1 meta key, 1 apply key and 1 raft key, takes {:?}",
+            region_id,
+            t.elapsed()
+        );
+        return Ok(());
+    }
+
     let last_index = last_index(raft_state);
     let mut first_index = last_index + 1;
     let begin_log_key = keys::raft_log_key(region_id, 0);
@@ -1227,6 +1336,7 @@ pub fn clear_meta(
 pub fn do_snapshot(
     mgr: SnapManager,
     raft_db: &DB,
+    raft_log_engine: Option<&RaftLogEngine>,
     snap: &DbSnapshot,
     region_id: u64,
 ) -> raft::Result<Snapshot> {
@@ -1247,7 +1357,7 @@ pub fn do_snapshot(
     let term = if idx == apply_state.get_truncated_state().get_index() {
         apply_state.get_truncated_state().get_term()
     } else {
-        match try!(raft_db.get_msg::<Entry>(&keys::raft_log_key(region_id, idx))) {
+        match try!(get_raft_entry(raft_db, raft_log_engine, region_id, idx)) {
             None => return Err(box_err!("entry {} of {} not found.", idx, region_id)),
             Some(entry) => entry.get_term(),
         }
@@ -1394,7 +1504,7 @@ mod test {
     use raft::{Error as RaftError, StorageError};
     use tempdir::*;
     use protobuf;
-    use raftstore::store::{bootstrap, Engines};
+    use raftstore::store::{bootstrap, Engines, RaftLogEngineConfig};
     use raftstore::store::worker::RegionRunner;
     use raftstore::store::worker::RegionTask;
     use util::worker::{Scheduler, Worker};
@@ -1406,6 +1516,14 @@ mod test {
     use super::*;
 
     fn new_storage(sched: Scheduler<RegionTask>, path: &TempDir) -> PeerStorage {
+        new_storage_opt(sched, path, None)
+    }
+
+    fn new_storage_opt(
+        sched: Scheduler<RegionTask>,
+        path: &TempDir,
+        raft_log_engine: Option<Arc<RaftLogEngine>>,
+    ) -> PeerStorage {
         let kv_db = Arc::new(new_engine(path.path().to_str().unwrap(), ALL_CFS).unwrap());
         let raft_path = path.path().join(Path::new("raft"));
         let raft_db = Arc::new(
@@ -1415,7 +1533,15 @@ mod test {
         bootstrap::bootstrap_store(&engines, 1, 1).expect("");
         let region = bootstrap::prepare_bootstrap(&engines, 1, 1, 1).expect("");
         let metrics = Rc::new(RefCell::new(CacheQueryStats::default()));
-        PeerStorage::new(kv_db, raft_db, &region, sched, "".to_owned(), metrics).unwrap()
+        PeerStorage::new(
+            kv_db,
+            raft_db,
+            raft_log_engine,
+            &region,
+            sched,
+            "".to_owned(),
+            metrics,
+        ).unwrap()
     }
 
     fn new_storage_from_ents(
@@ -1673,7 +1799,7 @@ mod test {
         let mut worker = Worker::new("snap_manager");
         let sched = worker.scheduler();
         let mut s = new_storage_from_ents(sched, &td, &ents);
-        let runner = RegionRunner::new(s.kv_engine.clone(), s.raft_engine.clone(), mgr, 0);
+        let runner = RegionRunner::new(s.kv_engine.clone(), s.raft_engine.clone(), None, mgr, 0);
         worker.start(runner).unwrap();
         let snap = s.snapshot();
         let unavailable = RaftError::Store(StorageError::SnapshotTemporarilyUnavailable);
@@ -1952,7 +2078,13 @@ mod test {
         let sched = worker.scheduler();
         let s1 = new_storage_from_ents(sched.clone(), &td1, &ents);
         let runner =
-            RegionRunner::new(s1.kv_engine.clone(), s1.raft_engine.clone(), mgr.clone(), 0);
+            RegionRunner::new(
+                s1.kv_engine.clone(),
+                s1.raft_engine.clone(),
+                None,
+                mgr.clone(),
+                0,
+            );
         worker.start(runner).unwrap();
         assert!(s1.snapshot().is_err());
         let snap1 = match *s1.snap_state.borrow() {
@@ -1997,6 +2129,140 @@ mod test {
         validate_cache(&s3, &[]);
     }
 
+    #[test]
+    fn test_storage_apply_snapshot_with_log_engine() {
+        let ents = vec![
+            new_entry(3, 3),
+            new_entry(4, 4),
+            new_entry(5, 5),
+            new_entry(6, 6),
+        ];
+        let td1 = TempDir::new("tikv-store-test").unwrap();
+        let snap_dir = TempDir::new("snap").unwrap();
+        let mgr = SnapManager::new(snap_dir.path().to_str().unwrap(), None);
+        let mut worker = Worker::new("snap_manager");
+        let sched = worker.scheduler();
+        let s1 = new_storage_from_ents(sched.clone(), &td1, &ents);
+        let runner =
+            RegionRunner::new(s1.kv_engine.clone(), s1.raft_engine.clone(), None, mgr, 0);
+        worker.start(runner).unwrap();
+        assert!(s1.snapshot().is_err());
+        let snap1 = match *s1.snap_state.borrow() {
+            SnapState::Generating(ref rx) => rx.recv_timeout(Duration::from_secs(3)).unwrap(),
+            ref s => panic!("unexpected state: {:?}", s),
+        };
+
+        let td2 = TempDir::new("tikv-store-test").unwrap();
+        let mut cfg = RaftLogEngineConfig::default();
+        cfg.enabled = true;
+        cfg.dir = td2.path().join("raftlog").to_str().unwrap().to_owned();
+        let engine = Arc::new(RaftLogEngine::open(cfg).unwrap());
+        let mut s2 = new_storage_opt(sched, &td2, Some(engine.clone()));
+        let mut ctx = InvokeContext::new(&s2);
+        let mut raft_wb = WriteBatch::new();
+        s2.append(&mut ctx, &[new_entry(6, 5), new_entry(7, 5)], &mut raft_wb)
+            .unwrap();
+        engine.write(&ctx.log_batch, true).unwrap();
+        s2.raft_state = ctx.raft_state;
+        assert_eq!(engine.first_index(1), Some(6));
+
+        let mut ctx = InvokeContext::new(&s2);
+        let kv_wb = WriteBatch::new();
+        let raft_wb = WriteBatch::new();
+        s2.apply_snapshot(&mut ctx, &snap1, &kv_wb, &raft_wb)
+            .unwrap();
+        engine.write(&ctx.log_batch, true).unwrap();
+        assert_eq!(ctx.raft_state.get_last_index(), 6);
+        assert!(engine.first_index(1).is_none());
+        assert!(engine.get_entry(1, 7).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_recover_from_applying_state_with_log_engine() {
+        let ents = vec![
+            new_entry(3, 3),
+            new_entry(4, 4),
+            new_entry(5, 5),
+            new_entry(6, 6),
+        ];
+        let td1 = TempDir::new("tikv-store-test").unwrap();
+        let snap_dir = TempDir::new("snap").unwrap();
+        let mgr = SnapManager::new(snap_dir.path().to_str().unwrap(), None);
+        let mut worker = Worker::new("snap_manager");
+        let sched = worker.scheduler();
+        let s1 = new_storage_from_ents(sched.clone(), &td1, &ents);
+        let runner =
+            RegionRunner::new(s1.kv_engine.clone(), s1.raft_engine.clone(), None, mgr, 0);
+        worker.start(runner).unwrap();
+        assert!(s1.snapshot().is_err());
+        let snap1 = match *s1.snap_state.borrow() {
+            SnapState::Generating(ref rx) => rx.recv_timeout(Duration::from_secs(3)).unwrap(),
+            ref s => panic!("unexpected state: {:?}", s),
+        };
+
+        // The store restarts after writing the kv write batch, the raft write batch and
+        // the log batch of the snapshot in turn, with entries [6, 7] before the snapshot
+        // and [7, 8] received with it.
+        let cases = vec![
+            (false, false, 7, Some(6)),
+            (true, false, 6, None),
+            (true, true, 8, Some(7)),
+        ];
+        for (write_raft_state, write_log_batch, exp_last, exp_first) in cases {
+            let td = TempDir::new("tikv-store-test").unwrap();
+            let mut cfg = RaftLogEngineConfig::default();
+            cfg.enabled = true;
+            cfg.dir = td.path().join("raftlog").to_str().unwrap().to_owned();
+            let engine = Arc::new(RaftLogEngine::open(cfg).unwrap());
+            let mut s = new_storage_opt(sched.clone(), &td, Some(engine.clone()));
+            let mut ctx = InvokeContext::new(&s);
+            let mut raft_wb = WriteBatch::new();
+            s.append(&mut ctx, &[new_entry(6, 5), new_entry(7, 5)], &mut raft_wb)
+                .unwrap();
+            ctx.save_raft_state_to(&mut raft_wb).unwrap();
+            engine.write(&ctx.log_batch, true).unwrap();
+            s.raft_engine.write(raft_wb).unwrap();
+            s.raft_state = ctx.raft_state;
+
+            let mut ctx = InvokeContext::new(&s);
+            let mut kv_wb = WriteBatch::new();
+            let mut raft_wb = WriteBatch::new();
+            s.apply_snapshot(&mut ctx, &snap1, &kv_wb, &raft_wb)
+                .unwrap();
+            s.append(&mut ctx, &[new_entry(7, 6), new_entry(8, 6)], &mut raft_wb)
+                .unwrap();
+            ctx.raft_state.mut_hard_state().set_commit(6);
+            ctx.save_raft_state_to(&mut raft_wb).unwrap();
+            ctx.save_snapshot_raft_state_to(6, &s.kv_engine, &mut kv_wb)
+                .unwrap();
+            ctx.save_apply_state_to(&s.kv_engine, &mut kv_wb).unwrap();
+            s.kv_engine.write(kv_wb).unwrap();
+            if write_raft_state {
+                s.raft_engine.write(raft_wb).unwrap();
+            }
+            if write_log_batch {
+                engine.write(&ctx.log_batch, true).unwrap();
+            }
+
+            recover_from_applying_state(&s.kv_engine, &s.raft_engine, Some(&engine), 1).unwrap();
+            let region = ctx.snap_region.unwrap();
+            let metrics = Rc::new(RefCell::new(CacheQueryStats::default()));
+            let s = PeerStorage::new(
+                s.kv_engine.clone(),
+                s.raft_engine.clone(),
+                Some(engine.clone()),
+                &region,
+                sched.clone(),
+                "".to_owned(),
+                metrics,
+            ).unwrap();
+            assert_eq!(s.last_index(), exp_last);
+            assert_eq!(engine.first_index(1), exp_first);
+            let fetched = s.entries(7, exp_last + 1, u64::MAX).unwrap();
+            assert_eq!(fetched.len() as u64, exp_last - 6);
+        }
+    }
+
     #[test]
     fn test_canceling_snapshot() {
         let td = TempDir::new("tikv-store-test").unwrap();
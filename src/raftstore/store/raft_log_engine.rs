@@ -0,0 +1,1056 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An append-only engine dedicated to raft log entries.
+//!
+//! All writes are appended to the active log file as records:
+//!
+//! ```text
+//! | checksum (u32) | payload length (u32) | payload |
+//! ```
+//!
+//! A payload is a sequence of items, each of which either appends entries to a region,
+//! compacts the entries of a region before an index, or cleans up a whole region. The
+//! position of every live entry is kept in a per-region in-memory index, so a read is a
+//! single `pread` on the file holding the entry. Compaction only updates the index; a log
+//! file is deleted once no region references it anymore. On startup all files are replayed
+//! to rebuild the indexes, and a torn record at the tail of the last file is truncated.
+//!
+//! Appends are serialized by the writer lock, which owns the write position of the active
+//! file. A record is written at its position first, and only then its entries are put into
+//! the indexes, so a reader never sees an entry that is not in the file yet. The index lock
+//! is only held to update or copy the indexes: reads and rewrites do their I/O without it,
+//! and the `fsync` of a write is issued after both locks are released, so neither readers
+//! nor other appends wait for it. Only rotating to a new file syncs under the writer lock,
+//! otherwise a crash could keep records of the new file but lose the tail of the old one.
+
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::u64;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+use protobuf::Message;
+
+use kvproto::eraftpb::Entry;
+use raftstore::Result;
+use util::collections::HashMap;
+use util::config::ReadableSize;
+use util::time::SlowTimer;
+use super::metrics::*;
+
+const LOG_FILE_SUFFIX: &'static str = ".raftlog";
+const LOG_FILE_NUM_LEN: usize = 16;
+const INIT_FILE_NUM: u64 = 1;
+// checksum + payload length.
+const RECORD_HEADER_LEN: usize = 8;
+
+const ITEM_ENTRIES: u8 = 1;
+const ITEM_COMPACT: u8 = 2;
+const ITEM_CLEAN: u8 = 3;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    // Store raft log entries in the raft log engine instead of raftdb.
+    pub enabled: bool,
+    pub dir: String,
+    // The active log file is rotated once it grows beyond this size.
+    pub target_file_size: ReadableSize,
+    // Unsynced writes are flushed to disk whenever they exceed this size.
+    pub bytes_per_sync: ReadableSize,
+    // When the total size of log files exceeds this value, regions still
+    // referencing the oldest file are rewritten so the file can be purged.
+    pub purge_threshold: ReadableSize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            enabled: false,
+            dir: "".to_owned(),
+            target_file_size: ReadableSize::mb(128),
+            bytes_per_sync: ReadableSize::kb(256),
+            purge_threshold: ReadableSize::gb(10),
+        }
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if self.target_file_size.0 == 0 {
+            return Err(box_err!("raft log engine target file size should be larger than 0."));
+        }
+        if self.purge_threshold.0 < self.target_file_size.0 {
+            return Err(box_err!(
+                "raft log engine purge threshold {} must >= target file size {}",
+                self.purge_threshold.0,
+                self.target_file_size.0
+            ));
+        }
+        Ok(())
+    }
+}
+
+enum LogItem {
+    Entries(u64, Vec<Entry>),
+    Compact(u64, u64),
+    Clean(u64),
+}
+
+/// A batch of raft log operations that are written to the engine atomically.
+#[derive(Default)]
+pub struct LogBatch {
+    items: Vec<LogItem>,
+}
+
+impl LogBatch {
+    pub fn new() -> LogBatch {
+        LogBatch::default()
+    }
+
+    /// Append entries to the region. Entries with index not less than the first one
+    /// are replaced.
+    pub fn add_entries(&mut self, region_id: u64, entries: &[Entry]) {
+        if entries.is_empty() {
+            return;
+        }
+        self.items
+            .push(LogItem::Entries(region_id, entries.to_vec()));
+    }
+
+    /// Discard all entries of the region whose index is less than `index`.
+    pub fn compact_to(&mut self, region_id: u64, index: u64) {
+        self.items.push(LogItem::Compact(region_id, index));
+    }
+
+    /// Discard all entries of the region.
+    pub fn clean_region(&mut self, region_id: u64) {
+        self.items.push(LogItem::Clean(region_id));
+    }
+
+    /// Move all items of `other` to the end of this batch.
+    pub fn append(&mut self, other: &mut LogBatch) {
+        self.items.append(&mut other.items);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut payload = vec![];
+        for item in &self.items {
+            match *item {
+                LogItem::Entries(region_id, ref entries) => {
+                    try!(payload.write_u8(ITEM_ENTRIES));
+                    try!(payload.write_u64::<BigEndian>(region_id));
+                    try!(payload.write_u64::<BigEndian>(entries.len() as u64));
+                    for e in entries {
+                        let data = try!(e.write_to_bytes());
+                        try!(payload.write_u64::<BigEndian>(e.get_index()));
+                        try!(payload.write_u32::<BigEndian>(data.len() as u32));
+                        payload.extend_from_slice(&data);
+                    }
+                }
+                LogItem::Compact(region_id, index) => {
+                    try!(payload.write_u8(ITEM_COMPACT));
+                    try!(payload.write_u64::<BigEndian>(region_id));
+                    try!(payload.write_u64::<BigEndian>(index));
+                }
+                LogItem::Clean(region_id) => {
+                    try!(payload.write_u8(ITEM_CLEAN));
+                    try!(payload.write_u64::<BigEndian>(region_id));
+                }
+            }
+        }
+        Ok(payload)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct EntryIndex {
+    index: u64,
+    file_num: u64,
+    offset: u64,
+    len: u64,
+}
+
+/// The in-memory index of all live entries of a region.
+#[derive(Default)]
+struct MemTable {
+    entries: VecDeque<EntryIndex>,
+}
+
+impl MemTable {
+    fn first_index(&self) -> Option<u64> {
+        self.entries.front().map(|e| e.index)
+    }
+
+    fn last_index(&self) -> Option<u64> {
+        self.entries.back().map(|e| e.index)
+    }
+
+    // Entries are always appended in the order of log files, so the first entry
+    // lives in the oldest file.
+    fn first_file_num(&self) -> Option<u64> {
+        self.entries.front().map(|e| e.file_num)
+    }
+
+    fn get(&self, index: u64) -> Option<EntryIndex> {
+        let first = match self.first_index() {
+            None => return None,
+            Some(first) => first,
+        };
+        if index < first {
+            return None;
+        }
+        self.entries.get((index - first) as usize).cloned()
+    }
+
+    fn append(&mut self, entries: Vec<EntryIndex>) {
+        let first_new = match entries.first() {
+            None => return,
+            Some(e) => e.index,
+        };
+        if let (Some(first), Some(last)) = (self.first_index(), self.last_index()) {
+            if first_new <= first || first_new > last + 1 {
+                // Either all entries are overwritten, or there is a hole and the
+                // stale entries can never be read again.
+                self.entries.clear();
+            } else {
+                self.entries.truncate((first_new - first) as usize);
+            }
+        }
+        self.entries.extend(entries);
+    }
+
+    fn compact_to(&mut self, index: u64) -> u64 {
+        let mut count = 0;
+        while self.first_index().map_or(false, |first| first < index) {
+            self.entries.pop_front();
+            count += 1;
+        }
+        if self.entries.capacity() > 2 * self.entries.len() {
+            self.entries.shrink_to_fit();
+        }
+        count
+    }
+}
+
+fn log_file_name(file_num: u64) -> String {
+    format!("{:0width$}{}", file_num, LOG_FILE_SUFFIX, width = LOG_FILE_NUM_LEN)
+}
+
+fn parse_log_file_num(name: &str) -> Option<u64> {
+    if !name.ends_with(LOG_FILE_SUFFIX) || name.len() != LOG_FILE_NUM_LEN + LOG_FILE_SUFFIX.len()
+    {
+        return None;
+    }
+    name[..LOG_FILE_NUM_LEN].parse().ok()
+}
+
+fn read_at(file: &File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    let mut read = 0;
+    while read < len {
+        let n = try!(file.read_at(&mut buf[read..], offset + read as u64));
+        if n == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("read {} bytes at {} but got {}", len, offset, read),
+            ));
+        }
+        read += n;
+    }
+    Ok(buf)
+}
+
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    let mut written = 0;
+    while written < buf.len() {
+        let n = try!(file.write_at(&buf[written..], offset + written as u64));
+        if n == 0 {
+            return Err(io::Error::new(
+                ErrorKind::WriteZero,
+                format!("write {} bytes at {} but wrote {}", buf.len(), offset, written),
+            ));
+        }
+        written += n;
+    }
+    Ok(())
+}
+
+/// Check the record at the beginning of `buf`, and return its payload length.
+/// Return `None` if the record is torn or corrupted.
+fn check_record(buf: &[u8]) -> Option<usize> {
+    if buf.len() < RECORD_HEADER_LEN {
+        return None;
+    }
+    let mut header = &buf[..RECORD_HEADER_LEN];
+    let checksum = header.read_u32::<BigEndian>().unwrap();
+    let len = header.read_u32::<BigEndian>().unwrap() as usize;
+    if buf.len() < RECORD_HEADER_LEN + len {
+        return None;
+    }
+    let payload = &buf[RECORD_HEADER_LEN..RECORD_HEADER_LEN + len];
+    if crc32::checksum_ieee(payload) != checksum {
+        return None;
+    }
+    Some(len)
+}
+
+/// Update the memtables according to a payload that is stored at `offset` of the file.
+fn apply_payload(
+    memtables: &mut HashMap<u64, MemTable>,
+    payload: &[u8],
+    file_num: u64,
+    offset: u64,
+) -> io::Result<()> {
+    let mut reader = payload;
+    while !reader.is_empty() {
+        let item_type = try!(reader.read_u8());
+        let region_id = try!(reader.read_u64::<BigEndian>());
+        match item_type {
+            ITEM_ENTRIES => {
+                let count = try!(reader.read_u64::<BigEndian>());
+                let mut entries = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let index = try!(reader.read_u64::<BigEndian>());
+                    let len = try!(reader.read_u32::<BigEndian>()) as usize;
+                    if reader.len() < len {
+                        return Err(io::Error::new(ErrorKind::InvalidData, "entry is truncated"));
+                    }
+                    let pos = (payload.len() - reader.len()) as u64;
+                    entries.push(EntryIndex {
+                        index: index,
+                        file_num: file_num,
+                        offset: offset + pos,
+                        len: len as u64,
+                    });
+                    reader = &reader[len..];
+                }
+                memtables
+                    .entry(region_id)
+                    .or_insert_with(MemTable::default)
+                    .append(entries);
+            }
+            ITEM_COMPACT => {
+                let index = try!(reader.read_u64::<BigEndian>());
+                if let Some(table) = memtables.get_mut(&region_id) {
+                    table.compact_to(index);
+                }
+            }
+            ITEM_CLEAN => {
+                memtables.remove(&region_id);
+            }
+            t => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unknown item type {}", t),
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+// The write position of the active file. It's only touched by appends.
+struct Writer {
+    file_num: u64,
+    file: Arc<File>,
+    offset: u64,
+    unsynced_bytes: u64,
+}
+
+struct Inner {
+    // A purged file stays readable through the handles still held by readers.
+    files: BTreeMap<u64, Arc<File>>,
+    active_file_num: u64,
+    // The size of the active file covered by the indexes.
+    active_file_size: u64,
+    memtables: HashMap<u64, MemTable>,
+}
+
+impl Inner {
+    fn entry_file(&self, region_id: u64, idx: &EntryIndex) -> Result<Arc<File>> {
+        match self.files.get(&idx.file_num) {
+            None => Err(box_err!(
+                "[region {}] log file {} for entry {} has been purged",
+                region_id,
+                idx.file_num,
+                idx.index
+            )),
+            Some(f) => Ok(f.clone()),
+        }
+    }
+}
+
+fn read_entry(file: &File, region_id: u64, idx: &EntryIndex) -> Result<Entry> {
+    let data = try!(read_at(file, idx.offset, idx.len as usize));
+    let mut entry = Entry::new();
+    try!(entry.merge_from_bytes(&data));
+    if entry.get_index() != idx.index {
+        return Err(box_err!(
+            "[region {}] corrupted entry in log file {} at {}, expect index {}, got {}",
+            region_id,
+            idx.file_num,
+            idx.offset,
+            idx.index,
+            entry.get_index()
+        ));
+    }
+    Ok(entry)
+}
+
+pub struct RaftLogEngine {
+    cfg: Config,
+    dir: PathBuf,
+    // Always acquired before `inner` if both are needed.
+    writer: Mutex<Writer>,
+    inner: Mutex<Inner>,
+}
+
+impl RaftLogEngine {
+    /// Open the engine in `cfg.dir`, and recover the indexes from existing log files.
+    pub fn open(cfg: Config) -> Result<RaftLogEngine> {
+        let dir = PathBuf::from(&cfg.dir);
+        if !dir.exists() {
+            try!(fs::create_dir_all(&dir));
+        }
+
+        let mut file_nums = vec![];
+        for entry in try!(fs::read_dir(&dir)) {
+            let entry = try!(entry);
+            if let Some(num) = entry.file_name().to_str().and_then(parse_log_file_num) {
+                file_nums.push(num);
+            }
+        }
+        file_nums.sort();
+
+        let t = SlowTimer::new();
+        let mut inner = Inner {
+            files: BTreeMap::new(),
+            active_file_num: INIT_FILE_NUM,
+            active_file_size: 0,
+            memtables: HashMap::default(),
+        };
+        for (i, &num) in file_nums.iter().enumerate() {
+            let is_last = i + 1 == file_nums.len();
+            let path = dir.join(log_file_name(num));
+            let mut file = try!(OpenOptions::new().read(true).write(true).open(&path));
+            let mut buf = vec![];
+            try!(file.read_to_end(&mut buf));
+
+            let mut offset = 0;
+            while offset < buf.len() {
+                let len = match check_record(&buf[offset..]) {
+                    Some(len) => len,
+                    None if is_last => {
+                        warn!(
+                            "raft log file {} has a torn record at {}, truncate {} bytes",
+                            path.display(),
+                            offset,
+                            buf.len() - offset
+                        );
+                        try!(file.set_len(offset as u64));
+                        try!(file.sync_all());
+                        break;
+                    }
+                    None => {
+                        return Err(box_err!(
+                            "raft log file {} is corrupted at {}",
+                            path.display(),
+                            offset
+                        ))
+                    }
+                };
+                let payload_offset = offset + RECORD_HEADER_LEN;
+                try!(apply_payload(
+                    &mut inner.memtables,
+                    &buf[payload_offset..payload_offset + len],
+                    num,
+                    payload_offset as u64
+                ));
+                offset = payload_offset + len;
+            }
+
+            inner.files.insert(num, Arc::new(file));
+            inner.active_file_num = num;
+            inner.active_file_size = offset as u64;
+        }
+        if inner.files.is_empty() {
+            let file = try!(create_log_file(&dir, INIT_FILE_NUM));
+            inner.files.insert(INIT_FILE_NUM, Arc::new(file));
+        }
+        let writer = Writer {
+            file_num: inner.active_file_num,
+            file: inner.files[&inner.active_file_num].clone(),
+            offset: inner.active_file_size,
+            unsynced_bytes: 0,
+        };
+        RAFT_LOG_ENGINE_FILES_GAUGE.set(inner.files.len() as f64);
+        slow_log!(
+            t,
+            "recover raft log engine from {} files with {} regions",
+            file_nums.len(),
+            inner.memtables.len()
+        );
+
+        Ok(RaftLogEngine {
+            cfg: cfg,
+            dir: dir,
+            writer: Mutex::new(writer),
+            inner: Mutex::new(inner),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Write the batch to the active log file. If `sync` is false, the data will be
+    /// flushed to disk together with later writes once `bytes_per_sync` is exceeded.
+    pub fn write(&self, batch: &LogBatch, sync: bool) -> Result<usize> {
+        if batch.is_empty() {
+            return Ok(0);
+        }
+        let payload = try!(batch.encode());
+        let writer = self.writer.lock().unwrap();
+        self.append_payload(writer, &payload, sync)
+    }
+
+    // The writer lock is released before syncing the file.
+    fn append_payload(
+        &self,
+        mut writer: MutexGuard<Writer>,
+        payload: &[u8],
+        sync: bool,
+    ) -> Result<usize> {
+        let timer = RAFT_LOG_ENGINE_WRITE_HISTOGRAM.start_coarse_timer();
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        try!(record.write_u32::<BigEndian>(crc32::checksum_ieee(payload)));
+        try!(record.write_u32::<BigEndian>(payload.len() as u32));
+        record.extend_from_slice(payload);
+
+        let offset = writer.offset;
+        try!(write_all_at(&writer.file, &record, offset));
+        writer.offset += record.len() as u64;
+        writer.unsynced_bytes += record.len() as u64;
+        {
+            let mut inner = self.inner.lock().unwrap();
+            // The payload is encoded by ourselves, it must be valid.
+            apply_payload(
+                &mut inner.memtables,
+                payload,
+                writer.file_num,
+                offset + RECORD_HEADER_LEN as u64,
+            ).unwrap();
+            inner.active_file_size = writer.offset;
+        }
+
+        let mut to_sync = None;
+        if writer.offset >= self.cfg.target_file_size.0 {
+            try!(self.rotate(&mut writer));
+        } else if sync || writer.unsynced_bytes >= self.cfg.bytes_per_sync.0 {
+            writer.unsynced_bytes = 0;
+            to_sync = Some(writer.file.clone());
+        }
+        drop(writer);
+        if let Some(file) = to_sync {
+            try!(file.sync_data());
+        }
+        timer.observe_duration();
+        Ok(record.len())
+    }
+
+    fn rotate(&self, writer: &mut Writer) -> Result<()> {
+        try!(writer.file.sync_all());
+        let file_num = writer.file_num + 1;
+        let file = Arc::new(try!(create_log_file(&self.dir, file_num)));
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.files.insert(file_num, file.clone());
+            inner.active_file_num = file_num;
+            inner.active_file_size = 0;
+            RAFT_LOG_ENGINE_FILES_GAUGE.set(inner.files.len() as f64);
+        }
+        writer.file_num = file_num;
+        writer.file = file;
+        writer.offset = 0;
+        writer.unsynced_bytes = 0;
+        Ok(())
+    }
+
+    /// Flush all written data to disk.
+    pub fn sync(&self) -> Result<()> {
+        let file = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.unsynced_bytes = 0;
+            writer.file.clone()
+        };
+        try!(file.sync_data());
+        Ok(())
+    }
+
+    pub fn first_index(&self, region_id: u64) -> Option<u64> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .memtables
+            .get(&region_id)
+            .and_then(|t| t.first_index())
+    }
+
+    pub fn last_index(&self, region_id: u64) -> Option<u64> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .memtables
+            .get(&region_id)
+            .and_then(|t| t.last_index())
+    }
+
+    pub fn get_entry(&self, region_id: u64, index: u64) -> Result<Option<Entry>> {
+        let (file, idx) = {
+            let inner = self.inner.lock().unwrap();
+            let idx = match inner.memtables.get(&region_id).and_then(|t| t.get(index)) {
+                None => return Ok(None),
+                Some(idx) => idx,
+            };
+            (try!(inner.entry_file(region_id, &idx)), idx)
+        };
+        read_entry(&file, region_id, &idx).map(Some)
+    }
+
+    /// Fetch entries in [low, high) to `buf`, and return the total size of fetched entries.
+    /// At least one entry is fetched if it exists, and fetching stops once the total size
+    /// exceeds `max_size`. It stops at the first missing entry, so callers should check
+    /// the count of fetched entries.
+    pub fn fetch_entries_to(
+        &self,
+        region_id: u64,
+        low: u64,
+        high: u64,
+        max_size: u64,
+        buf: &mut Vec<Entry>,
+    ) -> Result<u64> {
+        let mut total_size = 0;
+        let mut to_read = vec![];
+        {
+            let inner = self.inner.lock().unwrap();
+            let table = match inner.memtables.get(&region_id) {
+                None => return Ok(0),
+                Some(t) => t,
+            };
+            for index in low..high {
+                let idx = match table.get(index) {
+                    None => break,
+                    Some(idx) => idx,
+                };
+                total_size += idx.len;
+                if !to_read.is_empty() && total_size > max_size {
+                    break;
+                }
+                to_read.push((try!(inner.entry_file(region_id, &idx)), idx));
+                if total_size > max_size {
+                    break;
+                }
+            }
+        }
+        for &(ref file, ref idx) in &to_read {
+            buf.push(try!(read_entry(file, region_id, idx)));
+        }
+        Ok(total_size)
+    }
+
+    /// Discard entries of the region whose index is less than `index`, and return the
+    /// count of discarded entries.
+    pub fn compact_to(&self, region_id: u64, index: u64) -> Result<u64> {
+        // The indexes only change under the writer lock, so the count stays valid.
+        let writer = self.writer.lock().unwrap();
+        let count = {
+            let inner = self.inner.lock().unwrap();
+            let table = match inner.memtables.get(&region_id) {
+                None => return Ok(0),
+                Some(t) => t,
+            };
+            match table.first_index() {
+                Some(first) if first < index => {
+                    cmp::min(index, table.last_index().unwrap() + 1) - first
+                }
+                _ => return Ok(0),
+            }
+        };
+        let mut batch = LogBatch::new();
+        batch.compact_to(region_id, index);
+        let payload = try!(batch.encode());
+        try!(self.append_payload(writer, &payload, false));
+        Ok(count)
+    }
+
+    /// Discard all entries of the region.
+    pub fn clean_region(&self, region_id: u64) -> Result<()> {
+        let writer = self.writer.lock().unwrap();
+        if !self.inner.lock().unwrap().memtables.contains_key(&region_id) {
+            return Ok(());
+        }
+        let mut batch = LogBatch::new();
+        batch.clean_region(region_id);
+        let payload = try!(batch.encode());
+        try!(self.append_payload(writer, &payload, false));
+        Ok(())
+    }
+
+    /// Delete the log files that are not referenced by any region, and return the count
+    /// of deleted files. If the total size of log files exceeds `purge_threshold`, the
+    /// entries of regions that still reference the oldest file are rewritten to the
+    /// active file first.
+    pub fn purge_expired_files(&self) -> Result<usize> {
+        let total_size = {
+            let inner = self.inner.lock().unwrap();
+            (inner.files.len() as u64 - 1) * self.cfg.target_file_size.0 +
+                inner.active_file_size
+        };
+        if total_size > self.cfg.purge_threshold.0 {
+            try!(self.rewrite_oldest_file());
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let min_file_num = inner
+            .memtables
+            .values()
+            .filter_map(|t| t.first_file_num())
+            .min()
+            .map_or(inner.active_file_num, |n| cmp::min(n, inner.active_file_num));
+        let expired: Vec<u64> = inner
+            .files
+            .keys()
+            .take_while(|n| **n < min_file_num)
+            .cloned()
+            .collect();
+        for file_num in &expired {
+            inner.files.remove(file_num);
+            try!(fs::remove_file(self.dir.join(log_file_name(*file_num))));
+        }
+        if !expired.is_empty() {
+            info!(
+                "raft log engine purged {} files before {}",
+                expired.len(),
+                min_file_num
+            );
+            RAFT_LOG_ENGINE_PURGED_FILES_COUNTER.inc_by(expired.len() as f64).unwrap();
+            RAFT_LOG_ENGINE_FILES_GAUGE.set(inner.files.len() as f64);
+        }
+        Ok(expired.len())
+    }
+
+    // The entries are read without the lock, a region changed in the meantime is
+    // skipped and left to the next purge.
+    fn rewrite_oldest_file(&self) -> Result<()> {
+        let mut to_read = vec![];
+        let oldest = {
+            let inner = self.inner.lock().unwrap();
+            let oldest = *inner.files.keys().next().unwrap();
+            if oldest == inner.active_file_num {
+                return Ok(());
+            }
+            for (region_id, table) in &inner.memtables {
+                if table.first_file_num() != Some(oldest) {
+                    continue;
+                }
+                let mut idxes = Vec::with_capacity(table.entries.len());
+                for idx in &table.entries {
+                    idxes.push((try!(inner.entry_file(*region_id, idx)), *idx));
+                }
+                to_read.push((*region_id, idxes));
+            }
+            oldest
+        };
+
+        let mut rewrites = Vec::with_capacity(to_read.len());
+        for (region_id, idxes) in to_read {
+            let mut entries = Vec::with_capacity(idxes.len());
+            for &(ref file, ref idx) in &idxes {
+                entries.push(try!(read_entry(file, region_id, idx)));
+            }
+            let (first, last) = (idxes[0].1, idxes[idxes.len() - 1].1);
+            rewrites.push((region_id, first, last, entries));
+        }
+
+        let writer = self.writer.lock().unwrap();
+        let mut batch = LogBatch::new();
+        let mut count = 0;
+        {
+            let inner = self.inner.lock().unwrap();
+            for (region_id, first, last, entries) in rewrites {
+                // Compactions pop the front and appends replace the back, so the region is
+                // unchanged if both ends still point to the same positions.
+                let unchanged = inner.memtables.get(&region_id).map_or(false, |t| {
+                    t.entries.front() == Some(&first) && t.entries.back() == Some(&last)
+                });
+                if unchanged {
+                    batch.add_entries(region_id, &entries);
+                    count += 1;
+                }
+            }
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        info!(
+            "raft log engine rewrites {} regions referencing log file {}",
+            count,
+            oldest
+        );
+        let payload = try!(batch.encode());
+        try!(self.append_payload(writer, &payload, true));
+        Ok(())
+    }
+}
+
+fn create_log_file(dir: &Path, file_num: u64) -> Result<File> {
+    let file = try!(
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(dir.join(log_file_name(file_num)))
+    );
+    // Make sure the new file is persisted in the directory.
+    try!(try!(File::open(dir)).sync_all());
+    Ok(file)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::thread;
+    use std::u64;
+
+    use kvproto::eraftpb::Entry;
+    use tempdir::TempDir;
+    use util::config::ReadableSize;
+
+    use super::*;
+
+    fn new_entry(index: u64, term: u64) -> Entry {
+        let mut e = Entry::new();
+        e.set_index(index);
+        e.set_term(term);
+        e.set_data(vec![b'x'; 64]);
+        e
+    }
+
+    fn new_config(path: &TempDir) -> Config {
+        Config {
+            enabled: true,
+            dir: path.path().to_str().unwrap().to_owned(),
+            target_file_size: ReadableSize::kb(1),
+            bytes_per_sync: ReadableSize::kb(4),
+            purge_threshold: ReadableSize::mb(1),
+        }
+    }
+
+    fn append(engine: &RaftLogEngine, region_id: u64, low: u64, high: u64, term: u64) {
+        let ents: Vec<_> = (low..high).map(|i| new_entry(i, term)).collect();
+        let mut batch = LogBatch::new();
+        batch.add_entries(region_id, &ents);
+        engine.write(&batch, true).unwrap();
+    }
+
+    fn must_fetch(engine: &RaftLogEngine, region_id: u64, low: u64, high: u64, term: u64) {
+        let mut ents = vec![];
+        engine
+            .fetch_entries_to(region_id, low, high, u64::MAX, &mut ents)
+            .unwrap();
+        assert_eq!(ents.len(), (high - low) as usize);
+        for (i, e) in ents.iter().enumerate() {
+            assert_eq!(e.get_index(), low + i as u64);
+            assert_eq!(e.get_term(), term);
+        }
+    }
+
+    #[test]
+    fn test_append_and_fetch() {
+        let path = TempDir::new("test-raft-log-engine").unwrap();
+        let engine = RaftLogEngine::open(new_config(&path)).unwrap();
+
+        append(&engine, 1, 1, 20, 1);
+        append(&engine, 2, 1, 10, 1);
+        must_fetch(&engine, 1, 1, 20, 1);
+        must_fetch(&engine, 2, 1, 10, 1);
+        assert_eq!(engine.first_index(1), Some(1));
+        assert_eq!(engine.last_index(1), Some(19));
+        assert!(engine.get_entry(3, 1).unwrap().is_none());
+
+        // Overwrite the tail.
+        append(&engine, 1, 15, 18, 2);
+        assert_eq!(engine.last_index(1), Some(17));
+        must_fetch(&engine, 1, 15, 18, 2);
+        assert_eq!(engine.get_entry(1, 14).unwrap().unwrap().get_term(), 1);
+
+        // A hole drops all stale entries.
+        append(&engine, 2, 30, 32, 3);
+        assert_eq!(engine.first_index(2), Some(30));
+
+        // Fetch with size limit returns at least one entry.
+        let mut ents = vec![];
+        engine.fetch_entries_to(1, 1, 10, 0, &mut ents).unwrap();
+        assert_eq!(ents.len(), 1);
+
+        // Fetching stops at the first missing entry.
+        let mut ents = vec![];
+        engine
+            .fetch_entries_to(1, 10, 30, u64::MAX, &mut ents)
+            .unwrap();
+        assert_eq!(ents.len(), 8);
+    }
+
+    #[test]
+    fn test_compact_and_purge() {
+        let path = TempDir::new("test-raft-log-engine").unwrap();
+        let engine = RaftLogEngine::open(new_config(&path)).unwrap();
+
+        for i in 0..10 {
+            append(&engine, 1, i * 10 + 1, i * 10 + 11, 1);
+            append(&engine, 2, i * 10 + 1, i * 10 + 11, 1);
+        }
+        let file_count = engine.inner.lock().unwrap().files.len();
+        assert!(file_count > 2);
+
+        assert_eq!(engine.compact_to(1, 51).unwrap(), 50);
+        assert_eq!(engine.compact_to(1, 51).unwrap(), 0);
+        assert_eq!(engine.first_index(1), Some(51));
+        // Region 2 still references the oldest files.
+        assert_eq!(engine.purge_expired_files().unwrap(), 0);
+
+        assert_eq!(engine.compact_to(2, 61).unwrap(), 60);
+        assert!(engine.purge_expired_files().unwrap() > 0);
+        must_fetch(&engine, 1, 51, 101, 1);
+        must_fetch(&engine, 2, 61, 101, 1);
+
+        engine.clean_region(1).unwrap();
+        engine.clean_region(2).unwrap();
+        assert!(engine.first_index(1).is_none());
+        engine.purge_expired_files().unwrap();
+        assert_eq!(engine.inner.lock().unwrap().files.len(), 1);
+    }
+
+    #[test]
+    fn test_rewrite_oldest_file() {
+        let path = TempDir::new("test-raft-log-engine").unwrap();
+        let mut cfg = new_config(&path);
+        cfg.purge_threshold = ReadableSize::kb(4);
+        let engine = RaftLogEngine::open(cfg).unwrap();
+
+        // Region 1 is idle and pins the oldest file.
+        append(&engine, 1, 1, 3, 1);
+        for i in 0..20 {
+            append(&engine, 2, i * 10 + 1, i * 10 + 11, 1);
+            engine.compact_to(2, i * 10 + 1).unwrap();
+        }
+        for _ in 0..10 {
+            engine.purge_expired_files().unwrap();
+        }
+        let inner = engine.inner.lock().unwrap();
+        assert!(inner.files.len() <= 6, "{} files left", inner.files.len());
+        drop(inner);
+        must_fetch(&engine, 1, 1, 3, 1);
+    }
+
+    #[test]
+    fn test_recovery() {
+        let path = TempDir::new("test-raft-log-engine").unwrap();
+        {
+            let engine = RaftLogEngine::open(new_config(&path)).unwrap();
+            for i in 0..5 {
+                append(&engine, 1, i * 10 + 1, i * 10 + 11, 1);
+                append(&engine, 2, i * 10 + 1, i * 10 + 11, 1);
+            }
+            append(&engine, 1, 45, 48, 2);
+            engine.compact_to(1, 11).unwrap();
+            append(&engine, 3, 1, 5, 1);
+            engine.clean_region(3).unwrap();
+            engine.sync().unwrap();
+        }
+
+        let engine = RaftLogEngine::open(new_config(&path)).unwrap();
+        assert_eq!(engine.first_index(1), Some(11));
+        must_fetch(&engine, 1, 11, 45, 1);
+        must_fetch(&engine, 1, 45, 48, 2);
+        must_fetch(&engine, 2, 1, 51, 1);
+        assert!(engine.first_index(3).is_none());
+
+        // Append garbage to simulate a torn write.
+        let active = engine.inner.lock().unwrap().active_file_num;
+        drop(engine);
+        let mut f = OpenOptions::new()
+            .append(true)
+            .open(path.path().join(log_file_name(active)))
+            .unwrap();
+        f.write_all(b"torn record").unwrap();
+        drop(f);
+
+        let engine = RaftLogEngine::open(new_config(&path)).unwrap();
+        must_fetch(&engine, 2, 1, 51, 1);
+        append(&engine, 2, 51, 53, 1);
+        drop(engine);
+
+        let engine = RaftLogEngine::open(new_config(&path)).unwrap();
+        must_fetch(&engine, 2, 1, 53, 1);
+    }
+
+    #[test]
+    fn test_concurrent_append() {
+        let path = TempDir::new("test-raft-log-engine").unwrap();
+        let engine = Arc::new(RaftLogEngine::open(new_config(&path)).unwrap());
+        let handles: Vec<_> = (1..5)
+            .map(|region_id| {
+                let engine = engine.clone();
+                thread::spawn(move || for i in 1..51 {
+                    append(&engine, region_id, i, i + 1, 1);
+                    must_fetch(&engine, region_id, 1, i + 1, 1);
+                    if i % 10 == 0 {
+                        engine.compact_to(region_id, i - 5).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        // The appends span many files, and no region may lose an entry.
+        assert!(engine.inner.lock().unwrap().files.len() > 1);
+        for region_id in 1..5 {
+            assert_eq!(engine.first_index(region_id), Some(45));
+            must_fetch(&engine, region_id, 45, 51, 1);
+        }
+        drop(engine);
+
+        let engine = RaftLogEngine::open(new_config(&path)).unwrap();
+        for region_id in 1..5 {
+            assert_eq!(engine.first_index(region_id), Some(45));
+            must_fetch(&engine, region_id, 45, 51, 1);
+        }
+    }
+}
@@ -23,6 +23,9 @@ use std::str;
 use std::time;
 use std::thread;
 
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
 use protobuf::Message;
 use rocksdb::{CFHandle, Writable, WriteBatch, DB};
 use kvproto::eraftpb::Snapshot as RaftSnapshot;
@@ -35,6 +38,7 @@ use raftstore::store::Msg;
 use raftstore::store::util::check_key_in_region;
 use storage::{CfName, CF_DEFAULT, CF_LOCK, CF_WRITE};
 use util::transport::SendCh;
+use util::io_limiter::{self, IOLimiter, IOType};
 use util::HandyRwLock;
 use util::collections::{HashMap, HashMapEntry as Entry};
 use util::codec::bytes::{BytesEncoder, CompactBytesDecoder};
@@ -79,7 +83,9 @@ quick_error! {
 
 pub type Result<T> = result::Result<T, Error>;
 
-// CF_LOCK is relatively small, so we use plain file for performance issue.
+// CF_LOCK is relatively small, so we use plain file for performance issue. Snapshots of
+// `SNAPSHOT_VERSION` build it as an sst file instead. Snapshots built by old stores don't
+// record the format of cf files, so fall back to this when receiving or applying them.
 #[inline]
 fn plain_file_used(cf: &str) -> bool {
     cf == CF_LOCK
@@ -220,36 +226,22 @@ use std::path::PathBuf;
 use std::time::Instant;
 use crc::crc32::{self, Digest, Hasher32};
 use protobuf::RepeatedField;
-use kvproto::raft_serverpb::{SnapshotCFFile, SnapshotMeta};
+use kvproto::raft_serverpb::{SnapshotCFFile, SnapshotCFFileCompression, SnapshotCFFileFormat,
+                             SnapshotMeta};
 use rocksdb::{DBCompressionType, EnvOptions, IngestExternalFileOptions, SstFileWriter};
 use util::rocksdb;
 use util::time::duration_to_sec;
-use util::file::{delete_file_if_exist, file_exists, get_file_size};
+use util::file::{calc_crc32, delete_file_if_exist, file_exists, get_file_size};
 use util::rocksdb::get_fastest_supported_compression_type;
 
-pub const SNAPSHOT_VERSION: u64 = 2;
+pub const SNAPSHOT_VERSION: u64 = 3;
+// Snapshots of this version keep CF_LOCK in a plain file, all stores can apply them.
+pub const SNAPSHOT_VERSION_PLAIN_LOCK_CF: u64 = 2;
 const META_FILE_SUFFIX: &'static str = ".meta";
-const DIGEST_BUFFER_SIZE: usize = 10240;
+// Bytes scanned for a snapshot are requested from the IO limiter in batches of this size.
+const IO_REQUEST_BATCH_SIZE: usize = 1024 * 1024;
 
 
-fn calc_crc32(p: &PathBuf) -> io::Result<u32> {
-    let mut digest = Digest::new(crc32::IEEE);
-    let mut f = try!(OpenOptions::new().read(true).open(&p));
-    let mut buf = vec![0; DIGEST_BUFFER_SIZE];
-    loop {
-        match f.read(&mut buf[..]) {
-            Ok(0) => {
-                return Ok(digest.sum32());
-            }
-            Ok(n) => {
-                digest.write(&buf[..n]);
-            }
-            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
-            Err(err) => return Err(err),
-        }
-    }
-}
-
 fn gen_snapshot_meta(cf_files: &[CfFile]) -> RaftStoreResult<SnapshotMeta> {
     let mut meta = Vec::with_capacity(cf_files.len());
     for cf_file in cf_files {
@@ -264,6 +256,14 @@ fn gen_snapshot_meta(cf_files: &[CfFile]) -> RaftStoreResult<SnapshotMeta> {
         cf_file_meta.set_cf(cf_file.cf.to_owned());
         cf_file_meta.set_size(cf_file.size);
         cf_file_meta.set_checksum(cf_file.checksum);
+        if cf_file.plain {
+            cf_file_meta.set_format(SnapshotCFFileFormat::Plain);
+        } else {
+            cf_file_meta.set_format(SnapshotCFFileFormat::Sst);
+        }
+        if cf_file.compressed {
+            cf_file_meta.set_compression(SnapshotCFFileCompression::Deflate);
+        }
         meta.push(cf_file_meta);
     }
     let mut snapshot_meta = SnapshotMeta::new();
@@ -271,6 +271,19 @@ fn gen_snapshot_meta(cf_files: &[CfFile]) -> RaftStoreResult<SnapshotMeta> {
     Ok(snapshot_meta)
 }
 
+fn is_plain_cf_file(cf_file_meta: &SnapshotCFFile) -> bool {
+    match cf_file_meta.get_format() {
+        // Old stores don't record the format of cf files.
+        SnapshotCFFileFormat::Unknown => plain_file_used(cf_file_meta.get_cf()),
+        SnapshotCFFileFormat::Sst => false,
+        SnapshotCFFileFormat::Plain => true,
+    }
+}
+
+fn is_cf_file_compressed(cf_file_meta: &SnapshotCFFile) -> bool {
+    cf_file_meta.get_compression() == SnapshotCFFileCompression::Deflate
+}
+
 fn check_file_size(path: &PathBuf, expected_size: u64) -> RaftStoreResult<()> {
     let size = try!(get_file_size(path));
     if size != expected_size {
@@ -317,6 +330,30 @@ struct CfFile {
     pub written_size: u64,
     pub checksum: u32,
     pub write_digest: Option<Digest>,
+    // CF_LOCK is built as a plain file unless the snapshot is built in `SNAPSHOT_VERSION`.
+    pub plain: bool,
+    // Only plain cf files can be compressed.
+    pub compressed: bool,
+}
+
+/// Decides how the cf files of a snapshot are built, and so which stores can apply it.
+#[derive(Clone, Copy, Default)]
+pub struct SnapBuildOptions {
+    /// Build CF_LOCK as an sst file. Stores before `SNAPSHOT_VERSION` can't apply such
+    /// snapshots, so it's only turned on after all stores in the cluster are upgraded.
+    pub sst_lock_cf: bool,
+    /// Compress the plain cf files.
+    pub compress_plain_file: bool,
+}
+
+impl SnapBuildOptions {
+    pub fn version(&self) -> u64 {
+        if self.sst_lock_cf {
+            SNAPSHOT_VERSION
+        } else {
+            SNAPSHOT_VERSION_PLAIN_LOCK_CF
+        }
+    }
 }
 
 #[derive(Default)]
@@ -336,6 +373,7 @@ pub struct Snap {
     cf_index: usize,
     meta_file: MetaFile,
     size_track: Arc<RwLock<u64>>,
+    build_options: SnapBuildOptions,
 }
 
 impl Snap {
@@ -389,6 +427,7 @@ impl Snap {
             cf_index: 0,
             meta_file: meta_file,
             size_track: size_track,
+            build_options: SnapBuildOptions::default(),
         };
 
         // load snapshot meta if meta_file exists
@@ -419,9 +458,11 @@ impl Snap {
         key: &SnapKey,
         snap: &DbSnapshot,
         size_track: Arc<RwLock<u64>>,
+        build_options: SnapBuildOptions,
         deleter: Box<SnapshotDeleter>,
     ) -> RaftStoreResult<Snap> {
         let mut s = try!(Snap::new(dir, key, size_track, true, true, deleter));
+        s.build_options = build_options;
         try!(s.init_for_building(snap));
         Ok(s)
     }
@@ -498,8 +539,9 @@ impl Snap {
         if self.exists() {
             return Ok(());
         }
+        let options = self.build_options;
         for cf_file in &mut self.cf_files {
-            if plain_file_used(cf_file.cf) {
+            if !options.sst_lock_cf && plain_file_used(cf_file.cf) {
                 let f = try!(
                     OpenOptions::new()
                         .write(true)
@@ -508,6 +550,8 @@ impl Snap {
                         .open(&cf_file.tmp_path)
                 );
                 cf_file.file = Some(f);
+                cf_file.plain = true;
+                cf_file.compressed = options.compress_plain_file;
             } else {
                 let handle = try!(snap.cf_handle(cf_file.cf));
                 let mut io_options = snap.get_db().get_options_cf(handle).clone();
@@ -520,6 +564,8 @@ impl Snap {
                 let mut writer = SstFileWriter::new(EnvOptions::new(), io_options);
                 box_try!(writer.open(cf_file.tmp_path.as_path().to_str().unwrap()));
                 cf_file.sst_writer = Some(writer);
+                cf_file.plain = false;
+                cf_file.compressed = false;
             }
         }
         let file = try!(
@@ -566,6 +612,8 @@ impl Snap {
             }
             cf_file.size = meta.get_size();
             cf_file.checksum = meta.get_checksum();
+            cf_file.plain = is_plain_cf_file(meta);
+            cf_file.compressed = is_cf_file_compressed(meta);
         }
         self.meta_file.meta = snapshot_meta;
         Ok(())
@@ -637,7 +685,7 @@ impl Snap {
 
     fn save_cf_files(&mut self) -> io::Result<()> {
         for cf_file in &mut self.cf_files {
-            if plain_file_used(cf_file.cf) {
+            if cf_file.plain {
                 let _ = cf_file.file.take();
             } else if cf_file.kv_count == 0 {
                 let _ = cf_file.sst_writer.take().unwrap();
@@ -707,15 +755,32 @@ impl Snap {
         }
 
         let mut snap_key_count = 0;
+        let mut pending_io = 0;
         let (begin_key, end_key) = (enc_start_key(region), enc_end_key(region));
         for cf in SNAPSHOT_CFS {
             try!(self.switch_to_cf_file(cf));
-            let (cf_key_count, cf_size) = if plain_file_used(cf) {
-                let file = self.cf_files[self.cf_index].file.as_mut().unwrap();
-                try!(build_plain_cf_file(file, snap, cf, &begin_key, &end_key))
+            let (cf_key_count, cf_size) = if self.cf_files[self.cf_index].plain {
+                let cf_file = &mut self.cf_files[self.cf_index];
+                let compressed = cf_file.compressed;
+                let file = cf_file.file.as_mut().unwrap();
+                let res = if compressed {
+                    let mut encoder = DeflateEncoder::new(file, Compression::Fast);
+                    let res = try!(build_plain_cf_file(
+                        &mut encoder,
+                        snap,
+                        cf,
+                        &begin_key,
+                        &end_key
+                    ));
+                    try!(encoder.finish());
+                    res
+                } else {
+                    try!(build_plain_cf_file(file, snap, cf, &begin_key, &end_key))
+                };
+                pending_io += res.1;
+                res
             } else {
-                let mut key_count = 0;
-                let mut size = 0;
+                let (mut key_count, mut size) = (0, 0);
                 try!(snap.scan_cf(
                     cf,
                     &begin_key,
@@ -724,6 +789,11 @@ impl Snap {
                     &mut |key, value| {
                         key_count += 1;
                         size += key.len() + value.len();
+                        pending_io += key.len() + value.len();
+                        if pending_io >= IO_REQUEST_BATCH_SIZE {
+                            io_limiter::request_io(IOType::Snapshot, pending_io);
+                            pending_io = 0;
+                        }
                         try!(self.add_kv(key, value));
                         Ok(true)
                     }
@@ -747,6 +817,7 @@ impl Snap {
             );
         }
 
+        io_limiter::request_io(IOType::Snapshot, pending_io);
         try!(self.save_cf_files());
         stat.kv_count = snap_key_count;
         // save snapshot meta to meta file
@@ -797,6 +868,7 @@ fn apply_plain_cf_file<D: CompactBytesDecoder>(
         let key = box_try!(decoder.decode_compact_bytes());
         if key.is_empty() {
             if batch_size > 0 {
+                io_limiter::request_io(IOType::Snapshot, batch_size);
                 box_try!(options.db.write(wb));
             }
             break;
@@ -807,6 +879,7 @@ fn apply_plain_cf_file<D: CompactBytesDecoder>(
         batch_size += value.len();
         box_try!(wb.put_cf(handle, &key, &value));
         if batch_size >= options.write_batch_size {
+            io_limiter::request_io(IOType::Snapshot, batch_size);
             box_try!(options.db.write(wb));
             wb = WriteBatch::new();
             batch_size = 0;
@@ -831,7 +904,7 @@ impl Snapshot for Snap {
         stat.size = total_size;
         // set snapshot meta data
         snap_data.set_file_size(total_size);
-        snap_data.set_version(SNAPSHOT_VERSION);
+        snap_data.set_version(self.build_options.version());
         snap_data.set_meta(self.meta_file.meta.clone());
 
         SNAPSHOT_BUILD_TIME_HISTOGRAM.observe(duration_to_sec(t.elapsed()) as f64);
@@ -945,15 +1018,21 @@ impl Snapshot for Snap {
 
             try!(check_abort(&options.abort));
             let cf_handle = box_try!(rocksdb::get_cf_handle(&options.db, cf_file.cf));
-            if plain_file_used(cf_file.cf) {
+            if cf_file.plain {
                 let mut file = box_try!(File::open(&cf_file.path));
-                try!(apply_plain_cf_file(&mut file, &options, cf_handle));
+                if cf_file.compressed {
+                    let mut decoder = DeflateDecoder::new(file);
+                    try!(apply_plain_cf_file(&mut decoder, &options, cf_handle));
+                } else {
+                    try!(apply_plain_cf_file(&mut file, &options, cf_handle));
+                }
             } else {
                 let ingest_opt = IngestExternalFileOptions::new();
                 // TODO: move SST file instead of copy
                 // after changing logic in raft, ask for resending snapshot if applying fail.
                 // ingest_opt.move_files(true);
                 let path = cf_file.path.as_path().to_str().unwrap();
+                io_limiter::request_io(IOType::Snapshot, cf_file.size as usize);
                 box_try!(
                     options
                         .db
@@ -1088,24 +1167,60 @@ fn notify_stats(ch: Option<&SendCh<Msg>>) {
     }
 }
 
+struct TransferSlots {
+    // 0 means no limit.
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl TransferSlots {
+    fn new(limit: usize) -> TransferSlots {
+        TransferSlots {
+            limit: limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    fn try_acquire(slots: &Arc<TransferSlots>) -> Option<TransferSlot> {
+        let used = slots.used.fetch_add(1, Ordering::SeqCst);
+        if slots.limit > 0 && used >= slots.limit {
+            slots.used.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(TransferSlot {
+            slots: slots.clone(),
+        })
+    }
+}
+
+/// `TransferSlot` is held during a snapshot transfer, the slot is given back
+/// when it's dropped.
+pub struct TransferSlot {
+    slots: Arc<TransferSlots>,
+}
+
+impl Drop for TransferSlot {
+    fn drop(&mut self) {
+        self.slots.used.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 /// `SnapManagerCore` trace all current processing snapshots.
 #[derive(Clone)]
 pub struct SnapManager {
     // directory to store snapfile.
     core: Arc<RwLock<SnapManagerCore>>,
     ch: Option<SendCh<Msg>>,
+    send_limiter: Arc<IOLimiter>,
+    recv_limiter: Arc<IOLimiter>,
+    send_slots: Arc<TransferSlots>,
+    recv_slots: Arc<TransferSlots>,
+    build_options: SnapBuildOptions,
 }
 
 impl SnapManager {
     pub fn new<T: Into<String>>(path: T, ch: Option<SendCh<Msg>>) -> SnapManager {
-        SnapManager {
-            core: Arc::new(RwLock::new(SnapManagerCore {
-                base: path.into(),
-                registry: map![],
-                snap_size: Arc::new(RwLock::new(0)),
-            })),
-            ch: ch,
-        }
+        SnapManagerBuilder::default().build(path, ch)
     }
 
     pub fn init(&self) -> io::Result<()> {
@@ -1139,6 +1254,27 @@ impl SnapManager {
         Ok(())
     }
 
+    /// Limiter for the bytes sent to other stores.
+    pub fn send_limiter(&self) -> Arc<IOLimiter> {
+        self.send_limiter.clone()
+    }
+
+    /// Limiter for the bytes received from other stores.
+    pub fn recv_limiter(&self) -> Arc<IOLimiter> {
+        self.recv_limiter.clone()
+    }
+
+    /// Try to start sending a snapshot, return `None` if too many snapshots are being sent.
+    pub fn try_acquire_send_slot(&self) -> Option<TransferSlot> {
+        TransferSlots::try_acquire(&self.send_slots)
+    }
+
+    /// Try to start receiving a snapshot, return `None` if too many snapshots are being
+    /// received.
+    pub fn try_acquire_recv_slot(&self) -> Option<TransferSlot> {
+        TransferSlots::try_acquire(&self.recv_slots)
+    }
+
     // Return all snapshots which is idle not being used.
     pub fn list_idle_snap(&self) -> io::Result<Vec<(SnapKey, bool)>> {
         let core = self.core.rl();
@@ -1209,6 +1345,7 @@ impl SnapManager {
             key,
             snap,
             snap_size,
+            self.build_options,
             Box::new(self.clone())
         ));
         Ok(Box::new(f))
@@ -1336,6 +1473,70 @@ impl SnapManager {
     }
 }
 
+/// `SnapManagerBuilder` builds a `SnapManager` with transfer limits.
+///
+/// Zero rates and zero concurrency mean no limit.
+#[derive(Clone, Default)]
+pub struct SnapManagerBuilder {
+    max_send_bytes_per_sec: u64,
+    max_recv_bytes_per_sec: u64,
+    max_concurrent_send: usize,
+    max_concurrent_recv: usize,
+    build_options: SnapBuildOptions,
+}
+
+impl SnapManagerBuilder {
+    pub fn max_send_bytes_per_sec(mut self, bytes: u64) -> SnapManagerBuilder {
+        self.max_send_bytes_per_sec = bytes;
+        self
+    }
+
+    pub fn max_recv_bytes_per_sec(mut self, bytes: u64) -> SnapManagerBuilder {
+        self.max_recv_bytes_per_sec = bytes;
+        self
+    }
+
+    pub fn max_concurrent_send(mut self, count: usize) -> SnapManagerBuilder {
+        self.max_concurrent_send = count;
+        self
+    }
+
+    pub fn max_concurrent_recv(mut self, count: usize) -> SnapManagerBuilder {
+        self.max_concurrent_recv = count;
+        self
+    }
+
+    /// Build CF_LOCK of snapshots as an sst file. All stores in the cluster must be able
+    /// to apply snapshots of `SNAPSHOT_VERSION` before it's turned on.
+    pub fn sst_lock_cf(mut self, enabled: bool) -> SnapManagerBuilder {
+        self.build_options.sst_lock_cf = enabled;
+        self
+    }
+
+    /// Compress the plain cf files when building snapshots. All stores in the cluster
+    /// must be able to decode compressed cf files before it's turned on.
+    pub fn compress_plain_file(mut self, compress: bool) -> SnapManagerBuilder {
+        self.build_options.compress_plain_file = compress;
+        self
+    }
+
+    pub fn build<T: Into<String>>(&self, path: T, ch: Option<SendCh<Msg>>) -> SnapManager {
+        SnapManager {
+            core: Arc::new(RwLock::new(SnapManagerCore {
+                base: path.into(),
+                registry: map![],
+                snap_size: Arc::new(RwLock::new(0)),
+            })),
+            ch: ch,
+            send_limiter: Arc::new(IOLimiter::new(self.max_send_bytes_per_sec)),
+            recv_limiter: Arc::new(IOLimiter::new(self.max_recv_bytes_per_sec)),
+            send_slots: Arc::new(TransferSlots::new(self.max_concurrent_send)),
+            recv_slots: Arc::new(TransferSlots::new(self.max_concurrent_recv)),
+            build_options: self.build_options,
+        }
+    }
+}
+
 impl SnapshotDeleter for SnapManager {
     fn delete_snapshot(&self, key: &SnapKey, snap: &Snapshot, check_entry: bool) -> bool {
         let core = self.core.rl();
@@ -1367,18 +1568,24 @@ mod test {
     use std::sync::*;
     use std::sync::atomic::AtomicUsize;
     use tempdir::TempDir;
-    use protobuf::Message;
+    use flate2::Compression;
+    use flate2::write::DeflateEncoder;
+    use protobuf::{Message, RepeatedField};
 
-    use super::{ApplyOptions, Snap, SnapEntry, SnapKey, SnapManager, Snapshot, SnapshotDeleter,
-                SnapshotStatistics, META_FILE_SUFFIX, SNAPSHOT_CFS, SNAP_GEN_PREFIX};
+    use super::{build_plain_cf_file, ApplyOptions, Snap, SnapBuildOptions, SnapEntry, SnapKey,
+                SnapManager, SnapManagerBuilder, Snapshot, SnapshotDeleter, SnapshotStatistics,
+                META_FILE_SUFFIX, SNAPSHOT_CFS, SNAPSHOT_VERSION, SNAPSHOT_VERSION_PLAIN_LOCK_CF,
+                SNAP_GEN_PREFIX, SNAP_REV_PREFIX, SST_FILE_SUFFIX};
 
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
     use kvproto::metapb::{Peer, Region};
-    use kvproto::raft_serverpb::{RaftSnapshotData, SnapshotMeta};
+    use kvproto::raft_serverpb::{RaftSnapshotData, SnapshotCFFile, SnapshotCFFileCompression,
+                                 SnapshotMeta};
     use rocksdb::DB;
 
     use storage::{ALL_CFS, CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE};
     use util::{rocksdb, HandyRwLock};
+    use util::file::get_file_size;
     use raftstore::Result;
     use raftstore::store::keys;
     use raftstore::store::engine::{Iterable, Mutable, Peekable, Snapshot as DbSnapshot};
@@ -1421,6 +1628,40 @@ mod test {
         Ok(Arc::new(db))
     }
 
+    // Write a received snapshot the way stores before SNAPSHOT_VERSION 3 build it, that is,
+    // CF_LOCK is a plain file and the format of cf files is not recorded in the meta.
+    fn build_legacy_snap(dir: &Path, key: &SnapKey, db: Arc<DB>, region: &Region, compress: bool) {
+        let snap = DbSnapshot::new(db);
+        let prefix = format!("{}_{}", SNAP_REV_PREFIX, key);
+        let (begin_key, end_key) = (keys::enc_start_key(region), keys::enc_end_key(region));
+        let mut cf_files = Vec::with_capacity(SNAPSHOT_CFS.len());
+        for cf in SNAPSHOT_CFS {
+            let mut cf_file = SnapshotCFFile::new();
+            cf_file.set_cf(cf.to_string());
+            if *cf == CF_LOCK {
+                let path = dir.join(format!("{}_{}{}", prefix, cf, SST_FILE_SUFFIX));
+                let mut f = File::create(&path).unwrap();
+                if compress {
+                    let mut encoder = DeflateEncoder::new(&mut f, Compression::Fast);
+                    build_plain_cf_file(&mut encoder, &snap, cf, &begin_key, &end_key).unwrap();
+                    encoder.finish().unwrap();
+                    cf_file.set_compression(SnapshotCFFileCompression::Deflate);
+                } else {
+                    build_plain_cf_file(&mut f, &snap, cf, &begin_key, &end_key).unwrap();
+                }
+                f.sync_all().unwrap();
+                cf_file.set_size(get_file_size(&path).unwrap());
+                cf_file.set_checksum(super::calc_crc32(&path).unwrap());
+            }
+            cf_files.push(cf_file);
+        }
+        let mut meta = SnapshotMeta::new();
+        meta.set_cf_files(RepeatedField::from_vec(cf_files));
+        let meta_path = dir.join(format!("{}{}", prefix, META_FILE_SUFFIX));
+        let mut f = File::create(&meta_path).unwrap();
+        f.write_all(&meta.write_to_bytes().unwrap()).unwrap();
+    }
+
     pub fn get_kv_count(snap: &DbSnapshot) -> usize {
         let mut kv_count = 0;
         for cf in SNAPSHOT_CFS {
@@ -1528,15 +1769,35 @@ mod test {
 
     #[test]
     fn test_empty_snap_file() {
-        test_snap_file(get_test_empty_db);
+        test_snap_file(get_test_empty_db, SnapBuildOptions::default());
     }
 
     #[test]
     fn test_non_empty_snap_file() {
-        test_snap_file(get_test_db);
+        test_snap_file(get_test_db, SnapBuildOptions::default());
+    }
+
+    #[test]
+    fn test_compressed_snap_file() {
+        let options = SnapBuildOptions {
+            compress_plain_file: true,
+            ..Default::default()
+        };
+        test_snap_file(get_test_empty_db, options);
+        test_snap_file(get_test_db, options);
+    }
+
+    #[test]
+    fn test_sst_lock_cf_snap_file() {
+        let options = SnapBuildOptions {
+            sst_lock_cf: true,
+            compress_plain_file: true,
+        };
+        test_snap_file(get_test_empty_db, options);
+        test_snap_file(get_test_db, options);
     }
 
-    fn test_snap_file(get_db: fn(p: &TempDir) -> Result<Arc<DB>>) {
+    fn test_snap_file(get_db: fn(p: &TempDir) -> Result<Arc<DB>>, options: SnapBuildOptions) {
         let region_id = 1;
         let region = get_test_region(region_id, 1, 1);
         let src_db_dir = TempDir::new("test-snap-file-db-src").unwrap();
@@ -1552,6 +1813,7 @@ mod test {
             &key,
             &snapshot,
             size_track.clone(),
+            options,
             deleter.clone(),
         ).unwrap();
         // Ensure that this snapshot file doesn't exist before being built.
@@ -1586,6 +1848,21 @@ mod test {
 
         // TODO check meta data correct.
         let _ = s2.meta().unwrap();
+        // Ensure CF_LOCK is kept in a plain file until the sst format is turned on, and
+        // the format and compression of cf files are recorded in the meta.
+        if options.sst_lock_cf {
+            assert_eq!(snap_data.get_version(), SNAPSHOT_VERSION);
+        } else {
+            assert_eq!(snap_data.get_version(), SNAPSHOT_VERSION_PLAIN_LOCK_CF);
+        }
+        for cf_file_meta in snap_data.get_meta().get_cf_files() {
+            let plain = !options.sst_lock_cf && cf_file_meta.get_cf() == CF_LOCK;
+            assert_eq!(super::is_plain_cf_file(cf_file_meta), plain);
+            assert_eq!(
+                super::is_cf_file_compressed(cf_file_meta),
+                plain && options.compress_plain_file
+            );
+        }
 
         let dst_dir = TempDir::new("test-snap-file-dst").unwrap();
 
@@ -1669,6 +1946,7 @@ mod test {
             &key,
             &snapshot,
             size_track.clone(),
+            SnapBuildOptions::default(),
             deleter.clone(),
         ).unwrap();
         assert!(!s1.exists());
@@ -1690,6 +1968,7 @@ mod test {
             &key,
             &snapshot,
             size_track.clone(),
+            SnapBuildOptions::default(),
             deleter.clone(),
         ).unwrap();
         assert!(s2.exists());
@@ -1842,6 +2121,7 @@ mod test {
             &key,
             &snapshot,
             size_track.clone(),
+            SnapBuildOptions::default(),
             deleter.clone(),
         ).unwrap();
         assert!(!s1.exists());
@@ -1869,6 +2149,7 @@ mod test {
             &key,
             &snapshot,
             size_track.clone(),
+            SnapBuildOptions::default(),
             deleter.clone(),
         ).unwrap();
         assert!(!s2.exists());
@@ -1943,6 +2224,7 @@ mod test {
             &key,
             &snapshot,
             size_track.clone(),
+            SnapBuildOptions::default(),
             deleter.clone(),
         ).unwrap();
         assert!(!s1.exists());
@@ -1970,6 +2252,7 @@ mod test {
             &key,
             &snapshot,
             size_track.clone(),
+            SnapBuildOptions::default(),
             deleter.clone(),
         ).unwrap();
         assert!(!s2.exists());
@@ -2041,9 +2324,14 @@ mod test {
         let key1 = SnapKey::new(1, 1, 1);
         let size_track = Arc::new(RwLock::new(0));
         let deleter = Box::new(mgr.clone());
-        let mut s1 =
-            Snap::new_for_building(&path, &key1, &snapshot, size_track.clone(), deleter.clone())
-                .unwrap();
+        let mut s1 = Snap::new_for_building(
+            &path,
+            &key1,
+            &snapshot,
+            size_track.clone(),
+            SnapBuildOptions::default(),
+            deleter.clone(),
+        ).unwrap();
         let mut region = get_test_region(1, 1, 1);
         let mut snap_data = RaftSnapshotData::new();
         snap_data.set_region(region.clone());
@@ -2072,9 +2360,14 @@ mod test {
         let key2 = SnapKey::new(2, 1, 1);
         region.set_id(2);
         snap_data.set_region(region);
-        let s3 =
-            Snap::new_for_building(&path, &key2, &snapshot, size_track.clone(), deleter.clone())
-                .unwrap();
+        let s3 = Snap::new_for_building(
+            &path,
+            &key2,
+            &snapshot,
+            size_track.clone(),
+            SnapBuildOptions::default(),
+            deleter.clone(),
+        ).unwrap();
         let s4 = Snap::new_for_receiving(
             &path,
             &key2,
@@ -2179,4 +2472,67 @@ mod test {
         dst_mgr.delete_snapshot(&key, s4.as_ref(), false);
         assert!(s5.exists());
     }
+
+    #[test]
+    fn test_snap_mgr_transfer_slots() {
+        let mgr = SnapManagerBuilder::default()
+            .max_concurrent_send(2)
+            .build("", None);
+        let s1 = mgr.try_acquire_send_slot().unwrap();
+        let s2 = mgr.try_acquire_send_slot().unwrap();
+        assert!(mgr.try_acquire_send_slot().is_none());
+        // Receiving is not limited.
+        let recv_slots: Vec<_> = (0..10).map(|_| mgr.try_acquire_recv_slot()).collect();
+        assert!(recv_slots.iter().all(|s| s.is_some()));
+
+        drop(s1);
+        let s3 = mgr.try_acquire_send_slot().unwrap();
+        assert!(mgr.try_acquire_send_slot().is_none());
+        drop(s2);
+        drop(s3);
+        assert!(mgr.try_acquire_send_slot().is_some());
+    }
+
+    #[test]
+    fn test_apply_legacy_snap_file() {
+        for &compress in &[false, true] {
+            let region = get_test_region(1, 1, 1);
+            // Only CF_LOCK has data, so other cf files of the legacy snapshot are empty.
+            let src_db_dir = TempDir::new("test-legacy-snap-db-src").unwrap();
+            let src_db_path = src_db_dir.path().to_str().unwrap();
+            let src_db = Arc::new(rocksdb::new_engine(src_db_path, ALL_CFS).unwrap());
+            let handle = rocksdb::get_cf_handle(&src_db, CF_LOCK).unwrap();
+            let mut p = Peer::new();
+            p.set_store_id(TEST_STORE_ID);
+            p.set_id(1);
+            src_db
+                .put_msg_cf(handle, &keys::data_key(TEST_KEY), &p)
+                .unwrap();
+
+            let dir = TempDir::new("test-legacy-snap").unwrap();
+            let key = SnapKey::new(1, 1, 1);
+            build_legacy_snap(dir.path(), &key, src_db.clone(), &region, compress);
+
+            let size_track = Arc::new(RwLock::new(0));
+            let mut s =
+                Snap::new_for_applying(dir.path(), &key, size_track, Box::new(DummyDeleter {}))
+                    .unwrap();
+            assert!(s.exists());
+
+            let dst_db_dir = TempDir::new("test-legacy-snap-db-dst").unwrap();
+            let dst_db_path = dst_db_dir.path().to_str().unwrap();
+            let dst_db = Arc::new(rocksdb::new_engine(dst_db_path, ALL_CFS).unwrap());
+            let options = ApplyOptions {
+                db: dst_db.clone(),
+                region: region.clone(),
+                abort: Arc::new(AtomicUsize::new(JOB_STATUS_RUNNING)),
+                write_batch_size: TEST_WRITE_BATCH_SIZE,
+            };
+            s.apply(options).unwrap();
+            let p2: Option<Peer> = dst_db
+                .get_msg_cf(CF_LOCK, &keys::data_key(TEST_KEY))
+                .unwrap();
+            assert_eq!(p2.unwrap(), p);
+        }
+    }
 }
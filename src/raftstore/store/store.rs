@@ -32,6 +32,7 @@ use kvproto::raft_serverpb::{PeerState, RaftMessage, RaftSnapshotData, RaftTrunc
 use kvproto::eraftpb::{ConfChangeType, MessageType};
 use kvproto::pdpb::StoreStats;
 use util::{escape, rocksdb};
+use util::io_limiter::{self, IOType};
 use util::time::{duration_to_sec, SlowTimer};
 use pd::PdClient;
 use kvproto::raft_cmdpb::{AdminCmdType, AdminRequest, RaftCmdRequest, RaftCmdResponse,
@@ -46,13 +47,15 @@ use util::RingQueue;
 use util::collections::{HashMap, HashSet};
 use storage::{CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE};
 use raftstore::coprocessor::CoprocessorHost;
+use import::SSTImporter;
 use raftstore::coprocessor::split_observer::SplitObserver;
 use super::worker::{ApplyRunner, ApplyTask, ApplyTaskRes, CompactRunner, CompactTask,
                     ConsistencyCheckRunner, ConsistencyCheckTask, PdRunner, PdTask,
                     RaftlogGcRunner, RaftlogGcTask, RegionRunner, RegionTask, SplitCheckRunner,
                     SplitCheckTask};
 use super::worker::apply::{ChangePeer, ExecResult};
-use super::{util, Msg, SnapManager, SnapshotDeleter, SnapshotStatusMsg, Tick};
+use super::{util, LogBatch, Msg, RaftLogEngine, SnapManager, SnapshotDeleter, SnapshotStatusMsg,
+            Tick};
 use super::keys::{self, data_end_key, data_key, enc_end_key, enc_start_key};
 use super::engine::{Iterable, Peekable, Snapshot as EngineSnapshot};
 use super::config::Config;
@@ -74,6 +77,8 @@ const PENDING_VOTES_CAP: usize = 20;
 pub struct Engines {
     pub kv_engine: Arc<DB>,
     pub raft_engine: Arc<DB>,
+    pub raft_log_engine: Option<Arc<RaftLogEngine>>,
+    pub sst_importer: Option<Arc<SSTImporter>>,
 }
 
 impl Engines {
@@ -81,8 +86,23 @@ impl Engines {
         Engines {
             kv_engine: kv_engine,
             raft_engine: raft_engine,
+            raft_log_engine: None,
+            sst_importer: None,
         }
     }
+
+    /// Store raft log entries in `raft_log_engine` instead of `raft_engine`.
+    pub fn with_raft_log_engine(mut self, raft_log_engine: Arc<RaftLogEngine>) -> Engines {
+        self.raft_log_engine = Some(raft_log_engine);
+        self
+    }
+
+    /// Ingest the SST files uploaded to `sst_importer`, a store without it panics when
+    /// applying an ingest command.
+    pub fn with_sst_importer(mut self, sst_importer: Arc<SSTImporter>) -> Engines {
+        self.sst_importer = Some(sst_importer);
+        self
+    }
 }
 
 // A helper structure to bundle all channels for messages to `Store`.
@@ -120,6 +140,8 @@ pub struct Store<T, C: 'static> {
     cfg: Rc<Config>,
     kv_engine: Arc<DB>,
     raft_engine: Arc<DB>,
+    raft_log_engine: Option<Arc<RaftLogEngine>>,
+    sst_importer: Option<Arc<SSTImporter>>,
     store: metapb::Store,
     sendch: SendCh<Msg>,
 
@@ -160,6 +182,10 @@ pub struct Store<T, C: 'static> {
     pending_votes: RingQueue<RaftMessage>,
 
     store_stat: StoreStat,
+
+    // store_id -> the last time hibernated followers are woken up because the store
+    // is unreachable.
+    unreachable_stores: HashMap<u64, Instant>,
 }
 
 pub fn create_event_loop<T, C>(cfg: &Config) -> Result<EventLoop<Store<T, C>>>
@@ -203,6 +229,8 @@ impl<T, C> Store<T, C> {
             store: meta,
             kv_engine: engines.kv_engine,
             raft_engine: engines.raft_engine,
+            raft_log_engine: engines.raft_log_engine,
+            sst_importer: engines.sst_importer,
             sendch: sendch,
             sent_snapshot_count: 0,
             snapshot_status_receiver: ch.snapshot_status_receiver,
@@ -229,6 +257,7 @@ impl<T, C> Store<T, C> {
             start_time: time::get_time(),
             is_busy: false,
             store_stat: StoreStat::default(),
+            unreachable_stores: HashMap::default(),
         };
         try!(s.init());
         Ok(s)
@@ -249,6 +278,7 @@ impl<T, C> Store<T, C> {
         let t = Instant::now();
         let mut kv_wb = WriteBatch::new();
         let mut raft_wb = WriteBatch::new();
+        let mut tombstone_regions = vec![];
         try!(kv_engine.scan_cf(
             CF_RAFT,
             start_key,
@@ -272,6 +302,7 @@ impl<T, C> Store<T, C> {
                         self.store_id()
                     );
                     self.clear_stale_meta(&mut kv_wb, &mut raft_wb, region);
+                    tombstone_regions.push(region_id);
                     return Ok(true);
                 }
                 if local_state.get_state() == PeerState::Applying {
@@ -280,6 +311,7 @@ impl<T, C> Store<T, C> {
                     try!(peer_storage::recover_from_applying_state(
                         &self.kv_engine,
                         &self.raft_engine,
+                        self.raft_log_engine.as_ref().map(|e| e.as_ref()),
                         region_id
                     ));
                 }
@@ -312,6 +344,12 @@ impl<T, C> Store<T, C> {
             self.raft_engine.write(raft_wb).unwrap();
         }
 
+        if let Some(ref engine) = self.raft_log_engine {
+            for region_id in tombstone_regions {
+                try!(engine.clean_region(region_id));
+            }
+        }
+
         info!(
             "{} starts with {} regions, including {} tombstones and {} applying \
              regions, takes {:?}",
@@ -343,6 +381,7 @@ impl<T, C> Store<T, C> {
         peer_storage::clear_meta(
             &self.kv_engine,
             &self.raft_engine,
+            self.raft_log_engine.is_some(),
             kv_wb,
             raft_wb,
             region.get_id(),
@@ -406,6 +445,14 @@ impl<T, C> Store<T, C> {
         self.raft_engine.clone()
     }
 
+    pub fn raft_log_engine(&self) -> Option<Arc<RaftLogEngine>> {
+        self.raft_log_engine.clone()
+    }
+
+    pub fn sst_importer(&self) -> Option<Arc<SSTImporter>> {
+        self.sst_importer.clone()
+    }
+
     pub fn store_id(&self) -> u64 {
         self.store.get_id()
     }
@@ -418,6 +465,25 @@ impl<T, C> Store<T, C> {
         self.cfg.clone()
     }
 
+    // Only the fields that are read on every use take effect, fields used to
+    // build the event loop or raft groups are rejected by the config manager.
+    fn on_config_changed(&mut self, cfg: Config) {
+        info!("{} config changed", self.tag);
+        if cfg.region_max_size != self.cfg.region_max_size ||
+            cfg.region_split_size != self.cfg.region_split_size
+        {
+            let task =
+                SplitCheckTask::change_config(cfg.region_max_size.0, cfg.region_split_size.0);
+            if let Err(e) = self.split_check_worker.schedule(task) {
+                error!("{} failed to update split check config: {:?}", self.tag, e);
+            }
+        }
+        self.cfg = Rc::new(cfg);
+        for peer in self.region_peers.values_mut() {
+            peer.set_config(self.cfg.clone());
+        }
+    }
+
     fn poll_snapshot_status(&mut self) {
         if self.sent_snapshot_count == 0 {
             return;
@@ -503,6 +569,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         let runner = RegionRunner::new(
             self.kv_engine.clone(),
             self.raft_engine.clone(),
+            self.raft_log_engine.clone(),
             self.snap_mgr.clone(),
             self.cfg.snap_apply_batch_size.0 as usize,
         );
@@ -593,10 +660,21 @@ impl<T: Transport, C: PdClient> Store<T, C> {
                 continue;
             }
 
+            if peer.is_hibernated() {
+                peer.tick_hibernated(&self.trans);
+                continue;
+            }
+
             if peer.raft_group.tick() {
                 peer.mark_to_be_checked(&mut self.pending_raft_groups);
             }
 
+            peer.check_follower_reads();
+
+            if peer.check_hibernate(&self.trans) {
+                continue;
+            }
+
             // If this peer detects the leader is missing for a long long time,
             // it should consider itself as a stale peer which is removed from
             // the original cluster.
@@ -756,6 +834,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
 
     fn on_raft_message(&mut self, mut msg: RaftMessage) -> Result<()> {
         let region_id = msg.get_region_id();
+        log_fields!(region_id = region_id, peer_id = msg.get_to_peer().get_id());
         if !self.is_raft_msg_valid(&msg) {
             return Ok(());
         }
@@ -1044,7 +1123,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         self.raft_metrics.ready.pending_region += pending_count as u64;
 
         let mut region_proposals = Vec::with_capacity(pending_count);
-        let (kv_wb, raft_wb, append_res) = {
+        let (kv_wb, raft_wb, mut append_res) = {
             let mut ctx = ReadyContext::new(&mut self.raft_metrics, &self.trans, pending_count);
             for region_id in self.pending_raft_groups.drain() {
                 if let Some(peer) = self.region_peers.get_mut(&region_id) {
@@ -1079,8 +1158,26 @@ impl<T: Transport, C: PdClient> Store<T, C> {
                 });
         }
 
+        if let Some(ref engine) = self.raft_log_engine {
+            // Raft log entries must be persisted before the RaftLocalState pointing to them.
+            // A region applying a snapshot cleans up its old entries, which are still
+            // pointed to by the old RaftLocalState, so its log batch is written later.
+            let mut log_batch = LogBatch::new();
+            for &mut (_, ref mut invoke_ctx) in &mut append_res {
+                if !invoke_ctx.has_snapshot() {
+                    log_batch.append(&mut invoke_ctx.log_batch);
+                }
+            }
+            engine
+                .write(&log_batch, self.cfg.sync_log)
+                .unwrap_or_else(|e| {
+                    panic!("{} failed to save raft log entries: {:?}", self.tag, e);
+                });
+        }
+
         if !raft_wb.is_empty() {
             // RaftLocalState, Raft Log Entry
+            io_limiter::request_io(IOType::RaftLog, raft_wb.data_size());
             let mut write_opts = WriteOptions::new();
             write_opts.set_sync(self.cfg.sync_log);
             self.raft_engine
@@ -1090,6 +1187,19 @@ impl<T: Transport, C: PdClient> Store<T, C> {
                 });
         }
 
+        if let Some(ref engine) = self.raft_log_engine {
+            // Only the log batches of the regions applying a snapshot are left.
+            let mut log_batch = LogBatch::new();
+            for &mut (_, ref mut invoke_ctx) in &mut append_res {
+                log_batch.append(&mut invoke_ctx.log_batch);
+            }
+            engine
+                .write(&log_batch, self.cfg.sync_log)
+                .unwrap_or_else(|e| {
+                    panic!("{} failed to save raft log entries: {:?}", self.tag, e);
+                });
+        }
+
         let mut ready_results = Vec::with_capacity(append_res.len());
         for (mut ready, invoke_ctx) in append_res {
             let region_id = invoke_ctx.region_id;
@@ -1261,6 +1371,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         peer.raft_log_size_hint = peer.raft_log_size_hint * remain_cnt / total_cnt;
         let task = RaftlogGcTask {
             raft_engine: peer.get_store().get_raft_engine().clone(),
+            raft_log_engine: peer.get_store().get_raft_log_engine(),
             region_id: peer.get_store().get_region_id(),
             start_idx: peer.last_compacted_idx,
             end_idx: state.get_index() + 1,
@@ -1437,6 +1548,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
     }
 
     fn on_ready_result(&mut self, region_id: u64, exec_results: Vec<ExecResult>) {
+        log_fields!(region_id = region_id);
         // handle executing committed log results
         for result in exec_results {
             match result {
@@ -1553,7 +1665,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
             Some(peer) => peer,
             None => return Err(Error::RegionNotFound(region_id)),
         };
-        if !peer.is_leader() {
+        if !peer.is_leader() && !peer.can_serve_follower_read(msg) {
             return Err(Error::NotLeader(
                 region_id,
                 peer.get_peer_from_cache(peer.leader_id()),
@@ -1859,16 +1971,54 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         }
     }
 
+    fn on_scatter_region(&mut self, region_id: u64, epoch: metapb::RegionEpoch) {
+        let peer = match self.region_peers.get(&region_id) {
+            Some(peer) if peer.is_leader() => peer,
+            _ => {
+                info!(
+                    "[region {}] region on {} doesn't exist or is not leader, skip scatter.",
+                    region_id,
+                    self.store_id()
+                );
+                return;
+            }
+        };
+        if *peer.region().get_region_epoch() != epoch {
+            info!(
+                "{} epoch changed {:?} != {:?}, skip scatter",
+                peer.tag,
+                peer.region().get_region_epoch(),
+                epoch
+            );
+            return;
+        }
+
+        let task = PdTask::ScatterRegion {
+            region: peer.region().clone(),
+            peer: peer.peer.clone(),
+        };
+        if let Err(e) = self.pd_worker.schedule(task) {
+            error!("{} failed to notify pd to scatter: {}", peer.tag, e);
+        }
+    }
+
     fn on_pd_heartbeat_tick(&mut self, event_loop: &mut EventLoop<Self>) {
         for peer in self.region_peers.values_mut() {
             peer.check_peers();
         }
 
         let mut leader_count = 0;
+        let mut hibernated_count = 0;
         for peer in self.region_peers.values() {
+            if peer.is_hibernated() {
+                hibernated_count += 1;
+            }
             if peer.is_leader() {
                 leader_count += 1;
-                peer.heartbeat_pd(&self.pd_worker);
+                // The region doesn't change while hibernating.
+                if !peer.is_hibernated() {
+                    peer.heartbeat_pd(&self.pd_worker);
+                }
             }
         }
 
@@ -1878,6 +2028,9 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         STORE_PD_HEARTBEAT_GAUGE_VEC
             .with_label_values(&["region"])
             .set(self.region_peers.len() as f64);
+        STORE_PD_HEARTBEAT_GAUGE_VEC
+            .with_label_values(&["hibernated"])
+            .set(hibernated_count as f64);
 
         self.register_pd_heartbeat_tick(event_loop);
     }
@@ -2082,8 +2235,58 @@ impl<T: Transport, C: PdClient> Store<T, C> {
     }
 
     fn on_unreachable(&mut self, region_id: u64, to_peer_id: u64) {
-        if let Some(peer) = self.region_peers.get_mut(&region_id) {
-            peer.raft_group.report_unreachable(to_peer_id);
+        let to_store_id = match self.region_peers.get_mut(&region_id) {
+            Some(peer) => {
+                peer.raft_group.report_unreachable(to_peer_id);
+                peer.get_peer_from_cache(to_peer_id).map(|p| p.get_store_id())
+            }
+            None => None,
+        };
+        if let Some(store_id) = to_store_id {
+            self.wake_up_followers_of_store(store_id);
+        }
+    }
+
+    // The store may be down, so wake up the hibernated followers whose leader is on it.
+    // Otherwise they don't notice the leader is gone until the next leader check.
+    fn wake_up_followers_of_store(&mut self, store_id: u64) {
+        if !self.cfg.hibernate_regions {
+            return;
+        }
+        // Every message sent to the store fails when it's down, so the followers are
+        // checked at most once per election timeout.
+        let election_timeout = Duration::from_millis(
+            self.cfg.raft_base_tick_interval.as_millis() *
+                self.cfg.raft_election_timeout_ticks as u64,
+        );
+        let now = Instant::now();
+        if let Some(last) = self.unreachable_stores.get(&store_id) {
+            if now.duration_since(*last) < election_timeout {
+                return;
+            }
+        }
+        self.unreachable_stores.insert(store_id, now);
+
+        let mut woken = 0;
+        for peer in self.region_peers.values_mut() {
+            if !peer.is_hibernated() || peer.is_leader() {
+                continue;
+            }
+            let leader_id = peer.leader_id();
+            let leader_on_store = peer.get_peer_from_cache(leader_id)
+                .map_or(false, |p| p.get_store_id() == store_id);
+            if leader_on_store {
+                peer.wake_up_follower(&self.trans);
+                woken += 1;
+            }
+        }
+        if woken > 0 {
+            info!(
+                "{} store {} is unreachable, wake up {} hibernated followers",
+                self.tag,
+                store_id,
+                woken
+            );
         }
     }
 }
@@ -2395,6 +2598,7 @@ impl<T: Transport, C: PdClient> mio::Handler for Store<T, C> {
             } => {
                 self.on_unreachable(region_id, to_peer_id);
             }
+            Msg::ScatterRegion { region_id, epoch } => self.on_scatter_region(region_id, epoch),
             Msg::SnapshotStats => self.store_heartbeat_pd(),
             Msg::ComputeHashResult {
                 region_id,
@@ -2403,6 +2607,7 @@ impl<T: Transport, C: PdClient> mio::Handler for Store<T, C> {
             } => {
                 self.on_hash_computed(region_id, index, hash);
             }
+            Msg::ChangeConfig(cfg) => self.on_config_changed(cfg),
         }
     }
 
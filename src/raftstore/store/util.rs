@@ -90,6 +90,20 @@ pub fn conf_change_type_str(conf_type: &eraftpb::ConfChangeType) -> &'static str
     }
 }
 
+// The context of the heartbeat a leader sends before hibernating. Read index
+// contexts are 8 bytes ids, so they never equal it.
+const HIBERNATE_CONTEXT: &'static [u8] = b"hibernate";
+
+/// Mark the heartbeat as a request to hibernate the region.
+pub fn set_hibernate_msg(msg: &mut eraftpb::Message) {
+    msg.set_context(HIBERNATE_CONTEXT.to_vec())
+}
+
+#[inline]
+pub fn is_hibernate_msg(msg: &eraftpb::Message) -> bool {
+    msg.get_msg_type() == MessageType::MsgHeartbeat && msg.get_context() == HIBERNATE_CONTEXT
+}
+
 // Use delete range to delete all data in [start_key, end_key) for each column family.
 pub fn delete_all_in_range(db: &DB, start_key: &[u8], end_key: &[u8]) -> Result<()> {
     if start_key >= end_key {
@@ -268,6 +282,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_hibernate_msg() {
+        let mut msg = Message::new();
+        msg.set_msg_type(MessageType::MsgHeartbeat);
+        assert!(!is_hibernate_msg(&msg));
+        set_hibernate_msg(&mut msg);
+        assert!(is_hibernate_msg(&msg));
+        // Read index heartbeats carry 8 bytes contexts.
+        msg.set_context(vec![0; 8]);
+        assert!(!is_hibernate_msg(&msg));
+        msg.set_context(HIBERNATE_CONTEXT.to_vec());
+        msg.set_msg_type(MessageType::MsgAppend);
+        assert!(!is_hibernate_msg(&msg));
+    }
+
     #[test]
     fn test_epoch_stale() {
         let mut epoch = metapb::RegionEpoch::new();
@@ -17,9 +17,10 @@ use std::sync::mpsc::Sender;
 use std::fmt::{self, Debug, Display, Formatter};
 use std::collections::VecDeque;
 
-use rocksdb::{Writable, WriteBatch, DB};
+use rocksdb::{Writable, WriteBatch, WriteOptions, DB};
 use protobuf::RepeatedField;
 
+use kvproto::import_sstpb::SSTMeta;
 use kvproto::metapb::{Peer as PeerMeta, Region};
 use kvproto::eraftpb::{ConfChange, ConfChangeType, Entry, EntryType};
 use kvproto::raft_serverpb::{PeerState, RaftApplyState, RaftTruncatedState};
@@ -28,9 +29,11 @@ use kvproto::raft_cmdpb::{AdminCmdType, AdminRequest, AdminResponse, ChangePeerR
 
 use util::worker::Runnable;
 use util::{escape, rocksdb};
+use util::io_limiter::{self, IOType};
 use util::time::SlowTimer;
 use util::collections::{HashMap, HashMapEntry as MapEntry};
-use storage::{ALL_CFS, CF_DEFAULT, CF_LOCK, CF_RAFT};
+use storage::{ALL_CFS, CF_DEFAULT, CF_LOCK, CF_RAFT, DATA_CFS};
+use import::SSTImporter;
 use raftstore::{Error, Result};
 use raftstore::coprocessor::CoprocessorHost;
 use raftstore::store::{cmd_resp, keys, util, Store};
@@ -166,23 +169,47 @@ pub enum ExecResult {
 
 struct ApplyContext<'a> {
     pub host: &'a CoprocessorHost,
+    pub importer: Option<&'a SSTImporter>,
     pub wb: Option<WriteBatch>,
     pub cbs: Vec<(Callback, RaftCmdResponse)>,
     pub wb_last_bytes: u64,
     pub wb_last_keys: u64,
+    // The SST files ingested since the last write to the engine.
+    pub ingested_ssts: Vec<SSTMeta>,
 }
 
 impl<'a> ApplyContext<'a> {
-    fn new(host: &CoprocessorHost) -> ApplyContext {
+    fn new(host: &'a CoprocessorHost, importer: Option<&'a SSTImporter>) -> ApplyContext<'a> {
         ApplyContext {
             host: host,
+            importer: importer,
             wb: Some(WriteBatch::with_capacity(DEFAULT_APPLY_WB_SIZE)),
             cbs: vec![],
             wb_last_bytes: 0,
             wb_last_keys: 0,
+            ingested_ssts: vec![],
         }
     }
 
+    /// Writes the write batch to `engine`, then deletes the SST files ingested before.
+    /// The write is synced if there are such files, so the apply state of the commands
+    /// ingesting them is persisted and they are never needed to replay the log again.
+    pub fn write_to_engine(&mut self, engine: &DB, tag: &str) {
+        let wb = self.wb.take().unwrap();
+        io_limiter::request_io(IOType::ForegroundWrite, wb.data_size());
+        let mut write_opts = WriteOptions::new();
+        write_opts.set_sync(!self.ingested_ssts.is_empty());
+        engine.write_opt(wb, &write_opts).unwrap_or_else(|e| {
+            panic!("{} failed to write to engine, error: {:?}", tag, e)
+        });
+        for sst in self.ingested_ssts.drain(..) {
+            if let Err(e) = self.importer.unwrap().delete(&sst) {
+                warn!("{} failed to delete {:?}: {:?}", tag, sst, e);
+            }
+        }
+        self.wb = Some(WriteBatch::with_capacity(DEFAULT_APPLY_WB_SIZE));
+    }
+
     pub fn wb_mut(&mut self) -> &mut WriteBatch {
         self.wb.as_mut().unwrap()
     }
@@ -255,6 +282,15 @@ fn should_flush_to_engine(cmd: &RaftCmdRequest, wb_keys: usize) -> bool {
         return true;
     }
 
+    // Ingested files bypass the write batch, so the writes before them must be flushed
+    // to make the files newer.
+    if cmd.get_requests()
+        .iter()
+        .any(|req| req.get_cmd_type() == CmdType::IngestSST)
+    {
+        return true;
+    }
+
     // When write batch contains more than `recommended` keys, flush the batch to engine.
     if wb_keys >= WRITE_BATCH_MAX_KEYS {
         return true;
@@ -319,6 +355,7 @@ impl ApplyDelegate {
         apply_ctx: &mut ApplyContext,
         committed_entries: Vec<Entry>,
     ) -> Vec<ExecResult> {
+        log_fields!(region_id = self.region_id(), peer_id = self.id);
         if committed_entries.is_empty() {
             return vec![];
         }
@@ -412,17 +449,12 @@ impl ApplyDelegate {
                 self.update_metrics(apply_ctx);
 
                 // flush to engine
-                self.engine
-                    .write(apply_ctx.wb.take().unwrap())
-                    .unwrap_or_else(|e| {
-                        panic!("{} failed to write to engine, error: {:?}", self.tag, e)
-                    });
+                apply_ctx.write_to_engine(&self.engine, &self.tag);
 
                 // call callback
                 for (cb, resp) in apply_ctx.cbs.drain(..) {
                     cb(resp);
                 }
-                apply_ctx.wb = Some(WriteBatch::with_capacity(DEFAULT_APPLY_WB_SIZE));
                 apply_ctx.mark_last_bytes_and_keys();
             }
 
@@ -517,7 +549,7 @@ impl ApplyDelegate {
 
         let cmd_cb = self.find_cb(index, term, &cmd);
         apply_ctx.host.pre_apply(&self.region, &mut cmd);
-        let (mut resp, exec_result) = self.apply_raft_cmd(apply_ctx.wb_mut(), index, term, &cmd);
+        let (mut resp, exec_result) = self.apply_raft_cmd(apply_ctx, index, term, &cmd);
 
         debug!("{} applied command at log index {}", self.tag, index);
 
@@ -543,7 +575,7 @@ impl ApplyDelegate {
     // usually due to disk operation fail, which is rare, so just panic is ok.
     fn apply_raft_cmd(
         &mut self,
-        wb: &mut WriteBatch,
+        apply_ctx: &mut ApplyContext,
         index: u64,
         term: u64,
         req: &RaftCmdRequest,
@@ -551,7 +583,7 @@ impl ApplyDelegate {
         // if pending remove, apply should be aborted already.
         assert!(!self.pending_remove);
 
-        let mut ctx = self.new_ctx(wb, index, term, req);
+        let mut ctx = self.new_ctx(apply_ctx, index, term, req);
         ctx.wb.set_save_point();
         let (resp, exec_result) = self.exec_raft_cmd(&mut ctx).unwrap_or_else(|e| {
             // clear dirty values.
@@ -637,14 +669,16 @@ impl ApplyDelegate {
 
     fn new_ctx<'a>(
         &self,
-        wb: &'a mut WriteBatch,
+        apply_ctx: &'a mut ApplyContext,
         index: u64,
         term: u64,
         req: &'a RaftCmdRequest,
     ) -> ExecContext<'a> {
         ExecContext {
             apply_state: self.apply_state.clone(),
-            wb: wb,
+            wb: apply_ctx.wb.as_mut().unwrap(),
+            importer: apply_ctx.importer,
+            ingested_ssts: &mut apply_ctx.ingested_ssts,
             req: req,
             index: index,
             term: term,
@@ -655,6 +689,8 @@ impl ApplyDelegate {
 struct ExecContext<'a> {
     apply_state: RaftApplyState,
     wb: &'a mut WriteBatch,
+    importer: Option<&'a SSTImporter>,
+    ingested_ssts: &'a mut Vec<SSTMeta>,
     req: &'a RaftCmdRequest,
     index: u64,
     term: u64,
@@ -999,11 +1035,33 @@ impl ApplyDelegate {
 
     fn exec_write_cmd(
         &mut self,
-        ctx: &ExecContext,
+        ctx: &mut ExecContext,
     ) -> Result<(RaftCmdResponse, Option<ExecResult>)> {
         let requests = ctx.req.get_requests();
         let mut responses = Vec::with_capacity(requests.len());
 
+        // All the files are checked before any of them is ingested, so a command with an
+        // invalid file ingests none of them.
+        let ssts: Vec<_> = requests
+            .iter()
+            .filter(|req| req.get_cmd_type() == CmdType::IngestSST)
+            .map(|req| req.get_ingest_sst().get_sst())
+            .collect();
+        if !ssts.is_empty() {
+            let importer = ctx.importer.unwrap_or_else(|| {
+                panic!("{} can't ingest {:?} without an sst importer", self.tag, ssts)
+            });
+            if let Err(e) = self.check_ingest_ssts(&ssts) {
+                // The command fails on every peer, the files are of no use.
+                for sst in ssts {
+                    if let Err(e) = importer.delete(sst) {
+                        warn!("{} failed to delete {:?}: {:?}", self.tag, sst, e);
+                    }
+                }
+                return Err(e);
+            }
+        }
+
         let mut ranges = vec![];
         for req in requests {
             let cmd_type = req.get_cmd_type();
@@ -1011,6 +1069,7 @@ impl ApplyDelegate {
                 CmdType::Put => self.handle_put(ctx, req),
                 CmdType::Delete => self.handle_delete(ctx, req),
                 CmdType::DeleteRange => self.handle_delete_range(ctx, req, &mut ranges),
+                CmdType::IngestSST => self.handle_ingest_sst(ctx, req),
                 // Readonly commands are handled in raftstore directly.
                 // Don't panic here in case there are old entries need to be applied.
                 // It's also safe to skip them here, because a restart must have happened,
@@ -1180,6 +1239,56 @@ impl ApplyDelegate {
 
         Ok(resp)
     }
+
+    // Checks the files by their meta only, so every peer gets the same result, no
+    // matter whether it has the files.
+    fn check_ingest_ssts(&self, ssts: &[&SSTMeta]) -> Result<()> {
+        for sst in ssts {
+            let cf = sst.get_cf_name();
+            if DATA_CFS.iter().find(|x| **x == cf).is_none() {
+                return Err(box_err!("invalid ingest sst command, cf: {:?}", cf));
+            }
+            if sst.get_region_id() != self.region.get_id() {
+                return Err(box_err!(
+                    "{:?} is uploaded for region {}, not {}",
+                    sst,
+                    sst.get_region_id(),
+                    self.region.get_id()
+                ));
+            }
+            // The files are built for the range of the region at the epoch, any change
+            // of the region makes them stale.
+            if sst.get_region_epoch() != self.region.get_region_epoch() {
+                return Err(Error::StaleEpoch(
+                    format!(
+                        "{:?} is uploaded at epoch {:?}, current {:?}",
+                        sst,
+                        sst.get_region_epoch(),
+                        self.region.get_region_epoch()
+                    ),
+                    vec![self.region.clone()],
+                ));
+            }
+            let range = sst.get_range();
+            try!(util::check_key_in_region(range.get_start(), &self.region));
+            try!(util::check_key_in_region(range.get_end(), &self.region));
+        }
+        Ok(())
+    }
+
+    // The file was checked against its meta when it was uploaded to every store of the
+    // region, so failing to ingest it is fatal. It's kept until the apply state of the
+    // command is persisted, see `ApplyContext::write_to_engine`.
+    fn handle_ingest_sst(&mut self, ctx: &mut ExecContext, req: &Request) -> Result<Response> {
+        let sst = req.get_ingest_sst().get_sst();
+        if let Err(e) = ctx.importer.unwrap().ingest(sst, &self.engine) {
+            panic!("{} failed to ingest {:?}: {:?}", self.tag, sst, e);
+        }
+        info!("{} ingested {:?}", self.tag, sst);
+        ctx.ingested_ssts.push(sst.clone());
+        self.metrics.size_diff_hint += sst.get_length() as i64;
+        Ok(Response::new())
+    }
 }
 
 pub fn get_change_peer_cmd(msg: &RaftCmdRequest) -> Option<&ChangePeerRequest> {
@@ -1422,6 +1531,7 @@ pub enum TaskRes {
 pub struct Runner {
     db: Arc<DB>,
     host: Arc<CoprocessorHost>,
+    importer: Option<Arc<SSTImporter>>,
     delegates: HashMap<u64, ApplyDelegate>,
     notifier: Sender<TaskRes>,
 }
@@ -1436,6 +1546,7 @@ impl Runner {
         Runner {
             db: store.kv_engine(),
             host: store.coprocessor_host.clone(),
+            importer: store.sst_importer(),
             delegates: delegates,
             notifier: notifier,
         }
@@ -1445,7 +1556,8 @@ impl Runner {
         let _timer = STORE_APPLY_LOG_HISTOGRAM.start_coarse_timer();
 
         let mut applys_res = Vec::with_capacity(applys.len());
-        let mut apply_ctx = ApplyContext::new(self.host.as_ref());
+        let importer = self.importer.as_ref().map(|i| i.as_ref());
+        let mut apply_ctx = ApplyContext::new(self.host.as_ref(), importer);
         for apply in applys {
             if apply.entries.is_empty() {
                 continue;
@@ -1481,9 +1593,7 @@ impl Runner {
         }
 
         // Write to engine
-        self.db
-            .write(apply_ctx.wb.take().unwrap())
-            .unwrap_or_else(|e| panic!("failed to write to engine, error: {:?}", e));
+        apply_ctx.write_to_engine(&self.db, "[apply worker]");
 
         // Call callbacks
         for (cb, resp) in apply_ctx.cbs.drain(..) {
@@ -1580,10 +1690,13 @@ impl Runnable<Task> for Runner {
 
 #[cfg(test)]
 mod tests {
+    use std::fs::File;
+    use std::io::Read;
     use std::sync::*;
 
+    use crc::crc32;
     use tempdir::TempDir;
-    use rocksdb::{Writable, WriteBatch, DB};
+    use rocksdb::{ColumnFamilyOptions, EnvOptions, SstFileWriter, Writable, WriteBatch, DB};
     use protobuf::Message;
     use kvproto::metapb::RegionEpoch;
     use kvproto::raft_cmdpb::CmdType;
@@ -1604,6 +1717,7 @@ mod tests {
         Runner {
             db: db,
             host: host,
+            importer: None,
             delegates: HashMap::default(),
             notifier: tx,
         }
@@ -1867,6 +1981,14 @@ mod tests {
             self
         }
 
+        fn ingest_sst(mut self, sst: SSTMeta) -> EntryBuilder {
+            let mut cmd = Request::new();
+            cmd.set_cmd_type(CmdType::IngestSST);
+            cmd.mut_ingest_sst().set_sst(sst);
+            self.req.mut_requests().push(cmd);
+            self
+        }
+
         fn build(mut self) -> Entry {
             self.entry.set_data(self.req.write_to_bytes().unwrap());
             self.entry
@@ -1890,7 +2012,7 @@ mod tests {
             .capture_resp(&mut delegate, tx.clone())
             .build();
         let host = CoprocessorHost::new();
-        let mut apply_ctx = ApplyContext::new(&host);
+        let mut apply_ctx = ApplyContext::new(&host, None);
         let res = delegate.handle_raft_committed_entries(&mut apply_ctx, vec![put_entry]);
         db.write(apply_ctx.wb.take().unwrap()).unwrap();
         for (cb, resp) in apply_ctx.cbs.drain(..) {
@@ -1917,7 +2039,7 @@ mod tests {
             .put_cf(CF_LOCK, b"k1", b"v1")
             .epoch(1, 3)
             .build();
-        let mut apply_ctx = ApplyContext::new(&host);
+        let mut apply_ctx = ApplyContext::new(&host, None);
         delegate.handle_raft_committed_entries(&mut apply_ctx, vec![put_entry]);
         db.write(apply_ctx.wb.take().unwrap()).unwrap();
         for (cb, resp) in apply_ctx.cbs.drain(..) {
@@ -1940,7 +2062,7 @@ mod tests {
             .epoch(1, 1)
             .capture_resp(&mut delegate, tx.clone())
             .build();
-        let mut apply_ctx = ApplyContext::new(&host);
+        let mut apply_ctx = ApplyContext::new(&host, None);
         delegate.handle_raft_committed_entries(&mut apply_ctx, vec![put_entry]);
         db.write(apply_ctx.wb.take().unwrap()).unwrap();
         for (cb, resp) in apply_ctx.cbs.drain(..) {
@@ -1957,7 +2079,7 @@ mod tests {
             .epoch(1, 3)
             .capture_resp(&mut delegate, tx.clone())
             .build();
-        let mut apply_ctx = ApplyContext::new(&host);
+        let mut apply_ctx = ApplyContext::new(&host, None);
         delegate.handle_raft_committed_entries(&mut apply_ctx, vec![put_entry]);
         db.write(apply_ctx.wb.take().unwrap()).unwrap();
         for (cb, resp) in apply_ctx.cbs.drain(..) {
@@ -1983,7 +2105,7 @@ mod tests {
         let lock_written_bytes = delegate.metrics.lock_cf_written_bytes;
         let delete_keys_hint = delegate.metrics.delete_keys_hint;
         let size_diff_hint = delegate.metrics.size_diff_hint;
-        let mut apply_ctx = ApplyContext::new(&host);
+        let mut apply_ctx = ApplyContext::new(&host, None);
         delegate.handle_raft_committed_entries(&mut apply_ctx, vec![put_entry]);
         db.write(apply_ctx.wb.take().unwrap()).unwrap();
         for (cb, resp) in apply_ctx.cbs.drain(..) {
@@ -2007,7 +2129,7 @@ mod tests {
             .epoch(1, 3)
             .capture_resp(&mut delegate, tx.clone())
             .build();
-        let mut apply_ctx = ApplyContext::new(&host);
+        let mut apply_ctx = ApplyContext::new(&host, None);
         delegate.handle_raft_committed_entries(&mut apply_ctx, vec![delete_entry]);
         db.write(apply_ctx.wb.take().unwrap()).unwrap();
         for (cb, resp) in apply_ctx.cbs.drain(..) {
@@ -2021,7 +2143,7 @@ mod tests {
             .epoch(1, 3)
             .capture_resp(&mut delegate, tx.clone())
             .build();
-        let mut apply_ctx = ApplyContext::new(&host);
+        let mut apply_ctx = ApplyContext::new(&host, None);
         delegate.handle_raft_committed_entries(&mut apply_ctx, vec![delete_range_entry]);
         db.write(apply_ctx.wb.take().unwrap()).unwrap();
         for (cb, resp) in apply_ctx.cbs.drain(..) {
@@ -2038,7 +2160,7 @@ mod tests {
             .epoch(1, 3)
             .capture_resp(&mut delegate, tx.clone())
             .build();
-        let mut apply_ctx = ApplyContext::new(&host);
+        let mut apply_ctx = ApplyContext::new(&host, None);
         delegate.handle_raft_committed_entries(&mut apply_ctx, vec![delete_range_entry]);
         db.write(apply_ctx.wb.take().unwrap()).unwrap();
         for (cb, resp) in apply_ctx.cbs.drain(..) {
@@ -2059,7 +2181,7 @@ mod tests {
                 .build();
             entries.push(put_entry);
         }
-        let mut apply_ctx = ApplyContext::new(&host);
+        let mut apply_ctx = ApplyContext::new(&host, None);
         delegate.handle_raft_committed_entries(&mut apply_ctx, entries);
         db.write(apply_ctx.wb.take().unwrap()).unwrap();
         for (cb, resp) in apply_ctx.cbs.drain(..) {
@@ -2073,4 +2195,92 @@ mod tests {
             WRITE_BATCH_MAX_KEYS as u64 + 8
         );
     }
+
+    #[test]
+    fn test_ingest_sst() {
+        let (_path, db) = create_tmp_engine("test-ingest-sst");
+        let mut reg = Registration::default();
+        reg.region.set_end_key(b"k5".to_vec());
+        let mut delegate = ApplyDelegate::from_registration(db.clone(), reg);
+        let (tx, rx) = mpsc::channel();
+
+        let dir = TempDir::new("test-ingest-sst-file").unwrap();
+        let importer = SSTImporter::new(dir.path().join("import")).unwrap();
+        // Uploads a file of the keys built at the version of the region to the importer.
+        let new_sst = |uuid: u8, version: u64, origin_keys: &[&[u8]]| {
+            let path = dir.path().join(format!("{}.sst", uuid));
+            let mut writer = SstFileWriter::new(EnvOptions::new(), ColumnFamilyOptions::new());
+            writer.open(path.to_str().unwrap()).unwrap();
+            for key in origin_keys {
+                writer.put(&keys::data_key(key), b"v1").unwrap();
+            }
+            writer.finish().unwrap();
+            let mut data = vec![];
+            File::open(&path)
+                .unwrap()
+                .read_to_end(&mut data)
+                .unwrap();
+
+            let mut sst = SSTMeta::new();
+            sst.set_uuid(vec![uuid; 16]);
+            sst.set_cf_name(CF_DEFAULT.to_owned());
+            sst.mut_region_epoch().set_version(version);
+            sst.mut_range().set_start(origin_keys[0].to_vec());
+            sst.mut_range()
+                .set_end(origin_keys[origin_keys.len() - 1].to_vec());
+            sst.set_length(data.len() as u64);
+            sst.set_crc32(crc32::checksum_ieee(&data));
+            let mut file = importer.create(&sst).unwrap();
+            file.append(&data).unwrap();
+            file.finish().unwrap();
+            sst
+        };
+
+        let k1_k6 = new_sst(1, 0, &[b"k1", b"k6"]);
+        let k2 = new_sst(2, 0, &[b"k2"]);
+        let stale = new_sst(3, 1, &[b"k3"]);
+        let k1 = new_sst(4, 0, &[b"k1"]);
+        let entries = vec![
+            EntryBuilder::new(1, 1)
+                .put(b"k1", b"v0")
+                .capture_resp(&mut delegate, tx.clone())
+                .build(),
+            EntryBuilder::new(2, 1)
+                .ingest_sst(k1_k6.clone())
+                .capture_resp(&mut delegate, tx.clone())
+                .build(),
+            EntryBuilder::new(3, 1)
+                .ingest_sst(k2.clone())
+                .ingest_sst(stale.clone())
+                .capture_resp(&mut delegate, tx.clone())
+                .build(),
+            EntryBuilder::new(4, 1)
+                .ingest_sst(k1.clone())
+                .capture_resp(&mut delegate, tx.clone())
+                .build(),
+        ];
+        let host = CoprocessorHost::new();
+        let mut apply_ctx = ApplyContext::new(&host, Some(&importer));
+        delegate.handle_raft_committed_entries(&mut apply_ctx, entries);
+        // The ingested file is kept until the apply state is written.
+        assert!(importer.exists(&k1));
+        apply_ctx.write_to_engine(&db, &delegate.tag);
+        for (cb, resp) in apply_ctx.cbs.drain(..) {
+            cb(resp);
+        }
+        let errors: Vec<_> = (0..4)
+            .map(|_| rx.try_recv().unwrap().get_header().has_error())
+            .collect();
+        // The largest key of the first file is out of the region, and the second file
+        // of the third command is stale, so none of its files is ingested.
+        assert_eq!(errors, vec![false, true, true, false]);
+        // The put before the ingestion is overwritten.
+        assert_eq!(&*db.get(&keys::data_key(b"k1")).unwrap().unwrap(), b"v1");
+        assert!(db.get(&keys::data_key(b"k2")).unwrap().is_none());
+        assert!(db.get(&keys::data_key(b"k6")).unwrap().is_none());
+        assert_eq!(delegate.apply_state.get_applied_index(), 4);
+        for sst in &[k1_k6, k2, stale, k1] {
+            assert!(!importer.exists(sst));
+        }
+    }
 }
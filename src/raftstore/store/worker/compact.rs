@@ -11,16 +11,24 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use raftstore::store::keys;
 use util::worker::Runnable;
 use util::rocksdb;
+use util::properties::SizeProperties;
 use util::escape;
+use util::io_limiter::{self, IOType};
 
-use rocksdb::{CompactOptions, DB};
+use rocksdb::{CFHandle, CompactOptions, Range, DB};
 use std::sync::Arc;
 use std::fmt::{self, Display, Formatter};
 use std::error;
 use super::metrics::COMPACT_RANGE_CF;
 
+// A range is compacted in chunks of about this size, and the IO of a chunk is
+// requested right before it's compacted, so that compaction is paced instead of
+// reserving the IO of the whole range up front.
+const COMPACT_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
 pub struct Task {
     pub cf_name: String,
     pub start_key: Option<Vec<u8>>, // None means smallest key
@@ -73,7 +81,8 @@ impl Runner {
         let mut compact_opts = CompactOptions::new();
         // manual compaction can concurrently run with background compaction threads.
         compact_opts.set_exclusive_manual_compaction(false);
-        self.engine.compact_range_cf_opt(
+        compact_range(
+            &self.engine,
             cf_handle,
             &compact_opts,
             start_key.as_ref().map(Vec::as_slice),
@@ -85,6 +94,85 @@ impl Runner {
     }
 }
 
+/// Compacts `[start_key, end_key)` of the column family in chunks, `None` means
+/// unbounded. The IO of every chunk is requested as `IOType::Compaction` right before
+/// it's compacted.
+pub fn compact_range(
+    db: &DB,
+    cf_handle: &CFHandle,
+    opts: &CompactOptions,
+    start_key: Option<&[u8]>,
+    end_key: Option<&[u8]>,
+) {
+    // Both local and data keys are less than `DATA_MAX_KEY`.
+    let chunks = split_range(
+        db,
+        cf_handle,
+        start_key.unwrap_or(b""),
+        end_key.unwrap_or(keys::DATA_MAX_KEY),
+    );
+    let mut chunk_start = start_key.map(|k| k.to_vec());
+    for (chunk_end, size) in chunks {
+        io_limiter::request_io(IOType::Compaction, size as usize);
+        let chunk_end = chunk_end.or_else(|| end_key.map(|k| k.to_vec()));
+        db.compact_range_cf_opt(
+            cf_handle,
+            opts,
+            chunk_start.as_ref().map(Vec::as_slice),
+            chunk_end.as_ref().map(Vec::as_slice),
+        );
+        chunk_start = chunk_end;
+    }
+}
+
+// Splits the range by the size properties of the tables overlapping with it, see
+// `split_by_size`. The whole range is one chunk of size 0 if the tables have no
+// size properties.
+fn split_range(
+    db: &DB,
+    cf_handle: &CFHandle,
+    start: &[u8],
+    end: &[u8],
+) -> Vec<(Option<Vec<u8>>, u64)> {
+    let range = Range::new(start, end);
+    let collection = match db.get_properties_of_tables_in_range(cf_handle, &[range]) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("failed to get properties of range: {:?}", e);
+            return vec![(None, 0)];
+        }
+    };
+    let mut blocks = vec![];
+    for (_, v) in &*collection {
+        if let Ok(props) = SizeProperties::decode(v.user_collected_properties()) {
+            for (key, handle) in props.index_handles.iter() {
+                if key.as_slice() >= start && key.as_slice() < end {
+                    blocks.push((key.clone(), handle.size));
+                }
+            }
+        }
+    }
+    blocks.sort();
+    split_by_size(blocks, COMPACT_CHUNK_SIZE)
+}
+
+// Groups the sorted `(last key, size)` of blocks into chunks of at least `chunk_size`,
+// and returns the end key and size of every chunk. The end key of the last chunk is
+// `None`, which means the end of the whole range.
+fn split_by_size(blocks: Vec<(Vec<u8>, u64)>, chunk_size: u64) -> Vec<(Option<Vec<u8>>, u64)> {
+    let mut chunks = vec![];
+    let mut size = 0;
+    for (key, block_size) in blocks {
+        size += block_size;
+        if size >= chunk_size {
+            chunks.push((Some(key), size));
+            size = 0;
+        }
+    }
+    chunks.push((None, size));
+    chunks
+}
+
 impl Runnable<Task> for Runner {
     fn run(&mut self, task: Task) {
         let cf = task.cf_name.clone();
@@ -108,6 +196,26 @@ mod test {
 
     const ROCKSDB_TOTAL_SST_FILES_SIZE: &'static str = "rocksdb.total-sst-files-size";
 
+    #[test]
+    fn test_split_by_size() {
+        assert_eq!(split_by_size(vec![], 10), vec![(None, 0)]);
+        let blocks = vec![
+            (b"a".to_vec(), 4),
+            (b"b".to_vec(), 6),
+            (b"c".to_vec(), 3),
+            (b"d".to_vec(), 12),
+            (b"e".to_vec(), 1),
+        ];
+        assert_eq!(
+            split_by_size(blocks, 10),
+            vec![
+                (Some(b"b".to_vec()), 10),
+                (Some(b"d".to_vec()), 15),
+                (None, 1),
+            ]
+        );
+    }
+
     #[test]
     fn test_compact_range() {
         let path = TempDir::new("compact-range-test").unwrap();
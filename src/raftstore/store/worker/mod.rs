@@ -56,7 +56,7 @@ pub mod apply;
 
 pub use self::region::{Runner as RegionRunner, Task as RegionTask};
 pub use self::split_check::{Runner as SplitCheckRunner, Task as SplitCheckTask};
-pub use self::compact::{Runner as CompactRunner, Task as CompactTask};
+pub use self::compact::{compact_range, Runner as CompactRunner, Task as CompactTask};
 pub use self::raftlog_gc::{Runner as RaftlogGcRunner, Task as RaftlogGcTask};
 pub use self::pd::{Runner as PdRunner, Task as PdTask};
 pub use self::consistency_check::{Runner as ConsistencyCheckRunner, Task as ConsistencyCheckTask};
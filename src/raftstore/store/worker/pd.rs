@@ -65,6 +65,10 @@ pub enum Task {
         region: metapb::Region,
         peer: metapb::Peer,
     },
+    ScatterRegion {
+        region: metapb::Region,
+        peer: metapb::Peer,
+    },
 }
 
 impl Display for Task {
@@ -101,6 +105,9 @@ impl Display for Task {
                 ref region,
                 ref peer,
             } => write!(f, "validate peer {:?} with region {:?}", peer, region),
+            Task::ScatterRegion { ref region, .. } => {
+                write!(f, "scatter region {}", region.get_id())
+            }
         }
     }
 }
@@ -333,6 +340,28 @@ impl<T: PdClient> Runner<T> {
         handle.spawn(f);
     }
 
+    fn handle_scatter_region(&self, handle: &Handle, region: metapb::Region, peer: metapb::Peer) {
+        PD_REQ_COUNTER_VEC
+            .with_label_values(&["scatter region", "all"])
+            .inc();
+
+        let region_id = region.get_id();
+        let f = self.pd_client.scatter_region(region, peer).then(move |resp| {
+            match resp {
+                Ok(_) => {
+                    PD_REQ_COUNTER_VEC
+                        .with_label_values(&["scatter region", "success"])
+                        .inc();
+                }
+                Err(e) => {
+                    error!("[region {}] failed to scatter: {:?}", region_id, e);
+                }
+            }
+            Ok(())
+        });
+        handle.spawn(f);
+    }
+
     fn schedule_heartbeat_receiver(&mut self, handle: &Handle) {
         let ch = self.ch.clone();
         let store_id = self.store_id;
@@ -432,6 +461,9 @@ impl<T: PdClient> Runnable<Task> for Runner<T> {
             }
             Task::ReportSplit { left, right } => self.handle_report_split(handle, left, right),
             Task::ValidatePeer { region, peer } => self.handle_validate_peer(handle, region, peer),
+            Task::ScatterRegion { region, peer } => {
+                self.handle_scatter_region(handle, region, peer)
+            }
         };
     }
 }
@@ -13,6 +13,7 @@
 
 use raftstore::store::keys;
 use raftstore::store::engine::Iterable;
+use raftstore::store::RaftLogEngine;
 use util::worker::Runnable;
 
 use rocksdb::{Writable, WriteBatch, DB};
@@ -23,6 +24,7 @@ use std::sync::mpsc::Sender;
 
 pub struct Task {
     pub raft_engine: Arc<DB>,
+    pub raft_log_engine: Option<Arc<RaftLogEngine>>,
     pub region_id: u64,
     pub start_idx: u64,
     pub end_idx: u64,
@@ -65,6 +67,19 @@ impl Runner {
         Runner { ch: ch }
     }
 
+    /// Compact the logs in raft log engine, and purge log files that are not
+    /// needed anymore. Return the count of log collected.
+    fn gc_raft_log_engine(
+        &mut self,
+        engine: &RaftLogEngine,
+        region_id: u64,
+        end_idx: u64,
+    ) -> Result<u64, Error> {
+        let collected = box_try!(engine.compact_to(region_id, end_idx));
+        box_try!(engine.purge_expired_files());
+        Ok(collected)
+    }
+
     /// Do the gc job and return the count of log collected.
     fn gc_raft_log(
         &mut self,
@@ -116,12 +131,16 @@ impl Runnable<Task> for Runner {
             task.region_id,
             task.end_idx
         );
-        match self.gc_raft_log(
-            task.raft_engine,
-            task.region_id,
-            task.start_idx,
-            task.end_idx,
-        ) {
+        let res = match task.raft_log_engine {
+            Some(ref engine) => self.gc_raft_log_engine(engine, task.region_id, task.end_idx),
+            None => self.gc_raft_log(
+                task.raft_engine,
+                task.region_id,
+                task.start_idx,
+                task.end_idx,
+            ),
+        };
+        match res {
             Err(e) => {
                 error!("[region {}] failed to gc: {:?}", task.region_id, e);
                 self.report_collected(0);
@@ -141,6 +160,8 @@ mod test {
     use util::rocksdb::new_engine;
     use tempdir::TempDir;
     use storage::CF_DEFAULT;
+    use kvproto::eraftpb::Entry;
+    use raftstore::store::{LogBatch, RaftLogEngineConfig};
     use super::*;
 
     #[test]
@@ -165,6 +186,7 @@ mod test {
             (
                 Task {
                     raft_engine: raft_db.clone(),
+                    raft_log_engine: None,
                     region_id: region_id,
                     start_idx: 0,
                     end_idx: 10,
@@ -176,6 +198,7 @@ mod test {
             (
                 Task {
                     raft_engine: raft_db.clone(),
+                    raft_log_engine: None,
                     region_id: region_id,
                     start_idx: 0,
                     end_idx: 50,
@@ -187,6 +210,7 @@ mod test {
             (
                 Task {
                     raft_engine: raft_db.clone(),
+                    raft_log_engine: None,
                     region_id: region_id,
                     start_idx: 50,
                     end_idx: 50,
@@ -198,6 +222,7 @@ mod test {
             (
                 Task {
                     raft_engine: raft_db.clone(),
+                    raft_log_engine: None,
                     region_id: region_id,
                     start_idx: 50,
                     end_idx: 60,
@@ -217,6 +242,45 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_gc_raft_log_engine() {
+        let path = TempDir::new("gc-raft-log-engine-test").unwrap();
+        let raft_path = path.path().join("raft");
+        let raft_db = Arc::new(new_engine(raft_path.to_str().unwrap(), &[CF_DEFAULT]).unwrap());
+        let mut cfg = RaftLogEngineConfig::default();
+        cfg.enabled = true;
+        cfg.dir = path.path().join("raft-log").to_str().unwrap().to_owned();
+        let engine = Arc::new(RaftLogEngine::open(cfg).unwrap());
+
+        let (tx, rx) = mpsc::channel();
+        let mut runner = Runner::new(Some(tx));
+
+        let region_id = 1;
+        let mut batch = LogBatch::new();
+        let ents: Vec<_> = (1..100)
+            .map(|i| {
+                let mut e = Entry::new();
+                e.set_index(i);
+                e
+            })
+            .collect();
+        batch.add_entries(region_id, &ents);
+        engine.write(&batch, true).unwrap();
+
+        for &(end_idx, expected_collected) in &[(10, 9), (50, 40), (50, 0)] {
+            runner.run(Task {
+                raft_engine: raft_db.clone(),
+                raft_log_engine: Some(engine.clone()),
+                region_id: region_id,
+                start_idx: 0,
+                end_idx: end_idx,
+            });
+            let res = rx.recv_timeout(Duration::from_secs(3)).unwrap();
+            assert_eq!(res.collected, expected_collected);
+            assert_eq!(engine.first_index(region_id), Some(end_idx));
+        }
+    }
+
     fn raft_log_must_not_exist(raft_engine: &DB, region_id: u64, start_idx: u64, end_idx: u64) {
         for i in start_idx..end_idx {
             let k = keys::raft_log_key(region_id, i);
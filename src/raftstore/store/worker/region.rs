@@ -30,8 +30,8 @@ use raftstore::store::engine::{Mutable, Snapshot};
 use raftstore::store::peer_storage::{JOB_STATUS_CANCELLED, JOB_STATUS_CANCELLING,
                                      JOB_STATUS_FAILED, JOB_STATUS_FINISHED, JOB_STATUS_PENDING,
                                      JOB_STATUS_RUNNING};
-use raftstore::store::{self, check_abort, keys, ApplyOptions, Peekable, SnapEntry, SnapKey,
-                       SnapManager};
+use raftstore::store::{self, check_abort, keys, ApplyOptions, Peekable, RaftLogEngine, SnapEntry,
+                       SnapKey, SnapManager};
 use raftstore::store::snap::{Error, Result};
 use storage::CF_RAFT;
 
@@ -94,6 +94,7 @@ impl Display for Task {
 struct SnapContext {
     kv_db: Arc<DB>,
     raft_db: Arc<DB>,
+    raft_log_engine: Option<Arc<RaftLogEngine>>,
     batch_size: usize,
     mgr: SnapManager,
 }
@@ -107,6 +108,7 @@ impl SnapContext {
         let snap = box_try!(store::do_snapshot(
             self.mgr.clone(),
             &raft_db,
+            self.raft_log_engine.as_ref().map(|e| e.as_ref()),
             &raw_snap,
             region_id
         ));
@@ -268,12 +270,19 @@ pub struct Runner {
 }
 
 impl Runner {
-    pub fn new(kv_db: Arc<DB>, raft_db: Arc<DB>, mgr: SnapManager, batch_size: usize) -> Runner {
+    pub fn new(
+        kv_db: Arc<DB>,
+        raft_db: Arc<DB>,
+        raft_log_engine: Option<Arc<RaftLogEngine>>,
+        mgr: SnapManager,
+        batch_size: usize,
+    ) -> Runner {
         Runner {
             pool: ThreadPool::new_with_name(thd_name!("snap generator"), GENERATE_POOL_SIZE),
             ctx: SnapContext {
                 kv_db: kv_db,
                 raft_db: raft_db,
+                raft_log_engine: raft_log_engine,
                 mgr: mgr,
                 batch_size: batch_size,
             },
@@ -126,21 +126,43 @@ impl<'a> MergedIterator<'a> {
 }
 
 /// Split checking task.
-pub struct Task {
-    region: Region,
+pub enum Task {
+    SplitCheck { region: Region },
+    // Update the split thresholds after the config is reloaded.
+    ChangeConfig { region_max_size: u64, split_size: u64 },
 }
 
 impl Task {
     pub fn new(region: &Region) -> Task {
-        Task {
+        Task::SplitCheck {
             region: region.clone(),
         }
     }
+
+    pub fn change_config(region_max_size: u64, split_size: u64) -> Task {
+        Task::ChangeConfig {
+            region_max_size: region_max_size,
+            split_size: split_size,
+        }
+    }
 }
 
 impl Display for Task {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "Split Check Task for {}", self.region.get_id())
+        match *self {
+            Task::SplitCheck { ref region } => {
+                write!(f, "Split Check Task for {}", region.get_id())
+            }
+            Task::ChangeConfig {
+                region_max_size,
+                split_size,
+            } => write!(
+                f,
+                "Change split check config, region max size {}, split size {}",
+                region_max_size,
+                split_size
+            ),
+        }
     }
 }
 
@@ -167,9 +189,8 @@ impl<C> Runner<C> {
     }
 }
 
-impl<C: Sender<Msg>> Runnable<Task> for Runner<C> {
-    fn run(&mut self, task: Task) {
-        let region = &task.region;
+impl<C: Sender<Msg>> Runner<C> {
+    fn check_split(&mut self, region: &Region) {
         let region_id = region.get_id();
 
         // Check approximate size before scanning region.
@@ -249,6 +270,28 @@ impl<C: Sender<Msg>> Runnable<Task> for Runner<C> {
     }
 }
 
+impl<C: Sender<Msg>> Runnable<Task> for Runner<C> {
+    fn run(&mut self, task: Task) {
+        match task {
+            Task::SplitCheck { region } => self.check_split(&region),
+            Task::ChangeConfig {
+                region_max_size,
+                split_size,
+            } => {
+                info!(
+                    "split check config changed, region max size {} -> {}, split size {} -> {}",
+                    self.region_max_size,
+                    region_max_size,
+                    self.split_size,
+                    split_size
+                );
+                self.region_max_size = region_max_size;
+                self.split_size = split_size;
+            }
+        }
+    }
+}
+
 fn new_split_check_result(region_id: u64, epoch: RegionEpoch, split_key: Vec<u8>) -> Msg {
     Msg::SplitCheckResult {
         region_id: region_id,
@@ -352,6 +395,14 @@ mod tests {
             others => panic!("expect split check result, but got {:?}", others),
         }
 
+        // Raise the thresholds so the region doesn't need to split any more.
+        runnable.run(Task::change_config(1024 * 1024, 512 * 1024));
+        runnable.run(Task::new(&region));
+        match rx.try_recv() {
+            Err(TryRecvError::Empty) => {}
+            others => panic!("expect recv empty, but got {:?}", others),
+        }
+
         drop(rx);
         // It should be safe even the result can't be sent back.
         runnable.run(Task::new(&region));
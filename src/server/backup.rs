@@ -0,0 +1,874 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{cmp, error, fs, result, thread};
+use std::fs::File;
+use std::io::Write as IoWrite;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use kvproto::kvrpcpb::{Context, IsolationLevel};
+use kvproto::metapb::Region;
+use kvproto::raft_serverpb::PeerState;
+use rocksdb::{ColumnFamilyOptions, EnvOptions, SstFileWriter};
+use serde_json;
+
+use raftstore::store::util::{check_key_in_region, find_peer};
+use storage::{self, Engine, EngineError, Key, SafePointTracker, ScanMode, Snapshot,
+              SnapshotStore, Statistics, Storage};
+use storage::mvcc::{Error as MvccError, MvccReader, WriteType};
+use storage::txn::Error as TxnError;
+use util::{escape, unescape};
+use util::file::{calc_crc32, get_file_size};
+use util::io_limiter::{self, IOType};
+use util::rocksdb::get_fastest_supported_compression_type;
+use super::debug::Debugger;
+use super::metrics::*;
+
+const BACKUP_META_PREFIX: &'static str = "backupmeta_";
+const BACKUP_META_SUFFIX: &'static str = ".json";
+const SCAN_BATCH_SIZE: usize = 1024;
+// The physical part of a timestamp is the milliseconds since the unix epoch, shifted
+// by the bits of the logical part.
+const TS_LOGICAL_BITS: u64 = 18;
+// A region is backed up again once a lock in it is resolved, or after a backoff if the
+// lock can't be resolved yet. The backoff is doubled every time up to the max.
+const RESOLVE_LOCK_RETRY_LIMIT: usize = 20;
+const RESOLVE_LOCK_BACKOFF_MILLIS: u64 = 100;
+const RESOLVE_LOCK_MAX_BACKOFF_MILLIS: u64 = 10_000;
+const RESOLVE_LOCK_TIMEOUT_SECS: u64 = 60;
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum Error {
+        InvalidArgument(msg: String) {
+            description(msg)
+            display("Invalid Argument {:?}", msg)
+        }
+        KeyIsLocked(primary: Vec<u8>, ts: u64, ttl: u64) {
+            description("key is locked")
+            display("key is locked by txn {} with primary {}", ts, escape(primary))
+        }
+        Other(err: Box<error::Error + Sync + Send>) {
+            from()
+            cause(err.as_ref())
+            description(err.description())
+            display("{:?}", err)
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// An SST file of the key value pairs in a region visible at the backup ts. The keys
+/// are raw, and every value is prefixed by the commit ts of the visible version, see
+/// `encode_backup_value`. Older versions are not backed up.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct BackupFile {
+    pub name: String,
+    pub size: u64,
+    pub checksum: u32,
+    pub kvs: u64,
+}
+
+/// The backup of the part of a region inside the backup range. The keys are raw keys
+/// in escaped form, an empty end key means unbounded. A region without any data has
+/// no file.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct RegionBackup {
+    pub region_id: u64,
+    pub start_key: String,
+    pub end_key: String,
+    pub conf_ver: u64,
+    pub version: u64,
+    pub file: Option<BackupFile>,
+}
+
+/// The metadata of the regions backed up by a store, written as
+/// `backupmeta_<store_id>.json` in the backup directory.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct BackupMeta {
+    pub store_id: u64,
+    pub backup_ts: u64,
+    pub start_key: String,
+    pub end_key: String,
+    pub regions: Vec<RegionBackup>,
+}
+
+/// A backup of the raw key range `[start_key, end_key)` at `backup_ts` into `path`.
+/// The IO is paced by the process-wide IO limiter as `IOType::Backup`.
+#[derive(Clone, Debug, Default)]
+pub struct BackupTask {
+    pub start_key: Vec<u8>,
+    pub end_key: Vec<u8>,
+    pub backup_ts: u64,
+    pub path: PathBuf,
+}
+
+/// `Backup` writes the data of the leader regions on the store to SST files. Each
+/// region is read through a raft snapshot at the backup ts, so backups taken by all
+/// stores at the same ts make a consistent backup of the cluster.
+///
+/// Locks older than the backup ts are resolved by the status of their primary keys.
+/// GC on the store is held back to the backup ts while the backup runs, and a backup
+/// older than a GC safe point is refused.
+pub struct Backup {
+    debugger: Debugger,
+    storage: Storage,
+    engine: Box<Engine>,
+    safe_points: SafePointTracker,
+}
+
+impl Clone for Backup {
+    fn clone(&self) -> Backup {
+        Backup {
+            debugger: self.debugger.clone(),
+            storage: self.storage.clone(),
+            engine: self.engine.clone(),
+            safe_points: self.safe_points.clone(),
+        }
+    }
+}
+
+impl Backup {
+    pub fn new(debugger: Debugger, storage: Storage) -> Backup {
+        Backup {
+            debugger: debugger,
+            engine: storage.get_engine(),
+            safe_points: storage.get_safe_point_tracker(),
+            storage: storage,
+        }
+    }
+
+    /// Backs up the regions overlapping the range. `on_progress` is called for every
+    /// region with its id, its backup, the number of regions processed and the total
+    /// number. Regions not led by the store are skipped with no backup, they are backed
+    /// up by their leaders.
+    pub fn run<F>(&self, task: &BackupTask, mut on_progress: F) -> Result<BackupMeta>
+    where
+        F: FnMut(u64, Option<&RegionBackup>, usize, usize),
+    {
+        let store_id = box_try!(self.debugger.get_store_ident()).get_store_id();
+        box_try!(fs::create_dir_all(&task.path));
+        let meta_path = task.path.join(meta_file_name(store_id));
+        if meta_path.exists() {
+            return Err(Error::InvalidArgument(
+                format!("{} already exists", meta_path.display()),
+            ));
+        }
+        if !task.end_key.is_empty() && task.start_key >= task.end_key {
+            return Err(Error::InvalidArgument(format!(
+                "invalid range [{}, {})",
+                escape(&task.start_key),
+                escape(&task.end_key)
+            )));
+        }
+        // GC is held back until the backup is finished.
+        let _guard = match self.safe_points.register_backup(task.backup_ts) {
+            Ok(guard) => guard,
+            Err(e) => return Err(Error::InvalidArgument(format!("{}", e))),
+        };
+
+        let start = Key::from_raw(&task.start_key).encoded().clone();
+        let end = if task.end_key.is_empty() {
+            vec![]
+        } else {
+            Key::from_raw(&task.end_key).encoded().clone()
+        };
+        let mut regions: Vec<_> = box_try!(self.debugger.region_states())
+            .into_iter()
+            .filter(|s| s.get_state() == PeerState::Normal)
+            .map(|mut s| s.take_region())
+            .filter(|r| {
+                (end.is_empty() || r.get_start_key() < end.as_slice()) &&
+                    (r.get_end_key().is_empty() || start.as_slice() < r.get_end_key())
+            })
+            .collect();
+        regions.sort_by(|a, b| a.get_start_key().cmp(b.get_start_key()));
+
+        info!(
+            "store {} starts backup of [{}, {}) at {} to {}, {} regions",
+            store_id,
+            escape(&task.start_key),
+            escape(&task.end_key),
+            task.backup_ts,
+            task.path.display(),
+            regions.len()
+        );
+        let total = regions.len();
+        let mut done = 0;
+        let mut backups = vec![];
+        let mut not_leader = vec![];
+        for region in regions {
+            match try!(self.backup_region_with_retry(store_id, &region, task, &start, &end)) {
+                Some(backup) => {
+                    BACKUP_REGION_COUNTER.with_label_values(&["success"]).inc();
+                    done += 1;
+                    on_progress(region.get_id(), Some(&backup), done, total);
+                    backups.push(backup);
+                }
+                None => not_leader.push(region.get_id()),
+            }
+        }
+        // The leadership of a skipped region may have moved to this store while other
+        // regions were backed up, and its new leader may have skipped it too, so it's
+        // checked again with its current state.
+        for region_id in not_leader {
+            let backup = match try!(self.current_region(region_id)) {
+                Some(region) => {
+                    try!(self.backup_region_with_retry(store_id, &region, task, &start, &end))
+                }
+                None => None,
+            };
+            done += 1;
+            match backup {
+                Some(backup) => {
+                    BACKUP_REGION_COUNTER.with_label_values(&["success"]).inc();
+                    on_progress(region_id, Some(&backup), done, total);
+                    backups.push(backup);
+                }
+                None => {
+                    BACKUP_REGION_COUNTER.with_label_values(&["not_leader"]).inc();
+                    on_progress(region_id, None, done, total);
+                }
+            }
+        }
+
+        let meta = BackupMeta {
+            store_id: store_id,
+            backup_ts: task.backup_ts,
+            start_key: escape(&task.start_key),
+            end_key: escape(&task.end_key),
+            regions: backups,
+        };
+        let tmp_path = task.path.join(format!("{}.tmp", meta_file_name(store_id)));
+        {
+            let f = box_try!(File::create(&tmp_path));
+            box_try!(serde_json::to_writer_pretty(f, &meta));
+        }
+        box_try!(fs::rename(&tmp_path, &meta_path));
+        info!(
+            "store {} finishes backup at {}, {} regions are backed up",
+            store_id,
+            task.backup_ts,
+            meta.regions.len()
+        );
+        Ok(meta)
+    }
+
+    // Returns the region if it's still a normal region on the store.
+    fn current_region(&self, region_id: u64) -> Result<Option<Region>> {
+        let info = box_try!(self.debugger.region_info(region_id));
+        Ok(info.region_local_state.and_then(|mut state| {
+            if state.get_state() == PeerState::Normal {
+                Some(state.take_region())
+            } else {
+                None
+            }
+        }))
+    }
+
+    // Backs up the region, the locks older than the backup ts are resolved on the way.
+    // Returns `None` if the store is not the leader of the region.
+    fn backup_region_with_retry(
+        &self,
+        store_id: u64,
+        region: &Region,
+        task: &BackupTask,
+        start: &[u8],
+        end: &[u8],
+    ) -> Result<Option<RegionBackup>> {
+        let mut backoff = RESOLVE_LOCK_BACKOFF_MILLIS;
+        for _ in 0..RESOLVE_LOCK_RETRY_LIMIT {
+            let (primary, ts, ttl) = match self.backup_region(store_id, region, task, start, end) {
+                Err(Error::KeyIsLocked(primary, ts, ttl)) => (primary, ts, ttl),
+                res => return res,
+            };
+            if !try!(self.resolve_lock(store_id, region, &primary, ts, ttl)) {
+                thread::sleep(Duration::from_millis(backoff));
+                backoff = cmp::min(backoff * 2, RESOLVE_LOCK_MAX_BACKOFF_MILLIS);
+            }
+        }
+        Err(box_err!(
+            "region {} is still locked after {} retries",
+            region.get_id(),
+            RESOLVE_LOCK_RETRY_LIMIT
+        ))
+    }
+
+    // Resolves the locks of the txn started at `ts` in the region by the status of its
+    // primary key, the same way as the GC worker of TiDB. Returns false if the txn may
+    // be still running or the primary key is not led by the store, in which case the
+    // caller should back off and retry.
+    fn resolve_lock(
+        &self,
+        store_id: u64,
+        region: &Region,
+        primary: &[u8],
+        ts: u64,
+        ttl: u64,
+    ) -> Result<bool> {
+        if !is_lock_expired(ts, ttl) {
+            return Ok(false);
+        }
+        let primary_key = Key::from_raw(primary);
+        let primary_region = box_try!(self.debugger.region_states())
+            .into_iter()
+            .filter(|s| s.get_state() == PeerState::Normal)
+            .map(|mut s| s.take_region())
+            .find(|r| check_key_in_region(primary_key.encoded(), r).is_ok());
+        let primary_ctx = match primary_region.and_then(|r| region_context(&r, store_id)) {
+            Some(ctx) => ctx,
+            None => return Ok(false),
+        };
+
+        // Rolls back the txn if it's not committed.
+        let (tx, rx) = mpsc::channel();
+        box_try!(
+            self.storage
+                .async_cleanup(primary_ctx, primary_key, ts, box move |res| {
+                    let _ = tx.send(res);
+                })
+        );
+        let timeout = Duration::from_secs(RESOLVE_LOCK_TIMEOUT_SECS);
+        let commit_ts = match box_try!(rx.recv_timeout(timeout)) {
+            Ok(()) => None,
+            Err(storage::Error::Txn(TxnError::Mvcc(MvccError::Committed { commit_ts }))) => {
+                Some(commit_ts)
+            }
+            Err(e) => {
+                warn!("failed to check txn {} by primary {}: {:?}", ts, escape(primary), e);
+                return Ok(false);
+            }
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let ctx = region_context(region, store_id).unwrap();
+        box_try!(
+            self.storage
+                .async_resolve_lock(ctx, ts, commit_ts, box move |res| {
+                    let _ = tx.send(res);
+                })
+        );
+        if let Err(e) = box_try!(rx.recv_timeout(timeout)) {
+            warn!("failed to resolve locks of txn {}: {:?}", ts, e);
+            return Ok(false);
+        }
+        info!(
+            "locks of txn {} in region {} are resolved, commit ts {:?}",
+            ts,
+            region.get_id(),
+            commit_ts
+        );
+        Ok(true)
+    }
+
+    // Returns `None` if the store is not the leader of the region.
+    fn backup_region(
+        &self,
+        store_id: u64,
+        region: &Region,
+        task: &BackupTask,
+        start: &[u8],
+        end: &[u8],
+    ) -> Result<Option<RegionBackup>> {
+        let snapshot = match try!(leader_snapshot(self.engine.as_ref(), store_id, region)) {
+            Some(snapshot) => snapshot,
+            None => return Ok(None),
+        };
+
+        // The part of the region inside the backup range.
+        let scan_start = cmp::max(region.get_start_key(), start).to_vec();
+        let scan_end = if end.is_empty() {
+            region.get_end_key().to_vec()
+        } else if region.get_end_key().is_empty() {
+            end.to_vec()
+        } else {
+            cmp::min(region.get_end_key(), end).to_vec()
+        };
+        let upper_bound = if scan_end.is_empty() {
+            None
+        } else {
+            Some(scan_end.clone())
+        };
+
+        let name = format!("{}_{}.sst", store_id, region.get_id());
+        let path = task.path.join(&name);
+        let mut statistics = Statistics::default();
+        let mut write_statistics = Statistics::default();
+        // Looks up the commit ts of the versions returned by the scanner.
+        let mut reader = MvccReader::new(
+            snapshot.as_ref(),
+            &mut write_statistics,
+            Some(ScanMode::Forward),
+            false,
+            None,
+            IsolationLevel::SI,
+        );
+        let store = SnapshotStore::new(snapshot.as_ref(), task.backup_ts, IsolationLevel::SI);
+        let mut scanner = box_try!(store.scanner(
+            ScanMode::Forward,
+            false,
+            upper_bound,
+            &mut statistics
+        ));
+        // The writer is opened with the first pair, an SST file can't be empty.
+        let mut writer = None;
+        let (mut kvs, mut bytes) = (0, 0);
+        let mut key = Key::from_encoded(scan_start.clone());
+        loop {
+            let pairs = box_try!(scanner.scan(key, SCAN_BATCH_SIZE));
+            let finished = pairs.len() < SCAN_BATCH_SIZE;
+            let mut last_key = None;
+            for pair in pairs {
+                let (k, v) = match pair {
+                    Ok(pair) => pair,
+                    Err(TxnError::Mvcc(MvccError::KeyIsLocked {
+                        primary, ts, ttl, ..
+                    })) => return Err(Error::KeyIsLocked(primary, ts, ttl)),
+                    Err(e) => {
+                        return Err(box_err!(
+                            "failed to back up region {}: {:?}",
+                            region.get_id(),
+                            e
+                        ))
+                    }
+                };
+                if writer.is_none() {
+                    let mut opts = ColumnFamilyOptions::new();
+                    opts.compression(get_fastest_supported_compression_type());
+                    let mut w = SstFileWriter::new(EnvOptions::new(), opts);
+                    box_try!(w.open(path.to_str().unwrap()));
+                    writer = Some(w);
+                }
+                let commit_ts = try!(visible_commit_ts(&mut reader, &k, task.backup_ts));
+                let v = encode_backup_value(commit_ts, &v);
+                io_limiter::request_io(IOType::Backup, k.len() + v.len());
+                box_try!(writer.as_mut().unwrap().put(&k, &v));
+                kvs += 1;
+                bytes += k.len() + v.len();
+                last_key = Some(k);
+            }
+            match last_key {
+                Some(ref k) if !finished => key = Key::from_raw(k).append_ts(0),
+                _ => break,
+            }
+        }
+        BACKUP_BYTES_COUNTER.inc_by(bytes as f64).unwrap();
+
+        let file = match writer {
+            Some(mut writer) => {
+                box_try!(writer.finish());
+                Some(BackupFile {
+                    name: name,
+                    size: box_try!(get_file_size(&path)),
+                    checksum: box_try!(calc_crc32(&path)),
+                    kvs: kvs,
+                })
+            }
+            None => None,
+        };
+        Ok(Some(RegionBackup {
+            region_id: region.get_id(),
+            start_key: escape(&try!(raw_key(&scan_start))),
+            end_key: escape(&try!(raw_key(&scan_end))),
+            conf_ver: region.get_region_epoch().get_conf_ver(),
+            version: region.get_region_epoch().get_version(),
+            file: file,
+        }))
+    }
+}
+
+// Returns the context of requests to the peer of the store in the region, `None` if the
+// store has no peer in it.
+fn region_context(region: &Region, store_id: u64) -> Option<Context> {
+    let peer = match find_peer(region, store_id) {
+        Some(peer) => peer,
+        None => return None,
+    };
+    let mut ctx = Context::new();
+    ctx.set_peer(peer.clone());
+    ctx.set_region_id(region.get_id());
+    ctx.set_region_epoch(region.get_region_epoch().clone());
+    Some(ctx)
+}
+
+// Whether the lock has outlived its ttl in milliseconds.
+fn is_lock_expired(ts: u64, ttl: u64) -> bool {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let now_millis = now.as_secs() * 1000 + now.subsec_nanos() as u64 / 1_000_000;
+    (ts >> TS_LOGICAL_BITS) + ttl <= now_millis
+}
+
+/// Takes a snapshot of the region through raft, returns `None` if the store is not the
+/// leader of the region.
+pub fn leader_snapshot(
+    engine: &Engine,
+    store_id: u64,
+    region: &Region,
+) -> Result<Option<Box<Snapshot>>> {
+    let ctx = match region_context(region, store_id) {
+        Some(ctx) => ctx,
+        None => return Ok(None),
+    };
+    match engine.snapshot(&ctx) {
+        Ok(snapshot) => Ok(Some(snapshot)),
+        Err(EngineError::Request(ref e)) if e.has_not_leader() => Ok(None),
+        Err(e) => Err(box_err!(
+            "failed to get snapshot of region {}: {:?}",
+            region.get_id(),
+            e
+        )),
+    }
+}
+
+// Returns the commit ts of the put visible at `ts`, rollbacks and locks are skipped.
+fn visible_commit_ts(reader: &mut MvccReader, raw_key: &[u8], mut ts: u64) -> Result<u64> {
+    let key = Key::from_raw(raw_key);
+    loop {
+        match box_try!(reader.seek_write(&key, ts)) {
+            Some((commit_ts, ref write)) if write.write_type == WriteType::Put => {
+                return Ok(commit_ts)
+            }
+            Some((commit_ts, _)) if commit_ts > 0 => ts = commit_ts - 1,
+            _ => return Err(box_err!("no visible version of {} at {}", escape(raw_key), ts)),
+        }
+    }
+}
+
+/// Encodes a backed up value as the commit ts in big endian followed by the value.
+pub fn encode_backup_value(commit_ts: u64, value: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(8 + value.len());
+    res.write_u64::<BigEndian>(commit_ts).unwrap();
+    res.write_all(value).unwrap();
+    res
+}
+
+/// Decodes a value encoded by `encode_backup_value` into the commit ts and the value.
+pub fn decode_backup_value(mut data: &[u8]) -> Result<(u64, &[u8])> {
+    let commit_ts = box_try!(data.read_u64::<BigEndian>());
+    Ok((commit_ts, data))
+}
+
+fn meta_file_name(store_id: u64) -> String {
+    format!("{}{}{}", BACKUP_META_PREFIX, store_id, BACKUP_META_SUFFIX)
+}
+
+/// Decodes an encoded key of a region boundary, the empty key is left as it is.
+pub fn raw_key(encoded: &[u8]) -> Result<Vec<u8>> {
+    if encoded.is_empty() {
+        return Ok(vec![]);
+    }
+    Ok(box_try!(Key::from_encoded(encoded.to_vec()).raw()))
+}
+
+/// Reads the metadata written by all stores in the backup directory.
+pub fn load_backup_metas(dir: &Path) -> Result<Vec<BackupMeta>> {
+    let mut metas = vec![];
+    for entry in box_try!(fs::read_dir(dir)) {
+        let path = box_try!(entry).path();
+        let is_meta = path.file_name().and_then(|n| n.to_str()).map_or(false, |n| {
+            n.starts_with(BACKUP_META_PREFIX) && n.ends_with(BACKUP_META_SUFFIX)
+        });
+        if !is_meta {
+            continue;
+        }
+        let f = box_try!(File::open(&path));
+        let meta: BackupMeta = box_try!(serde_json::from_reader(f));
+        metas.push(meta);
+    }
+    metas.sort_by_key(|m| m.store_id);
+    Ok(metas)
+}
+
+/// Checks the backup in the directory, returns the problems found: stores backed up
+/// different ranges or at different timestamps, files are missing or corrupted, or
+/// parts of the range are not backed up by any store.
+pub fn check_backup(dir: &Path) -> Result<Vec<String>> {
+    let metas = try!(load_backup_metas(dir));
+    let first = match metas.first() {
+        Some(meta) => meta,
+        None => return Err(Error::InvalidArgument(format!("no backup in {}", dir.display()))),
+    };
+    let mut errors = vec![];
+    let mut ranges = vec![];
+    for meta in &metas {
+        if meta.backup_ts != first.backup_ts || meta.start_key != first.start_key ||
+            meta.end_key != first.end_key
+        {
+            errors.push(format!(
+                "store {} backed up [{}, {}) at {}, but store {} backed up [{}, {}) at {}",
+                meta.store_id,
+                meta.start_key,
+                meta.end_key,
+                meta.backup_ts,
+                first.store_id,
+                first.start_key,
+                first.end_key,
+                first.backup_ts
+            ));
+        }
+        for region in &meta.regions {
+            ranges.push((region.start_key.clone(), region.end_key.clone()));
+            let file = match region.file {
+                Some(ref file) => file,
+                None => continue,
+            };
+            let path = dir.join(&file.name);
+            let res = get_file_size(&path).and_then(|size| Ok((size, try!(calc_crc32(&path)))));
+            match res {
+                Ok((size, checksum)) => if size != file.size || checksum != file.checksum {
+                    errors.push(format!(
+                        "{} is corrupted, expect size {} and checksum {}, got {} and {}",
+                        file.name,
+                        file.size,
+                        file.checksum,
+                        size,
+                        checksum
+                    ));
+                },
+                Err(e) => errors.push(format!("failed to read {}: {:?}", file.name, e)),
+            }
+        }
+    }
+
+    let mut ranges: Vec<_> = ranges
+        .iter()
+        .map(|&(ref start, ref end)| (unescape(start), unescape(end)))
+        .collect();
+    ranges.sort();
+    let end = unescape(&first.end_key);
+    // The start of the range not covered yet, `None` if everything is covered.
+    let mut uncovered = Some(unescape(&first.start_key));
+    for (s, e) in ranges {
+        let from = match uncovered {
+            Some(ref from) => from.clone(),
+            None => break,
+        };
+        if s > from {
+            errors.push(format!("[{}, {}) is not backed up", escape(&from), escape(&s)));
+        }
+        if e.is_empty() {
+            uncovered = None;
+        } else if e > from {
+            uncovered = Some(e);
+        }
+    }
+    if let Some(from) = uncovered {
+        if end.is_empty() || from < end {
+            errors.push(format!("[{}, {}) is not backed up", escape(&from), escape(&end)));
+        }
+    }
+    Ok(errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use kvproto::kvrpcpb::Context;
+    use rocksdb::IngestExternalFileOptions;
+    use tempdir::TempDir;
+
+    use raftstore::store::{keys, Engines, Mutable};
+    use raftstore::store::util::new_peer;
+    use storage::{Config as StorageConfig, Engine, Key, Storage, ALL_CFS, CF_DEFAULT, CF_LOCK,
+                  CF_RAFT, CF_WRITE};
+    use storage::mvcc::{Lock, LockType, Write, WriteType};
+    use util::rocksdb::{get_cf_handle, new_engine};
+    use kvproto::metapb::Region;
+    use kvproto::raft_serverpb::{RegionLocalState, StoreIdent};
+    use super::*;
+
+    fn new_region(id: u64, start: &[u8], end: &[u8]) -> Region {
+        let mut region = Region::new();
+        region.set_id(id);
+        if !start.is_empty() {
+            region.set_start_key(Key::from_raw(start).encoded().clone());
+        }
+        if !end.is_empty() {
+            region.set_end_key(Key::from_raw(end).encoded().clone());
+        }
+        region.mut_peers().push(new_peer(1, id + 10));
+        region.mut_region_epoch().set_version(1);
+        region.mut_region_epoch().set_conf_ver(1);
+        region
+    }
+
+    fn must_put(engine: &Engine, key: &[u8], value: &[u8], commit_ts: u64) {
+        let write = Write::new(WriteType::Put, commit_ts - 1, Some(value.to_vec()));
+        let key = Key::from_raw(key).append_ts(commit_ts);
+        engine
+            .put_cf(&Context::new(), CF_WRITE, key, write.to_bytes())
+            .unwrap();
+    }
+
+    fn must_lock(engine: &Engine, key: &[u8], primary: &[u8], ts: u64, value: &[u8]) {
+        let lock = Lock::new(LockType::Put, primary.to_vec(), ts, 0, Some(value.to_vec()));
+        engine
+            .put_cf(&Context::new(), CF_LOCK, Key::from_raw(key), lock.to_bytes())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_backup() {
+        let tmp = TempDir::new("test_backup_meta").unwrap();
+        let db = Arc::new(new_engine(tmp.path().to_str().unwrap(), ALL_CFS).unwrap());
+        let debugger = Debugger::new(Engines::new(db.clone(), db.clone()));
+        let mut ident = StoreIdent::new();
+        ident.set_store_id(1);
+        db.put_msg(&keys::store_ident_key(), &ident).unwrap();
+        for region in &[new_region(1, b"", b"m"), new_region(2, b"m", b"w")] {
+            debugger.recreate_region(region.clone()).unwrap();
+        }
+        // Region 3 is led by store 2.
+        let mut region = new_region(3, b"w", b"");
+        region.mut_peers()[0] = new_peer(2, 13);
+        let mut state = RegionLocalState::new();
+        state.set_region(region);
+        let handle = get_cf_handle(&db, CF_RAFT).unwrap();
+        db.put_msg_cf(handle, &keys::region_state_key(3), &state)
+            .unwrap();
+
+        let cfg = StorageConfig::default();
+        let mut storage = Storage::new(&cfg).unwrap();
+        storage.start(&cfg).unwrap();
+        let engine = storage.get_engine();
+        must_put(engine.as_ref(), b"a1", b"v1", 11);
+        must_put(engine.as_ref(), b"b1", b"v1", 30);
+        must_put(engine.as_ref(), b"n1", b"v1", 11);
+        must_put(engine.as_ref(), b"x1", b"v1", 11);
+        let backup = Backup::new(debugger, storage.clone());
+
+        let dir = TempDir::new("test_backup").unwrap();
+        let task = BackupTask {
+            start_key: b"a".to_vec(),
+            end_key: b"x".to_vec(),
+            backup_ts: 20,
+            path: dir.path().to_path_buf(),
+        };
+        let mut progress = vec![];
+        let meta = backup
+            .run(&task, |id, r, done, total| {
+                progress.push((id, r.is_some(), done, total))
+            })
+            .unwrap();
+        assert_eq!(
+            progress,
+            vec![(1, true, 1, 3), (2, true, 2, 3), (3, false, 3, 3)]
+        );
+        let ranges: Vec<_> = meta.regions
+            .iter()
+            .map(|r| (r.start_key.as_str(), r.end_key.as_str()))
+            .collect();
+        assert_eq!(ranges, vec![("a", "m"), ("m", "w")]);
+        // b1 is committed after the backup ts, and x1 is out of the range.
+        let kvs: Vec<_> = meta.regions
+            .iter()
+            .map(|r| r.file.as_ref().unwrap().kvs)
+            .collect();
+        assert_eq!(kvs, vec![1, 1]);
+        // The commit ts is kept with the value.
+        let tmp = TempDir::new("test_backup_read").unwrap();
+        let read_db = new_engine(tmp.path().to_str().unwrap(), &[CF_DEFAULT]).unwrap();
+        let path = dir.path().join(&meta.regions[0].file.as_ref().unwrap().name);
+        read_db
+            .ingest_external_file(&IngestExternalFileOptions::new(), &[path.to_str().unwrap()])
+            .unwrap();
+        let value = read_db.get(b"a1").unwrap().unwrap();
+        assert_eq!(decode_backup_value(&value).unwrap(), (11, &b"v1"[..]));
+        assert_eq!(load_backup_metas(dir.path()).unwrap(), vec![meta]);
+        // Region 3 is left to its leader.
+        assert_eq!(
+            check_backup(dir.path()).unwrap(),
+            vec!["[w, x) is not backed up".to_owned()]
+        );
+        // The metadata is not overwritten.
+        assert!(backup.run(&task, |_, _, _, _| {}).is_err());
+
+        // The expired locks before the backup ts are resolved. The txn of a2 is rolled
+        // back as its primary a1 isn't committed, the txn of a3 is committed at 17.
+        must_lock(engine.as_ref(), b"a2", b"a1", 15, b"v2");
+        must_lock(engine.as_ref(), b"a3", b"a4", 16, b"v3");
+        must_put(engine.as_ref(), b"a4", b"v4", 17);
+        let dir = TempDir::new("test_backup_locked").unwrap();
+        let task = BackupTask {
+            path: dir.path().to_path_buf(),
+            ..task
+        };
+        let meta = backup.run(&task, |_, _, _, _| {}).unwrap();
+        assert_eq!(meta.regions[0].file.as_ref().unwrap().kvs, 3);
+        let snapshot = engine.snapshot(&Context::new()).unwrap();
+        for key in &[b"a2", b"a3"] {
+            let lock = snapshot.get_cf(CF_LOCK, &Key::from_raw(*key)).unwrap();
+            assert!(lock.is_none());
+        }
+
+        // A backup older than the GC safe point is refused.
+        storage.get_safe_point_tracker().gc_safe_point(30);
+        let dir = TempDir::new("test_backup_gc").unwrap();
+        let task = BackupTask {
+            path: dir.path().to_path_buf(),
+            ..task
+        };
+        assert!(backup.run(&task, |_, _, _, _| {}).is_err());
+    }
+
+    #[test]
+    fn test_check_backup() {
+        let dir = TempDir::new("test_check_backup").unwrap();
+        let region = |id, start: &str, end: &str| {
+            RegionBackup {
+                region_id: id,
+                start_key: start.to_owned(),
+                end_key: end.to_owned(),
+                ..Default::default()
+            }
+        };
+        let metas = vec![
+            BackupMeta {
+                store_id: 1,
+                backup_ts: 10,
+                start_key: "a".to_owned(),
+                end_key: "".to_owned(),
+                regions: vec![region(1, "a", "c"), region(3, "e", "g")],
+            },
+            BackupMeta {
+                store_id: 2,
+                backup_ts: 10,
+                start_key: "a".to_owned(),
+                end_key: "".to_owned(),
+                regions: vec![region(2, "b", "d")],
+            },
+        ];
+        for meta in &metas {
+            let f = File::create(dir.path().join(meta_file_name(meta.store_id))).unwrap();
+            serde_json::to_writer(f, meta).unwrap();
+        }
+        assert_eq!(
+            check_backup(dir.path()).unwrap(),
+            vec![
+                "[d, e) is not backed up".to_owned(),
+                "[g, ) is not backed up".to_owned(),
+            ]
+        );
+    }
+}
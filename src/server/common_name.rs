@@ -0,0 +1,120 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use futures::Future;
+use grpc::{ClientStreamingSink, RequestStream, RpcContext, RpcStatus, RpcStatusCode,
+           ServerStreamingSink, UnarySink};
+use kvproto::{coprocessor, debugpb, debugpb_grpc, import_sstpb, import_sstpb_grpc, kvrpcpb,
+              raft_serverpb, tikvpb_grpc};
+
+use util::security::SecurityManager;
+
+/// `CheckCommonName` wraps a gRPC service and fails every call whose client
+/// certificate doesn't have an allowed common name before it reaches the service.
+#[derive(Clone)]
+pub struct CheckCommonName<S> {
+    inner: S,
+    security_mgr: Arc<SecurityManager>,
+}
+
+impl<S> CheckCommonName<S> {
+    pub fn new(inner: S, security_mgr: Arc<SecurityManager>) -> CheckCommonName<S> {
+        CheckCommonName {
+            inner: inner,
+            security_mgr: security_mgr,
+        }
+    }
+}
+
+// Every method of the trait must be listed, so a new RPC can't skip the check.
+macro_rules! impl_check_common_name {
+    ($service:path, $($method:ident($req:ty, $sink:ty);)+) => {
+        impl<S: $service> $service for CheckCommonName<S> {
+            $(
+                fn $method(&self, ctx: RpcContext, req: $req, sink: $sink) {
+                    if !self.security_mgr.check_common_name(&ctx) {
+                        let status = RpcStatus::new(
+                            RpcStatusCode::Unauthenticated,
+                            Some("common name of the client certificate is not allowed"
+                                .to_owned()),
+                        );
+                        ctx.spawn(sink.fail(status).map_err(|_| ()));
+                        return;
+                    }
+                    self.inner.$method(ctx, req, sink)
+                }
+            )+
+        }
+    }
+}
+
+impl_check_common_name!(tikvpb_grpc::Tikv,
+    kv_get(kvrpcpb::GetRequest, UnarySink<kvrpcpb::GetResponse>);
+    kv_scan(kvrpcpb::ScanRequest, UnarySink<kvrpcpb::ScanResponse>);
+    kv_prewrite(kvrpcpb::PrewriteRequest, UnarySink<kvrpcpb::PrewriteResponse>);
+    kv_commit(kvrpcpb::CommitRequest, UnarySink<kvrpcpb::CommitResponse>);
+    kv_import(kvrpcpb::ImportRequest, UnarySink<kvrpcpb::ImportResponse>);
+    kv_cleanup(kvrpcpb::CleanupRequest, UnarySink<kvrpcpb::CleanupResponse>);
+    kv_batch_get(kvrpcpb::BatchGetRequest, UnarySink<kvrpcpb::BatchGetResponse>);
+    kv_batch_rollback(
+        kvrpcpb::BatchRollbackRequest,
+        UnarySink<kvrpcpb::BatchRollbackResponse>
+    );
+    kv_scan_lock(kvrpcpb::ScanLockRequest, UnarySink<kvrpcpb::ScanLockResponse>);
+    kv_resolve_lock(kvrpcpb::ResolveLockRequest, UnarySink<kvrpcpb::ResolveLockResponse>);
+    kv_gc(kvrpcpb::GCRequest, UnarySink<kvrpcpb::GCResponse>);
+    kv_delete_range(kvrpcpb::DeleteRangeRequest, UnarySink<kvrpcpb::DeleteRangeResponse>);
+    raw_get(kvrpcpb::RawGetRequest, UnarySink<kvrpcpb::RawGetResponse>);
+    raw_scan(kvrpcpb::RawScanRequest, UnarySink<kvrpcpb::RawScanResponse>);
+    raw_put(kvrpcpb::RawPutRequest, UnarySink<kvrpcpb::RawPutResponse>);
+    raw_delete(kvrpcpb::RawDeleteRequest, UnarySink<kvrpcpb::RawDeleteResponse>);
+    coprocessor(coprocessor::Request, UnarySink<coprocessor::Response>);
+    raft(
+        RequestStream<raft_serverpb::RaftMessage>,
+        ClientStreamingSink<raft_serverpb::Done>
+    );
+    snapshot(
+        RequestStream<raft_serverpb::SnapshotChunk>,
+        ClientStreamingSink<raft_serverpb::Done>
+    );
+    mvcc_get_by_key(kvrpcpb::MvccGetByKeyRequest, UnarySink<kvrpcpb::MvccGetByKeyResponse>);
+    mvcc_get_by_start_ts(
+        kvrpcpb::MvccGetByStartTsRequest,
+        UnarySink<kvrpcpb::MvccGetByStartTsResponse>
+    );
+);
+
+impl_check_common_name!(debugpb_grpc::Debug,
+    get(debugpb::GetRequest, UnarySink<debugpb::GetResponse>);
+    raft_log(debugpb::RaftLogRequest, UnarySink<debugpb::RaftLogResponse>);
+    region_info(debugpb::RegionInfoRequest, UnarySink<debugpb::RegionInfoResponse>);
+    region_size(debugpb::RegionSizeRequest, UnarySink<debugpb::RegionSizeResponse>);
+    compact(debugpb::CompactRequest, UnarySink<debugpb::CompactResponse>);
+    get_region_properties(
+        debugpb::GetRegionPropertiesRequest,
+        UnarySink<debugpb::GetRegionPropertiesResponse>
+    );
+    scan_mvcc(debugpb::ScanMvccRequest, ServerStreamingSink<debugpb::ScanMvccResponse>);
+    backup(debugpb::BackupRequest, ServerStreamingSink<debugpb::BackupResponse>);
+    restore(debugpb::RestoreRequest, ServerStreamingSink<debugpb::RestoreResponse>);
+);
+
+impl_check_common_name!(import_sstpb_grpc::ImportSst,
+    upload(
+        RequestStream<import_sstpb::UploadRequest>,
+        ClientStreamingSink<import_sstpb::UploadResponse>
+    );
+    ingest(import_sstpb::IngestRequest, UnarySink<import_sstpb::IngestResponse>);
+);
@@ -16,7 +16,7 @@ use std::ascii::AsciiExt;
 use sys_info;
 
 use util::collections::HashMap;
-use util::config::{self, ReadableSize};
+use util::config::{self, ReadableDuration, ReadableSize};
 
 use super::Result;
 
@@ -26,12 +26,16 @@ pub use storage::Config as StorageConfig;
 pub const DEFAULT_CLUSTER_ID: u64 = 0;
 pub const DEFAULT_LISTENING_ADDR: &'static str = "127.0.0.1:20160";
 const DEFAULT_ADVERTISE_LISTENING_ADDR: &'static str = "";
+const DEFAULT_STATUS_ADDR: &'static str = "";
 const DEFAULT_NOTIFY_CAPACITY: usize = 40960;
 const DEFAULT_GRPC_CONCURRENCY: usize = 4;
 const DEFAULT_GRPC_CONCURRENT_STREAM: usize = 1024;
 const DEFAULT_GRPC_RAFT_CONN_NUM: usize = 10;
 const DEFAULT_GRPC_STREAM_INITIAL_WINDOW_SIZE: u64 = 2 * 1024 * 1024;
 const DEFAULT_MESSAGES_PER_TICK: usize = 4096;
+const DEFAULT_CONCURRENT_SEND_SNAP_LIMIT: usize = 32;
+const DEFAULT_CONCURRENT_RECV_SNAP_LIMIT: usize = 32;
+const DEFAULT_END_POINT_SLOW_LOG_THRESHOLD_SECS: u64 = 1;
 
 // Assume a request can be finished in 1ms, a request at position x will wait about
 // 0.001 * x secs to be actual started. A server-is-busy error will trigger 2 seconds
@@ -52,6 +56,8 @@ pub struct Config {
     // Server advertise listening address for outer communication.
     // If not set, we will use listening address instead.
     pub advertise_addr: String,
+    // HTTP status server listening address, empty means disabled.
+    pub status_addr: String,
     pub notify_capacity: usize,
     pub messages_per_tick: usize,
     pub grpc_concurrency: usize,
@@ -60,6 +66,19 @@ pub struct Config {
     pub grpc_stream_initial_window_size: ReadableSize,
     pub end_point_concurrency: usize,
     pub end_point_max_tasks: usize,
+    // Coprocessor requests taking longer than this are written to the slow log.
+    pub end_point_slow_log_threshold: ReadableDuration,
+    // Byte rate limits of sending and receiving snapshots, 0 means no limit.
+    pub snap_max_send_bytes_per_sec: ReadableSize,
+    pub snap_max_recv_bytes_per_sec: ReadableSize,
+    pub concurrent_send_snap_limit: usize,
+    pub concurrent_recv_snap_limit: usize,
+    // Compress the plain cf files of snapshots, all stores in the cluster must
+    // support it before it's turned on.
+    pub snap_compression: bool,
+    // Build CF_LOCK of snapshots as an sst file, all stores in the cluster must
+    // support it before it's turned on.
+    pub snap_sst_lock_cf: bool,
     // Server labels to specify some attributes about this server.
     #[serde(with = "config::order_map_serde")]
     pub labels: HashMap<String, String>,
@@ -78,6 +97,7 @@ impl Default for Config {
             addr: DEFAULT_LISTENING_ADDR.to_owned(),
             labels: HashMap::default(),
             advertise_addr: DEFAULT_ADVERTISE_LISTENING_ADDR.to_owned(),
+            status_addr: DEFAULT_STATUS_ADDR.to_owned(),
             notify_capacity: DEFAULT_NOTIFY_CAPACITY,
             messages_per_tick: DEFAULT_MESSAGES_PER_TICK,
             grpc_concurrency: DEFAULT_GRPC_CONCURRENCY,
@@ -86,6 +106,15 @@ impl Default for Config {
             grpc_stream_initial_window_size: ReadableSize(DEFAULT_GRPC_STREAM_INITIAL_WINDOW_SIZE),
             end_point_concurrency: concurrency,
             end_point_max_tasks: DEFAULT_MAX_RUNNING_TASK_COUNT,
+            end_point_slow_log_threshold: ReadableDuration::secs(
+                DEFAULT_END_POINT_SLOW_LOG_THRESHOLD_SECS,
+            ),
+            snap_max_send_bytes_per_sec: ReadableSize(0),
+            snap_max_recv_bytes_per_sec: ReadableSize(0),
+            concurrent_send_snap_limit: DEFAULT_CONCURRENT_SEND_SNAP_LIMIT,
+            concurrent_recv_snap_limit: DEFAULT_CONCURRENT_RECV_SNAP_LIMIT,
+            snap_compression: false,
+            snap_sst_lock_cf: false,
         }
     }
 }
@@ -106,6 +135,10 @@ impl Config {
             ));
         }
 
+        if !self.status_addr.is_empty() {
+            box_try!(config::check_addr(&self.status_addr));
+        }
+
         if self.end_point_concurrency == 0 {
             return Err(box_err!("server.end-point-concurrency should not be 0."));
         }
@@ -114,6 +147,13 @@ impl Config {
             return Err(box_err!("server.end-point-max-tasks should not be 0."));
         }
 
+        if self.concurrent_send_snap_limit == 0 || self.concurrent_recv_snap_limit == 0 {
+            return Err(box_err!(
+                "server.concurrent-send-snap-limit and server.concurrent-recv-snap-limit \
+                 should not be 0."
+            ));
+        }
+
         for (k, v) in &self.labels {
             try!(validate_label(k, "key"));
             try!(validate_label(v, "value"));
@@ -173,6 +213,14 @@ mod tests {
         invalid_cfg.end_point_max_tasks = 0;
         assert!(invalid_cfg.validate().is_err());
 
+        let mut invalid_cfg = cfg.clone();
+        invalid_cfg.concurrent_send_snap_limit = 0;
+        assert!(invalid_cfg.validate().is_err());
+
+        let mut invalid_cfg = cfg.clone();
+        invalid_cfg.status_addr = "127.0.0.1".to_owned();
+        assert!(invalid_cfg.validate().is_err());
+
         invalid_cfg = Config::default();
         invalid_cfg.addr = "0.0.0.0:1000".to_owned();
         assert!(invalid_cfg.validate().is_err());
@@ -0,0 +1,1582 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{cmp, error, result};
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::Arc;
+
+use protobuf::{self, RepeatedField};
+use rocksdb::{CompactOptions, DBIterator, EnvOptions, IngestExternalFileOptions, SeekKey,
+              SstFileWriter, WriteBatch, DB};
+use serde_json;
+use kvproto::debugpb::DB as DBType;
+use kvproto::eraftpb::Entry;
+use kvproto::metapb::{Region, RegionEpoch};
+use kvproto::raft_serverpb::{PeerState, RaftApplyState, RaftLocalState, RegionLocalState,
+                             StoreIdent};
+
+use raftstore::store::{compact_range, keys, write_initial_apply_state, write_initial_raft_state,
+                       write_peer_state, Engines, Iterable, Mutable, Peekable};
+use raftstore::store::util::{delete_all_in_range, find_peer, get_region_approximate_size,
+                             get_region_properties_cf, is_epoch_stale};
+use raftstore::store::engine::{IterOption, Snapshot};
+use storage::{Key, MvccInfo, ALL_CFS, CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE, DATA_CFS,
+              LARGE_CFS};
+use storage::mvcc::{Lock, Write, WriteType};
+use util::{escape, unescape};
+use util::file::{calc_crc32, delete_file_if_exist, get_file_size};
+use util::collections::HashSet;
+use util::properties::{MvccProperties, SizeProperties};
+use util::rocksdb::get_cf_handle;
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum Error {
+        InvalidArgument(msg: String) {
+            description(msg)
+            display("Invalid Argument {:?}", msg)
+        }
+        NotFound(msg: String) {
+            description(msg)
+            display("Not Found {:?}", msg)
+        }
+        Other(err: Box<error::Error + Sync + Send>) {
+            from()
+            cause(err.as_ref())
+            description(err.description())
+            display("{:?}", err)
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// The raft states of a region, `None` if the state is not found.
+#[derive(Debug, Default, PartialEq)]
+pub struct RegionInfo {
+    pub raft_local_state: Option<RaftLocalState>,
+    pub raft_apply_state: Option<RaftApplyState>,
+    pub region_local_state: Option<RegionLocalState>,
+}
+
+/// The name of the manifest file in the directory of a region dump.
+pub const REGION_DUMP_MANIFEST: &'static str = "manifest.json";
+
+/// An SST file of a column family in a region dump. The smallest and largest keys are
+/// escaped keys without the data prefix.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct RegionDumpFile {
+    pub cf: String,
+    pub name: String,
+    pub size: u64,
+    pub checksum: u32,
+    pub kvs: u64,
+    pub smallest_key: String,
+    pub largest_key: String,
+}
+
+/// The manifest of a region dump. The keys are escaped, and column families without
+/// any data have no files.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct RegionDumpManifest {
+    pub region_id: u64,
+    pub start_key: String,
+    pub end_key: String,
+    pub conf_ver: u64,
+    pub version: u64,
+    pub files: Vec<RegionDumpFile>,
+}
+
+/// The raft log entries of a region in `[from, to]`, with the states of the raft log.
+#[derive(Debug, Default)]
+pub struct RaftLogs {
+    pub from: u64,
+    pub to: u64,
+    pub truncated_index: u64,
+    pub truncated_term: u64,
+    pub last_index: u64,
+    pub entries: Vec<Entry>,
+}
+
+impl RaftLogs {
+    /// Creates `RaftLogs` without entries. `from` 0 means the first index after the
+    /// truncated index, and `to` 0 means the last index of the raft state.
+    pub fn new(
+        raft_state: &RaftLocalState,
+        apply_state: &RaftApplyState,
+        from: u64,
+        to: u64,
+    ) -> Result<RaftLogs> {
+        let truncated = apply_state.get_truncated_state();
+        let from = if from == 0 {
+            truncated.get_index() + 1
+        } else {
+            from
+        };
+        let to = if to == 0 {
+            raft_state.get_last_index()
+        } else {
+            to
+        };
+        if from > to {
+            return Err(Error::InvalidArgument(format!("invalid range [{}, {}]", from, to)));
+        }
+        Ok(RaftLogs {
+            from: from,
+            to: to,
+            truncated_index: truncated.get_index(),
+            truncated_term: truncated.get_term(),
+            last_index: raft_state.get_last_index(),
+            entries: vec![],
+        })
+    }
+
+    /// Checks that the entries are contiguous and their terms never decrease, returns
+    /// the problems found. Entries before the truncated index may be left until they are
+    /// garbage collected, so they are not checked.
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = vec![];
+        let (mut expect_index, mut last_term) = (self.from, 0);
+        if self.from <= self.truncated_index + 1 {
+            expect_index = self.truncated_index + 1;
+            last_term = self.truncated_term;
+        }
+        for entry in &self.entries {
+            let (index, term) = (entry.get_index(), entry.get_term());
+            if index <= self.truncated_index {
+                continue;
+            }
+            if index > self.last_index {
+                errors.push(format!("entry {} is after the last index {}", index, self.last_index));
+            }
+            if index != expect_index {
+                errors.push(format!("entries in [{}, {}) are missing", expect_index, index));
+            }
+            if term < last_term {
+                errors.push(format!(
+                    "term {} of entry {} is less than the previous term {}",
+                    term,
+                    index,
+                    last_term
+                ));
+            }
+            expect_index = index + 1;
+            last_term = term;
+        }
+        let end = cmp::min(self.to, self.last_index);
+        if expect_index <= end {
+            errors.push(format!("entries in [{}, {}] are missing", expect_index, end));
+        }
+        if self.last_index < self.truncated_index {
+            errors.push(format!(
+                "last index {} is less than truncated index {}",
+                self.last_index,
+                self.truncated_index
+            ));
+        }
+        errors
+    }
+}
+
+/// `Debugger` reads the engines of a store for debugging, every call reads from a new
+/// snapshot, so it works on a running store.
+#[derive(Clone)]
+pub struct Debugger {
+    engines: Engines,
+}
+
+impl Debugger {
+    pub fn new(engines: Engines) -> Debugger {
+        Debugger { engines: engines }
+    }
+
+    pub fn get_engines(&self) -> &Engines {
+        &self.engines
+    }
+
+    fn get_db(&self, db: DBType) -> Result<&Arc<DB>> {
+        match db {
+            DBType::KV => Ok(&self.engines.kv_engine),
+            DBType::RAFT => Ok(&self.engines.raft_engine),
+            _ => Err(Error::InvalidArgument("invalid db type".to_owned())),
+        }
+    }
+
+    /// Gets the value of a raw key in the given column family.
+    pub fn get(&self, db: DBType, cf: &str, key: &[u8]) -> Result<Vec<u8>> {
+        let db = try!(self.get_db(db));
+        if db.cf_handle(cf).is_none() {
+            return Err(Error::InvalidArgument(format!("invalid cf {:?}", cf)));
+        }
+        let snap = Snapshot::new(db.clone());
+        match box_try!(snap.get_value_cf(cf, key)) {
+            Some(v) => Ok(v.to_vec()),
+            None => Err(Error::NotFound(format!("value for key {:?} in cf {:?}", key, cf))),
+        }
+    }
+
+    pub fn raft_log(&self, region_id: u64, log_index: u64) -> Result<Entry> {
+        let entry = match self.engines.raft_log_engine {
+            Some(ref engine) => box_try!(engine.get_entry(region_id, log_index)),
+            None => {
+                let key = keys::raft_log_key(region_id, log_index);
+                let snap = Snapshot::new(self.engines.raft_engine.clone());
+                box_try!(snap.get_msg(&key))
+            }
+        };
+        match entry {
+            Some(entry) => Ok(entry),
+            None => Err(Error::NotFound(
+                format!("raft log for region {} at index {}", region_id, log_index),
+            )),
+        }
+    }
+
+    /// Returns the raft log entries of the region in `[from, to]`, see `RaftLogs::new`.
+    pub fn raft_logs(&self, region_id: u64, from: u64, to: u64) -> Result<RaftLogs> {
+        let info = try!(self.region_info(region_id));
+        let (raft_state, apply_state) = match (info.raft_local_state, info.raft_apply_state) {
+            (Some(raft_state), Some(apply_state)) => (raft_state, apply_state),
+            _ => return Err(Error::NotFound(format!("raft states of region {}", region_id))),
+        };
+        let mut logs = try!(RaftLogs::new(&raft_state, &apply_state, from, to));
+        match self.engines.raft_log_engine {
+            Some(ref engine) => for index in logs.from..logs.to + 1 {
+                if let Some(entry) = box_try!(engine.get_entry(region_id, index)) {
+                    logs.entries.push(entry);
+                }
+            },
+            None => {
+                let start = keys::raft_log_key(region_id, logs.from);
+                let end = keys::raft_log_key(region_id, logs.to + 1);
+                let entries = &mut logs.entries;
+                box_try!(self.engines.raft_engine.scan(&start, &end, false, &mut |_, v| {
+                    entries.push(try!(protobuf::parse_from_bytes(v)));
+                    Ok(true)
+                }));
+            }
+        }
+        Ok(logs)
+    }
+
+    pub fn region_info(&self, region_id: u64) -> Result<RegionInfo> {
+        let kv_snap = Snapshot::new(self.engines.kv_engine.clone());
+        let raft_snap = Snapshot::new(self.engines.raft_engine.clone());
+        let info = RegionInfo {
+            raft_local_state: box_try!(raft_snap.get_msg(&keys::raft_state_key(region_id))),
+            raft_apply_state: box_try!(
+                kv_snap.get_msg_cf(CF_RAFT, &keys::apply_state_key(region_id))
+            ),
+            region_local_state: box_try!(
+                kv_snap.get_msg_cf(CF_RAFT, &keys::region_state_key(region_id))
+            ),
+        };
+        if info == RegionInfo::default() {
+            return Err(Error::NotFound(format!("info for region {}", region_id)));
+        }
+        Ok(info)
+    }
+
+    fn get_region_state(&self, region_id: u64) -> Result<RegionLocalState> {
+        let key = keys::region_state_key(region_id);
+        match box_try!(self.engines.kv_engine.get_msg_cf(CF_RAFT, &key)) {
+            Some(state) => Ok(state),
+            None => Err(Error::NotFound(format!("region {}", region_id))),
+        }
+    }
+
+    /// Returns the total size of the keys and values of the region in each column family.
+    pub fn region_size<T: AsRef<str>>(
+        &self,
+        region_id: u64,
+        cfs: Vec<T>,
+    ) -> Result<Vec<(T, u64)>> {
+        let snap = Snapshot::new(self.engines.kv_engine.clone());
+        let region = try!(self.get_region_state(region_id)).take_region();
+        let start_key = keys::data_key(region.get_start_key());
+        let end_key = keys::data_end_key(region.get_end_key());
+        let mut sizes = Vec::with_capacity(cfs.len());
+        for cf in cfs {
+            if !ALL_CFS.contains(&cf.as_ref()) || cf.as_ref() == CF_RAFT {
+                return Err(Error::InvalidArgument(format!("invalid cf {:?}", cf.as_ref())));
+            }
+            let mut size = 0;
+            box_try!(snap.scan_cf(cf.as_ref(), &start_key, &end_key, false, &mut |k, v| {
+                size += (k.len() + v.len()) as u64;
+                Ok(true)
+            }));
+            sizes.push((cf, size));
+        }
+        Ok(sizes)
+    }
+
+    /// Scans the MVCC records of the encoded keys in `[start, end)`, an empty `end` means
+    /// no upper bound and `limit` 0 means no limit. Returns the encoded keys with their
+    /// locks, writes and values.
+    pub fn scan_mvcc(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        limit: u64,
+    ) -> Result<Vec<(Vec<u8>, MvccInfo)>> {
+        if !end.is_empty() && start >= end {
+            return Err(Error::InvalidArgument(
+                "the start key should be less than the end key".to_owned(),
+            ));
+        }
+        let snap = Snapshot::new(self.engines.kv_engine.clone());
+        let start_key = keys::data_key(start);
+        let end_key = if end.is_empty() {
+            keys::DATA_MAX_KEY.to_vec()
+        } else {
+            keys::data_key(end)
+        };
+        let iter_opt = || IterOption::new(Some(end_key.clone()), false);
+        let mut lock_iter = box_try!(snap.new_iterator_cf(CF_LOCK, iter_opt()));
+        let mut default_iter = box_try!(snap.new_iterator_cf(CF_DEFAULT, iter_opt()));
+        let mut write_iter = box_try!(snap.new_iterator_cf(CF_WRITE, iter_opt()));
+        for iter in &mut [&mut lock_iter, &mut default_iter, &mut write_iter] {
+            iter.seek(SeekKey::Key(&start_key));
+        }
+
+        let mut res = vec![];
+        while limit == 0 || (res.len() as u64) < limit {
+            let lock_key = if lock_iter.valid() {
+                Some(keys::origin_key(lock_iter.key()).to_vec())
+            } else {
+                None
+            };
+            let default_key = try!(mvcc_user_key(&default_iter));
+            let write_key = try!(mvcc_user_key(&write_iter));
+            // The smallest key of the three column families is the next one.
+            let key = match vec![lock_key, default_key, write_key]
+                .into_iter()
+                .filter_map(|k| k)
+                .min()
+            {
+                Some(key) => key,
+                None => break,
+            };
+
+            let mut info = MvccInfo::default();
+            if lock_iter.valid() && keys::origin_key(lock_iter.key()) == key.as_slice() {
+                info.lock = Some(box_try!(Lock::parse(lock_iter.value())));
+                lock_iter.next();
+            }
+            while try!(mvcc_user_key(&write_iter)).as_ref() == Some(&key) {
+                let commit_ts = try!(mvcc_ts(&write_iter));
+                let mut write = box_try!(Write::parse(write_iter.value()));
+                if write.write_type == WriteType::Put {
+                    if let Some(v) = write.short_value.take() {
+                        info.values.push((write.start_ts, true, v));
+                    }
+                }
+                info.writes.push((commit_ts, write));
+                write_iter.next();
+            }
+            while try!(mvcc_user_key(&default_iter)).as_ref() == Some(&key) {
+                let start_ts = try!(mvcc_ts(&default_iter));
+                info.values.push((start_ts, false, default_iter.value().to_vec()));
+                default_iter.next();
+            }
+            res.push((key, info));
+        }
+        Ok(res)
+    }
+
+    /// Removes the peers on `store_ids` from the regions, all the regions of the store if
+    /// `region_ids` is `None`, and bumps their conf versions. Returns the regions before
+    /// and after the change, the regions without such peers are left untouched.
+    ///
+    /// It's used to recover the regions which lost the majority of their replicas
+    /// permanently, and must only be called when the store is stopped.
+    pub fn remove_failed_stores(
+        &self,
+        store_ids: Vec<u64>,
+        region_ids: Option<Vec<u64>>,
+    ) -> Result<Vec<(Region, Region)>> {
+        if store_ids.is_empty() {
+            return Err(Error::InvalidArgument("no store is given".to_owned()));
+        }
+        let store_ids: HashSet<u64> = store_ids.into_iter().collect();
+        let kv_engine = &self.engines.kv_engine;
+        let states = match region_ids {
+            Some(region_ids) => {
+                let mut states = Vec::with_capacity(region_ids.len());
+                for region_id in region_ids {
+                    let key = keys::region_state_key(region_id);
+                    match box_try!(kv_engine.get_msg_cf::<RegionLocalState>(CF_RAFT, &key)) {
+                        Some(state) => states.push(state),
+                        None => return Err(Error::NotFound(format!("region {}", region_id))),
+                    }
+                }
+                states
+            }
+            None => try!(self.region_states()),
+        };
+
+        let handle = box_try!(get_cf_handle(kv_engine, CF_RAFT));
+        let wb = WriteBatch::new();
+        let mut changes = vec![];
+        for mut state in states {
+            if state.get_state() == PeerState::Tombstone {
+                continue;
+            }
+            let origin = state.get_region().clone();
+            let (removed, kept): (Vec<_>, Vec<_>) = origin
+                .get_peers()
+                .iter()
+                .cloned()
+                .partition(|p| store_ids.contains(&p.get_store_id()));
+            if removed.is_empty() {
+                continue;
+            }
+            if kept.is_empty() {
+                return Err(Error::InvalidArgument(
+                    format!("all peers of region {} would be removed", origin.get_id()),
+                ));
+            }
+            {
+                let region = state.mut_region();
+                region.set_peers(RepeatedField::from_vec(kept));
+                let conf_ver = region.get_region_epoch().get_conf_ver() + removed.len() as u64;
+                region.mut_region_epoch().set_conf_ver(conf_ver);
+            }
+            let key = keys::region_state_key(origin.get_id());
+            box_try!(wb.put_msg_cf(handle, &key, &state));
+            changes.push((origin, state.take_region()));
+        }
+        if !changes.is_empty() {
+            box_try!(kv_engine.write(wb));
+        }
+        Ok(changes)
+    }
+
+    pub fn get_store_ident(&self) -> Result<StoreIdent> {
+        let key = keys::store_ident_key();
+        match box_try!(self.engines.kv_engine.get_msg::<StoreIdent>(&key)) {
+            Some(ident) => Ok(ident),
+            None => Err(Error::NotFound("store ident".to_owned())),
+        }
+    }
+
+    /// Returns the local states of all regions on the store.
+    pub fn region_states(&self) -> Result<Vec<RegionLocalState>> {
+        let mut states = vec![];
+        box_try!(self.engines.kv_engine.scan_cf(
+            CF_RAFT,
+            keys::REGION_META_MIN_KEY,
+            keys::REGION_META_MAX_KEY,
+            false,
+            &mut |key, value| {
+                let (_, suffix) = try!(keys::decode_region_meta_key(key));
+                if suffix == keys::REGION_STATE_SUFFIX {
+                    states.push(try!(protobuf::parse_from_bytes(value)));
+                }
+                Ok(true)
+            }
+        ));
+        Ok(states)
+    }
+
+    /// Checks the raft states and the ranges of all regions, returns the inconsistent
+    /// regions with the reasons, ordered by region id. A region may have more than one
+    /// reason.
+    pub fn bad_regions(&self) -> Result<Vec<(u64, String)>> {
+        let mut res = vec![];
+        let states = try!(self.region_states());
+        let region_ids: HashSet<u64> = states.iter().map(|s| s.get_region().get_id()).collect();
+
+        let mut apply_state_ids = vec![];
+        box_try!(self.engines.kv_engine.scan_cf(
+            CF_RAFT,
+            keys::REGION_RAFT_PREFIX_KEY,
+            keys::REGION_META_MIN_KEY,
+            false,
+            &mut |key, _| {
+                if let Ok((region_id, suffix)) = keys::decode_region_raft_key(key) {
+                    if suffix == keys::APPLY_STATE_SUFFIX {
+                        apply_state_ids.push(region_id);
+                    }
+                }
+                Ok(true)
+            }
+        ));
+        for region_id in apply_state_ids {
+            if !region_ids.contains(&region_id) {
+                res.push((region_id, "region local state is missing".to_owned()));
+            }
+        }
+
+        let mut regions = vec![];
+        for state in &states {
+            if state.get_state() == PeerState::Tombstone {
+                continue;
+            }
+            let region = state.get_region();
+            if let Some(reason) = try!(self.check_raft_states(region.get_id())) {
+                res.push((region.get_id(), reason));
+            }
+            regions.push(region);
+        }
+
+        // Every region is checked against the one reaching the furthest before it.
+        regions.sort_by(|a, b| a.get_start_key().cmp(b.get_start_key()));
+        let mut furthest: Option<&Region> = None;
+        for region in regions {
+            if let Some(prev) = furthest {
+                let end = prev.get_end_key();
+                if end.is_empty() || end > region.get_start_key() {
+                    let reason = |id| format!("range overlaps region {}", id);
+                    res.push((prev.get_id(), reason(region.get_id())));
+                    res.push((region.get_id(), reason(prev.get_id())));
+                }
+                if !end.is_empty() && (region.get_end_key().is_empty() ||
+                    region.get_end_key() > end)
+                {
+                    furthest = Some(region);
+                }
+            } else {
+                furthest = Some(region);
+            }
+        }
+
+        res.sort_by_key(|&(region_id, _)| region_id);
+        Ok(res)
+    }
+
+    fn check_raft_states(&self, region_id: u64) -> Result<Option<String>> {
+        let raft_state: Option<RaftLocalState> = box_try!(
+            self.engines
+                .raft_engine
+                .get_msg(&keys::raft_state_key(region_id))
+        );
+        let apply_state: Option<RaftApplyState> = box_try!(
+            self.engines
+                .kv_engine
+                .get_msg_cf(CF_RAFT, &keys::apply_state_key(region_id))
+        );
+        let (raft_state, apply_state) = match (raft_state, apply_state) {
+            (None, _) => return Ok(Some("raft local state is missing".to_owned())),
+            (_, None) => return Ok(Some("raft apply state is missing".to_owned())),
+            (Some(r), Some(a)) => (r, a),
+        };
+        let commit_index = raft_state.get_hard_state().get_commit();
+        let last_index = raft_state.get_last_index();
+        let applied_index = apply_state.get_applied_index();
+        let truncated_index = apply_state.get_truncated_state().get_index();
+        let reason = if applied_index > commit_index {
+            format!("apply index {} > commit index {}", applied_index, commit_index)
+        } else if commit_index > last_index {
+            format!("commit index {} > last index {}", commit_index, last_index)
+        } else if last_index < truncated_index {
+            format!("last index {} < truncated index {}", last_index, truncated_index)
+        } else if applied_index < truncated_index {
+            format!("apply index {} < truncated index {}", applied_index, truncated_index)
+        } else if let Some(reason) =
+            self.check_raft_log_engine(region_id, truncated_index, last_index)
+        {
+            reason
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(reason))
+    }
+
+    // Checks that the raft log engine, if it's used, keeps the entries after the
+    // truncated index.
+    fn check_raft_log_engine(
+        &self,
+        region_id: u64,
+        truncated_index: u64,
+        last_index: u64,
+    ) -> Option<String> {
+        let engine = match self.engines.raft_log_engine {
+            Some(ref engine) => engine,
+            None => return None,
+        };
+        if last_index == truncated_index {
+            return None;
+        }
+        match (engine.first_index(region_id), engine.last_index(region_id)) {
+            (Some(first), Some(last)) if first <= truncated_index + 1 && last >= last_index => {
+                None
+            }
+            (Some(first), Some(last)) => Some(format!(
+                "raft log engine has entries in [{}, {}], expect [{}, {}]",
+                first,
+                last,
+                truncated_index + 1,
+                last_index
+            )),
+            _ => Some(format!(
+                "entries in [{}, {}] are missing in raft log engine",
+                truncated_index + 1,
+                last_index
+            )),
+        }
+    }
+
+    /// Compacts the column family in `[from, to)`, an empty key means unbounded. `threads`
+    /// is the maximum number of sub-compactions running in parallel. The compaction is
+    /// paced by the IO limiter like the compactions scheduled by raftstore.
+    pub fn compact(
+        &self,
+        db: DBType,
+        cf: &str,
+        from: &[u8],
+        to: &[u8],
+        threads: u32,
+    ) -> Result<()> {
+        let db = try!(self.get_db(db));
+        let handle = match db.cf_handle(cf) {
+            Some(handle) => handle,
+            None => return Err(Error::InvalidArgument(format!("invalid cf {:?}", cf))),
+        };
+        let mut opts = CompactOptions::new();
+        // Manual compaction can run concurrently with background compactions.
+        opts.set_exclusive_manual_compaction(false);
+        opts.set_max_subcompactions(threads.max(1) as i32);
+        info!("compacting cf {} in [{}, {})", cf, escape(from), escape(to));
+        let start = if from.is_empty() { None } else { Some(from) };
+        let end = if to.is_empty() { None } else { Some(to) };
+        compact_range(db, handle, &opts, start, end);
+        Ok(())
+    }
+
+    /// Returns the properties of the tables overlapping the region, as name and value
+    /// pairs. The MVCC properties come from the write cf, and the middle key is estimated
+    /// by the size properties of the default and write cfs.
+    pub fn region_properties(&self, region_id: u64) -> Result<Vec<(String, String)>> {
+        let region = try!(self.get_region_state(region_id)).take_region();
+        let db = &self.engines.kv_engine;
+        let start = keys::enc_start_key(&region);
+        let end = keys::enc_end_key(&region);
+
+        let mut mvcc_props = MvccProperties::new();
+        let mut num_files = 0;
+        let collection = box_try!(get_region_properties_cf(db, CF_WRITE, &region));
+        for (_, v) in &*collection {
+            let props = box_try!(MvccProperties::decode(v.user_collected_properties()));
+            mvcc_props.add(&props);
+            num_files += 1;
+        }
+
+        // The handles inside the region with the sizes since their previous handles.
+        let mut handles = vec![];
+        for cf in LARGE_CFS {
+            let collection = box_try!(get_region_properties_cf(db, cf, &region));
+            for (_, v) in &*collection {
+                let props = box_try!(SizeProperties::decode(v.user_collected_properties()));
+                for (k, h) in props.index_handles.iter() {
+                    if k.as_slice() >= start.as_slice() && k.as_slice() < end.as_slice() {
+                        handles.push((k.clone(), h.size));
+                    }
+                }
+            }
+        }
+        handles.sort();
+        let total_size: u64 = handles.iter().map(|&(_, size)| size).sum();
+        let mut size = 0;
+        let mut middle_key = None;
+        for (k, s) in handles {
+            size += s;
+            if size * 2 >= total_size {
+                middle_key = Some(k);
+                break;
+            }
+        }
+
+        let mut res = vec![
+            ("mvcc.min_ts", mvcc_props.min_ts.to_string()),
+            ("mvcc.max_ts", mvcc_props.max_ts.to_string()),
+            ("mvcc.num_rows", mvcc_props.num_rows.to_string()),
+            ("mvcc.num_puts", mvcc_props.num_puts.to_string()),
+            ("mvcc.num_versions", mvcc_props.num_versions.to_string()),
+            ("mvcc.max_row_versions", mvcc_props.max_row_versions.to_string()),
+            ("num_files", num_files.to_string()),
+            ("approximate_size", total_size.to_string()),
+        ];
+        if let Some(k) = middle_key {
+            res.push((
+                "middle_key_by_approximate_size",
+                escape(keys::origin_key(&k)),
+            ));
+        }
+        Ok(res.into_iter().map(|(n, v)| (n.to_owned(), v)).collect())
+    }
+
+    /// Marks the local peer of the region as tombstone, returns the local region. If
+    /// `pd_region`, the region on PD, is given, the peer is only marked when PD has seen
+    /// the local epoch and has removed the peer.
+    ///
+    /// The store must be stopped, it cleans up the peer when it's started again.
+    pub fn set_region_tombstone(
+        &self,
+        region_id: u64,
+        pd_region: Option<Region>,
+    ) -> Result<Region> {
+        let kv_engine = &self.engines.kv_engine;
+        let key = keys::region_state_key(region_id);
+        let state = match box_try!(kv_engine.get_msg_cf::<RegionLocalState>(CF_RAFT, &key)) {
+            Some(state) => state,
+            None => return Err(Error::NotFound(format!("region {}", region_id))),
+        };
+        if state.get_state() == PeerState::Tombstone {
+            return Err(Error::InvalidArgument(
+                format!("region {} is already tombstone", region_id),
+            ));
+        }
+        let region = state.get_region();
+        if let Some(pd_region) = pd_region {
+            if is_epoch_stale(pd_region.get_region_epoch(), region.get_region_epoch()) {
+                return Err(Error::InvalidArgument(format!(
+                    "the epoch {:?} on PD is older than the local epoch {:?}",
+                    pd_region.get_region_epoch(),
+                    region.get_region_epoch()
+                )));
+            }
+            let store_id = try!(self.get_store_ident()).get_store_id();
+            let local_peer = find_peer(region, store_id);
+            if local_peer.is_some() && local_peer == find_peer(&pd_region, store_id) {
+                return Err(Error::InvalidArgument(
+                    format!("peer {:?} is still a member on PD", local_peer.unwrap()),
+                ));
+            }
+        }
+        let wb = WriteBatch::new();
+        box_try!(write_peer_state(kv_engine, &wb, region, PeerState::Tombstone));
+        box_try!(kv_engine.write(wb));
+        Ok(region.clone())
+    }
+
+    /// Creates an empty region with its initial raft states, the region must have a
+    /// peer on this store and must not overlap any local region except tombstones.
+    ///
+    /// The store must be stopped.
+    pub fn recreate_region(&self, region: Region) -> Result<()> {
+        let store_id = try!(self.get_store_ident()).get_store_id();
+        if find_peer(&region, store_id).is_none() {
+            return Err(Error::InvalidArgument(
+                format!("region {:?} has no peer on store {}", region, store_id),
+            ));
+        }
+        let (start, end) = (region.get_start_key(), region.get_end_key());
+        for state in try!(self.region_states()) {
+            let r = state.get_region();
+            if r.get_id() == region.get_id() {
+                return Err(Error::InvalidArgument(
+                    format!("region {} already exists", r.get_id()),
+                ));
+            }
+            if state.get_state() == PeerState::Tombstone {
+                continue;
+            }
+            let overlapped = (end.is_empty() || r.get_start_key() < end) &&
+                (r.get_end_key().is_empty() || start < r.get_end_key());
+            if overlapped {
+                return Err(Error::InvalidArgument(
+                    format!("region {:?} overlaps local region {:?}", region, r),
+                ));
+            }
+        }
+
+        let kv_engine = &self.engines.kv_engine;
+        let kv_wb = WriteBatch::new();
+        box_try!(write_peer_state(kv_engine, &kv_wb, &region, PeerState::Normal));
+        box_try!(write_initial_apply_state(kv_engine, &kv_wb, region.get_id()));
+        let raft_wb = WriteBatch::new();
+        box_try!(write_initial_raft_state(&raft_wb, region.get_id()));
+        box_try!(kv_engine.write(kv_wb));
+        box_try!(self.engines.raft_engine.write(raft_wb));
+        Ok(())
+    }
+
+    /// Writes the data of the region in the default, lock and write cfs into SST files
+    /// under `dir`, along with a manifest of the region range and the file checksums.
+    pub fn dump_region(&self, region_id: u64, dir: &Path) -> Result<RegionDumpManifest> {
+        let region = try!(self.get_region_state(region_id)).take_region();
+        box_try!(fs::create_dir_all(dir));
+        let manifest_path = dir.join(REGION_DUMP_MANIFEST);
+        if manifest_path.exists() {
+            return Err(Error::InvalidArgument(
+                format!("{} already exists", manifest_path.display()),
+            ));
+        }
+
+        let db = &self.engines.kv_engine;
+        let snap = Snapshot::new(db.clone());
+        let start = keys::enc_start_key(&region);
+        let end = keys::enc_end_key(&region);
+        let mut files = vec![];
+        for cf in DATA_CFS {
+            let handle = box_try!(get_cf_handle(db, cf));
+            let opts = db.get_options_cf(handle).clone();
+            let mut writer = SstFileWriter::new(EnvOptions::new(), opts);
+            let name = format!("{}_{}.sst", region_id, cf);
+            let path = dir.join(&name);
+            box_try!(writer.open(path.to_str().unwrap()));
+            let mut kvs = 0;
+            let (mut smallest, mut largest) = (vec![], vec![]);
+            box_try!(snap.scan_cf(cf, &start, &end, false, &mut |k, v| {
+                box_try!(writer.put(k, v));
+                if kvs == 0 {
+                    smallest = k.to_vec();
+                }
+                largest = k.to_vec();
+                kvs += 1;
+                Ok(true)
+            }));
+            if kvs == 0 {
+                // An SST file can't be finished without any entries.
+                drop(writer);
+                delete_file_if_exist(&path);
+                continue;
+            }
+            box_try!(writer.finish());
+            files.push(RegionDumpFile {
+                cf: cf.to_owned(),
+                name: name,
+                size: box_try!(get_file_size(&path)),
+                checksum: box_try!(calc_crc32(&path)),
+                kvs: kvs,
+                smallest_key: escape(keys::origin_key(&smallest)),
+                largest_key: escape(keys::origin_key(&largest)),
+            });
+        }
+
+        let manifest = RegionDumpManifest {
+            region_id: region_id,
+            start_key: escape(region.get_start_key()),
+            end_key: escape(region.get_end_key()),
+            conf_ver: region.get_region_epoch().get_conf_ver(),
+            version: region.get_region_epoch().get_version(),
+            files: files,
+        };
+        let tmp_path = dir.join(format!("{}.tmp", REGION_DUMP_MANIFEST));
+        {
+            let f = box_try!(File::create(&tmp_path));
+            box_try!(serde_json::to_writer_pretty(f, &manifest));
+        }
+        box_try!(fs::rename(&tmp_path, &manifest_path));
+        Ok(manifest)
+    }
+
+    /// Ingests a region dump under `dir` into the local region `region_id`, which must
+    /// have the same range as the dumped one and an epoch not older than it. The data of
+    /// the local region is replaced by the dump, and its applied index is moved to the
+    /// commit index, so committed entries aren't applied again on top of the dump.
+    ///
+    /// Every file is validated before the local data is deleted. The files are copied
+    /// when they are ingested, so a failed load can be retried with the same dump.
+    ///
+    /// The store must be stopped.
+    pub fn load_region(&self, region_id: u64, dir: &Path) -> Result<RegionDumpManifest> {
+        let manifest: RegionDumpManifest = {
+            let f = box_try!(File::open(dir.join(REGION_DUMP_MANIFEST)));
+            box_try!(serde_json::from_reader(f))
+        };
+        let state = try!(self.get_region_state(region_id));
+        if state.get_state() != PeerState::Normal {
+            return Err(Error::InvalidArgument(
+                format!("region {} is in {:?} state", region_id, state.get_state()),
+            ));
+        }
+        let region = state.get_region();
+        if region.get_start_key() != unescape(&manifest.start_key).as_slice() ||
+            region.get_end_key() != unescape(&manifest.end_key).as_slice()
+        {
+            return Err(Error::InvalidArgument(format!(
+                "range [{}, {}) of the dump doesn't match region {:?}",
+                manifest.start_key,
+                manifest.end_key,
+                region
+            )));
+        }
+        let mut epoch = RegionEpoch::new();
+        epoch.set_conf_ver(manifest.conf_ver);
+        epoch.set_version(manifest.version);
+        if is_epoch_stale(region.get_region_epoch(), &epoch) {
+            return Err(Error::InvalidArgument(format!(
+                "epoch {:?} of region {} is older than the dumped epoch {:?}",
+                region.get_region_epoch(),
+                region_id,
+                epoch
+            )));
+        }
+
+        let raft_state: RaftLocalState = match box_try!(
+            self.engines
+                .raft_engine
+                .get_msg(&keys::raft_state_key(region_id))
+        ) {
+            Some(state) => state,
+            None => return Err(Error::NotFound(format!("raft state of region {}", region_id))),
+        };
+        let mut apply_state: RaftApplyState = match box_try!(
+            self.engines
+                .kv_engine
+                .get_msg_cf(CF_RAFT, &keys::apply_state_key(region_id))
+        ) {
+            Some(state) => state,
+            None => return Err(Error::NotFound(format!("apply state of region {}", region_id))),
+        };
+
+        for file in &manifest.files {
+            if !DATA_CFS.iter().any(|cf| *cf == file.cf) {
+                return Err(Error::InvalidArgument(format!("invalid cf {:?}", file.cf)));
+            }
+            let (smallest, largest) = (unescape(&file.smallest_key), unescape(&file.largest_key));
+            let out_of_region = smallest.as_slice() < region.get_start_key() ||
+                (!region.get_end_key().is_empty() && largest.as_slice() >= region.get_end_key());
+            if file.kvs == 0 || smallest > largest || out_of_region {
+                return Err(Error::InvalidArgument(format!(
+                    "keys [{}, {}] of {} are out of region {:?}",
+                    file.smallest_key,
+                    file.largest_key,
+                    file.name,
+                    region
+                )));
+            }
+            let path = dir.join(&file.name);
+            let size = box_try!(get_file_size(&path));
+            let checksum = box_try!(calc_crc32(&path));
+            if size != file.size || checksum != file.checksum {
+                return Err(Error::InvalidArgument(format!(
+                    "{} is corrupted, expect size {} and checksum {}, got {} and {}",
+                    path.display(),
+                    file.size,
+                    file.checksum,
+                    size,
+                    checksum
+                )));
+            }
+        }
+
+        let db = &self.engines.kv_engine;
+        let start = keys::enc_start_key(region);
+        let end = keys::enc_end_key(region);
+        box_try!(delete_all_in_range(db, &start, &end));
+        let opts = IngestExternalFileOptions::new();
+        for file in &manifest.files {
+            let handle = box_try!(get_cf_handle(db, &file.cf));
+            let path = dir.join(&file.name);
+            info!("ingesting {} into region {}", path.display(), region_id);
+            box_try!(db.ingest_external_file_cf(handle, &opts, &[path.to_str().unwrap()]));
+        }
+
+        let commit_index = raft_state.get_hard_state().get_commit();
+        if apply_state.get_applied_index() < commit_index {
+            apply_state.set_applied_index(commit_index);
+            let handle = box_try!(get_cf_handle(db, CF_RAFT));
+            box_try!(db.put_msg_cf(handle, &keys::apply_state_key(region_id), &apply_state));
+        }
+        let size = box_try!(get_region_approximate_size(db, region));
+        info!(
+            "region {} is loaded, applied index {}, approximate size {}",
+            region_id,
+            apply_state.get_applied_index(),
+            size
+        );
+        Ok(manifest)
+    }
+}
+
+// Returns the encoded user key of the current entry in the default or write cf.
+fn mvcc_user_key(iter: &DBIterator) -> Result<Option<Vec<u8>>> {
+    if !iter.valid() {
+        return Ok(None);
+    }
+    let key = Key::from_encoded(keys::origin_key(iter.key()).to_vec());
+    let user_key = box_try!(key.truncate_ts());
+    Ok(Some(user_key.encoded().to_vec()))
+}
+
+fn mvcc_ts(iter: &DBIterator) -> Result<u64> {
+    let key = Key::from_encoded(keys::origin_key(iter.key()).to_vec());
+    Ok(box_try!(key.decode_ts()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use rocksdb::{ColumnFamilyOptions, DBOptions, Writable};
+    use kvproto::debugpb::DB as DBType;
+    use kvproto::metapb::Region;
+    use kvproto::raft_serverpb::RegionLocalState;
+    use tempdir::TempDir;
+
+    use raftstore::store::{keys, Engines, LogBatch, Mutable, RaftLogEngine,
+                           RaftLogEngineConfig};
+    use raftstore::store::util::new_peer;
+    use storage::{Key, ALL_CFS, CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE};
+    use storage::mvcc::{Lock, LockType, Write, WriteType};
+    use util::properties::{MvccPropertiesCollectorFactory, SizePropertiesCollectorFactory};
+    use util::rocksdb::{get_cf_handle, new_engine, new_engine_opt, CFOptions};
+    use super::*;
+
+    fn new_debugger() -> (TempDir, Debugger) {
+        let tmp = TempDir::new("test_debug").unwrap();
+        let path = tmp.path().to_str().unwrap();
+        let engine = Arc::new(new_engine(path, ALL_CFS).unwrap());
+        let engines = Engines::new(engine.clone(), engine);
+        (tmp, Debugger::new(engines))
+    }
+
+    #[test]
+    fn test_get() {
+        let (_tmp, debugger) = new_debugger();
+        let engine = &debugger.engines.kv_engine;
+        let handle = get_cf_handle(engine, CF_DEFAULT).unwrap();
+        engine.put_cf(handle, b"k1", b"v1").unwrap();
+
+        assert_eq!(debugger.get(DBType::KV, CF_DEFAULT, b"k1").unwrap(), b"v1");
+        match debugger.get(DBType::KV, CF_DEFAULT, b"k2") {
+            Err(Error::NotFound(_)) => {}
+            r => panic!("expect not found, got {:?}", r),
+        }
+        match debugger.get(DBType::KV, "foo", b"k1") {
+            Err(Error::InvalidArgument(_)) => {}
+            r => panic!("expect invalid argument, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_region_size() {
+        let (_tmp, debugger) = new_debugger();
+        let engine = &debugger.engines.kv_engine;
+        let mut region = Region::new();
+        region.set_id(1);
+        region.set_start_key(b"a".to_vec());
+        region.set_end_key(b"c".to_vec());
+        let mut state = RegionLocalState::new();
+        state.set_region(region);
+        let handle = get_cf_handle(engine, CF_RAFT).unwrap();
+        engine
+            .put_msg_cf(handle, &keys::region_state_key(1), &state)
+            .unwrap();
+
+        for &(cf, k) in &[(CF_DEFAULT, b"a1"), (CF_WRITE, b"b1"), (CF_WRITE, b"c1")] {
+            let handle = get_cf_handle(engine, cf).unwrap();
+            engine.put_cf(handle, &keys::data_key(k), b"value").unwrap();
+        }
+        let sizes = debugger
+            .region_size(1, vec![CF_DEFAULT, CF_WRITE, CF_LOCK])
+            .unwrap();
+        assert_eq!(sizes, vec![(CF_DEFAULT, 8), (CF_WRITE, 8), (CF_LOCK, 0)]);
+        assert!(debugger.region_size(2, vec![CF_DEFAULT]).is_err());
+        assert!(debugger.region_size(1, vec![CF_RAFT]).is_err());
+    }
+
+    #[test]
+    fn test_scan_mvcc() {
+        let (_tmp, debugger) = new_debugger();
+        let engine = &debugger.engines.kv_engine;
+        let put = |cf: &str, key: Vec<u8>, value: Vec<u8>| {
+            let handle = get_cf_handle(engine, cf).unwrap();
+            engine.put_cf(handle, &keys::data_key(&key), &value).unwrap();
+        };
+        let key = |k: &[u8]| Key::from_raw(k);
+
+        // k1 is committed with a short value, k2 has a long value and a lock, k3 is
+        // only locked.
+        let w = Write::new(WriteType::Put, 5, Some(b"v1".to_vec()));
+        put(CF_WRITE, key(b"k1").append_ts(10).encoded().clone(), w.to_bytes());
+        let w = Write::new(WriteType::Put, 5, None);
+        put(CF_WRITE, key(b"k2").append_ts(10).encoded().clone(), w.to_bytes());
+        put(CF_DEFAULT, key(b"k2").append_ts(5).encoded().clone(), b"v2".to_vec());
+        let lock = Lock::new(LockType::Put, b"k2".to_vec(), 20, 0, None);
+        put(CF_LOCK, key(b"k2").encoded().clone(), lock.to_bytes());
+        let lock = Lock::new(LockType::Lock, b"k2".to_vec(), 20, 0, None);
+        put(CF_LOCK, key(b"k3").encoded().clone(), lock.to_bytes());
+
+        let infos = debugger.scan_mvcc(b"", b"", 0).unwrap();
+        let keys: Vec<_> = infos.iter().map(|&(ref k, _)| k.clone()).collect();
+        let expect: Vec<_> = [b"k1", b"k2", b"k3"]
+            .iter()
+            .map(|k| key(*k).encoded().clone())
+            .collect();
+        assert_eq!(keys, expect);
+        assert!(infos[0].1.lock.is_none());
+        assert_eq!(infos[0].1.writes.len(), 1);
+        assert_eq!(infos[0].1.values, vec![(5, true, b"v1".to_vec())]);
+        assert_eq!(infos[1].1.lock.as_ref().unwrap().ts, 20);
+        assert_eq!(infos[1].1.values, vec![(5, false, b"v2".to_vec())]);
+        assert!(infos[2].1.writes.is_empty());
+        assert!(infos[2].1.lock.is_some());
+
+        let infos = debugger.scan_mvcc(&expect[1], b"", 1).unwrap();
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].0, expect[1]);
+        let infos = debugger.scan_mvcc(&expect[0], &expect[2], 0).unwrap();
+        assert_eq!(infos.len(), 2);
+        assert!(debugger.scan_mvcc(&expect[2], &expect[0], 0).is_err());
+    }
+
+    #[test]
+    fn test_remove_failed_stores() {
+        let (_tmp, debugger) = new_debugger();
+        let engine = &debugger.engines.kv_engine;
+        let handle = get_cf_handle(engine, CF_RAFT).unwrap();
+        let put_region = |id: u64, stores: &[u64], peer_state: PeerState| {
+            let mut region = Region::new();
+            region.set_id(id);
+            for (i, store_id) in stores.iter().enumerate() {
+                region.mut_peers().push(new_peer(*store_id, id * 10 + i as u64));
+            }
+            region.mut_region_epoch().set_conf_ver(stores.len() as u64);
+            let mut state = RegionLocalState::new();
+            state.set_region(region);
+            state.set_state(peer_state);
+            engine
+                .put_msg_cf(handle, &keys::region_state_key(id), &state)
+                .unwrap();
+        };
+        let get_region = |id: u64| {
+            let key = keys::region_state_key(id);
+            let state: RegionLocalState = engine.get_msg_cf(CF_RAFT, &key).unwrap().unwrap();
+            state.get_region().clone()
+        };
+        let stores = |region: &Region| -> Vec<u64> {
+            region.get_peers().iter().map(|p| p.get_store_id()).collect()
+        };
+        put_region(1, &[1, 2, 3], PeerState::Normal);
+        put_region(2, &[1, 4, 5], PeerState::Normal);
+        put_region(3, &[1, 2], PeerState::Tombstone);
+        put_region(4, &[2, 3], PeerState::Normal);
+
+        assert!(debugger.remove_failed_stores(vec![], None).is_err());
+        assert!(debugger.remove_failed_stores(vec![2], Some(vec![5])).is_err());
+        // All peers of region 4 would be removed.
+        assert!(debugger.remove_failed_stores(vec![2, 3], None).is_err());
+        assert_eq!(stores(&get_region(1)), vec![1, 2, 3]);
+
+        let changes = debugger
+            .remove_failed_stores(vec![2, 3], Some(vec![1, 2]))
+            .unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].0.get_id(), 1);
+        let region = get_region(1);
+        assert_eq!(region, changes[0].1);
+        assert_eq!(stores(&region), vec![1]);
+        assert_eq!(region.get_region_epoch().get_conf_ver(), 5);
+
+        let changes = debugger.remove_failed_stores(vec![4, 5], None).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(stores(&get_region(2)), vec![1]);
+        // Tombstone regions are left untouched.
+        assert_eq!(stores(&get_region(3)), vec![1, 2]);
+        assert_eq!(stores(&get_region(4)), vec![2, 3]);
+    }
+
+    fn put_store_ident(debugger: &Debugger, store_id: u64) {
+        let mut ident = StoreIdent::new();
+        ident.set_store_id(store_id);
+        debugger
+            .engines
+            .kv_engine
+            .put_msg(&keys::store_ident_key(), &ident)
+            .unwrap();
+    }
+
+    fn new_region(id: u64, start: &[u8], end: &[u8], peers: &[(u64, u64)]) -> Region {
+        let mut region = Region::new();
+        region.set_id(id);
+        region.set_start_key(start.to_vec());
+        region.set_end_key(end.to_vec());
+        for &(store_id, peer_id) in peers {
+            region.mut_peers().push(new_peer(store_id, peer_id));
+        }
+        region.mut_region_epoch().set_version(1);
+        region.mut_region_epoch().set_conf_ver(peers.len() as u64);
+        region
+    }
+
+    fn get_region_state(debugger: &Debugger, id: u64) -> RegionLocalState {
+        let key = keys::region_state_key(id);
+        let engine = &debugger.engines.kv_engine;
+        engine.get_msg_cf(CF_RAFT, &key).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_set_region_tombstone() {
+        let (_tmp, debugger) = new_debugger();
+        put_store_ident(&debugger, 1);
+        let region = new_region(1, b"", b"", &[(1, 11), (2, 12), (3, 13)]);
+        debugger.recreate_region(region.clone()).unwrap();
+
+        match debugger.set_region_tombstone(2, None) {
+            Err(Error::NotFound(_)) => {}
+            r => panic!("expect not found, got {:?}", r),
+        }
+        // The peer is still a member on PD.
+        let pd_region = new_region(1, b"", b"", &[(1, 11), (2, 12), (3, 13)]);
+        assert!(debugger.set_region_tombstone(1, Some(pd_region)).is_err());
+        // PD hasn't seen the local epoch.
+        let mut pd_region = new_region(1, b"", b"", &[(2, 12), (3, 13)]);
+        assert!(debugger.set_region_tombstone(1, Some(pd_region.clone())).is_err());
+        assert_eq!(get_region_state(&debugger, 1).get_state(), PeerState::Normal);
+
+        pd_region.mut_region_epoch().set_conf_ver(4);
+        let local = debugger.set_region_tombstone(1, Some(pd_region)).unwrap();
+        assert_eq!(local, region);
+        assert_eq!(get_region_state(&debugger, 1).get_state(), PeerState::Tombstone);
+        assert!(debugger.set_region_tombstone(1, None).is_err());
+    }
+
+    #[test]
+    fn test_recreate_region() {
+        let (_tmp, debugger) = new_debugger();
+        put_store_ident(&debugger, 1);
+        let region = new_region(1, b"a", b"c", &[(1, 11)]);
+        debugger.recreate_region(region.clone()).unwrap();
+        let info = debugger.region_info(1).unwrap();
+        assert_eq!(info.region_local_state.unwrap().get_region(), &region);
+        assert!(info.raft_local_state.is_some());
+        assert!(info.raft_apply_state.is_some());
+
+        // The same id, overlapped ranges and no local peer are rejected.
+        assert!(debugger.recreate_region(region).is_err());
+        assert!(debugger.recreate_region(new_region(2, b"b", b"", &[(1, 21)])).is_err());
+        assert!(debugger.recreate_region(new_region(2, b"", b"b", &[(1, 21)])).is_err());
+        assert!(debugger.recreate_region(new_region(2, b"c", b"", &[(2, 21)])).is_err());
+        debugger.recreate_region(new_region(2, b"c", b"", &[(1, 21)])).unwrap();
+
+        debugger.set_region_tombstone(1, None).unwrap();
+        debugger.recreate_region(new_region(3, b"", b"c", &[(1, 31)])).unwrap();
+    }
+
+    #[test]
+    fn test_bad_regions() {
+        let (_tmp, debugger) = new_debugger();
+        put_store_ident(&debugger, 1);
+        let ranges: Vec<(u64, &[u8], &[u8])> =
+            vec![(1, b"", b"b"), (2, b"b", b"d"), (3, b"d", b"f"), (4, b"f", b"")];
+        for (id, start, end) in ranges {
+            let region = new_region(id, start, end, &[(1, id * 10)]);
+            debugger.recreate_region(region).unwrap();
+        }
+        assert!(debugger.bad_regions().unwrap().is_empty());
+
+        let kv_engine = &debugger.engines.kv_engine;
+        let raft_engine = &debugger.engines.raft_engine;
+        let handle = get_cf_handle(kv_engine, CF_RAFT).unwrap();
+        // The applied index exceeds the commit index.
+        let key = keys::apply_state_key(1);
+        let mut apply_state: RaftApplyState = kv_engine.get_msg_cf(CF_RAFT, &key).unwrap().unwrap();
+        apply_state.set_applied_index(100);
+        kv_engine.put_msg_cf(handle, &key, &apply_state).unwrap();
+        // The raft state is lost.
+        raft_engine.delete(&keys::raft_state_key(2)).unwrap();
+        // The region state is lost.
+        kv_engine.delete_cf(handle, &keys::region_state_key(3)).unwrap();
+        // Region 5 overlaps region 4.
+        let region = new_region(5, b"g", b"h", &[(1, 50)]);
+        let mut state = RegionLocalState::new();
+        state.set_region(region);
+        kv_engine
+            .put_msg_cf(handle, &keys::region_state_key(5), &state)
+            .unwrap();
+
+        let bad_regions = debugger.bad_regions().unwrap();
+        let ids: Vec<_> = bad_regions.iter().map(|&(id, _)| id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4, 5, 5]);
+        assert!(bad_regions[0].1.starts_with("apply index 100 > commit index"));
+        assert_eq!(bad_regions[1].1, "raft local state is missing");
+        assert_eq!(bad_regions[2].1, "region local state is missing");
+        assert_eq!(bad_regions[3].1, "range overlaps region 5");
+        assert!(bad_regions[4..].iter().any(|&(_, ref r)| r == "raft local state is missing"));
+        assert!(bad_regions[4..].iter().any(|&(_, ref r)| r == "range overlaps region 4"));
+
+        // Tombstone regions are not checked.
+        for id in &[1, 5] {
+            let mut state = get_region_state(&debugger, *id);
+            state.set_state(PeerState::Tombstone);
+            kv_engine
+                .put_msg_cf(handle, &keys::region_state_key(*id), &state)
+                .unwrap();
+        }
+        let ids: Vec<_> = debugger.bad_regions().unwrap().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_bad_regions_with_raft_log_engine() {
+        let (tmp, debugger) = new_debugger();
+        let cfg = RaftLogEngineConfig {
+            enabled: true,
+            dir: tmp.path().join("raft-log").to_str().unwrap().to_owned(),
+            ..RaftLogEngineConfig::default()
+        };
+        let log_engine = Arc::new(RaftLogEngine::open(cfg).unwrap());
+        let engines = debugger.engines.clone();
+        let debugger = Debugger::new(engines.with_raft_log_engine(log_engine.clone()));
+        put_store_ident(&debugger, 1);
+        let ranges: Vec<(u64, &[u8], &[u8])> = vec![(1, b"", b"b"), (2, b"b", b"")];
+        for (id, start, end) in ranges {
+            let region = new_region(id, start, end, &[(1, id * 10)]);
+            debugger.recreate_region(region).unwrap();
+            let key = keys::raft_state_key(id);
+            let raft_engine = &debugger.engines.raft_engine;
+            let mut raft_state: RaftLocalState = raft_engine.get_msg(&key).unwrap().unwrap();
+            raft_state.set_last_index(8);
+            raft_engine.put_msg(&key, &raft_state).unwrap();
+        }
+        // Region 1 keeps all entries after the truncated index 5, region 2 lost the
+        // last one.
+        let entries = |last: u64| -> Vec<Entry> {
+            (6..last + 1)
+                .map(|index| {
+                    let mut entry = Entry::new();
+                    entry.set_index(index);
+                    entry.set_term(6);
+                    entry
+                })
+                .collect()
+        };
+        let mut batch = LogBatch::new();
+        batch.add_entries(1, &entries(8));
+        batch.add_entries(2, &entries(7));
+        log_engine.write(&batch, true).unwrap();
+
+        let bad_regions = debugger.bad_regions().unwrap();
+        assert_eq!(
+            bad_regions,
+            vec![
+                (
+                    2,
+                    "raft log engine has entries in [6, 7], expect [6, 8]".to_owned()
+                ),
+            ]
+        );
+
+        let mut batch = LogBatch::new();
+        batch.clean_region(1);
+        log_engine.write(&batch, true).unwrap();
+        let bad_regions = debugger.bad_regions().unwrap();
+        let ids: Vec<_> = bad_regions.iter().map(|&(id, _)| id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(bad_regions[0].1, "entries in [6, 8] are missing in raft log engine");
+    }
+
+    #[test]
+    fn test_compact_and_region_properties() {
+        let tmp = TempDir::new("test_debug_properties").unwrap();
+        let cfs_opts = ALL_CFS
+            .iter()
+            .map(|cf| {
+                let mut opts = ColumnFamilyOptions::new();
+                opts.set_level_zero_file_num_compaction_trigger(10);
+                if *cf == CF_WRITE {
+                    let f = Box::new(MvccPropertiesCollectorFactory::default());
+                    opts.add_table_properties_collector_factory("tikv.mvcc-collector", f);
+                }
+                let f = Box::new(SizePropertiesCollectorFactory::default());
+                opts.add_table_properties_collector_factory("tikv.size-collector", f);
+                CFOptions::new(cf, opts)
+            })
+            .collect();
+        let path = tmp.path().to_str().unwrap();
+        let engine = Arc::new(new_engine_opt(path, DBOptions::new(), cfs_opts).unwrap());
+        let debugger = Debugger::new(Engines::new(engine.clone(), engine.clone()));
+        put_store_ident(&debugger, 1);
+        debugger
+            .recreate_region(new_region(1, b"", b"", &[(1, 11)]))
+            .unwrap();
+
+        let handle = get_cf_handle(&engine, CF_WRITE).unwrap();
+        for ts in &[10, 20] {
+            for i in 0..10 {
+                let key = Key::from_raw(format!("k{}", i).as_bytes()).append_ts(*ts + 1);
+                let write = Write::new(WriteType::Put, *ts, Some(b"v".to_vec()));
+                engine
+                    .put_cf(handle, &keys::data_key(key.encoded()), &write.to_bytes())
+                    .unwrap();
+            }
+            engine.flush_cf(handle, true).unwrap();
+        }
+
+        let props: HashMap<_, _> = debugger.region_properties(1).unwrap().into_iter().collect();
+        assert_eq!(props["mvcc.min_ts"], "11");
+        assert_eq!(props["mvcc.max_ts"], "21");
+        assert_eq!(props["mvcc.num_rows"], "20");
+        assert_eq!(props["mvcc.num_puts"], "20");
+        assert_eq!(props["num_files"], "2");
+        assert!(props.contains_key("middle_key_by_approximate_size"));
+        assert!(debugger.region_properties(2).is_err());
+
+        let level0 = "rocksdb.num-files-at-level0";
+        assert_eq!(engine.get_property_int_cf(handle, level0), Some(2));
+        assert!(debugger.compact(DBType::KV, "foo", b"", b"", 1).is_err());
+        debugger.compact(DBType::KV, CF_WRITE, b"", b"", 2).unwrap();
+        assert_eq!(engine.get_property_int_cf(handle, level0), Some(0));
+        let props: HashMap<_, _> = debugger.region_properties(1).unwrap().into_iter().collect();
+        assert_eq!(props["mvcc.num_versions"], "20");
+        assert_eq!(props["mvcc.max_row_versions"], "2");
+        assert_eq!(props["num_files"], "1");
+    }
+
+    #[test]
+    fn test_raft_logs() {
+        let (_tmp, debugger) = new_debugger();
+        put_store_ident(&debugger, 1);
+        debugger
+            .recreate_region(new_region(1, b"", b"", &[(1, 11)]))
+            .unwrap();
+        let raft_engine = &debugger.engines.raft_engine;
+        let put_entry = |index: u64, term: u64| {
+            let mut entry = Entry::new();
+            entry.set_index(index);
+            entry.set_term(term);
+            raft_engine
+                .put_msg(&keys::raft_log_key(1, index), &entry)
+                .unwrap();
+        };
+        for index in 6..11 {
+            put_entry(index, 6);
+        }
+        let key = keys::raft_state_key(1);
+        let mut raft_state: RaftLocalState = raft_engine.get_msg(&key).unwrap().unwrap();
+        raft_state.set_last_index(10);
+        raft_engine.put_msg(&key, &raft_state).unwrap();
+
+        let logs = debugger.raft_logs(1, 0, 0).unwrap();
+        assert_eq!((logs.from, logs.to), (6, 10));
+        assert_eq!((logs.truncated_index, logs.last_index), (5, 10));
+        assert_eq!(logs.entries.len(), 5);
+        assert!(logs.check().is_empty(), "{:?}", logs.check());
+        let logs = debugger.raft_logs(1, 7, 8).unwrap();
+        let indexes: Vec<_> = logs.entries.iter().map(|e| e.get_index()).collect();
+        assert_eq!(indexes, vec![7, 8]);
+        assert!(logs.check().is_empty(), "{:?}", logs.check());
+        assert!(debugger.raft_logs(1, 8, 7).is_err());
+        assert!(debugger.raft_logs(2, 0, 0).is_err());
+
+        raft_engine.delete(&keys::raft_log_key(1, 8)).unwrap();
+        put_entry(9, 4);
+        raft_engine.delete(&keys::raft_log_key(1, 10)).unwrap();
+        // An entry left before the truncated index is ignored.
+        put_entry(3, 7);
+        let logs = debugger.raft_logs(1, 1, 0).unwrap();
+        assert_eq!(
+            logs.check(),
+            vec![
+                "entries in [8, 9) are missing".to_owned(),
+                "term 4 of entry 9 is less than the previous term 6".to_owned(),
+                "entries in [10, 10] are missing".to_owned(),
+            ]
+        );
+        // The term of the first entry is checked against the truncated term.
+        put_entry(6, 4);
+        let logs = debugger.raft_logs(1, 0, 7).unwrap();
+        assert_eq!(
+            logs.check(),
+            vec!["term 4 of entry 6 is less than the previous term 5".to_owned()]
+        );
+    }
+
+    #[test]
+    fn test_dump_and_load_region() {
+        let (_src_tmp, src) = new_debugger();
+        put_store_ident(&src, 1);
+        src.recreate_region(new_region(1, b"a", b"x", &[(1, 11)])).unwrap();
+        let src_engine = &src.engines.kv_engine;
+        for cf in &[CF_DEFAULT, CF_WRITE] {
+            let handle = get_cf_handle(src_engine, cf).unwrap();
+            for k in &[b"a1", b"b1", b"y1"] {
+                src_engine
+                    .put_cf(handle, &keys::data_key(*k), cf.as_bytes())
+                    .unwrap();
+            }
+        }
+
+        let dir = TempDir::new("test_dump_region").unwrap();
+        assert!(src.dump_region(2, dir.path()).is_err());
+        let manifest = src.dump_region(1, dir.path()).unwrap();
+        assert_eq!(manifest.start_key, "a");
+        assert_eq!(manifest.end_key, "x");
+        let files: Vec<_> = manifest
+            .files
+            .iter()
+            .map(|f| (f.cf.as_str(), f.kvs))
+            .collect();
+        assert_eq!(files, vec![(CF_DEFAULT, 2), (CF_WRITE, 2)]);
+        assert_eq!(
+            (&*manifest.files[0].smallest_key, &*manifest.files[0].largest_key),
+            ("a1", "b1")
+        );
+        // The manifest is not overwritten.
+        assert!(src.dump_region(1, dir.path()).is_err());
+
+        let (_dst_tmp, dst) = new_debugger();
+        put_store_ident(&dst, 2);
+        dst.recreate_region(new_region(5, b"a", b"x", &[(2, 21)])).unwrap();
+        dst.recreate_region(new_region(6, b"x", b"", &[(2, 22)])).unwrap();
+        let dst_engine = &dst.engines.kv_engine;
+        let handle = get_cf_handle(dst_engine, CF_LOCK).unwrap();
+        dst_engine
+            .put_cf(handle, &keys::data_key(b"c1"), b"stale")
+            .unwrap();
+        // The range doesn't match.
+        assert!(dst.load_region(6, dir.path()).is_err());
+        // Nothing is deleted if any file is invalid.
+        let manifest_path = dir.path().join(REGION_DUMP_MANIFEST);
+        let mut invalid = manifest.clone();
+        invalid.files[1].largest_key = "y1".to_owned();
+        serde_json::to_writer(File::create(&manifest_path).unwrap(), &invalid).unwrap();
+        assert!(dst.load_region(5, dir.path()).is_err());
+        assert!(dst_engine.get_cf(handle, &keys::data_key(b"c1")).unwrap().is_some());
+        serde_json::to_writer(File::create(&manifest_path).unwrap(), &manifest).unwrap();
+
+        // Entries committed but not applied are skipped.
+        let raft_engine = &dst.engines.raft_engine;
+        let key = keys::raft_state_key(5);
+        let mut raft_state: RaftLocalState = raft_engine.get_msg(&key).unwrap().unwrap();
+        raft_state.set_last_index(8);
+        raft_state.mut_hard_state().set_commit(7);
+        raft_engine.put_msg(&key, &raft_state).unwrap();
+
+        let loaded = dst.load_region(5, dir.path()).unwrap();
+        assert_eq!(loaded, manifest);
+        assert!(dst_engine.get_cf(handle, &keys::data_key(b"c1")).unwrap().is_none());
+        let apply_state: RaftApplyState = dst_engine
+            .get_msg_cf(CF_RAFT, &keys::apply_state_key(5))
+            .unwrap()
+            .unwrap();
+        assert_eq!(apply_state.get_applied_index(), 7);
+        for cf in &[CF_DEFAULT, CF_WRITE] {
+            let handle = get_cf_handle(dst_engine, cf).unwrap();
+            for k in &[b"a1", b"b1"] {
+                let v = dst_engine.get_cf(handle, &keys::data_key(*k)).unwrap();
+                assert_eq!(&*v.unwrap(), cf.as_bytes());
+            }
+            assert!(dst_engine.get_cf(handle, b"zy1").unwrap().is_none());
+        }
+
+        // A newer dump can't be loaded into an older region.
+        let mut state = get_region_state(&dst, 5);
+        state.mut_region().mut_region_epoch().set_version(0);
+        let handle = get_cf_handle(dst_engine, CF_RAFT).unwrap();
+        dst_engine
+            .put_msg_cf(handle, &keys::region_state_key(5), &state)
+            .unwrap();
+        assert!(dst.load_region(5, dir.path()).is_err());
+    }
+}
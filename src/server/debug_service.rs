@@ -0,0 +1,360 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use futures::{stream, Future, Sink, Stream};
+use futures::future::Either;
+use futures::sync::mpsc;
+use futures_cpupool::{Builder, CpuPool};
+use grpc::{Environment, Error as GrpcError, RpcContext, RpcStatus, RpcStatusCode,
+           ServerStreamingSink, UnarySink, WriteFlags};
+use protobuf::RepeatedField;
+use kvproto::debugpb::*;
+use kvproto::debugpb_grpc;
+
+use raftstore::store::Engines;
+use storage::{Key, Storage};
+use util::unescape;
+use util::security::SecurityManager;
+use super::backup::{Backup, BackupTask};
+use super::debug::{Debugger, Error};
+use super::grpc_service::extract_mvcc_info;
+use super::resolve::StoreAddrResolver;
+use super::restore::{Restore, RestoreTask};
+use super::transport::RaftStoreRouter;
+
+// Debug requests scan the engines, they run in a separate pool to not block grpc threads.
+const DEBUG_POOL_SIZE: usize = 1;
+// Backups and restores may take hours, they run in their own pool to not block debug
+// requests.
+const BACKUP_POOL_SIZE: usize = 1;
+
+fn error_to_status(e: Error) -> RpcStatus {
+    let code = match e {
+        Error::NotFound(_) => RpcStatusCode::NotFound,
+        Error::InvalidArgument(_) => RpcStatusCode::InvalidArgument,
+        Error::Other(_) => RpcStatusCode::Unknown,
+    };
+    RpcStatus::new(code, Some(format!("{:?}", e)))
+}
+
+fn on_grpc_error(tag: &'static str, e: &GrpcError) {
+    error!("{} failed: {:?}", tag, e);
+}
+
+/// Service handles the RPC messages of the `Debug` service.
+#[derive(Clone)]
+pub struct Service<T: RaftStoreRouter + 'static, S: StoreAddrResolver + 'static> {
+    pool: CpuPool,
+    backup_pool: CpuPool,
+    debugger: Debugger,
+    backup: Backup,
+    restore: Restore<T, S>,
+}
+
+impl<T: RaftStoreRouter + 'static, S: StoreAddrResolver + 'static> Service<T, S> {
+    pub fn new(
+        engines: Engines,
+        storage: Storage,
+        router: T,
+        resolver: S,
+        env: Arc<Environment>,
+        security_mgr: Arc<SecurityManager>,
+    ) -> Service<T, S> {
+        let pool = Builder::new()
+            .name_prefix(thd_name!("debugger"))
+            .pool_size(DEBUG_POOL_SIZE)
+            .create();
+        let backup_pool = Builder::new()
+            .name_prefix(thd_name!("backup"))
+            .pool_size(BACKUP_POOL_SIZE)
+            .create();
+        let debugger = Debugger::new(engines);
+        Service {
+            pool: pool,
+            backup_pool: backup_pool,
+            restore: Restore::new(
+                debugger.clone(),
+                storage.get_engine(),
+                router,
+                resolver,
+                env,
+                security_mgr,
+            ),
+            backup: Backup::new(debugger.clone(), storage),
+            debugger: debugger,
+        }
+    }
+
+    fn handle_response<F, P>(&self, ctx: RpcContext, sink: UnarySink<P>, resp: F, tag: &'static str)
+    where
+        P: Send + 'static,
+        F: Future<Item = P, Error = Error> + Send + 'static,
+    {
+        let f = resp.then(|v| match v {
+            Ok(resp) => sink.success(resp),
+            Err(e) => sink.fail(error_to_status(e)),
+        });
+        ctx.spawn(f.map_err(move |e| on_grpc_error(tag, &e)));
+    }
+}
+
+impl<T, S> debugpb_grpc::Debug for Service<T, S>
+where
+    T: RaftStoreRouter + 'static,
+    S: StoreAddrResolver + 'static,
+{
+    fn get(&self, ctx: RpcContext, mut req: GetRequest, sink: UnarySink<GetResponse>) {
+        let debugger = self.debugger.clone();
+        let db = req.get_db();
+        let cf = req.take_cf();
+        let key = req.take_key();
+        let f = self.pool.spawn_fn(move || {
+            debugger.get(db, &cf, &key).map(|value| {
+                let mut resp = GetResponse::new();
+                resp.set_value(value);
+                resp
+            })
+        });
+        self.handle_response(ctx, sink, f, "debug_get");
+    }
+
+    fn raft_log(&self, ctx: RpcContext, req: RaftLogRequest, sink: UnarySink<RaftLogResponse>) {
+        let debugger = self.debugger.clone();
+        let (region_id, log_index) = (req.get_region_id(), req.get_log_index());
+        let f = self.pool.spawn_fn(move || {
+            debugger.raft_log(region_id, log_index).map(|entry| {
+                let mut resp = RaftLogResponse::new();
+                resp.set_entry(entry);
+                resp
+            })
+        });
+        self.handle_response(ctx, sink, f, "debug_raft_log");
+    }
+
+    fn region_info(
+        &self,
+        ctx: RpcContext,
+        req: RegionInfoRequest,
+        sink: UnarySink<RegionInfoResponse>,
+    ) {
+        let debugger = self.debugger.clone();
+        let region_id = req.get_region_id();
+        let f = self.pool.spawn_fn(move || {
+            debugger.region_info(region_id).map(|info| {
+                let mut resp = RegionInfoResponse::new();
+                if let Some(state) = info.raft_local_state {
+                    resp.set_raft_local_state(state);
+                }
+                if let Some(state) = info.raft_apply_state {
+                    resp.set_raft_apply_state(state);
+                }
+                if let Some(state) = info.region_local_state {
+                    resp.set_region_local_state(state);
+                }
+                resp
+            })
+        });
+        self.handle_response(ctx, sink, f, "debug_region_info");
+    }
+
+    fn region_size(
+        &self,
+        ctx: RpcContext,
+        mut req: RegionSizeRequest,
+        sink: UnarySink<RegionSizeResponse>,
+    ) {
+        let debugger = self.debugger.clone();
+        let region_id = req.get_region_id();
+        let cfs = req.take_cfs().into_vec();
+        let f = self.pool.spawn_fn(move || {
+            debugger.region_size(region_id, cfs).map(|sizes| {
+                let entries = sizes
+                    .into_iter()
+                    .map(|(cf, size)| {
+                        let mut entry = RegionSizeResponse_Entry::new();
+                        entry.set_cf(cf);
+                        entry.set_size(size);
+                        entry
+                    })
+                    .collect();
+                let mut resp = RegionSizeResponse::new();
+                resp.set_entries(RepeatedField::from_vec(entries));
+                resp
+            })
+        });
+        self.handle_response(ctx, sink, f, "debug_region_size");
+    }
+
+    fn compact(&self, ctx: RpcContext, mut req: CompactRequest, sink: UnarySink<CompactResponse>) {
+        let debugger = self.debugger.clone();
+        let db = req.get_db();
+        let cf = req.take_cf();
+        let from = req.take_from_key();
+        let to = req.take_to_key();
+        let threads = req.get_threads();
+        let f = self.pool.spawn_fn(move || {
+            debugger
+                .compact(db, &cf, &from, &to, threads)
+                .map(|_| CompactResponse::new())
+        });
+        self.handle_response(ctx, sink, f, "debug_compact");
+    }
+
+    fn get_region_properties(
+        &self,
+        ctx: RpcContext,
+        req: GetRegionPropertiesRequest,
+        sink: UnarySink<GetRegionPropertiesResponse>,
+    ) {
+        let debugger = self.debugger.clone();
+        let region_id = req.get_region_id();
+        let f = self.pool.spawn_fn(move || {
+            debugger.region_properties(region_id).map(|props| {
+                let props = props
+                    .into_iter()
+                    .map(|(name, value)| {
+                        let mut prop = Property::new();
+                        prop.set_name(name);
+                        prop.set_value(value);
+                        prop
+                    })
+                    .collect();
+                let mut resp = GetRegionPropertiesResponse::new();
+                resp.set_props(RepeatedField::from_vec(props));
+                resp
+            })
+        });
+        self.handle_response(ctx, sink, f, "debug_get_region_properties");
+    }
+
+    fn scan_mvcc(
+        &self,
+        ctx: RpcContext,
+        mut req: ScanMvccRequest,
+        sink: ServerStreamingSink<ScanMvccResponse>,
+    ) {
+        let debugger = self.debugger.clone();
+        let from = req.take_from_key();
+        let to = req.take_to_key();
+        let limit = req.get_limit();
+        let f = self.pool
+            .spawn_fn(move || debugger.scan_mvcc(&from, &to, limit))
+            .then(|res| match res {
+                Ok(infos) => {
+                    let resps = infos.into_iter().map(|(key, info)| {
+                        let mut resp = ScanMvccResponse::new();
+                        resp.set_info(extract_mvcc_info(Key::from_encoded(key.clone()), info));
+                        resp.set_key(key);
+                        Ok::<_, GrpcError>((resp, WriteFlags::default()))
+                    });
+                    Either::A(sink.send_all(stream::iter(resps)).map(|_| ()))
+                }
+                Err(e) => Either::B(sink.fail(error_to_status(e))),
+            });
+        ctx.spawn(f.map_err(|e| on_grpc_error("debug_scan_mvcc", &e)));
+    }
+
+    fn backup(
+        &self,
+        ctx: RpcContext,
+        mut req: BackupRequest,
+        sink: ServerStreamingSink<BackupResponse>,
+    ) {
+        let backup = self.backup.clone();
+        let task = BackupTask {
+            start_key: req.take_start_key(),
+            end_key: req.take_end_key(),
+            backup_ts: req.get_backup_ts(),
+            path: PathBuf::from(req.take_path()),
+        };
+        // The progress of each region is streamed to the client, a failure is sent as the
+        // last response with the error set.
+        let (tx, rx) = mpsc::unbounded();
+        self.backup_pool
+            .spawn_fn(move || {
+                let res = backup.run(&task, |region_id, region, done, total| {
+                    let mut resp = BackupResponse::new();
+                    resp.set_region_id(region_id);
+                    match region {
+                        Some(region) => {
+                            resp.set_start_key(unescape(&region.start_key));
+                            resp.set_end_key(unescape(&region.end_key));
+                            if let Some(ref file) = region.file {
+                                resp.set_kvs(file.kvs);
+                                resp.set_size(file.size);
+                            }
+                        }
+                        None => resp.set_not_leader(true),
+                    }
+                    resp.set_done(done as u64);
+                    resp.set_total(total as u64);
+                    let _ = mpsc::UnboundedSender::send(&tx, resp);
+                });
+                if let Err(e) = res {
+                    error!("backup failed: {:?}", e);
+                    let mut resp = BackupResponse::new();
+                    resp.set_error(format!("{:?}", e));
+                    let _ = mpsc::UnboundedSender::send(&tx, resp);
+                }
+                Ok::<_, ()>(())
+            })
+            .forget();
+        let resps = rx.map(|resp| (resp, WriteFlags::default()))
+            .map_err(|_| GrpcError::RemoteStopped);
+        let f = sink.send_all(resps).map(|_| ());
+        ctx.spawn(f.map_err(|e| on_grpc_error("debug_backup", &e)));
+    }
+
+    fn restore(
+        &self,
+        ctx: RpcContext,
+        mut req: RestoreRequest,
+        sink: ServerStreamingSink<RestoreResponse>,
+    ) {
+        let restore = self.restore.clone();
+        let task = RestoreTask {
+            path: PathBuf::from(req.take_path()),
+            old_prefix: req.take_old_prefix(),
+            new_prefix: req.take_new_prefix(),
+            restore_ts: req.get_restore_ts(),
+        };
+        let (tx, rx) = mpsc::unbounded();
+        self.backup_pool
+            .spawn_fn(move || {
+                let res = restore.run(&task, |region, done, total| {
+                    let mut resp = RestoreResponse::new();
+                    resp.set_region_id(region.region_id);
+                    resp.set_kvs(region.kvs);
+                    resp.set_size(region.size);
+                    resp.set_done(done as u64);
+                    resp.set_total(total as u64);
+                    let _ = mpsc::UnboundedSender::send(&tx, resp);
+                });
+                if let Err(e) = res {
+                    error!("restore failed: {:?}", e);
+                    let mut resp = RestoreResponse::new();
+                    resp.set_error(format!("{:?}", e));
+                    let _ = mpsc::UnboundedSender::send(&tx, resp);
+                }
+                Ok::<_, ()>(())
+            })
+            .forget();
+        let resps = rx.map(|resp| (resp, WriteFlags::default()))
+            .map_err(|_| GrpcError::RemoteStopped);
+        let f = sink.send_all(resps).map(|_| ());
+        ctx.spawn(f.map_err(|e| on_grpc_error("debug_restore", &e)));
+    }
+}
@@ -16,10 +16,13 @@ use std::fmt::Debug;
 use std::io::Write;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use mio::Token;
 use grpc::{ClientStreamingSink, RequestStream, RpcContext, RpcStatus, RpcStatusCode, UnarySink};
 use futures::{future, Future, Stream};
+use futures::future::Either;
 use futures::sync::oneshot;
+use tokio_timer::Timer;
 use protobuf::RepeatedField;
 use kvproto::tikvpb_grpc;
 use kvproto::raft_serverpb::*;
@@ -29,7 +32,9 @@ use kvproto::errorpb::{Error as RegionError, ServerIsBusy};
 
 use util::worker::Scheduler;
 use util::buf::PipeBuffer;
-use storage::{self, Key, Mutation, Options, Storage, Value};
+use util::time::duration_to_sec;
+use raftstore::store::SnapManager;
+use storage::{self, ClientAddrGuard, Key, Mutation, Options, Storage, Value};
 use storage::txn::Error as TxnError;
 use storage::mvcc::{Error as MvccError, Write as MvccWrite, WriteType};
 use storage::engine::Error as EngineError;
@@ -51,6 +56,9 @@ pub struct Service<T: RaftStoreRouter + 'static> {
     ch: T,
     // For handling snapshot.
     snap_scheduler: Scheduler<SnapTask>,
+    snap_mgr: SnapManager,
+    // For throttling received snapshots without blocking grpc threads.
+    timer: Timer,
     token: Arc<AtomicUsize>, // TODO: remove it.
 }
 
@@ -60,12 +68,15 @@ impl<T: RaftStoreRouter + 'static> Service<T> {
         end_point_scheduler: Scheduler<EndPointTask>,
         ch: T,
         snap_scheduler: Scheduler<SnapTask>,
+        snap_mgr: SnapManager,
     ) -> Service<T> {
         Service {
             storage: storage,
             end_point_scheduler: end_point_scheduler,
             ch: ch,
             snap_scheduler: snap_scheduler,
+            snap_mgr: snap_mgr,
+            timer: Timer::default(),
             token: Arc::new(AtomicUsize::new(1)),
         }
     }
@@ -95,6 +106,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
             .with_label_values(&[label])
             .start_coarse_timer();
 
+        let _client_addr = ClientAddrGuard::new(ctx.peer());
         let (cb, future) = make_callback();
         let res = self.storage.async_get(
             req.take_context(),
@@ -142,6 +154,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         let mut options = Options::default();
         options.key_only = req.get_key_only();
 
+        let _client_addr = ClientAddrGuard::new(ctx.peer());
         let (cb, future) = make_callback();
         let res = storage.async_scan(
             req.take_context(),
@@ -201,6 +214,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         options.lock_ttl = req.get_lock_ttl();
         options.skip_constraint_check = req.get_skip_constraint_check();
 
+        let _client_addr = ClientAddrGuard::new(ctx.peer());
         let (cb, future) = make_callback();
         let res = self.storage.async_prewrite(
             req.take_context(),
@@ -244,6 +258,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
 
         let keys = req.get_keys().iter().map(|x| Key::from_raw(x)).collect();
 
+        let _client_addr = ClientAddrGuard::new(ctx.peer());
         let (cb, future) = make_callback();
         let res = self.storage.async_commit(
             req.take_context(),
@@ -293,6 +308,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
             .with_label_values(&[label])
             .start_coarse_timer();
 
+        let _client_addr = ClientAddrGuard::new(ctx.peer());
         let (cb, future) = make_callback();
         let res = self.storage.async_cleanup(
             req.take_context(),
@@ -346,6 +362,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
             .map(|x| Key::from_raw(x))
             .collect();
 
+        let _client_addr = ClientAddrGuard::new(ctx.peer());
         let (cb, future) = make_callback();
         let res = self.storage
             .async_batch_get(req.take_context(), keys, req.get_version(), cb);
@@ -391,6 +408,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
             .map(|x| Key::from_raw(x))
             .collect();
 
+        let _client_addr = ClientAddrGuard::new(ctx.peer());
         let (cb, future) = make_callback();
         let res = self.storage
             .async_rollback(req.take_context(), keys, req.get_start_version(), cb);
@@ -431,6 +449,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
             .with_label_values(&[label])
             .start_coarse_timer();
 
+        let _client_addr = ClientAddrGuard::new(ctx.peer());
         let (cb, future) = make_callback();
         let res = self.storage
             .async_scan_lock(req.take_context(), req.get_max_version(), cb);
@@ -479,6 +498,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
             x => Some(x),
         };
 
+        let _client_addr = ClientAddrGuard::new(ctx.peer());
         let (cb, future) = make_callback();
         let res = self.storage
             .async_resolve_lock(req.take_context(), req.get_start_version(), commit_ts, cb);
@@ -514,6 +534,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
             .with_label_values(&[label])
             .start_coarse_timer();
 
+        let _client_addr = ClientAddrGuard::new(ctx.peer());
         let (cb, future) = make_callback();
         let res = self.storage
             .async_gc(req.take_context(), req.get_safe_point(), cb);
@@ -554,6 +575,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
             .with_label_values(&[label])
             .start_coarse_timer();
 
+        let _client_addr = ClientAddrGuard::new(ctx.peer());
         let (cb, future) = make_callback();
         let res = self.storage.async_delete_range(
             req.take_context(),
@@ -593,6 +615,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
             .with_label_values(&[label])
             .start_coarse_timer();
 
+        let _client_addr = ClientAddrGuard::new(ctx.peer());
         let (cb, future) = make_callback();
         let res = self.storage
             .async_raw_get(req.take_context(), req.take_key(), cb);
@@ -632,6 +655,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
             .with_label_values(&[label])
             .start_coarse_timer();
 
+        let _client_addr = ClientAddrGuard::new(ctx.peer());
         let (cb, future) = make_callback();
         let res = self.storage.async_raw_scan(
             req.take_context(),
@@ -671,6 +695,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
             .with_label_values(&[label])
             .start_coarse_timer();
 
+        let _client_addr = ClientAddrGuard::new(ctx.peer());
         let (cb, future) = make_callback();
         let res = self.storage
             .async_raw_put(req.take_context(), req.take_key(), req.take_value(), cb);
@@ -711,6 +736,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
             .with_label_values(&[label])
             .start_coarse_timer();
 
+        let _client_addr = ClientAddrGuard::new(ctx.peer());
         let (cb, future) = make_callback();
         let res = self.storage
             .async_raw_delete(req.take_context(), req.take_key(), cb);
@@ -747,8 +773,9 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
             .start_coarse_timer();
 
         let (cb, future) = make_callback();
-        let res = self.end_point_scheduler
-            .schedule(EndPointTask::Request(RequestTask::new(req, cb)));
+        let mut task = RequestTask::new(req, cb);
+        task.set_client_addr(ctx.peer());
+        let res = self.end_point_scheduler.schedule(EndPointTask::Request(task));
         if let Err(e) = res {
             self.send_fail_status(ctx, sink, Error::from(e), RpcStatusCode::ResourceExhausted);
             return;
@@ -797,18 +824,37 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         stream: RequestStream<SnapshotChunk>,
         sink: ClientStreamingSink<Done>,
     ) {
+        let slot = match self.snap_mgr.try_acquire_recv_slot() {
+            Some(slot) => slot,
+            None => {
+                SNAP_TASK_COUNTER
+                    .with_label_values(&["recv_rejected"])
+                    .inc();
+                warn!("too many receiving snapshots, reject the snapshot stream");
+                let status = RpcStatus::new(
+                    RpcStatusCode::ResourceExhausted,
+                    Some("too many receiving snapshots".to_owned()),
+                );
+                ctx.spawn(sink.fail(status).map_err(|_| ()));
+                return;
+            }
+        };
         let token = Token(self.token.fetch_add(1, Ordering::SeqCst));
         let sched = self.snap_scheduler.clone();
         let sched2 = sched.clone();
+        let limiter = self.snap_mgr.recv_limiter();
+        let timer = self.timer.clone();
         ctx.spawn(
             stream
                 .map_err(Error::from)
                 .for_each(move |mut chunk| {
+                    let mut wait = Duration::default();
                     let res = if chunk.has_message() {
                         sched
                             .schedule(SnapTask::Register(token, chunk.take_message()))
                             .map_err(Error::from)
                     } else if !chunk.get_data().is_empty() {
+                        wait = limiter.consume(chunk.get_data().len());
                         // TODO: Remove PipeBuffer or take good use of it.
                         let mut b = PipeBuffer::new(chunk.get_data().len());
                         b.write_all(chunk.get_data()).unwrap();
@@ -818,9 +864,21 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                     } else {
                         Err(box_err!("empty chunk"))
                     };
-                    future::result(res)
+                    if res.is_err() || wait == Duration::default() {
+                        return Either::A(future::result(res));
+                    }
+                    // Delay polling the next chunk so that the sender is throttled
+                    // by the flow control of grpc.
+                    SNAP_LIMIT_WAIT_HISTOGRAM_VEC
+                        .with_label_values(&["recv"])
+                        .observe(duration_to_sec(wait));
+                    Either::B(timer.sleep(wait).map_err(|e| -> Error {
+                        box_err!("failed to throttle snapshot: {:?}", e)
+                    }))
                 })
                 .then(move |res| {
+                    // Release the slot after the stream is finished.
+                    drop(slot);
                     let res = match res {
                         Ok(_) => sched2.schedule(SnapTask::Close(token)),
                         Err(e) => {
@@ -849,6 +907,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         let storage = self.storage.clone();
 
         let key = Key::from_raw(req.get_key());
+        let _client_addr = ClientAddrGuard::new(ctx.peer());
         let (cb, future) = make_callback();
         let res = storage.async_mvcc_by_key(req.take_context(), key.clone(), cb);
         if let Err(e) = res {
@@ -895,6 +954,7 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
 
         let storage = self.storage.clone();
 
+        let _client_addr = ClientAddrGuard::new(ctx.peer());
         let (cb, future) = make_callback();
 
         let res = storage.async_mvcc_by_start_ts(req.take_context(), req.get_start_ts(), cb);
@@ -1016,7 +1076,7 @@ fn extract_kv_pairs(res: storage::Result<Vec<storage::Result<storage::KvPair>>>)
     }
 }
 
-fn extract_mvcc_info(key: Key, mvcc: storage::MvccInfo) -> MvccInfo {
+pub fn extract_mvcc_info(key: Key, mvcc: storage::MvccInfo) -> MvccInfo {
     let mut mvcc_info = MvccInfo::new();
     if let Some(lock) = mvcc.lock {
         let mut lock_info = LockInfo::new();
@@ -27,6 +27,13 @@ lazy_static! {
             &["type"]
         ).unwrap();
 
+    pub static ref SNAP_LIMIT_WAIT_HISTOGRAM_VEC: HistogramVec =
+        register_histogram_vec!(
+            "tikv_server_snapshot_limit_wait_duration_seconds",
+            "Bucketed histogram of time waiting for snapshot transfer rate limit",
+            &["type"]
+        ).unwrap();
+
     pub static ref GRPC_MSG_HISTOGRAM_VEC: HistogramVec =
         register_histogram_vec!(
             "tikv_grpc_msg_duration_seconds",
@@ -60,4 +67,17 @@ lazy_static! {
             "Total number of reporting failure messages",
             &["type", "store_id"]
         ).unwrap();
+
+    pub static ref BACKUP_REGION_COUNTER: CounterVec =
+        register_counter_vec!(
+            "tikv_backup_region_total",
+            "Total number of regions processed by backup",
+            &["result"]
+        ).unwrap();
+
+    pub static ref BACKUP_BYTES_COUNTER: Counter =
+        register_counter!(
+            "tikv_backup_bytes_total",
+            "Total bytes of key value pairs backed up"
+        ).unwrap();
 }
@@ -13,17 +13,24 @@
 
 use std::boxed::FnBox;
 use kvproto::coprocessor::Response;
+
 mod metrics;
+mod common_name;
 mod grpc_service;
+mod debug_service;
 mod raft_client;
 
+pub mod backup;
 pub mod config;
+pub mod debug;
 pub mod errors;
 pub mod server;
 pub mod transport;
 pub mod node;
 pub mod resolve;
+pub mod restore;
 pub mod snap;
+pub mod status_server;
 
 pub use self::config::{Config, DEFAULT_CLUSTER_ID, DEFAULT_LISTENING_ADDR};
 pub use self::errors::{Error, Result};
@@ -32,5 +39,6 @@ pub use self::transport::{ServerRaftStoreRouter, ServerTransport};
 pub use self::node::{create_raft_storage, Node};
 pub use self::resolve::{PdStoreAddrResolver, StoreAddrResolver};
 pub use self::raft_client::RaftClient;
+pub use self::status_server::StatusServer;
 
 pub type OnResponse = Box<FnBox(Response) + Send>;
@@ -39,8 +39,9 @@ pub fn create_raft_storage<S>(router: S, db: Arc<DB>, cfg: &StorageConfig) -> Re
 where
     S: RaftStoreRouter + 'static,
 {
-    let engine = box RaftKv::new(db, router);
-    let store = try!(Storage::from_engine(engine, cfg));
+    let engine = box RaftKv::new(db.clone(), router);
+    let mut store = try!(Storage::from_engine(engine, cfg));
+    store.set_flow_control_db(db);
     Ok(store)
 }
 
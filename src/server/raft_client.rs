@@ -27,6 +27,7 @@ const MAX_GRPC_SEND_MSG_LEN: usize = 10 * 1024 * 1024;
 const INITIAL_BUFFER_CAP: usize = 1024;
 
 use util::collections::HashMap;
+use util::security::SecurityManager;
 use super::{Config, Error, Result};
 use super::metrics::*;
 
@@ -41,16 +42,22 @@ struct Conn {
 }
 
 impl Conn {
-    fn new(env: Arc<Environment>, addr: SocketAddr, cfg: &Config, store_id: u64) -> Conn {
+    fn new(
+        env: Arc<Environment>,
+        addr: SocketAddr,
+        cfg: &Config,
+        security_mgr: &SecurityManager,
+        store_id: u64,
+    ) -> Conn {
         info!("server: new connection with tikv endpoint: {}", addr);
 
         let alive = Arc::new(AtomicBool::new(true));
         let alive1 = alive.clone();
-        let channel = ChannelBuilder::new(env)
+        let cb = ChannelBuilder::new(env)
             .stream_initial_window_size(cfg.grpc_stream_initial_window_size.0 as usize)
             .max_receive_message_len(MAX_GRPC_RECV_MSG_LEN)
-            .max_send_message_len(MAX_GRPC_SEND_MSG_LEN)
-            .connect(&format!("{}", addr));
+            .max_send_message_len(MAX_GRPC_SEND_MSG_LEN);
+        let channel = security_mgr.connect(cb, &format!("{}", addr));
         let client = TikvClient::new(channel);
         let (tx, rx) = mpsc::unbounded();
         let (tx_close, rx_close) = oneshot::channel();
@@ -100,15 +107,21 @@ pub struct RaftClient {
     conns: HashMap<(SocketAddr, usize), Conn>,
     pub addrs: HashMap<u64, SocketAddr>,
     cfg: Config,
+    security_mgr: Arc<SecurityManager>,
 }
 
 impl RaftClient {
-    pub fn new(env: Arc<Environment>, cfg: Config) -> RaftClient {
+    pub fn new(
+        env: Arc<Environment>,
+        cfg: Config,
+        security_mgr: Arc<SecurityManager>,
+    ) -> RaftClient {
         RaftClient {
             env: env,
             conns: HashMap::default(),
             addrs: HashMap::default(),
             cfg: cfg,
+            security_mgr: security_mgr,
         }
     }
 
@@ -116,9 +129,10 @@ impl RaftClient {
         let index = region_id as usize % self.cfg.grpc_raft_conn_num;
         let cfg = &self.cfg;
         let env = &self.env;
+        let security_mgr = &self.security_mgr;
         self.conns
             .entry((addr, index))
-            .or_insert_with(|| Conn::new(env.clone(), addr, cfg, store_id))
+            .or_insert_with(|| Conn::new(env.clone(), addr, cfg, security_mgr, store_id))
     }
 
     pub fn send(&mut self, store_id: u64, addr: SocketAddr, msg: RaftMessage) -> Result<()> {
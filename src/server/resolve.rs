@@ -229,6 +229,9 @@ mod tests {
         fn report_split(&self, _: metapb::Region, _: metapb::Region) -> PdFuture<()> {
             unimplemented!();
         }
+        fn scatter_region(&self, _: metapb::Region, _: metapb::Peer) -> PdFuture<()> {
+            unimplemented!();
+        }
     }
 
     fn new_store(addr: &str, state: metapb::StoreState) -> metapb::Store {
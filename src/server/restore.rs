@@ -0,0 +1,762 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use futures::{stream, Future, Stream};
+use grpc::{CallOption, ChannelBuilder, Environment, Error as GrpcError, WriteFlags};
+use kvproto::errorpb::Error as RegionError;
+use kvproto::import_sstpb::{IngestRequest, SSTMeta, UploadRequest};
+use kvproto::import_sstpb_grpc::ImportSstClient;
+use kvproto::metapb::{Peer, Region};
+use kvproto::raft_serverpb::PeerState;
+use protobuf::RepeatedField;
+use rand::{self, Rng};
+use rocksdb::{ColumnFamilyOptions, EnvOptions, IngestExternalFileOptions, SeekKey,
+              SstFileWriter, DB};
+use tempdir::TempDir;
+
+use raftstore::store::{keys, Msg};
+use raftstore::store::util::find_peer;
+use storage::{is_short_value, Engine, Key, CF_DEFAULT, CF_WRITE};
+use storage::mvcc::{Write, WriteType};
+use util::{escape, unescape};
+use util::file::{calc_crc32, get_file_size};
+use util::io_limiter::{self, IOType};
+use util::rocksdb::{get_fastest_supported_compression_type, get_sst_key_range, new_engine};
+use util::security::SecurityManager;
+use super::backup::{check_backup, decode_backup_value, leader_snapshot, load_backup_metas,
+                    raw_key, Error, Result};
+use super::debug::Debugger;
+use super::resolve::StoreAddrResolver;
+use super::transport::RaftStoreRouter;
+
+const SPLIT_RETRY_LIMIT: usize = 30;
+const SPLIT_CHECK_INTERVAL_SECS: u64 = 1;
+// The peers of a region may be moved several times while it's scattered.
+const INGEST_RETRY_LIMIT: usize = 30;
+const INGEST_RETRY_INTERVAL_SECS: u64 = 1;
+const INGEST_TIMEOUT_SECS: u64 = 60;
+const RESOLVE_TIMEOUT_SECS: u64 = 10;
+const UPLOAD_CHUNK_LEN: usize = 1024 * 1024;
+
+/// A restore of the backup in `path`. Keys starting with `old_prefix` get `new_prefix`
+/// instead, keys without it are skipped, an empty `old_prefix` matches all keys.
+///
+/// A backup only holds the versions visible at the backup ts. By default each of them
+/// is restored at its original commit ts. A non-zero `restore_ts` rewrites all of them
+/// to be committed at `restore_ts` instead, which loses their commit ts, e.g. for
+/// restoring into a cluster whose timestamps are behind the backup ts.
+#[derive(Clone, Debug, Default)]
+pub struct RestoreTask {
+    pub path: PathBuf,
+    pub old_prefix: Vec<u8>,
+    pub new_prefix: Vec<u8>,
+    pub restore_ts: u64,
+}
+
+/// The data restored into a region.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RegionRestore {
+    pub region_id: u64,
+    pub kvs: u64,
+    pub size: u64,
+}
+
+// An SST file built for a region.
+struct RegionSST {
+    meta: SSTMeta,
+    path: PathBuf,
+    kvs: u64,
+}
+
+/// `Restore` loads a backup into the leader regions on the store. The regions are
+/// split at the boundaries of the backed up regions first, and PD is asked to scatter
+/// the new regions over the stores, so the restored data doesn't stay on the stores of
+/// the original region. The data of each region is written to SST files, which are
+/// uploaded to every store holding a peer of the region by the `ImportSst` service.
+/// Then the leader proposes a raft command carrying only the meta of the files, so
+/// every replica ingests the same files from its own store. The IO is paced by the
+/// import limiter on the store running the restore, applying the command isn't
+/// throttled.
+///
+/// Every store running the restore must be able to read the backup directory. The
+/// files are built again and uploaded with new uuids if the region changes before
+/// they are ingested.
+pub struct Restore<T: RaftStoreRouter, S: StoreAddrResolver> {
+    debugger: Debugger,
+    engine: Box<Engine>,
+    router: T,
+    resolver: S,
+    env: Arc<Environment>,
+    security_mgr: Arc<SecurityManager>,
+}
+
+impl<T: RaftStoreRouter, S: StoreAddrResolver> Clone for Restore<T, S> {
+    fn clone(&self) -> Restore<T, S> {
+        Restore {
+            debugger: self.debugger.clone(),
+            engine: self.engine.clone(),
+            router: self.router.clone(),
+            resolver: self.resolver.clone(),
+            env: self.env.clone(),
+            security_mgr: self.security_mgr.clone(),
+        }
+    }
+}
+
+impl<T: RaftStoreRouter, S: StoreAddrResolver> Restore<T, S> {
+    pub fn new(
+        debugger: Debugger,
+        engine: Box<Engine>,
+        router: T,
+        resolver: S,
+        env: Arc<Environment>,
+        security_mgr: Arc<SecurityManager>,
+    ) -> Restore<T, S> {
+        Restore {
+            debugger: debugger,
+            engine: engine,
+            router: router,
+            resolver: resolver,
+            env: env,
+            security_mgr: security_mgr,
+        }
+    }
+
+    /// Restores the backup, `on_progress` is called with the data restored into each
+    /// region, the number of regions processed and the total number.
+    pub fn run<F>(&self, task: &RestoreTask, mut on_progress: F) -> Result<()>
+    where
+        F: FnMut(&RegionRestore, usize, usize),
+    {
+        let errors = try!(check_backup(&task.path));
+        if !errors.is_empty() {
+            return Err(Error::InvalidArgument(errors.join("; ")));
+        }
+        let metas = try!(load_backup_metas(&task.path));
+        let store_id = box_try!(self.debugger.get_store_ident()).get_store_id();
+
+        let mut split_keys = vec![];
+        for meta in &metas {
+            for region in &meta.regions {
+                let start = unescape(&region.start_key);
+                if let Some(key) = rewrite_key(&start, &task.old_prefix, &task.new_prefix) {
+                    if !key.is_empty() {
+                        split_keys.push(Key::from_raw(&key).encoded().clone());
+                    }
+                }
+            }
+        }
+        split_keys.sort();
+        split_keys.dedup();
+        try!(self.split_regions(store_id, &split_keys));
+        let regions = try!(self.leader_regions(store_id));
+        // The regions are restored while they are being scattered, the ingestion follows
+        // the peers moved by the retries on region errors.
+        for region in &regions {
+            let msg = Msg::ScatterRegion {
+                region_id: region.get_id(),
+                epoch: region.get_region_epoch().clone(),
+            };
+            if let Err(e) = self.router.try_send(msg) {
+                warn!("failed to scatter region {}: {:?}", region.get_id(), e);
+            }
+        }
+        let mut ranges = Vec::with_capacity(regions.len());
+        for region in &regions {
+            let start = try!(raw_key(region.get_start_key()));
+            let end = try!(raw_key(region.get_end_key()));
+            ranges.push((start, end));
+        }
+
+        // The backed up pairs rewritten into the leader regions are loaded into a
+        // temporary db, so the pairs of a region can be read in order no matter how the
+        // backup was split.
+        let tmp = box_try!(TempDir::new_in(&task.path, "restore"));
+        let db = box_try!(new_engine(tmp.path().to_str().unwrap(), &[CF_DEFAULT]));
+        let opts = IngestExternalFileOptions::new();
+        for meta in &metas {
+            for region in &meta.regions {
+                let file = match region.file {
+                    Some(ref file) => file,
+                    None => continue,
+                };
+                let (start, end) = (unescape(&region.start_key), unescape(&region.end_key));
+                let overlapped = match rewrite_range(&start, &end, task) {
+                    Some((start, end)) => ranges.iter().any(|&(ref s, ref e)| {
+                        (e.is_empty() || start < *e) && end.as_ref().map_or(true, |end| s < end)
+                    }),
+                    None => false,
+                };
+                if !overlapped {
+                    continue;
+                }
+                io_limiter::request_io(IOType::Import, file.size as usize);
+                let path = task.path.join(&file.name);
+                box_try!(db.ingest_external_file(&opts, &[path.to_str().unwrap()]));
+            }
+        }
+
+        // The files of a region are removed once they are uploaded.
+        let dir = box_try!(TempDir::new_in(&task.path, "restore_ssts"));
+        info!(
+            "store {} starts restore from {} backed up at {}, rewrite ts to {}, rewrite \
+             prefix {} to {}, {} regions",
+            store_id,
+            task.path.display(),
+            metas[0].backup_ts,
+            task.restore_ts,
+            escape(&task.old_prefix),
+            escape(&task.new_prefix),
+            regions.len()
+        );
+        let total = regions.len();
+        for (i, region) in regions.into_iter().enumerate() {
+            let restore = try!(self.restore_region(store_id, &db, region, task, dir.path()));
+            on_progress(&restore, i + 1, total);
+        }
+        info!(
+            "store {} finishes restore from {}",
+            store_id,
+            task.path.display()
+        );
+        Ok(())
+    }
+
+    // Restores the pairs rewritten into the range of the region. The region may change
+    // before the files are ingested, then they are built again for the new region and
+    // uploaded with new uuids. If the region was split, the rest of the range is
+    // restored into the regions split from it.
+    fn restore_region(
+        &self,
+        store_id: u64,
+        db: &DB,
+        mut region: Region,
+        task: &RestoreTask,
+        dir: &Path,
+    ) -> Result<RegionRestore> {
+        let mut restore = RegionRestore {
+            region_id: region.get_id(),
+            ..Default::default()
+        };
+        let (start_key, end_key) = (region.get_start_key().to_vec(), region.get_end_key().to_vec());
+        let mut leader = find_peer(&region, store_id).unwrap().clone();
+        // The regions reported by stale epoch errors, to find the regions split from it.
+        let mut new_regions = vec![];
+        let mut retry = 0;
+        loop {
+            // The region may be merged, only the original range is restored into it.
+            let mut target = region.clone();
+            if target.get_start_key() < start_key.as_slice() {
+                target.set_start_key(start_key.clone());
+            }
+            if !end_key.is_empty() &&
+                (target.get_end_key().is_empty() || target.get_end_key() > end_key.as_slice())
+            {
+                target.set_end_key(end_key.clone());
+            }
+            let ssts = try!(build_region_ssts(db, &target, task, dir));
+            if !ssts.is_empty() {
+                let res = self.upload(&region, &ssts)
+                    .and_then(|_| self.ingest(&region, &leader, &ssts));
+                for sst in &ssts {
+                    if let Err(e) = fs::remove_file(&sst.path) {
+                        warn!("failed to remove {}: {:?}", sst.path.display(), e);
+                    }
+                }
+                if let Some(mut e) = try!(res) {
+                    retry += 1;
+                    if retry > INGEST_RETRY_LIMIT {
+                        return Err(box_err!(
+                            "failed to ingest into region {} after {} retries: {:?}",
+                            region.get_id(),
+                            INGEST_RETRY_LIMIT,
+                            e
+                        ));
+                    }
+                    warn!("failed to ingest into region {}: {:?}", region.get_id(), e);
+                    if e.has_not_leader() && e.get_not_leader().has_leader() {
+                        leader = e.mut_not_leader().take_leader();
+                    } else if e.has_not_leader() || e.has_region_not_found() {
+                        // The peer is moved away or doesn't know the leader, the other
+                        // peers may know the region.
+                        leader = next_peer(&region, &leader);
+                    } else if e.has_stale_epoch() {
+                        new_regions.extend(e.mut_stale_epoch().take_new_regions().into_iter());
+                        region = try!(find_region(&new_regions, target.get_start_key()));
+                        leader = pick_leader(&region, leader.get_store_id());
+                    } else {
+                        return Err(box_err!(
+                            "failed to ingest into region {}: {:?}",
+                            region.get_id(),
+                            e
+                        ));
+                    }
+                    thread::sleep(Duration::from_secs(INGEST_RETRY_INTERVAL_SECS));
+                    continue;
+                }
+                restore.size += ssts.iter().map(|sst| sst.meta.get_length()).sum::<u64>();
+                // The write cf comes last, it has a pair for every key.
+                restore.kvs += ssts.last().unwrap().kvs;
+            }
+            let region_end = region.get_end_key().to_vec();
+            if region_end.is_empty() || (!end_key.is_empty() && region_end >= end_key) {
+                return Ok(restore);
+            }
+            // The region was split, the rest of the range is in the regions after it.
+            region = try!(find_region(&new_regions, &region_end));
+            leader = pick_leader(&region, leader.get_store_id());
+        }
+    }
+
+    fn connect(&self, store_id: u64) -> Result<ImportSstClient> {
+        let (tx, rx) = mpsc::channel();
+        box_try!(self.resolver.resolve(
+            store_id,
+            box move |addr| {
+                let _ = tx.send(addr);
+            }
+        ));
+        let addr: SocketAddr =
+            box_try!(box_try!(rx.recv_timeout(Duration::from_secs(RESOLVE_TIMEOUT_SECS))));
+        let channel = self.security_mgr
+            .connect(ChannelBuilder::new(self.env.clone()), &format!("{}", addr));
+        Ok(ImportSstClient::new(channel))
+    }
+
+    // Uploads the files to every store holding a peer of the region.
+    fn upload(&self, region: &Region, ssts: &[RegionSST]) -> Result<()> {
+        for peer in region.get_peers() {
+            let client = try!(self.connect(peer.get_store_id()));
+            for sst in ssts {
+                let mut data = Vec::with_capacity(sst.meta.get_length() as usize);
+                box_try!(box_try!(File::open(&sst.path)).read_to_end(&mut data));
+                io_limiter::request_io(IOType::Import, data.len());
+                let mut chunk = UploadRequest::new();
+                chunk.set_meta(sst.meta.clone());
+                let mut chunks = vec![Ok((chunk, WriteFlags::default()))];
+                for data in data.chunks(UPLOAD_CHUNK_LEN) {
+                    let mut chunk = UploadRequest::new();
+                    chunk.set_data(data.to_vec());
+                    chunks.push(Ok((chunk, WriteFlags::default())));
+                }
+                let (sink, receiver) = client.upload();
+                let res = stream::iter::<_, _, GrpcError>(chunks)
+                    .forward(sink)
+                    .and_then(|_| receiver)
+                    .wait();
+                if let Err(e) = res {
+                    return Err(box_err!(
+                        "failed to upload {:?} to store {}: {:?}",
+                        sst.meta,
+                        peer.get_store_id(),
+                        e
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Asks the store of the leader to ingest the files, returns the region error if
+    // they are not ingested.
+    fn ingest(
+        &self,
+        region: &Region,
+        leader: &Peer,
+        ssts: &[RegionSST],
+    ) -> Result<Option<RegionError>> {
+        let mut req = IngestRequest::new();
+        req.mut_context().set_region_id(region.get_id());
+        req.mut_context()
+            .set_region_epoch(region.get_region_epoch().clone());
+        req.mut_context().set_peer(leader.clone());
+        let metas = ssts.iter().map(|sst| sst.meta.clone()).collect();
+        req.set_ssts(RepeatedField::from_vec(metas));
+
+        let client = try!(self.connect(leader.get_store_id()));
+        let option = CallOption::default().timeout(Duration::from_secs(INGEST_TIMEOUT_SECS));
+        let mut resp = box_try!(client.ingest_opt(req, option));
+        if resp.has_error() {
+            return Ok(Some(resp.take_error()));
+        }
+        Ok(None)
+    }
+
+    fn leader_regions(&self, store_id: u64) -> Result<Vec<Region>> {
+        let mut regions = vec![];
+        for mut state in box_try!(self.debugger.region_states()) {
+            if state.get_state() != PeerState::Normal {
+                continue;
+            }
+            let region = state.take_region();
+            if try!(leader_snapshot(self.engine.as_ref(), store_id, &region)).is_some() {
+                regions.push(region);
+            }
+        }
+        regions.sort_by(|a, b| a.get_start_key().cmp(b.get_start_key()));
+        Ok(regions)
+    }
+
+    // Asks the leader regions to split at the keys until none of them contains a key.
+    // It gives up after a while, the data can still be restored into larger regions.
+    fn split_regions(&self, store_id: u64, split_keys: &[Vec<u8>]) -> Result<()> {
+        for _ in 0..SPLIT_RETRY_LIMIT {
+            let mut pending = 0;
+            for region in try!(self.leader_regions(store_id)) {
+                let (start, end) = (region.get_start_key(), region.get_end_key());
+                let key = split_keys
+                    .iter()
+                    .find(|k| start < k.as_slice() && (end.is_empty() || k.as_slice() < end));
+                let key = match key {
+                    Some(key) => key,
+                    None => continue,
+                };
+                pending += 1;
+                let msg = Msg::SplitCheckResult {
+                    region_id: region.get_id(),
+                    epoch: region.get_region_epoch().clone(),
+                    split_key: keys::data_key(key),
+                };
+                if let Err(e) = self.router.try_send(msg) {
+                    warn!("failed to split region {}: {:?}", region.get_id(), e);
+                }
+            }
+            if pending == 0 {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_secs(SPLIT_CHECK_INTERVAL_SECS));
+        }
+        warn!(
+            "store {} gives up splitting regions for restore after {} retries",
+            store_id,
+            SPLIT_RETRY_LIMIT
+        );
+        Ok(())
+    }
+}
+
+/// Replaces `old_prefix` of the key with `new_prefix`, returns `None` if the key
+/// doesn't start with `old_prefix`.
+pub fn rewrite_key(key: &[u8], old_prefix: &[u8], new_prefix: &[u8]) -> Option<Vec<u8>> {
+    if !key.starts_with(old_prefix) {
+        return None;
+    }
+    let mut res = new_prefix.to_vec();
+    res.extend_from_slice(&key[old_prefix.len()..]);
+    Some(res)
+}
+
+// Returns the range the raw keys in `[start, end)` are rewritten into by the task, or
+// `None` if none of them is rewritten. The range may be larger than the rewritten keys,
+// `None` as the end key means unbounded.
+fn rewrite_range(
+    start: &[u8],
+    end: &[u8],
+    task: &RestoreTask,
+) -> Option<(Vec<u8>, Option<Vec<u8>>)> {
+    let (old_prefix, new_prefix) = (&task.old_prefix, &task.new_prefix);
+    let start = if start.starts_with(old_prefix) {
+        rewrite_key(start, old_prefix, new_prefix).unwrap()
+    } else if start < old_prefix.as_slice() {
+        new_prefix.clone()
+    } else {
+        return None;
+    };
+    let end = if end.is_empty() {
+        None
+    } else if end <= old_prefix.as_slice() {
+        return None;
+    } else if end.starts_with(old_prefix) {
+        rewrite_key(end, old_prefix, new_prefix)
+    } else {
+        None
+    };
+    Some((start, end))
+}
+
+// Returns the region containing the key.
+fn find_region(regions: &[Region], key: &[u8]) -> Result<Region> {
+    match regions.iter().rev().find(|r| {
+        r.get_start_key() <= key && (r.get_end_key().is_empty() || key < r.get_end_key())
+    }) {
+        Some(region) => Ok(region.clone()),
+        None => Err(box_err!("no region contains key {}", escape(key))),
+    }
+}
+
+// Returns the peer of the region on the store, or the first peer of it. A wrong guess
+// is corrected by the not leader error of the ingestion.
+fn pick_leader(region: &Region, store_id: u64) -> Peer {
+    match find_peer(region, store_id) {
+        Some(peer) => peer.clone(),
+        None => region.get_peers()[0].clone(),
+    }
+}
+
+// Returns the peer after `peer` in the region.
+fn next_peer(region: &Region, peer: &Peer) -> Peer {
+    let peers = region.get_peers();
+    match peers.iter().position(|p| p.get_id() == peer.get_id()) {
+        Some(i) => peers[(i + 1) % peers.len()].clone(),
+        None => peers[0].clone(),
+    }
+}
+
+// Writes the pairs in `db` falling into the region after rewriting to SST files in
+// `dir`, committed at their backed up commit ts, or at the restore ts of the task if
+// it's set. Each file gets a new uuid. Returns the files with the number of pairs in
+// each of them, the default cf comes first so values are in place before the writes.
+fn build_region_ssts(
+    db: &DB,
+    region: &Region,
+    task: &RestoreTask,
+    dir: &Path,
+) -> Result<Vec<RegionSST>> {
+    let start = try!(raw_key(region.get_start_key()));
+    let end = try!(raw_key(region.get_end_key()));
+    let (old_prefix, new_prefix) = (&task.old_prefix, &task.new_prefix);
+    // The smallest key in `db` which is rewritten into the region.
+    let seek_key = if start.starts_with(new_prefix) {
+        rewrite_key(&start, new_prefix, old_prefix).unwrap()
+    } else if start.as_slice() < new_prefix.as_slice() {
+        old_prefix.clone()
+    } else {
+        return Ok(vec![]);
+    };
+
+    let path = |cf: &str| {
+        dir.join(format!(
+            "{}_{}_{}_{}.sst",
+            region.get_id(),
+            region.get_region_epoch().get_conf_ver(),
+            region.get_region_epoch().get_version(),
+            cf
+        ))
+    };
+    let new_writer = |cf: &str| -> Result<SstFileWriter> {
+        let mut opts = ColumnFamilyOptions::new();
+        opts.compression(get_fastest_supported_compression_type());
+        let mut writer = SstFileWriter::new(EnvOptions::new(), opts);
+        box_try!(writer.open(path(cf).to_str().unwrap()));
+        Ok(writer)
+    };
+    let mut default_writer = None;
+    let mut write_writer = None;
+    let (mut default_kvs, mut write_kvs) = (0, 0);
+    let mut iter = db.iter();
+    iter.seek(SeekKey::Key(&seek_key));
+    while iter.valid() {
+        let key = match rewrite_key(iter.key(), old_prefix, new_prefix) {
+            Some(key) => key,
+            None => break,
+        };
+        if !end.is_empty() && key >= end {
+            break;
+        }
+        io_limiter::request_io(IOType::Import, key.len() + iter.value().len());
+        let (commit_ts, value) = try!(decode_backup_value(iter.value()));
+        let ts = if task.restore_ts == 0 {
+            commit_ts
+        } else {
+            task.restore_ts
+        };
+        let key = Key::from_raw(&key).append_ts(ts);
+        let write = if is_short_value(value) {
+            Write::new(WriteType::Put, ts, Some(value.to_vec()))
+        } else {
+            if default_writer.is_none() {
+                default_writer = Some(try!(new_writer(CF_DEFAULT)));
+            }
+            let data_key = keys::data_key(key.encoded());
+            box_try!(default_writer.as_mut().unwrap().put(&data_key, value));
+            default_kvs += 1;
+            Write::new(WriteType::Put, ts, None)
+        };
+        if write_writer.is_none() {
+            write_writer = Some(try!(new_writer(CF_WRITE)));
+        }
+        let data_key = keys::data_key(key.encoded());
+        box_try!(write_writer.as_mut().unwrap().put(&data_key, &write.to_bytes()));
+        write_kvs += 1;
+        iter.next();
+    }
+
+    if write_kvs == 0 {
+        return Ok(vec![]);
+    }
+    let mut ssts = vec![];
+    for (cf, writer, kvs) in vec![
+        (CF_DEFAULT, default_writer, default_kvs),
+        (CF_WRITE, write_writer, write_kvs),
+    ] {
+        let mut writer = match writer {
+            Some(writer) => writer,
+            None => continue,
+        };
+        box_try!(writer.finish());
+        let path = path(cf);
+        let (smallest, largest) = match box_try!(get_sst_key_range(path.to_str().unwrap())) {
+            Some(range) => range,
+            None => continue,
+        };
+        let mut uuid = vec![0; 16];
+        rand::thread_rng().fill_bytes(&mut uuid);
+        let mut meta = SSTMeta::new();
+        meta.set_uuid(uuid);
+        meta.mut_range().set_start(keys::origin_key(&smallest).to_vec());
+        meta.mut_range().set_end(keys::origin_key(&largest).to_vec());
+        meta.set_crc32(box_try!(calc_crc32(&path)));
+        meta.set_length(box_try!(get_file_size(&path)));
+        meta.set_cf_name(cf.to_owned());
+        meta.set_region_id(region.get_id());
+        meta.set_region_epoch(region.get_region_epoch().clone());
+        ssts.push(RegionSST {
+            meta: meta,
+            path: path,
+            kvs: kvs,
+        });
+    }
+    Ok(ssts)
+}
+
+#[cfg(test)]
+mod tests {
+    use rocksdb::Writable;
+    use tempdir::TempDir;
+
+    use raftstore::store::engine::Peekable;
+    use server::backup::encode_backup_value;
+    use storage::ALL_CFS;
+    use util::rocksdb::get_cf_handle;
+    use super::*;
+
+    #[test]
+    fn test_rewrite_key() {
+        assert_eq!(rewrite_key(b"t1_r1", b"t1", b"t22"), Some(b"t22_r1".to_vec()));
+        assert_eq!(rewrite_key(b"t1_r1", b"", b"x"), Some(b"xt1_r1".to_vec()));
+        assert_eq!(rewrite_key(b"t1_r1", b"t1", b""), Some(b"_r1".to_vec()));
+        assert_eq!(rewrite_key(b"t2_r1", b"t1", b"t3"), None);
+        assert_eq!(rewrite_key(b"t", b"t1", b"t3"), None);
+    }
+
+    #[test]
+    fn test_rewrite_range() {
+        let task = RestoreTask {
+            old_prefix: b"t1".to_vec(),
+            new_prefix: b"t3".to_vec(),
+            ..Default::default()
+        };
+        let range = |start: &[u8], end: &[u8]| rewrite_range(start, end, &task);
+        let some = |start: &[u8], end: Option<&[u8]>| {
+            Some((start.to_vec(), end.map(|e| e.to_vec())))
+        };
+        assert_eq!(range(b"t1_1", b"t1_5"), some(b"t3_1", Some(&b"t3_5"[..])));
+        assert_eq!(range(b"a", b"t1_5"), some(b"t3", Some(&b"t3_5"[..])));
+        assert_eq!(range(b"t1_1", b""), some(b"t3_1", None));
+        assert_eq!(range(b"t1_1", b"t2"), some(b"t3_1", None));
+        assert_eq!(range(b"a", b"t1"), None);
+        assert_eq!(range(b"t2", b"t3"), None);
+    }
+
+    #[test]
+    fn test_build_region_ssts() {
+        let tmp = TempDir::new("test_build_region_ssts_src").unwrap();
+        let src = new_engine(tmp.path().to_str().unwrap(), &[CF_DEFAULT]).unwrap();
+        let long_value = vec![b'v'; 100];
+        let put = |key: &[u8], commit_ts, value: &[u8]| {
+            src.put(key, &encode_backup_value(commit_ts, value)).unwrap();
+        };
+        put(b"a1", 5, b"v");
+        put(b"t1_1", 5, b"v1");
+        put(b"t1_2", 6, &long_value);
+        put(b"t1_9", 5, b"v9");
+        put(b"t2_1", 5, b"v");
+
+        // The region covers ["t3_", "t3_5") after rewriting.
+        let mut region = Region::new();
+        region.set_id(1);
+        region.set_start_key(Key::from_raw(b"t3_").encoded().clone());
+        region.set_end_key(Key::from_raw(b"t3_5").encoded().clone());
+        let task = RestoreTask {
+            old_prefix: b"t1".to_vec(),
+            new_prefix: b"t3".to_vec(),
+            restore_ts: 10,
+            ..Default::default()
+        };
+        let dir = TempDir::new("test_build_region_ssts").unwrap();
+        let ssts = build_region_ssts(&src, &region, &task, dir.path()).unwrap();
+        let cfs: Vec<_> = ssts.iter()
+            .map(|sst| (sst.meta.get_cf_name(), sst.kvs))
+            .collect();
+        assert_eq!(cfs, vec![(CF_DEFAULT, 1), (CF_WRITE, 2)]);
+        assert_ne!(ssts[0].meta.get_uuid(), ssts[1].meta.get_uuid());
+        for sst in &ssts {
+            let path = sst.path.to_str().unwrap();
+            let (smallest, largest) = get_sst_key_range(path).unwrap().unwrap();
+            assert!(smallest >= keys::enc_start_key(&region));
+            assert!(largest < keys::enc_end_key(&region));
+            assert_eq!(sst.meta.get_range().get_start(), keys::origin_key(&smallest));
+            assert_eq!(sst.meta.get_range().get_end(), keys::origin_key(&largest));
+            assert_eq!(sst.meta.get_region_id(), region.get_id());
+            assert_eq!(sst.meta.get_length(), get_file_size(&sst.path).unwrap());
+            assert_eq!(sst.meta.get_crc32(), calc_crc32(&sst.path).unwrap());
+        }
+
+        let tmp = TempDir::new("test_build_region_ssts_dst").unwrap();
+        let dst = new_engine(tmp.path().to_str().unwrap(), ALL_CFS).unwrap();
+        let opts = IngestExternalFileOptions::new();
+        for sst in &ssts {
+            let handle = get_cf_handle(&dst, sst.meta.get_cf_name()).unwrap();
+            dst.ingest_external_file_cf(handle, &opts, &[sst.path.to_str().unwrap()])
+                .unwrap();
+        }
+        let data_key = |key: &[u8]| keys::data_key(Key::from_raw(key).append_ts(10).encoded());
+        let get_write = |key: &[u8]| {
+            dst.get_value_cf(CF_WRITE, &data_key(key))
+                .unwrap()
+                .map(|v| Write::parse(&v).unwrap())
+        };
+        let write = get_write(b"t3_1").unwrap();
+        assert_eq!(write.start_ts, 10);
+        assert_eq!(write.short_value, Some(b"v1".to_vec()));
+        let write = get_write(b"t3_2").unwrap();
+        assert_eq!(write.short_value, None);
+        let value = dst.get_value_cf(CF_DEFAULT, &data_key(b"t3_2")).unwrap();
+        assert_eq!(value.unwrap().to_vec(), long_value);
+        assert!(get_write(b"t3_9").is_none());
+        assert!(get_write(b"t1_1").is_none());
+
+        // Without a restore ts, the backed up commit ts are kept.
+        let task = RestoreTask {
+            restore_ts: 0,
+            ..task
+        };
+        let dir = TempDir::new("test_build_region_ssts_keep_ts").unwrap();
+        let ssts = build_region_ssts(&src, &region, &task, dir.path()).unwrap();
+        let write_sst = ssts.last().unwrap();
+        assert_eq!(write_sst.meta.get_cf_name(), CF_WRITE);
+        let path = write_sst.path.to_str().unwrap();
+        let (smallest, largest) = get_sst_key_range(path).unwrap().unwrap();
+        let data_key = |key: &[u8], ts| keys::data_key(Key::from_raw(key).append_ts(ts).encoded());
+        assert_eq!(smallest, data_key(b"t3_1", 5));
+        assert_eq!(largest, data_key(b"t3_2", 6));
+    }
+}
@@ -11,20 +11,30 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::sync::{Arc, RwLock};
-use std::sync::mpsc::Sender;
+use std::mem;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
+use futures::Future;
 use grpc::{ChannelBuilder, EnvBuilder, Environment, Server as GrpcServer, ServerBuilder};
 use kvproto::tikvpb_grpc::*;
-use util::worker::Worker;
+use kvproto::debugpb_grpc::create_debug;
+use kvproto::import_sstpb_grpc::create_import_sst;
+use import::ImportSSTService;
+use util::security::SecurityManager;
+use util::worker::{Scheduler, Worker};
 use storage::Storage;
-use raftstore::store::{SnapManager, SnapshotStatusMsg};
+use raftstore::store::{Engines, SnapManager, SnapshotStatusMsg};
 
 use super::{Config, Result};
 use coprocessor::{EndPointHost, EndPointTask};
+use super::common_name::CheckCommonName;
 use super::grpc_service::Service;
+use super::debug_service::Service as DebugService;
 use super::transport::{RaftStoreRouter, ServerTransport};
 use super::resolve::StoreAddrResolver;
 use super::snap::{Runner as SnapHandler, Task as SnapTask};
@@ -32,11 +42,54 @@ use super::raft_client::RaftClient;
 
 const DEFAULT_COPROCESSOR_BATCH: usize = 256;
 const MAX_GRPC_RECV_MSG_LEN: usize = 10 * 1024 * 1024;
+const CERT_CHECK_INTERVAL_SECS: u64 = 10;
+
+// Builds the grpc server, again when the certificates are reloaded.
+#[derive(Clone)]
+struct GrpcServerFactory<T: RaftStoreRouter + 'static, S: StoreAddrResolver + 'static> {
+    env: Arc<Environment>,
+    cfg: Config,
+    region_split_size: usize,
+    service: Service<T>,
+    debug_service: Option<DebugService<T, S>>,
+    import_service: Option<ImportSSTService<T>>,
+    security_mgr: Arc<SecurityManager>,
+}
+
+impl<T: RaftStoreRouter + 'static, S: StoreAddrResolver + 'static> GrpcServerFactory<T, S> {
+    fn build(&self, ip: String, port: u16) -> Result<GrpcServer> {
+        let channel_args = ChannelBuilder::new(self.env.clone())
+            .stream_initial_window_size(self.cfg.grpc_stream_initial_window_size.0 as usize)
+            .max_concurrent_stream(self.cfg.grpc_concurrent_stream)
+            .max_receive_message_len(MAX_GRPC_RECV_MSG_LEN)
+            .max_send_message_len(self.region_split_size * 4)
+            .build_args();
+        let service = CheckCommonName::new(self.service.clone(), self.security_mgr.clone());
+        let mut sb = ServerBuilder::new(self.env.clone()).register_service(create_tikv(service));
+        if let Some(ref debug_service) = self.debug_service {
+            let service = CheckCommonName::new(debug_service.clone(), self.security_mgr.clone());
+            sb = sb.register_service(create_debug(service));
+        }
+        if let Some(ref import_service) = self.import_service {
+            let service = CheckCommonName::new(import_service.clone(), self.security_mgr.clone());
+            sb = sb.register_service(create_import_sst(service));
+        }
+        let grpc_server = try!(
+            self.security_mgr
+                .bind(sb, &ip, port)
+                .channel_args(channel_args)
+                .build()
+        );
+        Ok(grpc_server)
+    }
+}
 
 pub struct Server<T: RaftStoreRouter + 'static, S: StoreAddrResolver + 'static> {
     env: Arc<Environment>,
-    // Grpc server.
-    grpc_server: GrpcServer,
+    // Grpc server, it's rebuilt by the cert watcher when the certificates change.
+    grpc_server: Arc<Mutex<GrpcServer>>,
+    grpc_server_factory: GrpcServerFactory<T, S>,
+    cert_watcher: Option<(Sender<()>, JoinHandle<()>)>,
     local_addr: SocketAddr,
     // Transport.
     trans: ServerTransport<T, S>,
@@ -59,6 +112,8 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
         snapshot_status_sender: Sender<SnapshotStatusMsg>,
         resolver: S,
         snap_mgr: SnapManager,
+        security_mgr: Arc<SecurityManager>,
+        debug_engines: Option<Engines>,
     ) -> Result<Server<T, S>> {
         let env = Arc::new(
             EnvBuilder::new()
@@ -66,7 +121,9 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
                 .name_prefix(thd_name!("grpc-server"))
                 .build(),
         );
-        let raft_client = Arc::new(RwLock::new(RaftClient::new(env.clone(), cfg.clone())));
+        let raft_client = Arc::new(RwLock::new(
+            RaftClient::new(env.clone(), cfg.clone(), security_mgr.clone()),
+        ));
         let end_point_worker = Worker::new("end-point-worker");
         let snap_worker = Worker::new("snap-handler");
 
@@ -75,22 +132,34 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
             end_point_worker.scheduler(),
             raft_router.clone(),
             snap_worker.scheduler(),
+            snap_mgr.clone(),
         );
+        // The uploaded SST files are ingested by the store of the debug engines.
+        let import_service = debug_engines
+            .as_ref()
+            .and_then(|e| e.sst_importer.clone())
+            .map(|importer| ImportSSTService::new(raft_router.clone(), importer));
+        let debug_service = debug_engines.map(|e| {
+            DebugService::new(
+                e,
+                storage.clone(),
+                raft_router.clone(),
+                resolver.clone(),
+                env.clone(),
+                security_mgr.clone(),
+            )
+        });
+        let grpc_server_factory = GrpcServerFactory {
+            env: env.clone(),
+            cfg: cfg.clone(),
+            region_split_size: region_split_size,
+            service: h,
+            import_service: import_service,
+            debug_service: debug_service,
+            security_mgr: security_mgr,
+        };
         let addr = try!(SocketAddr::from_str(&cfg.addr));
-        let ip = format!("{}", addr.ip());
-        let channel_args = ChannelBuilder::new(env.clone())
-            .stream_initial_window_size(cfg.grpc_stream_initial_window_size.0 as usize)
-            .max_concurrent_stream(cfg.grpc_concurrent_stream)
-            .max_receive_message_len(MAX_GRPC_RECV_MSG_LEN)
-            .max_send_message_len(region_split_size as usize * 4)
-            .build_args();
-        let grpc_server = try!(
-            ServerBuilder::new(env.clone())
-                .register_service(create_tikv(h))
-                .bind(ip, addr.port())
-                .channel_args(channel_args)
-                .build()
-        );
+        let grpc_server = try!(grpc_server_factory.build(format!("{}", addr.ip()), addr.port()));
 
         let addr = {
             let (ref host, port) = grpc_server.bind_addrs()[0];
@@ -107,7 +176,9 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
 
         let svr = Server {
             env: env.clone(),
-            grpc_server: grpc_server,
+            grpc_server: Arc::new(Mutex::new(grpc_server)),
+            grpc_server_factory: grpc_server_factory,
+            cert_watcher: None,
             local_addr: addr,
             trans: trans,
             raft_router: raft_router,
@@ -120,6 +191,10 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
         Ok(svr)
     }
 
+    pub fn end_point_scheduler(&self) -> Scheduler<EndPointTask> {
+        self.end_point_worker.scheduler()
+    }
+
     pub fn transport(&self) -> ServerTransport<T, S> {
         self.trans.clone()
     }
@@ -129,6 +204,7 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
             self.storage.get_engine(),
             self.end_point_worker.scheduler(),
             cfg,
+            self.storage.get_resource_groups(),
         );
         box_try!(
             self.end_point_worker
@@ -138,20 +214,76 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
             self.env.clone(),
             self.snap_mgr.clone(),
             self.raft_router.clone(),
+            self.grpc_server_factory.security_mgr.clone(),
         );
         box_try!(self.snap_worker.start(snap_runner));
-        self.grpc_server.start();
+        self.grpc_server.lock().unwrap().start();
+        if self.grpc_server_factory.security_mgr.is_tls_enabled() {
+            try!(self.start_cert_watcher());
+        }
         info!("TiKV is ready to serve");
         Ok(())
     }
 
+    // The credentials of a grpc server can't be changed after it's built, so a new
+    // server is built on the same address when the certificates are reloaded. grpc
+    // binds with SO_REUSEPORT, so the new server is started while the old one is still
+    // serving, and the old one is shut down only after that, which lets the calls on
+    // its connections finish. If the new server can't be bound, the old one is kept.
+    fn start_cert_watcher(&mut self) -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let factory = self.grpc_server_factory.clone();
+        let grpc_server = self.grpc_server.clone();
+        let addr = self.local_addr;
+        let h = try!(
+            thread::Builder::new()
+                .name(thd_name!("cert-watcher"))
+                .spawn(move || {
+                    let security_mgr = factory.security_mgr.clone();
+                    let mut generation = security_mgr.generation();
+                    let interval = Duration::from_secs(CERT_CHECK_INTERVAL_SECS);
+                    while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                        security_mgr.reload();
+                        if security_mgr.generation() == generation {
+                            continue;
+                        }
+                        let mut new_server =
+                            match factory.build(format!("{}", addr.ip()), addr.port()) {
+                                Ok(s) => s,
+                                // Retried in the next round.
+                                Err(e) => {
+                                    error!("failed to rebuild grpc server: {:?}", e);
+                                    continue;
+                                }
+                            };
+                        new_server.start();
+                        let mut old_server =
+                            mem::replace(&mut *grpc_server.lock().unwrap(), new_server);
+                        generation = security_mgr.generation();
+                        info!("grpc server is rebuilt with the reloaded certificates");
+                        if let Err(e) = old_server.shutdown().wait() {
+                            error!("failed to shutdown the old grpc server: {:?}", e);
+                        }
+                    }
+                })
+        );
+        self.cert_watcher = Some((tx, h));
+        Ok(())
+    }
+
     pub fn stop(&mut self) -> Result<()> {
+        if let Some((tx, h)) = self.cert_watcher.take() {
+            drop(tx);
+            if let Err(e) = h.join() {
+                error!("failed to join cert watcher: {:?}", e);
+            }
+        }
         self.end_point_worker.stop();
         self.snap_worker.stop();
         if let Err(e) = self.storage.stop() {
             error!("failed to stop store: {:?}", e);
         }
-        self.grpc_server.shutdown();
+        self.grpc_server.lock().unwrap().shutdown();
         Ok(())
     }
 
@@ -249,6 +381,8 @@ mod tests {
             snapshot_status_sender,
             MockResolver { addr: addr.clone() },
             SnapManager::new("", None),
+            Arc::new(SecurityManager::default()),
+            None,
         ).unwrap();
         *addr.lock().unwrap() = Some(server.listening_addr());
 
@@ -16,9 +16,11 @@ use std::io;
 use std::iter::{self, Once};
 use std::net::SocketAddr;
 use std::boxed::FnBox;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::result;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::collections::VecDeque;
+use std::thread;
 
 use threadpool::ThreadPool;
 use mio::Token;
@@ -28,10 +30,13 @@ use kvproto::raft_serverpb::SnapshotChunk;
 use kvproto::raft_serverpb::RaftMessage;
 use kvproto::tikvpb_grpc::TikvClient;
 
-use raftstore::store::{SnapEntry, SnapKey, SnapManager, Snapshot};
+use raftstore::store::{SnapEntry, SnapKey, SnapManager, Snapshot, TransferSlot};
 use util::worker::Runnable;
 use util::buf::PipeBuffer;
 use util::collections::{HashMap, HashMapEntry as Entry};
+use util::io_limiter::IOLimiter;
+use util::security::SecurityManager;
+use util::time::duration_to_sec;
 use util::HandyRwLock;
 
 use super::metrics::*;
@@ -78,6 +83,7 @@ impl Display for Task {
 struct SnapChunk {
     snap: Arc<RwLock<Box<Snapshot>>>,
     remain_bytes: usize,
+    limiter: Arc<IOLimiter>,
 }
 
 const SNAP_CHUNK_LEN: usize = 1024 * 1024;
@@ -91,6 +97,13 @@ impl Iterator for SnapChunk {
             n if n > SNAP_CHUNK_LEN => vec![0; SNAP_CHUNK_LEN],
             n => vec![0; n],
         };
+        let wait = self.limiter.consume(buf.len());
+        if wait > Duration::default() {
+            SNAP_LIMIT_WAIT_HISTOGRAM_VEC
+                .with_label_values(&["send"])
+                .observe(duration_to_sec(wait));
+            thread::sleep(wait);
+        }
         match self.snap.wl().read_exact(buf.as_mut_slice()) {
             Ok(_) => {
                 self.remain_bytes -= buf.len();
@@ -106,6 +119,7 @@ impl Iterator for SnapChunk {
 /// It will first send the normal raft snapshot message and then send the snapshot file.
 fn send_snap(
     env: Arc<Environment>,
+    security_mgr: &SecurityManager,
     mgr: SnapManager,
     addr: SocketAddr,
     msg: RaftMessage,
@@ -136,6 +150,7 @@ fn send_snap(
         let snap_chunk = SnapChunk {
             snap: s.clone(),
             remain_bytes: total_size as usize,
+            limiter: mgr.send_limiter(),
         };
         let first: Once<Result<(SnapshotChunk, _)>> = iter::once({
             let mut chunk = SnapshotChunk::new();
@@ -152,7 +167,7 @@ fn send_snap(
         first.chain(rests)
     };
 
-    let channel = ChannelBuilder::new(env).connect(&format!("{}", addr));
+    let channel = security_mgr.connect(ChannelBuilder::new(env), &format!("{}", addr));
     let client = TikvClient::new(channel);
     let (sink, receiver) = client.snapshot();
     let send = stream::iter(chunks.into_iter()).forward(sink);
@@ -175,21 +190,68 @@ fn send_snap(
     res
 }
 
+// A snapshot waiting for a send slot.
+struct PendingSend {
+    addr: SocketAddr,
+    msg: RaftMessage,
+    cb: Callback,
+}
+
+// Sends the snapshot, then the queued ones with the same slot until the queue is empty.
+fn send_with_slot(
+    env: Arc<Environment>,
+    security_mgr: Arc<SecurityManager>,
+    mgr: SnapManager,
+    pending_sends: Arc<Mutex<VecDeque<PendingSend>>>,
+    slot: TransferSlot,
+    mut send: PendingSend,
+) {
+    loop {
+        let PendingSend { addr, msg, cb } = send;
+        let res = send_snap(env.clone(), &security_mgr, mgr.clone(), addr, msg);
+        if res.is_err() {
+            error!("failed to send snap to {}: {:?}", addr, res);
+        }
+        cb(res);
+
+        let mut queue = pending_sends.lock().unwrap();
+        send = match queue.pop_front() {
+            Some(send) => send,
+            None => {
+                // Give back the slot under the lock, so a snapshot queued concurrently
+                // either sees the free slot or is picked up above.
+                drop(slot);
+                return;
+            }
+        };
+    }
+}
+
 pub struct Runner<R: RaftStoreRouter + 'static> {
     env: Arc<Environment>,
+    security_mgr: Arc<SecurityManager>,
     snap_mgr: SnapManager,
     files: HashMap<Token, (Box<Snapshot>, RaftMessage)>,
     pool: ThreadPool,
+    // Snapshots to send once a send slot is given back.
+    pending_sends: Arc<Mutex<VecDeque<PendingSend>>>,
     raft_router: R,
 }
 
 impl<R: RaftStoreRouter + 'static> Runner<R> {
-    pub fn new(env: Arc<Environment>, snap_mgr: SnapManager, r: R) -> Runner<R> {
+    pub fn new(
+        env: Arc<Environment>,
+        snap_mgr: SnapManager,
+        r: R,
+        security_mgr: Arc<SecurityManager>,
+    ) -> Runner<R> {
         Runner {
             env: env,
+            security_mgr: security_mgr,
             snap_mgr: snap_mgr,
             files: map![],
             pool: ThreadPool::new_with_name(thd_name!("snap sender"), DEFAULT_SENDER_POOL_SIZE),
+            pending_sends: Arc::new(Mutex::new(VecDeque::new())),
             raft_router: r,
         }
     }
@@ -292,14 +354,33 @@ impl<R: RaftStoreRouter + 'static> Runnable<Task> for Runner<R> {
             }
             Task::SendTo { addr, msg, cb } => {
                 SNAP_TASK_COUNTER.with_label_values(&["send"]).inc();
+                let send = PendingSend {
+                    addr: addr,
+                    msg: msg,
+                    cb: cb,
+                };
+                let slot = {
+                    let mut pending_sends = self.pending_sends.lock().unwrap();
+                    match self.snap_mgr.try_acquire_send_slot() {
+                        Some(slot) => slot,
+                        None => {
+                            SNAP_TASK_COUNTER.with_label_values(&["send_queued"]).inc();
+                            info!(
+                                "[region {}] too many sending snapshots, queue snapshot to {}",
+                                send.msg.get_region_id(),
+                                addr
+                            );
+                            pending_sends.push_back(send);
+                            return;
+                        }
+                    }
+                };
                 let env = self.env.clone();
+                let security_mgr = self.security_mgr.clone();
                 let mgr = self.snap_mgr.clone();
+                let pending_sends = self.pending_sends.clone();
                 self.pool.execute(move || {
-                    let res = send_snap(env, mgr, addr, msg);
-                    if res.is_err() {
-                        error!("failed to send snap to {}: {:?}", addr, res);
-                    }
-                    cb(res)
+                    send_with_slot(env, security_mgr, mgr, pending_sends, slot, send)
                 });
             }
         }
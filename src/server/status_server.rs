@@ -0,0 +1,354 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use hyper::method::Method;
+use hyper::server::{Handler, Listening, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use prometheus::{self, Encoder, TextEncoder, TEXT_FORMAT};
+use serde::Serialize;
+use serde_json;
+use toml;
+
+use util::profiling;
+use super::Result;
+
+// A cpu profile blocks a thread for up to `MAX_CPU_PROFILE_DURATION_SECS`, and only
+// one is taken at a time, so the other threads keep serving metrics and status.
+const STATUS_SERVER_THREADS: usize = 3;
+
+const CONTENT_TYPE_TEXT: &'static str = "text/plain; charset=utf-8";
+const CONTENT_TYPE_JSON: &'static str = "application/json";
+const CONTENT_TYPE_TOML: &'static str = "application/toml";
+const CONTENT_TYPE_BINARY: &'static str = "application/octet-stream";
+
+const DEFAULT_CPU_PROFILE_SECS: u64 = 10;
+
+#[derive(Default, Serialize)]
+struct StoreStatus {
+    store_id: u64,
+    bootstrapped: bool,
+}
+
+#[derive(Default)]
+struct ConfigDump {
+    toml: String,
+    json: String,
+}
+
+#[derive(Default)]
+struct StatusState {
+    store: Mutex<StoreStatus>,
+    config: Mutex<ConfigDump>,
+    // Whether a handler thread is taking a cpu profile.
+    profiling: AtomicBool,
+}
+
+// Resets `StatusState::profiling` when the profile is done.
+struct ProfilingGuard<'a>(&'a AtomicBool);
+
+impl<'a> Drop for ProfilingGuard<'a> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+struct StatusHandler {
+    state: Arc<StatusState>,
+}
+
+type HttpResponse = (StatusCode, &'static str, Vec<u8>);
+
+impl StatusHandler {
+    fn route(&self, uri: &str) -> HttpResponse {
+        let mut parts = uri.splitn(2, '?');
+        let path = parts.next().unwrap();
+        let query = parts.next().unwrap_or("");
+        match path {
+            "/metrics" => self.metrics(),
+            "/status" => self.status(),
+            "/config" => self.config(query),
+            "/debug/pprof/profile" => self.cpu_profile(query),
+            "/debug/pprof/heap" => self.heap_profile(),
+            _ => (
+                StatusCode::NotFound,
+                CONTENT_TYPE_TEXT,
+                b"not found".to_vec(),
+            ),
+        }
+    }
+
+    fn metrics(&self) -> HttpResponse {
+        let encoder = TextEncoder::new();
+        let mut buf = vec![];
+        match encoder.encode(&prometheus::gather(), &mut buf) {
+            Ok(()) => (StatusCode::Ok, TEXT_FORMAT, buf),
+            Err(e) => (
+                StatusCode::InternalServerError,
+                CONTENT_TYPE_TEXT,
+                format!("failed to encode metrics: {:?}", e).into_bytes(),
+            ),
+        }
+    }
+
+    // Reports unavailable until the store is bootstrapped and started, so it
+    // can be used as a readiness probe.
+    fn status(&self) -> HttpResponse {
+        let store = self.state.store.lock().unwrap();
+        let code = if store.bootstrapped {
+            StatusCode::Ok
+        } else {
+            StatusCode::ServiceUnavailable
+        };
+        (code, CONTENT_TYPE_JSON, serde_json::to_vec(&*store).unwrap())
+    }
+
+    fn config(&self, query: &str) -> HttpResponse {
+        let config = self.state.config.lock().unwrap();
+        if query_param(query, "format") == Some("json") {
+            (
+                StatusCode::Ok,
+                CONTENT_TYPE_JSON,
+                config.json.clone().into_bytes(),
+            )
+        } else {
+            (
+                StatusCode::Ok,
+                CONTENT_TYPE_TOML,
+                config.toml.clone().into_bytes(),
+            )
+        }
+    }
+
+    // Blocks for the whole profiling duration, and returns folded stacks
+    // which can be rendered by flame graph tools. Requests arriving while a
+    // profile is being taken are rejected instead of occupying more threads.
+    fn cpu_profile(&self, query: &str) -> HttpResponse {
+        let seconds = match query_param(query, "seconds").map(|s| s.parse()) {
+            None => DEFAULT_CPU_PROFILE_SECS,
+            Some(Ok(s)) => s,
+            Some(Err(e)) => return bad_request(format!("invalid seconds: {:?}", e)),
+        };
+        let frequency = match query_param(query, "frequency").map(|s| s.parse()) {
+            None => profiling::DEFAULT_CPU_PROFILE_FREQUENCY,
+            Some(Ok(f)) => f,
+            Some(Err(e)) => return bad_request(format!("invalid frequency: {:?}", e)),
+        };
+        if self.state.profiling.compare_and_swap(false, true, Ordering::SeqCst) {
+            return (
+                StatusCode::TooManyRequests,
+                CONTENT_TYPE_TEXT,
+                b"another cpu profile is being taken".to_vec(),
+            );
+        }
+        let _guard = ProfilingGuard(&self.state.profiling);
+        match profiling::profile_cpu(Duration::from_secs(seconds), frequency) {
+            Ok(folded) => (StatusCode::Ok, CONTENT_TYPE_TEXT, folded.into_bytes()),
+            Err(e) => (
+                StatusCode::InternalServerError,
+                CONTENT_TYPE_TEXT,
+                e.into_bytes(),
+            ),
+        }
+    }
+
+    fn heap_profile(&self) -> HttpResponse {
+        match profiling::read_heap_profile() {
+            Ok(profile) => (StatusCode::Ok, CONTENT_TYPE_BINARY, profile),
+            Err(e) => (
+                StatusCode::InternalServerError,
+                CONTENT_TYPE_TEXT,
+                e.into_bytes(),
+            ),
+        }
+    }
+}
+
+fn query_param<'a>(query: &'a str, key: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|kv| {
+            let mut parts = kv.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(k), Some(v)) if k == key => Some(v),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+fn bad_request(msg: String) -> HttpResponse {
+    (StatusCode::BadRequest, CONTENT_TYPE_TEXT, msg.into_bytes())
+}
+
+impl Handler for StatusHandler {
+    fn handle(&self, req: Request, mut res: Response) {
+        let (code, content_type, body) = match (&req.method, &req.uri) {
+            (&Method::Get, &RequestUri::AbsolutePath(ref uri)) => self.route(uri),
+            (&Method::Get, _) => (
+                StatusCode::NotFound,
+                CONTENT_TYPE_TEXT,
+                b"not found".to_vec(),
+            ),
+            _ => (
+                StatusCode::MethodNotAllowed,
+                CONTENT_TYPE_TEXT,
+                b"method not allowed".to_vec(),
+            ),
+        };
+        *res.status_mut() = code;
+        res.headers_mut()
+            .set_raw("Content-Type", vec![content_type.as_bytes().to_vec()]);
+        if let Err(e) = res.send(&body) {
+            warn!("failed to send status response: {:?}", e);
+        }
+    }
+}
+
+/// `StatusServer` serves the status of tikv-server over HTTP:
+///
+/// - `/metrics` returns the prometheus metrics for pull scraping.
+/// - `/status` returns the store id and whether the store is bootstrapped.
+/// - `/config` returns the effective config in TOML, or JSON with `?format=json`.
+/// - `/debug/pprof/profile?seconds=10&frequency=99` takes a CPU profile and
+///   returns it as folded stacks.
+/// - `/debug/pprof/heap` returns a jemalloc heap profile.
+pub struct StatusServer {
+    state: Arc<StatusState>,
+    listening: Option<Listening>,
+}
+
+impl StatusServer {
+    pub fn new() -> StatusServer {
+        StatusServer {
+            state: Arc::new(StatusState::default()),
+            listening: None,
+        }
+    }
+
+    pub fn set_config<C: Serialize>(&self, cfg: &C) -> Result<()> {
+        let toml = box_try!(toml::to_string_pretty(cfg));
+        let json = box_try!(serde_json::to_string_pretty(cfg));
+        let mut config = self.state.config.lock().unwrap();
+        config.toml = toml;
+        config.json = json;
+        Ok(())
+    }
+
+    /// Mark the store as bootstrapped and started.
+    pub fn set_store_id(&self, store_id: u64) {
+        let mut store = self.state.store.lock().unwrap();
+        store.store_id = store_id;
+        store.bootstrapped = true;
+    }
+
+    pub fn start(&mut self, addr: &str) -> Result<()> {
+        let server = box_try!(Server::http(addr));
+        let handler = StatusHandler {
+            state: self.state.clone(),
+        };
+        let listening = box_try!(server.handle_threads(handler, STATUS_SERVER_THREADS));
+        info!("status server listening on {}", listening.socket);
+        self.listening = Some(listening);
+        Ok(())
+    }
+
+    pub fn listening_addr(&self) -> Option<SocketAddr> {
+        self.listening.as_ref().map(|l| l.socket)
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(mut listening) = self.listening.take() {
+            if let Err(e) = listening.close() {
+                warn!("failed to stop status server: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use hyper::Client;
+    use hyper::status::StatusCode;
+    use serde_json::{self, Value};
+
+    use config::TiKvConfig;
+    use super::*;
+
+    fn get(server: &StatusServer, path: &str) -> (StatusCode, String) {
+        let url = format!("http://{}{}", server.listening_addr().unwrap(), path);
+        let mut resp = Client::new().get(&url).send().unwrap();
+        let mut body = String::new();
+        resp.read_to_string(&mut body).unwrap();
+        (resp.status, body)
+    }
+
+    #[test]
+    fn test_status_server() {
+        let mut server = StatusServer::new();
+        let cfg = TiKvConfig::default();
+        server.set_config(&cfg).unwrap();
+        server.start("127.0.0.1:0").unwrap();
+
+        let (code, body) = get(&server, "/status");
+        assert_eq!(code, StatusCode::ServiceUnavailable);
+        let status: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(status["bootstrapped"], Value::Bool(false));
+
+        server.set_store_id(3);
+        let (code, body) = get(&server, "/status");
+        assert_eq!(code, StatusCode::Ok);
+        let status: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(status["store_id"].as_u64(), Some(3));
+        assert_eq!(status["bootstrapped"], Value::Bool(true));
+
+        let (code, _) = get(&server, "/metrics");
+        assert_eq!(code, StatusCode::Ok);
+
+        let (code, body) = get(&server, "/config");
+        assert_eq!(code, StatusCode::Ok);
+        let _: TiKvConfig = toml::from_str(&body).unwrap();
+
+        let (code, body) = get(&server, "/config?format=json");
+        assert_eq!(code, StatusCode::Ok);
+        let json: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            json["server"]["addr"].as_str(),
+            Some(cfg.server.addr.as_str())
+        );
+
+        let (code, _) = get(&server, "/debug/pprof/profile?seconds=x");
+        assert_eq!(code, StatusCode::BadRequest);
+        let (code, _) = get(&server, "/debug/pprof/profile?seconds=1&frequency=0");
+        assert_eq!(code, StatusCode::InternalServerError);
+        // Only one cpu profile is taken at a time, other requests are still served.
+        server.state.profiling.store(true, Ordering::SeqCst);
+        let (code, _) = get(&server, "/debug/pprof/profile?seconds=1");
+        assert_eq!(code, StatusCode::TooManyRequests);
+        let (code, _) = get(&server, "/metrics");
+        assert_eq!(code, StatusCode::Ok);
+        server.state.profiling.store(false, Ordering::SeqCst);
+
+        let (code, _) = get(&server, "/unknown");
+        assert_eq!(code, StatusCode::NotFound);
+
+        server.stop();
+    }
+}
@@ -15,7 +15,7 @@ use std::error::Error;
 
 use sys_info;
 
-use util::config;
+use util::config::{self, ReadableDuration, ReadableSize};
 
 pub const DEFAULT_DATA_DIR: &'static str = "";
 pub const DEFAULT_ROCKSDB_SUB_DIR: &'static str = "db";
@@ -24,6 +24,12 @@ const DEFAULT_SCHED_CAPACITY: usize = 10240;
 const DEFAULT_SCHED_MSG_PER_TICK: usize = 1024;
 const DEFAULT_SCHED_CONCURRENCY: usize = 102400;
 const DEFAULT_SCHED_TOO_BUSY_THRESHOLD: usize = 1000;
+const DEFAULT_SCHED_SLOW_LOG_THRESHOLD_SECS: u64 = 1;
+// Lower than the stall triggers of RocksDB, so clients are throttled before RocksDB stalls.
+const DEFAULT_FLOW_CONTROL_SOFT_L0_FILES: u64 = 12;
+const DEFAULT_FLOW_CONTROL_HARD_L0_FILES: u64 = 20;
+const DEFAULT_FLOW_CONTROL_SOFT_PENDING_COMPACTION_GB: u64 = 32;
+const DEFAULT_FLOW_CONTROL_HARD_PENDING_COMPACTION_GB: u64 = 128;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(default)]
@@ -36,6 +42,16 @@ pub struct Config {
     pub scheduler_concurrency: usize,
     pub scheduler_worker_pool_size: usize,
     pub scheduler_too_busy_threshold: usize,
+    // Commands taking longer than this are written to the slow log.
+    pub scheduler_slow_log_threshold: ReadableDuration,
+    // Writes are throttled when the L0 files or the pending compaction bytes of any
+    // column family exceed the soft limit, and throttled to the minimum rate at the
+    // hard limit.
+    pub flow_control_enable: bool,
+    pub flow_control_soft_l0_files: u64,
+    pub flow_control_hard_l0_files: u64,
+    pub flow_control_soft_pending_compaction_bytes: ReadableSize,
+    pub flow_control_hard_pending_compaction_bytes: ReadableSize,
 }
 
 impl Default for Config {
@@ -49,6 +65,18 @@ impl Default for Config {
             scheduler_concurrency: DEFAULT_SCHED_CONCURRENCY,
             scheduler_worker_pool_size: if total_cpu >= 16 { 8 } else { 4 },
             scheduler_too_busy_threshold: DEFAULT_SCHED_TOO_BUSY_THRESHOLD,
+            scheduler_slow_log_threshold: ReadableDuration::secs(
+                DEFAULT_SCHED_SLOW_LOG_THRESHOLD_SECS,
+            ),
+            flow_control_enable: true,
+            flow_control_soft_l0_files: DEFAULT_FLOW_CONTROL_SOFT_L0_FILES,
+            flow_control_hard_l0_files: DEFAULT_FLOW_CONTROL_HARD_L0_FILES,
+            flow_control_soft_pending_compaction_bytes: ReadableSize::gb(
+                DEFAULT_FLOW_CONTROL_SOFT_PENDING_COMPACTION_GB,
+            ),
+            flow_control_hard_pending_compaction_bytes: ReadableSize::gb(
+                DEFAULT_FLOW_CONTROL_HARD_PENDING_COMPACTION_GB,
+            ),
         }
     }
 }
@@ -58,6 +86,22 @@ impl Config {
         if self.data_dir != DEFAULT_DATA_DIR {
             self.data_dir = try!(config::canonicalize_path(&self.data_dir))
         }
+        if self.flow_control_soft_l0_files >= self.flow_control_hard_l0_files {
+            return Err(
+                "storage.flow-control-soft-l0-files should be less than \
+                 storage.flow-control-hard-l0-files"
+                    .into(),
+            );
+        }
+        if self.flow_control_soft_pending_compaction_bytes.0 >=
+            self.flow_control_hard_pending_compaction_bytes.0
+        {
+            return Err(
+                "storage.flow-control-soft-pending-compaction-bytes should be less than \
+                 storage.flow-control-hard-pending-compaction-bytes"
+                    .into(),
+            );
+        }
         Ok(())
     }
 }
@@ -232,6 +232,13 @@ impl Statistics {
         self.lock.processed + self.write.processed + self.data.processed
     }
 
+    pub fn total_seek_count(&self) -> usize {
+        [&self.lock, &self.write, &self.data]
+            .iter()
+            .map(|cf| cf.seek + cf.seek_for_prev)
+            .sum()
+    }
+
     pub fn details(&self) -> Vec<(&str, Vec<(&str, usize)>)> {
         vec![
             (CF_DEFAULT, self.data.details()),
@@ -235,6 +235,9 @@ impl<S: RaftStoreRouter> RaftKv<S> {
         if ctx.get_term() != 0 {
             header.set_term(ctx.get_term());
         }
+        if ctx.get_follower_read() {
+            header.set_follower_read(true);
+        }
         header
     }
 
@@ -64,6 +64,12 @@ lazy_static! {
             &["type"]
         ).unwrap();
 
+    pub static ref SCHED_WRITE_FLOW_LIMIT_GAUGE: Gauge =
+        register_gauge!(
+            "tikv_scheduler_write_flow_limit_bytes",
+            "The rate writes are throttled to by flow control, 0 means not throttled."
+        ).unwrap();
+
     pub static ref SCHED_COMMANDS_PRI_COUNTER_VEC: CounterVec =
         register_counter_vec!(
             "tikv_scheduler_commands_pri_total",
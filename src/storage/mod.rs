@@ -13,14 +13,17 @@
 
 use std::thread;
 use std::boxed::FnBox;
+use std::cell::RefCell;
 use std::fmt::{self, Debug, Display, Formatter};
 use std::sync::mpsc::{self, Receiver};
 use std::error;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::io::Error as IoError;
 use std::u64;
 use kvproto::kvrpcpb::{CommandPri, LockInfo};
 use kvproto::errorpb;
+use rocksdb::DB;
+use util::resource_group::ResourceGroupManager;
 use self::metrics::*;
 
 pub mod engine;
@@ -29,12 +32,14 @@ pub mod txn;
 pub mod config;
 pub mod types;
 mod metrics;
+mod safe_point;
 
 pub use self::config::{Config, DEFAULT_DATA_DIR, DEFAULT_ROCKSDB_SUB_DIR};
 pub use self::engine::{new_local_engine, CFStatistics, Cursor, Engine, Error as EngineError,
                        Modify, ScanMode, Snapshot, Statistics, TEMP_DIR};
 pub use self::engine::raftkv::RaftKv;
-pub use self::txn::{Msg, Scheduler, SnapshotStore, StoreScanner};
+pub use self::txn::{FlowController, Msg, Scheduler, SnapshotStore, StoreScanner};
+pub use self::safe_point::{BackupGuard, SafePointTracker};
 pub use self::types::{make_key, Key, KvPair, MvccInfo, Value};
 pub type Callback<T> = Box<FnBox(Result<T>) + Send>;
 
@@ -158,6 +163,23 @@ pub enum Command {
     MvccByStartTs { ctx: Context, start_ts: u64 },
 }
 
+fn bound_keys<'a, I>(keys: I) -> Option<(&'a Key, Option<&'a Key>)>
+where
+    I: Iterator<Item = &'a Key>,
+{
+    let mut bound: Option<(&Key, &Key)> = None;
+    for key in keys {
+        bound = Some(match bound {
+            None => (key, key),
+            Some((min, max)) => (
+                if key.encoded() < min.encoded() { key } else { min },
+                if key.encoded() > max.encoded() { key } else { max },
+            ),
+        });
+    }
+    bound.map(|(min, max)| (min, Some(max)))
+}
+
 impl Display for Command {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match *self {
@@ -391,6 +413,48 @@ impl Command {
         }
     }
 
+    /// Returns the smallest and the largest key the command touches. `None` for the end
+    /// means the command scans to the end of the region.
+    pub fn key_range(&self) -> Option<(&Key, Option<&Key>)> {
+        match *self {
+            Command::Get { ref key, .. } |
+            Command::Cleanup { ref key, .. } |
+            Command::RawGet { ref key, .. } |
+            Command::MvccByKey { ref key, .. } => Some((key, Some(key))),
+            Command::BatchGet { ref keys, .. } |
+            Command::Commit { ref keys, .. } |
+            Command::Rollback { ref keys, .. } => bound_keys(keys.iter()),
+            Command::Prewrite { ref mutations, .. } => {
+                bound_keys(mutations.iter().map(|m| m.key()))
+            }
+            Command::Scan { ref start_key, .. } | Command::RawScan { ref start_key, .. } => {
+                Some((start_key, None))
+            }
+            Command::DeleteRange {
+                ref start_key,
+                ref end_key,
+                ..
+            } => Some((start_key, Some(end_key))),
+            Command::ResolveLock {
+                ref scan_key,
+                ref keys,
+                ..
+            } |
+            Command::Gc {
+                ref scan_key,
+                ref keys,
+                ..
+            } => if keys.is_empty() {
+                scan_key.as_ref().map(|k| (k, None))
+            } else {
+                bound_keys(keys.iter())
+            },
+            Command::ScanLock { .. } | Command::Pause { .. } | Command::MvccByStartTs { .. } => {
+                None
+            }
+        }
+    }
+
     pub fn get_context(&self) -> &Context {
         match *self {
             Command::Get { ref ctx, .. } |
@@ -453,6 +517,28 @@ impl Options {
     }
 }
 
+thread_local! {
+    // The address of the client whose request is being handled by current thread.
+    static CLIENT_ADDR: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Attributes the next command sent by current thread to the client at `addr`,
+/// until the guard is dropped. The address is only used by the slow log.
+pub struct ClientAddrGuard;
+
+impl ClientAddrGuard {
+    pub fn new(addr: String) -> ClientAddrGuard {
+        CLIENT_ADDR.with(|a| *a.borrow_mut() = Some(addr));
+        ClientAddrGuard
+    }
+}
+
+impl Drop for ClientAddrGuard {
+    fn drop(&mut self) {
+        CLIENT_ADDR.with(|a| a.borrow_mut().take());
+    }
+}
+
 struct StorageHandle {
     handle: Option<thread::JoinHandle<()>>,
     receiver: Option<Receiver<Msg>>,
@@ -462,9 +548,13 @@ pub struct Storage {
     engine: Box<Engine>,
     sendch: SyncSendCh<Msg>,
     handle: Arc<Mutex<StorageHandle>>,
+    resource_groups: ResourceGroupManager,
+    // The kv rocksdb watched by flow control, writes are never throttled if it's `None`.
+    flow_control_db: Option<Arc<DB>>,
 
-    // Storage configurations.
-    gc_ratio_threshold: f64,
+    // Storage configurations, shared by all clones so it can be changed online.
+    gc_ratio_threshold: Arc<RwLock<f64>>,
+    safe_points: SafePointTracker,
 }
 
 impl Storage {
@@ -480,7 +570,10 @@ impl Storage {
                 handle: None,
                 receiver: Some(rx),
             })),
-            gc_ratio_threshold: config.gc_ratio_threshold,
+            resource_groups: ResourceGroupManager::default(),
+            flow_control_db: None,
+            gc_ratio_threshold: Arc::new(RwLock::new(config.gc_ratio_threshold)),
+            safe_points: SafePointTracker::default(),
         })
     }
 
@@ -501,6 +594,9 @@ impl Storage {
         let sched_concurrency = config.scheduler_concurrency;
         let sched_worker_pool_size = config.scheduler_worker_pool_size;
         let sched_too_busy_threshold = config.scheduler_too_busy_threshold;
+        let slow_log_threshold = config.scheduler_slow_log_threshold.0;
+        let resource_groups = self.resource_groups.clone();
+        let flow_controller = FlowController::new(self.flow_control_db.clone(), config);
         let ch = self.sendch.clone();
         let h = try!(builder.spawn(move || {
             let mut sched = Scheduler::new(
@@ -509,6 +605,9 @@ impl Storage {
                 sched_concurrency,
                 sched_worker_pool_size,
                 sched_too_busy_threshold,
+                slow_log_threshold,
+                resource_groups,
+                flow_controller,
             );
             if let Err(e) = sched.run(rx) {
                 panic!("scheduler run err:{:?}", e);
@@ -540,12 +639,43 @@ impl Storage {
         Ok(())
     }
 
+    /// Applies the mutable fields of a reloaded config.
+    pub fn change_config(&self, config: &Config) -> Result<()> {
+        *self.gc_ratio_threshold.write().unwrap() = config.gc_ratio_threshold;
+        box_try!(self.sendch.send(Msg::ChangeConfig(config.clone())));
+        Ok(())
+    }
+
     pub fn get_engine(&self) -> Box<Engine> {
         self.engine.clone()
     }
 
+    /// Returns the tracker which keeps GC away from running backups.
+    pub fn get_safe_point_tracker(&self) -> SafePointTracker {
+        self.safe_points.clone()
+    }
+
+    /// Use `mgr` to schedule and throttle requests, it must be set before `start`.
+    pub fn set_resource_groups(&mut self, mgr: ResourceGroupManager) {
+        self.resource_groups = mgr;
+    }
+
+    pub fn get_resource_groups(&self) -> ResourceGroupManager {
+        self.resource_groups.clone()
+    }
+
+    /// Throttles writes by the stall indicators of `db`, it must be set before `start`.
+    pub fn set_flow_control_db(&mut self, db: Arc<DB>) {
+        self.flow_control_db = Some(db);
+    }
+
     fn send(&self, cmd: Command, cb: StorageCb) -> Result<()> {
-        box_try!(self.sendch.try_send(Msg::RawCmd { cmd: cmd, cb: cb }));
+        let client_addr = CLIENT_ADDR.with(|a| a.borrow_mut().take());
+        box_try!(self.sendch.try_send(Msg::RawCmd {
+            cmd: cmd,
+            cb: cb,
+            client_addr: client_addr,
+        }));
         Ok(())
     }
 
@@ -766,8 +896,8 @@ impl Storage {
     pub fn async_gc(&self, ctx: Context, safe_point: u64, callback: Callback<()>) -> Result<()> {
         let cmd = Command::Gc {
             ctx: ctx,
-            safe_point: safe_point,
-            ratio_threshold: self.gc_ratio_threshold,
+            safe_point: self.safe_points.gc_safe_point(safe_point),
+            ratio_threshold: *self.gc_ratio_threshold.read().unwrap(),
             scan_key: None,
             keys: vec![],
         };
@@ -879,7 +1009,10 @@ impl Clone for Storage {
             engine: self.engine.clone(),
             sendch: self.sendch.clone(),
             handle: self.handle.clone(),
-            gc_ratio_threshold: self.gc_ratio_threshold,
+            resource_groups: self.resource_groups.clone(),
+            flow_control_db: self.flow_control_db.clone(),
+            gc_ratio_threshold: self.gc_ratio_threshold.clone(),
+            safe_points: self.safe_points.clone(),
         }
     }
 }
@@ -1008,6 +1141,16 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_client_addr_guard() {
+        let addr = || CLIENT_ADDR.with(|a| a.borrow().clone());
+        {
+            let _g = ClientAddrGuard::new("ipv4:127.0.0.1:3456".to_owned());
+            assert_eq!(addr(), Some("ipv4:127.0.0.1:3456".to_owned()));
+        }
+        assert_eq!(addr(), None);
+    }
+
     #[test]
     fn test_get_put() {
         let config = Config::default();
@@ -1599,4 +1742,38 @@ mod tests {
         rx.recv().unwrap();
         storage.stop().unwrap();
     }
+
+    #[test]
+    fn test_command_key_range() {
+        let keys = vec![make_key(b"b"), make_key(b"c"), make_key(b"a")];
+        let cmd = Command::Commit {
+            ctx: Context::new(),
+            keys: keys.clone(),
+            lock_ts: 1,
+            commit_ts: 2,
+        };
+        let (start, end) = cmd.key_range().unwrap();
+        assert_eq!(*start, make_key(b"a"));
+        assert_eq!(*end.unwrap(), make_key(b"c"));
+
+        let cmd = Command::Scan {
+            ctx: Context::new(),
+            start_key: make_key(b"x"),
+            limit: 10,
+            start_ts: 1,
+            options: Options::default(),
+        };
+        let (start, end) = cmd.key_range().unwrap();
+        assert_eq!(*start, make_key(b"x"));
+        assert!(end.is_none());
+
+        let cmd = Command::Gc {
+            ctx: Context::new(),
+            safe_point: 1,
+            ratio_threshold: 1.1,
+            scan_key: None,
+            keys: vec![],
+        };
+        assert!(cmd.key_range().is_none());
+    }
 }
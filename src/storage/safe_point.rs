@@ -0,0 +1,108 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{Arc, Mutex};
+
+use super::Result;
+
+#[derive(Default)]
+struct SafePoints {
+    // The newest safe point GC has been requested at since the store started.
+    max_safe_point: u64,
+    // The timestamps of the running backups.
+    backups: Vec<u64>,
+}
+
+/// `SafePointTracker` keeps GC away from the versions needed by running backups. A
+/// backup registers its timestamp, GC never goes beyond the timestamp of any running
+/// backup, and a backup can't start at a timestamp older than a safe point GC has been
+/// requested at, as the versions it needs may be gone.
+///
+/// The safe points are only tracked in memory, and only for the GC requests handled by
+/// the store.
+#[derive(Clone, Default)]
+pub struct SafePointTracker {
+    inner: Arc<Mutex<SafePoints>>,
+}
+
+impl SafePointTracker {
+    /// Registers a backup at `ts`, GC is held back until the returned guard is dropped.
+    pub fn register_backup(&self, ts: u64) -> Result<BackupGuard> {
+        let mut inner = self.inner.lock().unwrap();
+        if ts < inner.max_safe_point {
+            return Err(box_err!(
+                "backup ts {} is older than the GC safe point {}",
+                ts,
+                inner.max_safe_point
+            ));
+        }
+        inner.backups.push(ts);
+        Ok(BackupGuard {
+            tracker: self.clone(),
+            ts: ts,
+        })
+    }
+
+    /// Returns the safe point GC can run at when it's requested at `safe_point`.
+    pub fn gc_safe_point(&self, safe_point: u64) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        if safe_point > inner.max_safe_point {
+            inner.max_safe_point = safe_point;
+        }
+        match inner.backups.iter().min() {
+            Some(&ts) if ts < safe_point => {
+                info!("GC at {} is held back to {} by a running backup", safe_point, ts);
+                ts
+            }
+            _ => safe_point,
+        }
+    }
+}
+
+/// Unregisters the backup when it's dropped, see `SafePointTracker::register_backup`.
+pub struct BackupGuard {
+    tracker: SafePointTracker,
+    ts: u64,
+}
+
+impl Drop for BackupGuard {
+    fn drop(&mut self) {
+        let mut inner = self.tracker.inner.lock().unwrap();
+        if let Some(pos) = inner.backups.iter().position(|ts| *ts == self.ts) {
+            inner.backups.swap_remove(pos);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_safe_point_tracker() {
+        let tracker = SafePointTracker::default();
+        assert_eq!(tracker.gc_safe_point(10), 10);
+        assert!(tracker.register_backup(9).is_err());
+
+        let guard1 = tracker.register_backup(20).unwrap();
+        let guard2 = tracker.register_backup(15).unwrap();
+        assert_eq!(tracker.gc_safe_point(12), 12);
+        assert_eq!(tracker.gc_safe_point(30), 15);
+        // The requested safe point is tracked even if GC is held back.
+        assert!(tracker.register_backup(25).is_err());
+        drop(guard2);
+        assert_eq!(tracker.gc_safe_point(30), 20);
+        drop(guard1);
+        assert_eq!(tracker.gc_safe_point(30), 30);
+    }
+}
@@ -0,0 +1,267 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rocksdb::DB;
+
+use storage::Config;
+use util::rocksdb as rocksdb_util;
+use util::rocksdb::engine_metrics::{ROCKSDB_NUM_FILES_AT_LEVEL0,
+                                    ROCKSDB_PENDING_COMPACTION_BYTES};
+use util::time::duration_to_sec;
+use super::super::metrics::*;
+
+// The stall indicators are checked at most once in this interval.
+const UPDATE_INTERVAL_MILLIS: u64 = 1000;
+// Writes are never throttled below this rate, in bytes per second.
+const MIN_WRITE_RATE: f64 = 1024.0 * 1024.0;
+// The weight of the latest interval in the smoothed write rate.
+const RATE_SMOOTHING: f64 = 0.3;
+
+/// The stall indicators of RocksDB, the maximum of all column families.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EngineStallStats {
+    pub l0_files: u64,
+    pub pending_compaction_bytes: u64,
+}
+
+impl EngineStallStats {
+    fn collect(db: &DB) -> EngineStallStats {
+        let mut stats = EngineStallStats::default();
+        for cf in db.cf_names() {
+            let handle = rocksdb_util::get_cf_handle(db, cf).unwrap();
+            if let Some(n) = db.get_property_int_cf(handle, ROCKSDB_NUM_FILES_AT_LEVEL0) {
+                stats.l0_files = stats.l0_files.max(n);
+            }
+            if let Some(n) = db.get_property_int_cf(handle, ROCKSDB_PENDING_COMPACTION_BYTES) {
+                stats.pending_compaction_bytes = stats.pending_compaction_bytes.max(n);
+            }
+        }
+        stats
+    }
+}
+
+// Returns how far `value` is from `soft` to `hard`, in [0, 1].
+fn pressure(value: u64, soft: u64, hard: u64) -> f64 {
+    if value <= soft {
+        return 0.0;
+    }
+    if value >= hard {
+        return 1.0;
+    }
+    (value - soft) as f64 / (hard - soft) as f64
+}
+
+/// `FlowController` throttles writes before RocksDB stalls them.
+///
+/// When the L0 files or the pending compaction bytes exceed the soft limits, writes
+/// are limited by a token bucket, whose rate drops linearly from the write rate seen
+/// before throttling to `MIN_WRITE_RATE` as the indicators approach the hard limits.
+/// The cost of a write is only known after it's prepared, so the bucket can go into
+/// debt, and writes are rejected until the debt is paid off.
+pub struct FlowController {
+    db: Option<Arc<DB>>,
+    enabled: bool,
+    soft_l0_files: u64,
+    hard_l0_files: u64,
+    soft_pending_compaction_bytes: u64,
+    hard_pending_compaction_bytes: u64,
+
+    last_update: Instant,
+    // Bytes written since `last_update`.
+    written_bytes: u64,
+    // Smoothed write rate while writes are not throttled.
+    write_rate: f64,
+    // The rate writes are limited to, `None` means writes are not throttled.
+    limit: Option<f64>,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl FlowController {
+    /// Creates a flow controller watching `db`, it never throttles if `db` is `None`.
+    pub fn new(db: Option<Arc<DB>>, cfg: &Config) -> FlowController {
+        let now = Instant::now();
+        let mut controller = FlowController {
+            db: db,
+            enabled: false,
+            soft_l0_files: 0,
+            hard_l0_files: 0,
+            soft_pending_compaction_bytes: 0,
+            hard_pending_compaction_bytes: 0,
+            last_update: now,
+            written_bytes: 0,
+            write_rate: 0.0,
+            limit: None,
+            tokens: 0.0,
+            last_refill: now,
+        };
+        controller.set_config(cfg);
+        controller
+    }
+
+    pub fn set_config(&mut self, cfg: &Config) {
+        self.enabled = cfg.flow_control_enable;
+        self.soft_l0_files = cfg.flow_control_soft_l0_files;
+        self.hard_l0_files = cfg.flow_control_hard_l0_files;
+        self.soft_pending_compaction_bytes = cfg.flow_control_soft_pending_compaction_bytes.0;
+        self.hard_pending_compaction_bytes = cfg.flow_control_hard_pending_compaction_bytes.0;
+        if !self.enabled {
+            self.set_limit(None);
+        }
+    }
+
+    /// Checks whether a new write should be rejected.
+    pub fn should_throttle(&mut self) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let now = Instant::now();
+        if now.duration_since(self.last_update) >= Duration::from_millis(UPDATE_INTERVAL_MILLIS) {
+            let elapsed = duration_to_sec(now.duration_since(self.last_update));
+            self.last_update = now;
+            if let Some(stats) = self.db.as_ref().map(|db| EngineStallStats::collect(db)) {
+                self.update(stats, elapsed);
+            }
+        }
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return false,
+        };
+        let elapsed = duration_to_sec(now.duration_since(self.last_refill));
+        self.tokens = (self.tokens + elapsed * limit).min(limit);
+        self.last_refill = now;
+        self.tokens <= 0.0
+    }
+
+    /// Records the bytes written by an admitted write.
+    pub fn consume(&mut self, bytes: usize) {
+        self.written_bytes += bytes as u64;
+        if self.limit.is_some() {
+            self.tokens -= bytes as f64;
+        }
+    }
+
+    // Adjusts the limit by the stall indicators, `elapsed` is the seconds since the
+    // last update.
+    fn update(&mut self, stats: EngineStallStats, elapsed: f64) {
+        let written_bytes = self.written_bytes;
+        self.written_bytes = 0;
+        let p = pressure(stats.l0_files, self.soft_l0_files, self.hard_l0_files).max(pressure(
+            stats.pending_compaction_bytes,
+            self.soft_pending_compaction_bytes,
+            self.hard_pending_compaction_bytes,
+        ));
+        if p <= 0.0 {
+            if elapsed > 0.0 {
+                let rate = written_bytes as f64 / elapsed;
+                self.write_rate = if self.write_rate <= 0.0 {
+                    rate
+                } else {
+                    self.write_rate * (1.0 - RATE_SMOOTHING) + rate * RATE_SMOOTHING
+                };
+            }
+            if self.limit.is_some() {
+                info!("flow control stops throttling writes, stats {:?}", stats);
+            }
+            self.set_limit(None);
+            return;
+        }
+        // The write rate is frozen while throttling, it's the rate RocksDB fell behind.
+        let limit = (self.write_rate * (1.0 - p)).max(MIN_WRITE_RATE);
+        if self.limit.is_none() {
+            info!(
+                "flow control starts throttling writes to {} bytes/s, stats {:?}",
+                limit as u64,
+                stats
+            );
+        }
+        self.set_limit(Some(limit));
+    }
+
+    fn set_limit(&mut self, limit: Option<f64>) {
+        if let (None, Some(l)) = (self.limit, limit) {
+            self.tokens = l;
+            self.last_refill = Instant::now();
+        }
+        self.limit = limit;
+        SCHED_WRITE_FLOW_LIMIT_GAUGE.set(limit.unwrap_or(0.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use storage::Config;
+    use util::config::ReadableSize;
+    use super::*;
+
+    fn new_stats(l0_files: u64, pending_compaction_bytes: u64) -> EngineStallStats {
+        EngineStallStats {
+            l0_files: l0_files,
+            pending_compaction_bytes: pending_compaction_bytes,
+        }
+    }
+
+    #[test]
+    #[allow(float_cmp)]
+    fn test_pressure() {
+        assert_eq!(pressure(5, 10, 20), 0.0);
+        assert_eq!(pressure(10, 10, 20), 0.0);
+        assert_eq!(pressure(15, 10, 20), 0.5);
+        assert_eq!(pressure(20, 10, 20), 1.0);
+        assert_eq!(pressure(30, 10, 20), 1.0);
+    }
+
+    #[test]
+    #[allow(float_cmp)]
+    fn test_flow_controller() {
+        let mut cfg = Config::default();
+        cfg.flow_control_soft_l0_files = 10;
+        cfg.flow_control_hard_l0_files = 20;
+        cfg.flow_control_soft_pending_compaction_bytes = ReadableSize::gb(1);
+        cfg.flow_control_hard_pending_compaction_bytes = ReadableSize::gb(2);
+        let mut controller = FlowController::new(None, &cfg);
+        let rate = 100.0 * MIN_WRITE_RATE;
+
+        controller.consume(rate as usize);
+        controller.update(new_stats(5, 0), 1.0);
+        assert_eq!(controller.write_rate, rate);
+        assert!(controller.limit.is_none());
+        assert!(!controller.should_throttle());
+
+        // The pressure is decided by the worse indicator.
+        controller.update(new_stats(15, ReadableSize::mb(1024 + 256).0), 1.0);
+        assert_eq!(controller.limit, Some(rate * 0.5));
+        assert!(!controller.should_throttle());
+        controller.consume(rate as usize);
+        assert!(controller.should_throttle());
+
+        controller.update(new_stats(25, 0), 1.0);
+        assert_eq!(controller.limit, Some(MIN_WRITE_RATE));
+        // The write rate is not updated while throttling.
+        assert_eq!(controller.write_rate, rate);
+
+        controller.update(new_stats(5, 0), 1.0);
+        assert!(controller.limit.is_none());
+        assert!(!controller.should_throttle());
+
+        controller.update(new_stats(15, 0), 1.0);
+        assert!(controller.limit.is_some());
+        cfg.flow_control_enable = false;
+        controller.set_config(&cfg);
+        assert!(controller.limit.is_none());
+        assert!(!controller.should_throttle());
+    }
+}
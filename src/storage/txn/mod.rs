@@ -14,11 +14,13 @@
 mod store;
 mod scheduler;
 mod latch;
+mod flow_controller;
 
 use std::error;
 use std::io::Error as IoError;
 
 pub use self::scheduler::{Msg, Scheduler, GC_BATCH_SIZE, RESOLVE_LOCK_BATCH_SIZE};
+pub use self::flow_controller::FlowController;
 pub use self::store::{SnapshotStore, StoreScanner};
 
 quick_error! {
@@ -36,14 +36,14 @@ use std::sync::mpsc::Receiver;
 use std::time::Duration;
 use std::thread;
 use std::hash::{Hash, Hasher};
+use std::sync::Arc;
 use std::u64;
 
-use threadpool::ThreadPool;
 use prometheus::HistogramTimer;
 use kvproto::kvrpcpb::{CommandPri, Context, LockInfo};
 
-use storage::{Command, Engine, Error as StorageError, Result as StorageResult, ScanMode, Snapshot,
-              Statistics, StorageCb};
+use storage::{Command, Config, Engine, Error as StorageError, Result as StorageResult, ScanMode,
+              Snapshot, Statistics, StorageCb};
 use storage::mvcc::{Error as MvccError, Lock as MvccLock, MvccReader, MvccTxn, Write, WriteType,
                     MAX_TXN_WRITE_SIZE};
 use storage::{Key, KvPair, MvccInfo, Value, CMD_TAG_GC};
@@ -53,11 +53,17 @@ use raftstore::store::engine::IterOption;
 use util::transport::{Error as TransportError, SyncSendCh};
 use util::time::SlowTimer;
 use util::collections::HashMap;
+use util::io_limiter::{self, IOType};
+use util::metrics::RESOURCE_GROUP_WAIT_HISTOGRAM_VEC;
+use util::resource_group::{self, ResourceGroup, ResourceGroupManager};
+use util::threadpool::{Context as ThreadContext, ContextFactory, ThreadPool,
+                       DEFAULT_TASKS_PER_TICK};
 
 use super::Result;
 use super::Error;
 use super::store::SnapshotStore;
 use super::latch::{Latches, Lock};
+use super::flow_controller::FlowController;
 use super::super::metrics::*;
 
 // TODO: make it configurable.
@@ -83,7 +89,11 @@ type SnapshotResult = (Vec<u64>, CbContext, EngineResult<Box<Snapshot>>);
 /// Message types for the scheduler event loop.
 pub enum Msg {
     Quit,
-    RawCmd { cmd: Command, cb: StorageCb },
+    RawCmd {
+        cmd: Command,
+        cb: StorageCb,
+        client_addr: Option<String>,
+    },
     RetryGetSnapshots(Vec<(Context, Vec<u64>)>),
     SnapshotFinished {
         cids: Vec<u64>,
@@ -91,12 +101,18 @@ pub enum Msg {
         snapshot: EngineResult<Box<Snapshot>>,
     },
     BatchSnapshotFinished { batch: Vec<SnapshotResult> },
-    ReadFinished { cid: u64, pr: ProcessResult },
+    ReadFinished {
+        cid: u64,
+        cmd: Command,
+        pr: ProcessResult,
+        statistics: Statistics,
+    },
     WritePrepareFinished {
         cid: u64,
         cmd: Command,
         pr: ProcessResult,
         to_be_write: Vec<Modify>,
+        statistics: Statistics,
     },
     WritePrepareFailed { cid: u64, err: Error },
     WriteFinished {
@@ -105,6 +121,7 @@ pub enum Msg {
         cb_ctx: CbContext,
         result: EngineResult<()>,
     },
+    ChangeConfig(Config),
 }
 
 /// Debug for messages.
@@ -129,6 +146,7 @@ impl Debug for Msg {
                 write!(f, "WritePrepareFailed [cid={}, err={:?}]", cid, err)
             }
             Msg::WriteFinished { cid, .. } => write!(f, "WriteFinished [cid={}]", cid),
+            Msg::ChangeConfig(_) => write!(f, "ChangeConfig"),
         }
     }
 }
@@ -183,14 +201,31 @@ pub struct RunningCtx {
     tag: &'static str,
     ts: u64,
     region_id: u64,
+    // The resource group the command is accounted to.
+    group: Arc<ResourceGroup>,
     latch_timer: Option<HistogramTimer>,
     _timer: HistogramTimer,
     slow_timer: SlowTimer,
+    // The following fields are only used by the slow log. The key range is read
+    // from `cmd`, which is kept after processing until the command finishes.
+    client_addr: Option<String>,
+    // Time spent before the command is delivered to the worker pool.
+    wait_time: Option<Duration>,
+    statistics: Statistics,
 }
 
 impl RunningCtx {
-    /// Creates a context for a running command.
-    pub fn new(cid: u64, cmd: Command, lock: Lock, cb: StorageCb) -> RunningCtx {
+    /// Creates a context for a running command, the command is logged to the slow log
+    /// if it takes longer than `slow_log_threshold`.
+    pub fn new(
+        cid: u64,
+        cmd: Command,
+        lock: Lock,
+        cb: StorageCb,
+        group: Arc<ResourceGroup>,
+        client_addr: Option<String>,
+        slow_log_threshold: Duration,
+    ) -> RunningCtx {
         let tag = cmd.tag();
         let ts = cmd.ts();
         let region_id = cmd.get_context().get_region_id();
@@ -202,6 +237,7 @@ impl RunningCtx {
             tag: tag,
             ts: ts,
             region_id: region_id,
+            group: group,
             latch_timer: Some(
                 SCHED_LATCH_HISTOGRAM_VEC
                     .with_label_values(&[tag])
@@ -210,19 +246,45 @@ impl RunningCtx {
             _timer: SCHED_HISTOGRAM_VEC
                 .with_label_values(&[tag])
                 .start_coarse_timer(),
-            slow_timer: SlowTimer::new(),
+            slow_timer: SlowTimer::from(slow_log_threshold),
+            client_addr: client_addr,
+            wait_time: None,
+            statistics: Statistics::default(),
+        }
+    }
+
+    fn format_key_range(&self) -> String {
+        let cmd = match self.cmd {
+            Some(ref cmd) => cmd,
+            // The command failed while it was being processed.
+            None => return "unknown".to_owned(),
+        };
+        match cmd.key_range() {
+            None => "none".to_owned(),
+            Some((start, None)) => format!("[{}, +inf)", start),
+            Some((start, Some(end))) => format!("[{}, {}]", start, end),
         }
     }
 }
 
 impl Drop for RunningCtx {
     fn drop(&mut self) {
+        let wait_time = self.wait_time.unwrap_or_else(|| self.slow_timer.elapsed());
         slow_log!(
             self.slow_timer,
-            "[region {}] scheduler handle command: {}, ts: {}",
+            "[region {}] slow command: {}, cid: {}, ts: {}, keys: {}, wait: {:?}, \
+             process: {:?}, processed keys: {}, total ops: {}, seeks: {}, client: {}",
             self.region_id,
             self.tag,
-            self.ts
+            self.cid,
+            self.ts,
+            self.format_key_range(),
+            wait_time,
+            self.slow_timer.elapsed().checked_sub(wait_time).unwrap_or_default(),
+            self.statistics.total_processed(),
+            self.statistics.total_op_count(),
+            self.statistics.total_seek_count(),
+            self.client_addr.as_ref().map_or("unknown", |a| a.as_str())
         );
     }
 }
@@ -249,6 +311,38 @@ fn make_engine_cb(cid: u64, pr: ProcessResult, ch: SyncSendCh<Msg>) -> EngineCal
     })
 }
 
+// The worker pools of the scheduler keep no per thread states.
+struct SchedContext;
+
+impl ThreadContext for SchedContext {
+    fn on_task_started(&mut self) {}
+    fn on_task_finished(&mut self) {}
+    fn on_tick(&mut self) {}
+}
+
+struct SchedContextFactory;
+
+impl ContextFactory<SchedContext> for SchedContextFactory {
+    fn create(&self) -> SchedContext {
+        SchedContext
+    }
+}
+
+// Returns the bytes to be written by the modifies, it's used by flow control and to charge
+// resource groups.
+fn modifies_size(modifies: &[Modify]) -> usize {
+    modifies
+        .iter()
+        .map(|m| match *m {
+            Modify::Delete(_, ref k) => k.encoded().len(),
+            Modify::Put(_, ref k, ref v) => k.encoded().len() + v.len(),
+            Modify::DeleteRange(_, ref start, ref end) => {
+                start.encoded().len() + end.encoded().len()
+            }
+        })
+        .sum()
+}
+
 #[derive(Clone)]
 struct HashableContext(Context);
 
@@ -296,11 +390,20 @@ pub struct Scheduler {
 
     sched_too_busy_threshold: usize,
 
+    // commands taking longer than this are logged to the slow log.
+    slow_log_threshold: Duration,
+
+    // commands of resource groups share the worker pools by the weights of the groups.
+    resource_groups: ResourceGroupManager,
+
+    // throttles writes when RocksDB is about to stall.
+    flow_controller: FlowController,
+
     // worker pool
-    worker_pool: ThreadPool,
+    worker_pool: ThreadPool<SchedContext>,
 
     // high priority commands will be delivered to this pool
-    high_priority_pool: ThreadPool,
+    high_priority_pool: ThreadPool<SchedContext>,
 
     has_gc_command: bool,
 
@@ -353,6 +456,9 @@ impl Scheduler {
         concurrency: usize,
         worker_pool_size: usize,
         sched_too_busy_threshold: usize,
+        slow_log_threshold: Duration,
+        resource_groups: ResourceGroupManager,
+        flow_controller: FlowController,
     ) -> Scheduler {
         Scheduler {
             engine: engine,
@@ -365,11 +471,21 @@ impl Scheduler {
             id_alloc: 0,
             latches: Latches::new(concurrency),
             sched_too_busy_threshold: sched_too_busy_threshold,
-            worker_pool: ThreadPool::new_with_name(
+            slow_log_threshold: slow_log_threshold,
+            resource_groups: resource_groups,
+            flow_controller: flow_controller,
+            worker_pool: ThreadPool::new(
                 thd_name!("sched-worker-pool"),
                 worker_pool_size,
+                DEFAULT_TASKS_PER_TICK,
+                SchedContextFactory,
+            ),
+            high_priority_pool: ThreadPool::new(
+                thd_name!("sched-high-pri-pool"),
+                1,
+                DEFAULT_TASKS_PER_TICK,
+                SchedContextFactory,
             ),
-            high_priority_pool: ThreadPool::new_with_name(thd_name!("sched-high-pri-pool"), 1),
             has_gc_command: false,
             running_write_count: 0,
         }
@@ -379,6 +495,7 @@ impl Scheduler {
 /// Processes a read command within a worker thread, then posts `ReadFinished` message back to the
 /// event loop.
 fn process_read(cid: u64, mut cmd: Command, ch: SyncSendCh<Msg>, snapshot: Box<Snapshot>) {
+    log_fields!(cid = cid, region_id = cmd.get_context().get_region_id());
     debug!("process read cmd(cid={}) in worker pool.", cid);
     SCHED_WORKER_COUNTER_VEC
         .with_label_values(&[cmd.tag(), "read"])
@@ -692,7 +809,12 @@ fn process_read(cid: u64, mut cmd: Command, ch: SyncSendCh<Msg>, snapshot: Box<S
         _ => panic!("unsupported read command"),
     };
 
-    if let Err(e) = ch.send(Msg::ReadFinished { cid: cid, pr: pr }) {
+    if let Err(e) = ch.send(Msg::ReadFinished {
+        cid: cid,
+        cmd: cmd,
+        pr: pr,
+        statistics: statistics,
+    }) {
         // Todo: if this happens we need to clean up command's context
         panic!("send read finished failed, cid={}, err={:?}", cid, e);
     }
@@ -719,6 +841,7 @@ fn process_rawscan(
 /// Processes a write command within a worker thread, then posts either a `WritePrepareFinished`
 /// message if successful or a `WritePrepareFailed` message back to the event loop.
 fn process_write(cid: u64, cmd: Command, ch: SyncSendCh<Msg>, snapshot: Box<Snapshot>) {
+    log_fields!(cid = cid, region_id = cmd.get_context().get_region_id());
     SCHED_WORKER_COUNTER_VEC
         .with_label_values(&[cmd.tag(), "write"])
         .inc();
@@ -911,6 +1034,8 @@ fn process_write_impl(
                     break;
                 }
             }
+            // Only one GC command runs at the same time, so it blocks one worker at most.
+            io_limiter::request_io(IOType::Gc, txn.write_size());
             if scan_key.is_none() {
                 (ProcessResult::Res, txn.modifies())
             } else {
@@ -934,6 +1059,7 @@ fn process_write_impl(
         cmd: cmd,
         pr: pr,
         to_be_write: modifies,
+        statistics: statistics,
     }));
 
     Ok(())
@@ -978,15 +1104,16 @@ impl Scheduler {
         ctx.tag
     }
 
-    fn fetch_worker_pool(&self, priority: CommandPri) -> &ThreadPool {
+    fn fetch_worker_pool(&mut self, priority: CommandPri) -> &mut ThreadPool<SchedContext> {
         match priority {
-            CommandPri::Low | CommandPri::Normal => &self.worker_pool,
-            CommandPri::High => &self.high_priority_pool,
+            CommandPri::Low | CommandPri::Normal => &mut self.worker_pool,
+            CommandPri::High => &mut self.high_priority_pool,
         }
     }
 
     /// Delivers a command to a worker thread for processing.
     fn process_by_worker(&mut self, cid: u64, cb_ctx: CbContext, snapshot: Box<Snapshot>) {
+        log_fields!(cid = cid);
         SCHED_STAGE_COUNTER_VEC
             .with_label_values(&[self.get_ctx_tag(cid), "process"])
             .inc();
@@ -995,26 +1122,34 @@ impl Scheduler {
             cid,
             cb_ctx
         );
-        let mut cmd = {
+        let (mut cmd, group) = {
             let ctx = &mut self.cmd_ctxs.get_mut(&cid).unwrap();
             assert_eq!(ctx.cid, cid);
-            ctx.cmd.take().unwrap()
+            ctx.wait_time = Some(ctx.slow_timer.elapsed());
+            (ctx.cmd.take().unwrap(), ctx.group.clone())
         };
         if let Some(term) = cb_ctx.term {
             cmd.mut_context().set_term(term);
         }
         let ch = self.schedch.clone();
         let readcmd = cmd.readonly();
+        let wait_timer = RESOURCE_GROUP_WAIT_HISTOGRAM_VEC
+            .with_label_values(&[group.name(), "scheduler"])
+            .start_coarse_timer();
         let worker_pool = self.fetch_worker_pool(cmd.priority());
-        if readcmd {
-            worker_pool.execute(move || process_read(cid, cmd, ch, snapshot));
-        } else {
-            worker_pool.execute(move || process_write(cid, cmd, ch, snapshot));
-        }
+        worker_pool.execute_with_group(group.id(), group.weight(), move |_: &mut SchedContext| {
+            wait_timer.observe_duration();
+            if readcmd {
+                process_read(cid, cmd, ch, snapshot)
+            } else {
+                process_write(cid, cmd, ch, snapshot)
+            }
+        });
     }
 
     /// Calls the callback with an error.
     fn finish_with_err(&mut self, cid: u64, err: Error) {
+        log_fields!(cid = cid);
         debug!("command cid={}, finished with error", cid);
         SCHED_STAGE_COUNTER_VEC
             .with_label_values(&[self.get_ctx_tag(cid), "error"])
@@ -1047,7 +1182,13 @@ impl Scheduler {
     /// Note that once a command is ready to execute, the snapshot is always up-to-date during the
     /// execution because 1) all the conflicting commands (if any) must be in the waiting queues;
     /// 2) there may be non-conflicitng commands running concurrently, but it doesn't matter.
-    fn schedule_command(&mut self, cmd: Command, callback: StorageCb) {
+    fn schedule_command(
+        &mut self,
+        cmd: Command,
+        callback: StorageCb,
+        group: Arc<ResourceGroup>,
+        client_addr: Option<String>,
+    ) {
         SCHED_STAGE_COUNTER_VEC
             .with_label_values(&[cmd.tag(), "new"])
             .inc();
@@ -1055,20 +1196,59 @@ impl Scheduler {
             .with_label_values(&[cmd.priority_tag()])
             .inc();
         let cid = self.gen_id();
+        log_fields!(cid = cid, region_id = cmd.get_context().get_region_id());
         debug!("received new command, cid={}, cmd={}", cid, cmd);
         let lock = gen_command_lock(&self.latches, &cmd);
-        let ctx = RunningCtx::new(cid, cmd, lock, callback);
+        let ctx = RunningCtx::new(
+            cid,
+            cmd,
+            lock,
+            callback,
+            group,
+            client_addr,
+            self.slow_log_threshold,
+        );
         self.insert_ctx(ctx);
         self.lock_and_register_get_snapshot(cid);
     }
 
+    fn on_config_changed(&mut self, cfg: Config) {
+        info!(
+            "scheduler config changed, too busy threshold {} -> {}, worker pool size {}, \
+             slow log threshold {:?} -> {:?}",
+            self.sched_too_busy_threshold,
+            cfg.scheduler_too_busy_threshold,
+            cfg.scheduler_worker_pool_size,
+            self.slow_log_threshold,
+            cfg.scheduler_slow_log_threshold.0
+        );
+        self.sched_too_busy_threshold = cfg.scheduler_too_busy_threshold;
+        self.slow_log_threshold = cfg.scheduler_slow_log_threshold.0;
+        self.flow_controller.set_config(&cfg);
+        self.worker_pool.set_num_threads(cfg.scheduler_worker_pool_size);
+    }
+
+    fn shutdown(&mut self) {
+        if let Err(e) = self.worker_pool.stop() {
+            error!("failed to stop scheduler worker pool: {:?}", e);
+        }
+        if let Err(e) = self.high_priority_pool.stop() {
+            error!("failed to stop scheduler high priority pool: {:?}", e);
+        }
+    }
+
     fn too_busy(&self) -> bool {
         self.running_write_count >= self.sched_too_busy_threshold
     }
 
-    fn on_receive_new_cmd(&mut self, cmd: Command, callback: StorageCb) {
+    fn on_receive_new_cmd(
+        &mut self,
+        cmd: Command,
+        callback: StorageCb,
+        client_addr: Option<String>,
+    ) {
         // write flow control
-        if cmd.need_flow_control() && self.too_busy() {
+        if cmd.need_flow_control() && (self.too_busy() || self.flow_controller.should_throttle()) {
             SCHED_TOO_BUSY_COUNTER_VEC
                 .with_label_values(&[cmd.tag()])
                 .inc();
@@ -1094,7 +1274,25 @@ impl Scheduler {
             return;
 
         }
-        self.schedule_command(cmd, callback);
+        let group = self.resource_groups.get(cmd.get_context().get_resource_group_name());
+        let throttled = if cmd.readonly() {
+            group.is_read_throttled()
+        } else {
+            group.is_write_throttled()
+        };
+        if throttled {
+            SCHED_TOO_BUSY_COUNTER_VEC
+                .with_label_values(&[cmd.tag()])
+                .inc();
+            execute_callback(
+                callback,
+                ProcessResult::Failed {
+                    err: StorageError::SchedTooBusy,
+                },
+            );
+            return;
+        }
+        self.schedule_command(cmd, callback, group, client_addr);
     }
 
     /// Tries to acquire all the required latches for a command.
@@ -1246,9 +1444,21 @@ impl Scheduler {
     ///
     /// If a next command is present, continues to execute; otherwise, delivers the result to the
     /// callback.
-    fn on_read_finished(&mut self, cid: u64, pr: ProcessResult) {
+    fn on_read_finished(
+        &mut self,
+        cid: u64,
+        cmd: Command,
+        pr: ProcessResult,
+        statistics: Statistics,
+    ) {
+        log_fields!(cid = cid);
         debug!("read command(cid={}) finished", cid);
         let mut ctx = self.remove_ctx(cid);
+        ctx.group
+            .consume_read(resource_group::read_request_units(statistics.total_op_count()));
+        ctx.statistics = statistics;
+        // Kept for the slow log.
+        ctx.cmd = Some(cmd);
         SCHED_STAGE_COUNTER_VEC
             .with_label_values(&[ctx.tag, "read_finish"])
             .inc();
@@ -1257,7 +1467,9 @@ impl Scheduler {
             SCHED_STAGE_COUNTER_VEC
                 .with_label_values(&[ctx.tag, "next_cmd"])
                 .inc();
-            self.schedule_command(cmd, cb);
+            let group = ctx.group.clone();
+            let client_addr = ctx.client_addr.take();
+            self.schedule_command(cmd, cb, group, client_addr);
         } else {
             execute_callback(cb, pr);
         }
@@ -1287,7 +1499,17 @@ impl Scheduler {
         cmd: Command,
         pr: ProcessResult,
         to_be_write: Vec<Modify>,
+        statistics: Statistics,
     ) {
+        let write_bytes = modifies_size(&to_be_write);
+        self.flow_controller.consume(write_bytes);
+        {
+            let ctx = self.cmd_ctxs.get_mut(&cid).unwrap();
+            ctx.group.consume_write(resource_group::write_request_units(write_bytes));
+            ctx.statistics = statistics;
+            // Kept for the slow log.
+            ctx.cmd = Some(cmd);
+        }
         SCHED_STAGE_COUNTER_VEC
             .with_label_values(&[self.get_ctx_tag(cid), "write"])
             .inc();
@@ -1296,7 +1518,7 @@ impl Scheduler {
         }
         let engine_cb = make_engine_cb(cid, pr, self.schedch.clone());
         if let Err(e) = self.engine
-            .async_write(cmd.get_context(), to_be_write, engine_cb)
+            .async_write(self.extract_context(cid), to_be_write, engine_cb)
         {
             SCHED_STAGE_COUNTER_VEC
                 .with_label_values(&[self.get_ctx_tag(cid), "async_write_err"])
@@ -1307,6 +1529,7 @@ impl Scheduler {
 
     /// Event handler for the success of write.
     fn on_write_finished(&mut self, cid: u64, pr: ProcessResult, result: EngineResult<()>) {
+        log_fields!(cid = cid);
         SCHED_STAGE_COUNTER_VEC
             .with_label_values(&[self.get_ctx_tag(cid), "write_finish"])
             .inc();
@@ -1323,7 +1546,9 @@ impl Scheduler {
             SCHED_STAGE_COUNTER_VEC
                 .with_label_values(&[ctx.tag, "next_cmd"])
                 .inc();
-            self.schedule_command(cmd, cb);
+            let group = ctx.group.clone();
+            let client_addr = ctx.client_addr.take();
+            self.schedule_command(cmd, cb, group, client_addr);
         } else {
             execute_callback(cb, pr);
         }
@@ -1367,8 +1592,15 @@ impl Scheduler {
 
             for msg in msgs.drain(..) {
                 match msg {
-                    Msg::Quit => return Ok(()),
-                    Msg::RawCmd { cmd, cb } => self.on_receive_new_cmd(cmd, cb),
+                    Msg::Quit => {
+                        self.shutdown();
+                        return Ok(());
+                    }
+                    Msg::RawCmd {
+                        cmd,
+                        cb,
+                        client_addr,
+                    } => self.on_receive_new_cmd(cmd, cb, client_addr),
                     Msg::RetryGetSnapshots(tasks) => for (ctx, cids) in tasks {
                         self.get_snapshot(&ctx, cids);
                     },
@@ -1380,17 +1612,24 @@ impl Scheduler {
                     Msg::BatchSnapshotFinished { batch } => for (cids, cb_ctx, snapshot) in batch {
                         self.on_snapshot_finished(cids, cb_ctx, snapshot)
                     },
-                    Msg::ReadFinished { cid, pr } => self.on_read_finished(cid, pr),
+                    Msg::ReadFinished {
+                        cid,
+                        cmd,
+                        pr,
+                        statistics,
+                    } => self.on_read_finished(cid, cmd, pr, statistics),
                     Msg::WritePrepareFinished {
                         cid,
                         cmd,
                         pr,
                         to_be_write,
-                    } => self.on_write_prepare_finished(cid, cmd, pr, to_be_write),
+                        statistics,
+                    } => self.on_write_prepare_finished(cid, cmd, pr, to_be_write, statistics),
                     Msg::WritePrepareFailed { cid, err } => self.on_write_prepare_failed(cid, err),
                     Msg::WriteFinished {
                         cid, pr, result, ..
                     } => self.on_write_finished(cid, pr, result),
+                    Msg::ChangeConfig(cfg) => self.on_config_changed(cfg),
                 }
             }
 
@@ -15,13 +15,48 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use time::{self, Timespec, Tm};
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, Write};
-use std::fmt::Arguments;
-use std::path::Path;
+use std::fmt::{self, Arguments};
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
 
 use super::logger::LogWriter;
 
 const ONE_DAY_SECONDS: u64 = 60 * 60 * 24;
+const COMPRESSED_SUFFIX: &'static str = ".gz";
+
+/// Controls when log files are rotated and how rotated files are retained.
+#[derive(Clone, Debug)]
+pub struct RotationConfig {
+    /// Rotate the file when a period of `timespan` is over, periods are
+    /// aligned to the local midnight. 0 means never rotate by time.
+    pub timespan: Duration,
+    /// Rotate the file when it grows to `max_size` bytes, 0 means never
+    /// rotate by size.
+    pub max_size: u64,
+    /// How many rotated files to keep, 0 means no limit.
+    pub max_backups: usize,
+    /// How many days to keep rotated files, 0 means no limit.
+    pub max_days: u64,
+    /// Whether to gzip rotated files.
+    pub compress: bool,
+}
+
+impl Default for RotationConfig {
+    fn default() -> RotationConfig {
+        RotationConfig {
+            timespan: Duration::from_secs(ONE_DAY_SECONDS),
+            max_size: 0,
+            max_backups: 0,
+            max_days: 0,
+            compress: false,
+        }
+    }
+}
 
 fn systemtime_to_tm(t: SystemTime) -> Tm {
     let duration = t.duration_since(UNIX_EPOCH).unwrap();
@@ -29,7 +64,9 @@ fn systemtime_to_tm(t: SystemTime) -> Tm {
     time::at(spec)
 }
 
-fn compute_rollover_time(tm: Tm) -> Tm {
+/// Returns the end of the period `tm` is in, periods of `timespan` start from
+/// the local midnight of the day.
+fn compute_rollover_time(tm: Tm, timespan: Duration) -> Tm {
     let day_start_tm = Tm {
         tm_hour: 0,
         tm_min: 0,
@@ -37,14 +74,17 @@ fn compute_rollover_time(tm: Tm) -> Tm {
         tm_nsec: 0,
         ..tm
     };
-    let duration = time::Duration::from_std(Duration::new(ONE_DAY_SECONDS, 0)).unwrap();
+    let span = timespan.as_secs();
+    let elapsed = (tm.to_utc() - day_start_tm.to_utc()).num_seconds() as u64;
+    let periods = elapsed / span + 1;
+    let duration = time::Duration::from_std(Duration::new(periods * span, 0)).unwrap();
     (day_start_tm.to_utc() + duration).to_local()
 }
 
-/// Returns a Tm at the time one day before the given Tm.
+/// Returns a Tm at `timespan` before the given Tm.
 /// It expects the argument `tm` to be in local timezone. The resulting Tm is in local timezone.
-fn one_day_before(tm: Tm) -> Tm {
-    let duration = time::Duration::from_std(Duration::new(ONE_DAY_SECONDS, 0)).unwrap();
+fn time_before(tm: Tm, timespan: Duration) -> Tm {
+    let duration = time::Duration::from_std(timespan).unwrap();
     (tm.to_utc() - duration).to_local()
 }
 
@@ -57,50 +97,201 @@ fn open_log_file(path: &str) -> io::Result<File> {
     OpenOptions::new().append(true).create(true).open(path)
 }
 
+fn compress_file(path: &Path) -> io::Result<()> {
+    let mut gz_path = path.as_os_str().to_owned();
+    gz_path.push(COMPRESSED_SUFFIX);
+    {
+        let mut src = try!(File::open(path));
+        let dst = try!(File::create(&gz_path));
+        let mut encoder = GzEncoder::new(dst, Compression::Default);
+        try!(io::copy(&mut src, &mut encoder));
+        try!(encoder.finish());
+    }
+    fs::remove_file(path)
+}
+
+/// Removes the rotated files of `file_path` exceeding the retention limits.
+fn purge_rotated_files(file_path: &str, cfg: &RotationConfig) -> io::Result<()> {
+    if cfg.max_backups == 0 && cfg.max_days == 0 {
+        return Ok(());
+    }
+    let path = Path::new(file_path);
+    let prefix = format!("{}.", path.file_name().unwrap().to_str().unwrap());
+    let mut rotated = vec![];
+    for entry in try!(fs::read_dir(path.parent().unwrap())) {
+        let entry = try!(entry);
+        let is_rotated = entry
+            .file_name()
+            .to_str()
+            .map_or(false, |name| name.starts_with(&prefix));
+        if is_rotated {
+            let modified = try!(entry.metadata().and_then(|m| m.modified()));
+            rotated.push((modified, entry.path()));
+        }
+    }
+    // Newest first.
+    rotated.sort_by(|a, b| b.cmp(a));
+
+    let now = SystemTime::now();
+    let max_age = Duration::from_secs(cfg.max_days * ONE_DAY_SECONDS);
+    for (i, &(modified, ref p)) in rotated.iter().enumerate() {
+        let too_many = cfg.max_backups > 0 && i >= cfg.max_backups;
+        let too_old = cfg.max_days > 0 &&
+            now.duration_since(modified).map(|d| d > max_age).unwrap_or(false);
+        if too_many || too_old {
+            try!(fs::remove_file(p));
+        }
+    }
+    Ok(())
+}
+
 struct RotatingFileLoggerCore {
-    rollover_time: Tm,
+    cfg: RotationConfig,
+    rollover_time: Option<Tm>,
     file_path: String,
     file: File,
+    file_size: u64,
+    // Rotated files are sent to a background thread to be compressed and
+    // purged, so that writers never wait for it.
+    rotated_tx: Option<Sender<PathBuf>>,
+    background: Option<JoinHandle<()>>,
 }
 
 impl RotatingFileLoggerCore {
-    fn new(path: &str) -> io::Result<RotatingFileLoggerCore> {
+    fn new(path: &str, cfg: RotationConfig) -> io::Result<RotatingFileLoggerCore> {
         let file = try!(open_log_file(path));
-        let file_attr = fs::metadata(path).unwrap();
-        let file_modified_time = file_attr.modified().unwrap();
-        let rollover_time = compute_rollover_time(systemtime_to_tm(file_modified_time));
+        let file_attr = try!(fs::metadata(path));
+        let file_modified_time = try!(file_attr.modified());
+        let rollover_time = if cfg.timespan.as_secs() > 0 {
+            Some(compute_rollover_time(
+                systemtime_to_tm(file_modified_time),
+                cfg.timespan,
+            ))
+        } else {
+            None
+        };
         let ret = RotatingFileLoggerCore {
+            cfg: cfg,
             rollover_time: rollover_time,
             file_path: path.to_string(),
             file: file,
+            file_size: file_attr.len(),
+            rotated_tx: None,
+            background: None,
         };
         Ok(ret)
     }
 
     fn open(&mut self) {
-        self.file = open_log_file(&self.file_path).unwrap()
+        self.file = open_log_file(&self.file_path).unwrap();
+        self.file_size = 0;
     }
 
     fn should_rollover(&mut self) -> bool {
-        time::now() > self.rollover_time
+        if self.cfg.max_size > 0 && self.file_size >= self.cfg.max_size {
+            return true;
+        }
+        self.rollover_time.map_or(false, |t| time::now() > t)
+    }
+
+    // Returns a name for the rotated file which doesn't exist yet.
+    fn rotated_file_path(&self) -> PathBuf {
+        let (period_start, fmt) = match self.rollover_time {
+            Some(t) if self.cfg.timespan.as_secs() >= ONE_DAY_SECONDS => {
+                (time_before(t, self.cfg.timespan), "%Y%m%d")
+            }
+            Some(t) => (time_before(t, self.cfg.timespan), "%Y%m%d-%H%M"),
+            None => (time::now(), "%Y%m%d-%H%M%S"),
+        };
+        let base = format!(
+            "{}.{}",
+            self.file_path,
+            time::strftime(fmt, &period_start).unwrap()
+        );
+        let exists = |p: &str| {
+            Path::new(p).exists() || Path::new(&format!("{}{}", p, COMPRESSED_SUFFIX)).exists()
+        };
+        let mut path = base.clone();
+        let mut i = 0;
+        while exists(&path) {
+            i += 1;
+            path = format!("{}.{}", base, i);
+        }
+        PathBuf::from(path)
     }
 
     fn do_rollover(&mut self) {
         self.close();
-        let mut s = self.file_path.clone();
-        s.push_str(".");
-        s.push_str(&time::strftime(
-            "%Y%m%d",
-            &one_day_before(self.rollover_time),
-        ).unwrap());
-        fs::rename(&self.file_path, &s).unwrap();
+        let rotated = self.rotated_file_path();
+        fs::rename(&self.file_path, &rotated).unwrap();
         self.update_rollover_time();
-        self.open()
+        self.open();
+
+        if self.rotated_tx.is_none() {
+            self.start_background();
+        }
+        if let Some(ref tx) = self.rotated_tx {
+            // The thread may have exited after a panic, the file is just left as it is.
+            if let Err(e) = tx.send(rotated) {
+                let _ = writeln!(
+                    io::stderr(),
+                    "log rotation thread has exited, {:?} is not compressed or purged",
+                    e.0
+                );
+            }
+        }
+    }
+
+    fn start_background(&mut self) {
+        let (tx, rx) = mpsc::channel::<PathBuf>();
+        let cfg = self.cfg.clone();
+        let file_path = self.file_path.clone();
+        let res = thread::Builder::new()
+            .name(thd_name!("log-rotation"))
+            .spawn(move || for rotated in rx {
+                if cfg.compress {
+                    if let Err(e) = compress_file(&rotated) {
+                        let _ = writeln!(io::stderr(), "failed to compress {:?}: {:?}", rotated, e);
+                    }
+                }
+                if let Err(e) = purge_rotated_files(&file_path, &cfg) {
+                    let _ = writeln!(io::stderr(), "failed to purge rotated logs: {:?}", e);
+                }
+            });
+        match res {
+            Ok(h) => {
+                self.rotated_tx = Some(tx);
+                self.background = Some(h);
+            }
+            Err(e) => {
+                let _ = writeln!(io::stderr(), "failed to spawn log rotation thread: {:?}", e);
+            }
+        }
+    }
+
+    // Waits for the background thread to handle all rotated files and exit.
+    fn wait_background(&mut self) {
+        self.rotated_tx.take();
+        if let Some(h) = self.background.take() {
+            let _ = h.join();
+        }
     }
 
     fn update_rollover_time(&mut self) {
-        let now = time::now();
-        self.rollover_time = compute_rollover_time(now);
+        if self.rollover_time.is_some() {
+            let now = time::now();
+            self.rollover_time = Some(compute_rollover_time(now, self.cfg.timespan));
+        }
+    }
+
+    fn write(&mut self, args: Arguments) {
+        if self.should_rollover() {
+            self.do_rollover()
+        };
+        let s = fmt::format(args);
+        if self.file.write_all(s.as_bytes()).is_ok() {
+            self.file_size += s.len() as u64;
+        }
     }
 
     fn close(&mut self) {
@@ -108,14 +299,19 @@ impl RotatingFileLoggerCore {
     }
 }
 
-/// A log implemetation which writes to file and rotates by day.
+/// A log implemetation which writes to file and rotates by time and size.
 pub struct RotatingFileLogger {
     core: Mutex<RotatingFileLoggerCore>,
 }
 
 impl RotatingFileLogger {
+    /// Creates a logger which rotates the file every day.
     pub fn new(file_path: &str) -> io::Result<RotatingFileLogger> {
-        let core = try!(RotatingFileLoggerCore::new(file_path));
+        RotatingFileLogger::with_rotation(file_path, RotationConfig::default())
+    }
+
+    pub fn with_rotation(file_path: &str, cfg: RotationConfig) -> io::Result<RotatingFileLogger> {
+        let core = try!(RotatingFileLoggerCore::new(file_path, cfg));
         let ret = RotatingFileLogger {
             core: Mutex::new(core),
         };
@@ -126,38 +322,55 @@ impl RotatingFileLogger {
 impl LogWriter for RotatingFileLogger {
     fn write(&self, args: Arguments) {
         let mut core = self.core.lock().unwrap();
-        if core.should_rollover() {
-            core.do_rollover()
-        };
-        let _ = core.file.write_fmt(args);
+        core.write(args);
     }
 }
 
 impl Drop for RotatingFileLogger {
     fn drop(&mut self) {
-        let mut core = self.core.lock().unwrap();
-        core.close()
+        let core = self.core.get_mut().unwrap();
+        core.close();
+        core.wait_background();
     }
 }
 
-
 #[cfg(test)]
 mod tests {
     extern crate log;
     extern crate rand;
     extern crate utime;
-    use time::{self, Timespec};
+    use time::{self, Timespec, Tm};
     use std::io::prelude::*;
     use std::fs::OpenOptions;
     use std::path::Path;
     use tempdir::TempDir;
-    use super::{RotatingFileLoggerCore, ONE_DAY_SECONDS};
+    use std::fs;
+    use std::time::Duration;
+    use super::{RotatingFileLoggerCore, RotationConfig, COMPRESSED_SUFFIX, ONE_DAY_SECONDS};
 
     #[test]
-    fn test_one_day_before() {
+    fn test_time_before() {
         let tm = time::strptime("2016-08-30", "%Y-%m-%d").unwrap().to_local();
         let one_day_ago = time::strptime("2016-08-29", "%Y-%m-%d").unwrap().to_local();
-        assert_eq!(one_day_ago, super::one_day_before(tm));
+        let one_day = Duration::from_secs(ONE_DAY_SECONDS);
+        assert_eq!(one_day_ago, super::time_before(tm, one_day));
+    }
+
+    #[test]
+    fn test_compute_rollover_time() {
+        let tm = Tm {
+            tm_hour: 13,
+            tm_min: 20,
+            tm_sec: 0,
+            tm_nsec: 0,
+            ..time::now()
+        };
+        let next_hour = super::compute_rollover_time(tm, Duration::from_secs(3600));
+        assert_eq!((next_hour.tm_hour, next_hour.tm_min, next_hour.tm_sec), (14, 0, 0));
+        let one_day = Duration::from_secs(ONE_DAY_SECONDS);
+        let next_day = super::compute_rollover_time(tm, one_day);
+        assert_eq!((next_day.tm_hour, next_day.tm_min, next_day.tm_sec), (0, 0, 0));
+        assert!(next_day > tm);
     }
 
     fn file_exists(file: &str) -> bool {
@@ -188,16 +401,56 @@ mod tests {
         let time_in_sec = one_day_ago.sec as u64;
         utime::set_file_times(&log_file, time_in_sec, time_in_sec).unwrap();
         // initialize the logger
-        let mut core = RotatingFileLoggerCore::new(&log_file).unwrap();
+        let mut core = RotatingFileLoggerCore::new(&log_file, RotationConfig::default()).unwrap();
         assert!(core.should_rollover());
         core.do_rollover();
         // check the rotated file exist
         let mut rotated_file = log_file.clone();
         rotated_file.push_str(".");
-        let file_suffix_time =
-            super::one_day_before(super::compute_rollover_time(time::at(one_day_ago)));
+        let one_day = Duration::from_secs(ONE_DAY_SECONDS);
+        let file_suffix_time = super::time_before(
+            super::compute_rollover_time(time::at(one_day_ago), one_day),
+            one_day,
+        );
         rotated_file.push_str(&time::strftime("%Y%m%d", &file_suffix_time).unwrap());
         assert!(file_exists(&rotated_file));
         assert!(!core.should_rollover());
     }
+
+    #[test]
+    fn test_rotate_by_size() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let log_file = tmp_dir
+            .path()
+            .join("test_rotate_by_size.log")
+            .to_str()
+            .unwrap()
+            .to_string();
+        let cfg = RotationConfig {
+            timespan: Duration::from_secs(0),
+            max_size: 10,
+            max_backups: 2,
+            max_days: 0,
+            compress: true,
+        };
+        let mut core = RotatingFileLoggerCore::new(&log_file, cfg).unwrap();
+        for _ in 0..5 {
+            core.write(format_args!("hello world!\n"));
+        }
+        core.wait_background();
+        // 5 writes, the last 4 of which trigger a rotation.
+        let mut rotated = vec![];
+        for entry in fs::read_dir(tmp_dir.path()).unwrap() {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            if name != "test_rotate_by_size.log" {
+                rotated.push(name);
+            }
+        }
+        assert_eq!(rotated.len(), 2, "{:?}", rotated);
+        for name in rotated {
+            assert!(name.starts_with("test_rotate_by_size.log."), "{}", name);
+            assert!(name.ends_with(COMPRESSED_SUFFIX), "{}", name);
+        }
+        assert!(!core.should_rollover());
+    }
 }
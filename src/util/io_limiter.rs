@@ -0,0 +1,345 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cmp;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use prometheus::Counter;
+
+use util::metrics::{IO_LIMITER_BYTES_VEC, IO_LIMITER_THROTTLED_DURATION_VEC};
+use util::time::duration_to_sec;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const PRIORITY_COUNT: usize = 3;
+// High priority IO isn't paced, it delays lower priorities by at most this much, so a
+// burst of foreground writes doesn't block background tasks for long after it's over.
+const MAX_PREEMPT_MILLIS: u64 = 1000;
+
+fn bytes_cost(bytes: usize, bytes_per_sec: u64) -> Duration {
+    let cost = bytes as u64 * NANOS_PER_SEC / bytes_per_sec;
+    Duration::new(cost / NANOS_PER_SEC, (cost % NANOS_PER_SEC) as u32)
+}
+
+struct LimiterCore {
+    bytes_per_sec: u64,
+    // The time when all bytes requested so far are allowed to pass.
+    next_free: Instant,
+}
+
+/// `IOLimiter` paces IO to a configured number of bytes per second.
+///
+/// A zero rate means no limit. Callers either block in `request` or ask for the
+/// time to wait through `consume` when they can't sleep in place.
+pub struct IOLimiter {
+    core: Mutex<LimiterCore>,
+}
+
+impl IOLimiter {
+    pub fn new(bytes_per_sec: u64) -> IOLimiter {
+        IOLimiter {
+            core: Mutex::new(LimiterCore {
+                bytes_per_sec: bytes_per_sec,
+                next_free: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn set_bytes_per_sec(&self, bytes_per_sec: u64) {
+        let mut core = self.core.lock().unwrap();
+        core.bytes_per_sec = bytes_per_sec;
+        core.next_free = Instant::now();
+    }
+
+    pub fn get_bytes_per_sec(&self) -> u64 {
+        self.core.lock().unwrap().bytes_per_sec
+    }
+
+    /// Account `bytes` and return how long the caller should wait before doing the IO.
+    pub fn consume(&self, bytes: usize) -> Duration {
+        let mut core = self.core.lock().unwrap();
+        if core.bytes_per_sec == 0 {
+            return Duration::default();
+        }
+        let now = Instant::now();
+        if core.next_free < now {
+            core.next_free = now;
+        }
+        let wait = core.next_free - now;
+        core.next_free += bytes_cost(bytes, core.bytes_per_sec);
+        wait
+    }
+
+    /// Block the current thread until `bytes` are allowed to pass.
+    pub fn request(&self, bytes: usize) {
+        let wait = self.consume(bytes);
+        if wait > Duration::default() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IOPriority {
+    /// Never throttled, but it takes the rate from lower priorities.
+    High = 0,
+    Medium = 1,
+    Low = 2,
+}
+
+/// The kinds of IO going through the process-wide limiter, see `request_io`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IOType {
+    ForegroundWrite,
+    RaftLog,
+    Snapshot,
+    Compaction,
+    Gc,
+    Import,
+    Backup,
+}
+
+const IO_TYPES: [IOType; 7] = [
+    IOType::ForegroundWrite,
+    IOType::RaftLog,
+    IOType::Snapshot,
+    IOType::Compaction,
+    IOType::Gc,
+    IOType::Import,
+    IOType::Backup,
+];
+
+impl IOType {
+    pub fn priority(self) -> IOPriority {
+        match self {
+            IOType::ForegroundWrite | IOType::RaftLog => IOPriority::High,
+            IOType::Snapshot => IOPriority::Medium,
+            IOType::Compaction | IOType::Gc | IOType::Import | IOType::Backup => {
+                IOPriority::Low
+            }
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            IOType::ForegroundWrite => "foreground_write",
+            IOType::RaftLog => "raft_log",
+            IOType::Snapshot => "snapshot",
+            IOType::Compaction => "compaction",
+            IOType::Gc => "gc",
+            IOType::Import => "import",
+            IOType::Backup => "backup",
+        }
+    }
+}
+
+struct PriorityLimiterCore {
+    bytes_per_sec: u64,
+    // The time when all bytes requested so far at the priority or higher priorities
+    // are allowed to pass, indexed by `IOPriority`.
+    next_free: [Instant; PRIORITY_COUNT],
+}
+
+/// `PriorityIOLimiter` paces IO of different priorities to a shared rate.
+///
+/// A request only waits for the bytes requested at its priority or higher ones, so
+/// lower priorities get what's left by higher ones. `High` requests never wait, and
+/// they don't take the lock either: their bytes are only added up, and charged to
+/// lower priorities by the next request of a lower priority.
+pub struct PriorityIOLimiter {
+    // Mirrors `PriorityLimiterCore::bytes_per_sec` so that unlimited requests skip
+    // the lock.
+    bytes_per_sec: AtomicUsize,
+    // `High` bytes which are not charged to lower priorities yet.
+    high_bytes: AtomicUsize,
+    core: Mutex<PriorityLimiterCore>,
+}
+
+impl PriorityIOLimiter {
+    pub fn new(bytes_per_sec: u64) -> PriorityIOLimiter {
+        let now = Instant::now();
+        PriorityIOLimiter {
+            bytes_per_sec: AtomicUsize::new(bytes_per_sec as usize),
+            high_bytes: AtomicUsize::new(0),
+            core: Mutex::new(PriorityLimiterCore {
+                bytes_per_sec: bytes_per_sec,
+                next_free: [now; PRIORITY_COUNT],
+            }),
+        }
+    }
+
+    pub fn set_bytes_per_sec(&self, bytes_per_sec: u64) {
+        let mut core = self.core.lock().unwrap();
+        core.bytes_per_sec = bytes_per_sec;
+        core.next_free = [Instant::now(); PRIORITY_COUNT];
+        self.high_bytes.store(0, Ordering::Relaxed);
+        self.bytes_per_sec.store(bytes_per_sec as usize, Ordering::Relaxed);
+    }
+
+    pub fn get_bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec.load(Ordering::Relaxed) as u64
+    }
+
+    /// Account `bytes` and return how long the caller should wait before doing the IO.
+    pub fn consume(&self, priority: IOPriority, bytes: usize) -> Duration {
+        if self.get_bytes_per_sec() == 0 {
+            return Duration::default();
+        }
+        if priority == IOPriority::High {
+            self.high_bytes.fetch_add(bytes, Ordering::Relaxed);
+            return Duration::default();
+        }
+
+        let mut core = self.core.lock().unwrap();
+        if core.bytes_per_sec == 0 {
+            return Duration::default();
+        }
+        let now = Instant::now();
+        let high_bytes = self.high_bytes.swap(0, Ordering::Relaxed);
+        if high_bytes > 0 {
+            let cost = bytes_cost(high_bytes, core.bytes_per_sec);
+            let preempt_limit = now + Duration::from_millis(MAX_PREEMPT_MILLIS);
+            for next_free in &mut core.next_free {
+                let delayed = cmp::min(cmp::max(*next_free, now) + cost, preempt_limit);
+                *next_free = cmp::max(delayed, *next_free);
+            }
+        }
+        let p = priority as usize;
+        let wait = cmp::max(core.next_free[p], now) - now;
+        let cost = bytes_cost(bytes, core.bytes_per_sec);
+        for q in p..PRIORITY_COUNT {
+            core.next_free[q] = cmp::max(core.next_free[q], now) + cost;
+        }
+        wait
+    }
+
+    /// Block the current thread until `bytes` are allowed to pass, returns the time
+    /// spent waiting.
+    pub fn request(&self, priority: IOPriority, bytes: usize) -> Duration {
+        let wait = self.consume(priority, bytes);
+        if wait > Duration::default() {
+            thread::sleep(wait);
+        }
+        wait
+    }
+}
+
+lazy_static! {
+    static ref PROCESS_IO_LIMITER: PriorityIOLimiter = PriorityIOLimiter::new(0);
+    // Resolved once, so that requesting IO doesn't look up the metric vectors.
+    static ref IO_BYTES_COUNTERS: Vec<Counter> = IO_TYPES
+        .iter()
+        .map(|t| IO_LIMITER_BYTES_VEC.with_label_values(&[t.as_str()]))
+        .collect();
+    static ref IO_THROTTLED_COUNTERS: Vec<Counter> = IO_TYPES
+        .iter()
+        .map(|t| IO_LIMITER_THROTTLED_DURATION_VEC.with_label_values(&[t.as_str()]))
+        .collect();
+}
+
+/// Sets the rate of the process-wide IO limiter, 0 means no limit.
+pub fn set_background_io_rate_limit(bytes_per_sec: u64) {
+    PROCESS_IO_LIMITER.set_bytes_per_sec(bytes_per_sec);
+}
+
+/// Requests `bytes` of `io_type` from the process-wide IO limiter, blocking the
+/// current thread until they are allowed to pass. IO of foreground writes and raft
+/// logs never blocks, it's only accounted so background tasks yield to it.
+///
+/// Nothing is accounted when there is no limit.
+pub fn request_io(io_type: IOType, bytes: usize) {
+    if bytes == 0 || PROCESS_IO_LIMITER.get_bytes_per_sec() == 0 {
+        return;
+    }
+    let idx = io_type as usize;
+    IO_BYTES_COUNTERS[idx].inc_by(bytes as f64).unwrap();
+    let wait = PROCESS_IO_LIMITER.request(io_type.priority(), bytes);
+    if wait > Duration::default() {
+        IO_THROTTLED_COUNTERS[idx]
+            .inc_by(duration_to_sec(wait))
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = IOLimiter::new(0);
+        for _ in 0..100 {
+            assert_eq!(limiter.consume(1024 * 1024), Duration::default());
+        }
+    }
+
+    #[test]
+    fn test_consume() {
+        let limiter = IOLimiter::new(1024);
+        assert_eq!(limiter.consume(1024), Duration::default());
+        // The second request has to wait for the first one to pass.
+        let wait = limiter.consume(1024);
+        assert!(wait > Duration::from_millis(900), "{:?}", wait);
+        assert!(wait <= Duration::from_secs(1), "{:?}", wait);
+
+        limiter.set_bytes_per_sec(0);
+        assert_eq!(limiter.consume(1024), Duration::default());
+    }
+
+    #[test]
+    fn test_request() {
+        let limiter = IOLimiter::new(10 * 1024);
+        let t = Instant::now();
+        for _ in 0..3 {
+            limiter.request(1024);
+        }
+        assert!(t.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_priority_limiter() {
+        let limiter = PriorityIOLimiter::new(1024);
+        // High priority never waits, but takes the rate from lower priorities.
+        assert_eq!(limiter.consume(IOPriority::High, 1024), Duration::default());
+        assert_eq!(limiter.consume(IOPriority::High, 1024), Duration::default());
+        let wait = limiter.consume(IOPriority::Low, 512);
+        assert!(wait > Duration::from_millis(900), "{:?}", wait);
+        assert!(wait <= Duration::from_secs(1), "{:?}", wait);
+
+        // Medium priority doesn't wait for low priority.
+        let wait = limiter.consume(IOPriority::Medium, 512);
+        assert!(wait > Duration::from_millis(900), "{:?}", wait);
+        assert!(wait <= Duration::from_secs(1), "{:?}", wait);
+        let wait = limiter.consume(IOPriority::Medium, 1024);
+        assert!(wait > Duration::from_millis(1400), "{:?}", wait);
+        assert!(wait <= Duration::from_millis(1500), "{:?}", wait);
+        // Low priority waits for all bytes requested before.
+        let wait = limiter.consume(IOPriority::Low, 1024);
+        assert!(wait > Duration::from_millis(2900), "{:?}", wait);
+        assert!(wait <= Duration::from_secs(3), "{:?}", wait);
+
+        limiter.set_bytes_per_sec(0);
+        assert_eq!(limiter.consume(IOPriority::Low, 1024), Duration::default());
+    }
+
+    #[test]
+    fn test_io_types() {
+        for (i, t) in IO_TYPES.iter().enumerate() {
+            assert_eq!(*t as usize, i);
+        }
+    }
+}
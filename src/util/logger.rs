@@ -13,23 +13,77 @@
 
 use std::io::{self, Write};
 use std::fmt::Arguments;
+use std::fmt::Write as FmtWrite;
+use std::cell::RefCell;
 
-use time;
-use log::{self, Log, LogMetadata, LogRecord, SetLoggerError};
+use time::{self, Tm};
+use log::{self, Log, LogLevel, LogMetadata, LogRecord, SetLoggerError};
+use serde_json;
 
 pub use log::LogLevelFilter;
 
 const ENABLED_TARGETS: &[&'static str] = &["tikv::", "tests::", "benches::"];
 
+/// Logs with this target are written by the slow log writer if there is one.
+pub const SLOW_LOG_TARGET: &'static str = "tikv::slow_log";
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// `time file:line: [level] message`, one record per line.
+    Text,
+    /// One JSON object per line, including the fields attached by `log_fields!`.
+    Json,
+}
+
+thread_local! {
+    static LOG_FIELDS: RefCell<Vec<(&'static str, u64)>> = RefCell::new(vec![]);
+}
+
+/// Removes the fields pushed by `push_log_fields` when dropped.
+pub struct LogFieldsGuard {
+    len: usize,
+}
+
+impl Drop for LogFieldsGuard {
+    fn drop(&mut self) {
+        LOG_FIELDS.with(|fields| fields.borrow_mut().truncate(self.len));
+    }
+}
+
+/// Attaches `fields` to all the logs of current thread until the returned guard is dropped.
+///
+/// Only the json format outputs the fields, the text format keeps the message untouched.
+/// Use `log_fields!` instead of calling it directly.
+pub fn push_log_fields(fields: &[(&'static str, u64)]) -> LogFieldsGuard {
+    LOG_FIELDS.with(|f| {
+        let mut f = f.borrow_mut();
+        let len = f.len();
+        f.extend_from_slice(fields);
+        LogFieldsGuard { len: len }
+    })
+}
+
 pub fn init_log<W: LogWriter + Sync + Send + 'static>(
     writer: W,
     level: LogLevelFilter,
+) -> Result<(), SetLoggerError> {
+    init_log_with_slow_writer(writer, None, level, LogFormat::Text)
+}
+
+pub fn init_log_with_slow_writer<W: LogWriter + Sync + Send + 'static>(
+    writer: W,
+    slow_writer: Option<Box<LogWriter + Sync + Send>>,
+    level: LogLevelFilter,
+    format: LogFormat,
 ) -> Result<(), SetLoggerError> {
     log::set_logger(|filter| {
         filter.set(level);
         Box::new(Logger {
             level: level,
+            format: format,
             writer: writer,
+            slow_writer: slow_writer,
             tikv_only: false,
         })
     })
@@ -43,7 +97,9 @@ pub fn init_log_for_tikv_only<W: LogWriter + Sync + Send + 'static>(
         filter.set(level);
         Box::new(Logger {
             level: level,
+            format: LogFormat::Text,
             writer: writer,
+            slow_writer: None,
             tikv_only: true,
         })
     })
@@ -53,9 +109,18 @@ pub trait LogWriter {
     fn write(&self, args: Arguments);
 }
 
+impl<W: LogWriter + ?Sized> LogWriter for Box<W> {
+    #[inline]
+    fn write(&self, args: Arguments) {
+        (**self).write(args)
+    }
+}
+
 struct Logger<W: LogWriter> {
     level: LogLevelFilter,
+    format: LogFormat,
     writer: W,
+    slow_writer: Option<Box<LogWriter + Sync + Send>>,
     tikv_only: bool,
 }
 
@@ -74,18 +139,85 @@ impl<W: LogWriter + Sync + Send> Log for Logger<W> {
         }
         if self.enabled(record.metadata()) {
             let t = time::now();
-            let time_str = time::strftime("%Y/%m/%d %H:%M:%S.%f", &t).unwrap();
-            // TODO allow formatter to be configurable.
-            self.writer.write(format_args!(
-                "{} {}:{}: [{}] {}\n",
-                &time_str[..time_str.len() - 6],
-                record.location().file().rsplit('/').nth(0).unwrap(),
-                record.location().line(),
-                record.level(),
-                record.args()
-            ));
+            let writer: &LogWriter = match self.slow_writer {
+                Some(ref w) if record.target() == SLOW_LOG_TARGET => &**w,
+                _ => &self.writer,
+            };
+            match self.format {
+                LogFormat::Text => {
+                    let time_str = time::strftime("%Y/%m/%d %H:%M:%S.%f", &t).unwrap();
+                    writer.write(format_args!(
+                        "{} {}:{}: [{}] {}\n",
+                        &time_str[..time_str.len() - 6],
+                        record.location().file().rsplit('/').nth(0).unwrap(),
+                        record.location().line(),
+                        record.level(),
+                        record.args()
+                    ));
+                }
+                LogFormat::Json => {
+                    let line = LOG_FIELDS.with(|fields| {
+                        format_json(
+                            &t,
+                            record.level(),
+                            record.location().module_path(),
+                            record.location().file(),
+                            record.location().line(),
+                            record.args(),
+                            &fields.borrow(),
+                        )
+                    });
+                    writer.write(format_args!("{}\n", line));
+                }
+            }
+        }
+    }
+}
+
+fn push_json_str(buf: &mut String, s: &str) {
+    // Serializing a str never fails.
+    buf.push_str(&serde_json::to_string(s).unwrap());
+}
+
+/// Formats a record as a json object. If a field is attached more than once,
+/// the innermost one wins.
+fn format_json(
+    t: &Tm,
+    level: LogLevel,
+    module: &str,
+    file: &str,
+    line: u32,
+    msg: &Arguments,
+    fields: &[(&'static str, u64)],
+) -> String {
+    let mut buf = String::with_capacity(256);
+    let time_str = format!(
+        "{}.{:03}{}",
+        time::strftime("%Y-%m-%dT%H:%M:%S", t).unwrap(),
+        t.tm_nsec / 1_000_000,
+        time::strftime("%z", t).unwrap()
+    );
+    buf.push_str("{\"time\":");
+    push_json_str(&mut buf, &time_str);
+    buf.push_str(",\"level\":");
+    push_json_str(&mut buf, &level.to_string());
+    buf.push_str(",\"module\":");
+    push_json_str(&mut buf, module);
+    buf.push_str(",\"caller\":");
+    let caller = format!("{}:{}", file.rsplit('/').nth(0).unwrap(), line);
+    push_json_str(&mut buf, &caller);
+    for (i, &(key, value)) in fields.iter().enumerate().rev() {
+        if fields[i + 1..].iter().any(|&(k, _)| k == key) {
+            continue;
         }
+        buf.push(',');
+        push_json_str(&mut buf, key);
+        write!(buf, ":{}", value).unwrap();
     }
+    buf.push_str(",\"message\":");
+    push_json_str(&mut buf, &msg.to_string());
+    buf.push('}');
+    buf
 }
 
 pub struct StderrLogger;
@@ -109,3 +241,54 @@ pub fn get_level_by_string(lv: &str) -> LogLevelFilter {
         _ => LogLevelFilter::Info,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use time;
+    use serde_json::{self, Value};
+    use log::LogLevel;
+
+    use super::*;
+
+    #[test]
+    fn test_format_json() {
+        let t = time::now();
+        let format = |fields: &[(&'static str, u64)]| {
+            let s = format_json(
+                &t,
+                LogLevel::Info,
+                "tikv::raftstore",
+                "src/raftstore/store/peer.rs",
+                10,
+                &format_args!("say \"{}\"\n", "hi"),
+                fields,
+            );
+            serde_json::from_str::<Value>(&s).unwrap()
+        };
+
+        let v = format(&[]);
+        assert_eq!(v["level"], "INFO");
+        assert_eq!(v["module"], "tikv::raftstore");
+        assert_eq!(v["caller"], "peer.rs:10");
+        assert_eq!(v["message"], "say \"hi\"\n");
+        assert!(v.get("region_id").is_none());
+
+        let v = format(&[("region_id", 1), ("peer_id", 2), ("region_id", 3)]);
+        assert_eq!(v["region_id"], 3);
+        assert_eq!(v["peer_id"], 2);
+    }
+
+    #[test]
+    fn test_log_fields_guard() {
+        let fields = || LOG_FIELDS.with(|f| f.borrow().clone());
+        {
+            let _g1 = push_log_fields(&[("region_id", 1)]);
+            {
+                let _g2 = push_log_fields(&[("peer_id", 2), ("cid", 3)]);
+                assert_eq!(fields(), vec![("region_id", 1), ("peer_id", 2), ("cid", 3)]);
+            }
+            assert_eq!(fields(), vec![("region_id", 1)]);
+        }
+        assert!(fields().is_empty());
+    }
+}
@@ -125,15 +125,42 @@ macro_rules! recover_safe {
     })
 }
 
-/// Log slow operations with warn!.
+/// Log slow operations with warn! to the slow log.
 macro_rules! slow_log {
     ($t:expr, $($arg:tt)*) => {{
         if $t.is_slow() {
-            warn!("{} [takes {:?}]", format_args!($($arg)*), $t.elapsed());
+            warn!(
+                target: $crate::util::logger::SLOW_LOG_TARGET,
+                "{} [takes {:?}]",
+                format_args!($($arg)*),
+                $t.elapsed()
+            );
         }
     }}
 }
 
+/// Attaches key/value fields to the logs of current thread until the end of the scope.
+///
+/// The fields only show up when `log-format` is `json`.
+///
+/// # Examples
+///
+/// ```
+/// # #[macro_use] extern crate tikv;
+/// # #[macro_use] extern crate log;
+/// # fn main() {
+/// let (region_id, peer_id) = (1, 2);
+/// log_fields!(region_id = region_id, peer_id = peer_id);
+/// info!("the record carries region_id and peer_id");
+/// # }
+/// ```
+#[macro_export]
+macro_rules! log_fields {
+    ($($k:ident = $v:expr),+) => (
+        let __log_fields = $crate::util::logger::push_log_fields(&[$((stringify!($k), $v)),+]);
+    );
+}
+
 /// make a thread name with additional tag inheriting from current thread.
 #[macro_export]
 macro_rules! thd_name {
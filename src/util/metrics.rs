@@ -11,7 +11,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use prometheus::CounterVec;
+use prometheus::{exponential_buckets, CounterVec, HistogramVec};
 
 lazy_static! {
     pub static ref CHANNEL_FULL_COUNTER_VEC: CounterVec =
@@ -20,4 +20,40 @@ lazy_static! {
             "Total number of channel full errors.",
             &["type"]
         ).unwrap();
+
+    pub static ref IO_LIMITER_BYTES_VEC: CounterVec =
+        register_counter_vec!(
+            "tikv_io_limiter_bytes_total",
+            "Total bytes requested from the IO limiter.",
+            &["type"]
+        ).unwrap();
+
+    pub static ref IO_LIMITER_THROTTLED_DURATION_VEC: CounterVec =
+        register_counter_vec!(
+            "tikv_io_limiter_throttled_duration_seconds",
+            "Total time tasks are throttled by the IO limiter.",
+            &["type"]
+        ).unwrap();
+
+    pub static ref RESOURCE_GROUP_REQUEST_UNITS_VEC: CounterVec =
+        register_counter_vec!(
+            "tikv_resource_group_request_units_total",
+            "Total number of request units consumed by resource groups.",
+            &["group", "type"]
+        ).unwrap();
+
+    pub static ref RESOURCE_GROUP_THROTTLED_COUNTER_VEC: CounterVec =
+        register_counter_vec!(
+            "tikv_resource_group_throttled_total",
+            "Total number of requests rejected for exceeding the quota of resource groups.",
+            &["group", "type"]
+        ).unwrap();
+
+    pub static ref RESOURCE_GROUP_WAIT_HISTOGRAM_VEC: HistogramVec =
+        register_histogram_vec!(
+            "tikv_resource_group_wait_duration_seconds",
+            "Bucketed histogram of time requests of resource groups wait for a worker.",
+            &["group", "type"],
+            exponential_buckets(0.0005, 2.0, 20).unwrap()
+        ).unwrap();
 }
@@ -38,10 +38,14 @@ pub mod buf;
 pub mod transport;
 pub mod file;
 pub mod file_log;
+pub mod io_limiter;
 pub mod metrics;
 pub mod threadpool;
 pub mod collections;
 pub mod time;
+pub mod profiling;
+pub mod resource_group;
+pub mod security;
 
 pub use self::rocksdb::properties;
 
@@ -0,0 +1,552 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! On-demand CPU and heap profiling.
+//!
+//! CPU profiles are sampled with `ITIMER_PROF` and returned as folded stacks,
+//! which can be rendered by flame graph tools directly. The signal handler
+//! only walks the frame pointers of the interrupted thread, so stacks through
+//! code built without frame pointers are truncated. Frames are read through
+//! `process_vm_readv`, so a garbage frame pointer ends the walk instead of
+//! crashing the process. Heap profiles are dumped
+//! by jemalloc, so they require the `mem-profiling` feature.
+
+use std::result;
+
+pub const DEFAULT_CPU_PROFILE_FREQUENCY: u32 = 99;
+pub const MAX_CPU_PROFILE_FREQUENCY: u32 = 1000;
+pub const MAX_CPU_PROFILE_DURATION_SECS: u64 = 300;
+
+pub type Result<T> = result::Result<T, String>;
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod cpu {
+    use std::cell::UnsafeCell;
+    use std::fmt::Write;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT,
+                            ATOMIC_USIZE_INIT};
+    use std::time::Duration;
+    use std::{cmp, io, mem, ptr, thread};
+
+    use backtrace::{self, Symbol};
+    use libc::{self, c_int, c_void};
+
+    use util::collections::HashMap;
+    use super::{Result, MAX_CPU_PROFILE_DURATION_SECS, MAX_CPU_PROFILE_FREQUENCY};
+
+    const MAX_DEPTH: usize = 64;
+    // About 17MB, allocated only while profiling.
+    const MAX_SAMPLES: usize = 32 * 1024;
+    const ITIMER_PROF: c_int = 2;
+    // Frames farther than this from the stack pointer are treated as garbage.
+    const MAX_STACK_SIZE: usize = 64 * 1024 * 1024;
+    // Indexes of the registers in `gregs`, see `sys/ucontext.h`.
+    const REG_RBP: usize = 10;
+    const REG_RSP: usize = 15;
+    const REG_RIP: usize = 16;
+    const SYS_PROCESS_VM_READV: libc::c_long = 310;
+
+    #[repr(C)]
+    struct ItimerVal {
+        it_interval: libc::timeval,
+        it_value: libc::timeval,
+    }
+
+    extern "C" {
+        fn setitimer(which: c_int, new_value: *const ItimerVal, old_value: *mut ItimerVal)
+            -> c_int;
+    }
+
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct StackT {
+        ss_sp: *mut c_void,
+        ss_flags: c_int,
+        ss_size: usize,
+    }
+
+    // The leading part of glibc's `ucontext_t` on x86_64.
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct UContext {
+        uc_flags: libc::c_ulong,
+        uc_link: *mut UContext,
+        uc_stack: StackT,
+        gregs: [usize; 23],
+    }
+
+    struct Sample {
+        depth: usize,
+        frames: [usize; MAX_DEPTH],
+    }
+
+    struct SampleBuffer {
+        next: AtomicUsize,
+        samples: Vec<UnsafeCell<Sample>>,
+    }
+
+    impl SampleBuffer {
+        fn new(capacity: usize) -> SampleBuffer {
+            SampleBuffer {
+                next: AtomicUsize::new(0),
+                samples: (0..capacity)
+                    .map(|_| {
+                        UnsafeCell::new(Sample {
+                            depth: 0,
+                            frames: [0; MAX_DEPTH],
+                        })
+                    })
+                    .collect(),
+            }
+        }
+    }
+
+    static PROFILING: AtomicBool = ATOMIC_BOOL_INIT;
+    // Address of the `SampleBuffer` being filled, 0 if not profiling.
+    static BUFFER: AtomicUsize = ATOMIC_USIZE_INIT;
+    // Number of signal handlers that may still be accessing the buffer.
+    static IN_HANDLER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    // Reads the saved frame pointer and return address of the frame at `fp`.
+    // The kernel returns `EFAULT` for unmapped memory instead of raising
+    // SIGSEGV, and a syscall is safe to issue in a signal handler.
+    unsafe fn read_frame(pid: libc::pid_t, fp: usize) -> Option<[usize; 2]> {
+        let mut frame = [0usize; 2];
+        let size = mem::size_of_val(&frame);
+        let local = libc::iovec {
+            iov_base: frame.as_mut_ptr() as *mut c_void,
+            iov_len: size,
+        };
+        let remote = libc::iovec {
+            iov_base: fp as *mut c_void,
+            iov_len: size,
+        };
+        let n = libc::syscall(
+            SYS_PROCESS_VM_READV,
+            pid as libc::c_long,
+            &local as *const libc::iovec,
+            1 as libc::c_ulong,
+            &remote as *const libc::iovec,
+            1 as libc::c_ulong,
+            0 as libc::c_ulong,
+        );
+        if n == size as libc::c_long {
+            Some(frame)
+        } else {
+            None
+        }
+    }
+
+    // Records the pc of the interrupted thread and the return addresses found
+    // by following its frame pointers. It runs in a signal handler, so it must
+    // not allocate, lock or unwind; symbolization happens after collection.
+    unsafe fn walk_stack(ctx: &UContext, frames: &mut [usize; MAX_DEPTH]) -> usize {
+        let pid = libc::getpid();
+        let sp = ctx.gregs[REG_RSP];
+        let mut fp = ctx.gregs[REG_RBP];
+        frames[0] = ctx.gregs[REG_RIP];
+        let mut depth = 1;
+        while depth < MAX_DEPTH {
+            // A valid frame lives above the stack pointer and is aligned, anything
+            // else means the chain is broken by code without frame pointers.
+            if fp < sp || fp - sp > MAX_STACK_SIZE || fp % mem::size_of::<usize>() != 0 {
+                break;
+            }
+            let (next_fp, ret) = match read_frame(pid, fp) {
+                Some(frame) => (frame[0], frame[1]),
+                None => break,
+            };
+            if ret == 0 {
+                break;
+            }
+            // Point into the call instruction rather than the one after it.
+            frames[depth] = ret - 1;
+            depth += 1;
+            // The stack grows downwards, so callers' frames are always higher.
+            if next_fp <= fp {
+                break;
+            }
+            fp = next_fp;
+        }
+        depth
+    }
+
+    extern "C" fn on_sigprof(_: c_int, _: *mut libc::siginfo_t, ctx: *mut c_void) {
+        IN_HANDLER.fetch_add(1, Ordering::SeqCst);
+        let buf = BUFFER.load(Ordering::SeqCst) as *const SampleBuffer;
+        if !buf.is_null() && !ctx.is_null() {
+            let buf = unsafe { &*buf };
+            let idx = buf.next.fetch_add(1, Ordering::SeqCst);
+            if idx < buf.samples.len() {
+                // Every index is handed out only once, so it's safe to write.
+                let sample = unsafe { &mut *buf.samples[idx].get() };
+                let ctx = unsafe { &*(ctx as *const UContext) };
+                sample.depth = unsafe { walk_stack(ctx, &mut sample.frames) };
+            }
+        }
+        IN_HANDLER.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn register_handler() -> Result<()> {
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = on_sigprof as usize;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(libc::SIGPROF, &action, ptr::null_mut()) != 0 {
+                return Err(format!(
+                    "failed to register SIGPROF handler: {}",
+                    io::Error::last_os_error()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // A frequency of 0 stops the timer.
+    fn set_timer(frequency: u32) -> Result<()> {
+        let interval_us = if frequency == 0 {
+            0
+        } else {
+            1_000_000 / frequency as i64
+        };
+        let tv = libc::timeval {
+            tv_sec: (interval_us / 1_000_000) as libc::time_t,
+            tv_usec: (interval_us % 1_000_000) as libc::suseconds_t,
+        };
+        let timer = ItimerVal {
+            it_interval: tv,
+            it_value: tv,
+        };
+        if unsafe { setitimer(ITIMER_PROF, &timer, ptr::null_mut()) } != 0 {
+            return Err(format!(
+                "failed to set profiling timer: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Samples the call stacks of the process for `duration` at `frequency` Hz,
+    /// and returns them as folded stacks, one `frame;frame;... count` per line.
+    ///
+    /// Only one CPU profile can be taken at a time.
+    pub fn profile_cpu(duration: Duration, frequency: u32) -> Result<String> {
+        if frequency == 0 || frequency > MAX_CPU_PROFILE_FREQUENCY {
+            return Err(format!(
+                "frequency should be in [1, {}], got {}",
+                MAX_CPU_PROFILE_FREQUENCY,
+                frequency
+            ));
+        }
+        if duration > Duration::from_secs(MAX_CPU_PROFILE_DURATION_SECS) {
+            return Err(format!(
+                "duration should not be longer than {}s, got {:?}",
+                MAX_CPU_PROFILE_DURATION_SECS,
+                duration
+            ));
+        }
+        if PROFILING.compare_and_swap(false, true, Ordering::SeqCst) {
+            return Err("another cpu profiling is running".to_owned());
+        }
+        let res = collect(duration, frequency);
+        PROFILING.store(false, Ordering::SeqCst);
+        res
+    }
+
+    fn collect(duration: Duration, frequency: u32) -> Result<String> {
+        // The handler is kept after profiling, since the default action of a
+        // late SIGPROF is to terminate the process.
+        try!(register_handler());
+
+        let buf = box SampleBuffer::new(MAX_SAMPLES);
+        BUFFER.store(&*buf as *const SampleBuffer as usize, Ordering::SeqCst);
+        let res = set_timer(frequency);
+        if res.is_ok() {
+            thread::sleep(duration);
+        }
+        if let Err(e) = set_timer(0) {
+            error!("failed to stop profiling timer: {}", e);
+        }
+        BUFFER.store(0, Ordering::SeqCst);
+        while IN_HANDLER.load(Ordering::SeqCst) > 0 {
+            thread::yield_now();
+        }
+        try!(res);
+
+        let count = cmp::min(buf.next.load(Ordering::SeqCst), buf.samples.len());
+        if count == buf.samples.len() {
+            warn!("cpu profile is truncated to {} samples", count);
+        }
+        let samples = buf.samples[..count].iter().map(|cell| {
+            let sample = unsafe { &*cell.get() };
+            &sample.frames[..sample.depth]
+        });
+        Ok(fold_stacks(samples))
+    }
+
+    fn resolve_name(ip: usize) -> String {
+        let mut name = None;
+        backtrace::resolve(ip as *mut c_void, &mut |symbol: &Symbol| if name.is_none() {
+            name = symbol.name().map(|n| n.to_string());
+        });
+        // `;` separates frames in folded stacks.
+        name.map_or_else(|| format!("{:#x}", ip), |n| n.replace(';', ","))
+    }
+
+    fn fold_stacks<'a, I: Iterator<Item = &'a [usize]>>(samples: I) -> String {
+        let mut names = HashMap::default();
+        let mut stacks = HashMap::default();
+        for frames in samples {
+            let mut stack: Vec<_> = frames
+                .iter()
+                .map(|ip| {
+                    names
+                        .entry(*ip)
+                        .or_insert_with(|| resolve_name(*ip))
+                        .clone()
+                })
+                .collect();
+            if stack.is_empty() {
+                continue;
+            }
+            stack.reverse();
+            *stacks.entry(stack.join(";")).or_insert(0) += 1;
+        }
+
+        let mut stacks: Vec<_> = stacks.into_iter().collect();
+        stacks.sort();
+        let mut folded = String::new();
+        for (stack, count) in stacks {
+            writeln!(folded, "{} {}", stack, count).unwrap();
+        }
+        folded
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::thread;
+        use std::time::Duration;
+        use std::{mem, ptr};
+
+        use super::*;
+
+        #[test]
+        fn test_profile_cpu() {
+            assert!(profile_cpu(Duration::from_secs(1), 0).is_err());
+            assert!(profile_cpu(Duration::from_secs(1), MAX_CPU_PROFILE_FREQUENCY + 1).is_err());
+            let too_long = Duration::from_secs(MAX_CPU_PROFILE_DURATION_SECS + 1);
+            assert!(profile_cpu(too_long, 99).is_err());
+
+            let stop = Arc::new(AtomicBool::new(false));
+            let stop2 = stop.clone();
+            let h = thread::spawn(move || {
+                let mut n = 0u64;
+                while !stop2.load(Ordering::SeqCst) {
+                    n = n.wrapping_mul(31).wrapping_add(1);
+                }
+                n
+            });
+
+            let folded = profile_cpu(Duration::from_millis(500), 999).unwrap();
+            stop.store(true, Ordering::SeqCst);
+            h.join().unwrap();
+
+            assert!(!folded.is_empty());
+            for line in folded.lines() {
+                let count = line.rsplit(' ').next().unwrap();
+                assert!(count.parse::<u64>().unwrap() > 0, "{}", line);
+                assert!(!line.contains("on_sigprof"), "{}", line);
+            }
+        }
+
+        #[test]
+        fn test_walk_stack() {
+            let mut stack = [0usize; 8];
+            let base = stack.as_ptr() as usize;
+            let word = mem::size_of::<usize>();
+            // Two frames, the second one points to itself and ends the chain.
+            stack[2] = base + 5 * word;
+            stack[3] = 0x1001;
+            stack[5] = base + 5 * word;
+            stack[6] = 0x2001;
+            let mut ctx = UContext {
+                uc_flags: 0,
+                uc_link: ptr::null_mut(),
+                uc_stack: StackT {
+                    ss_sp: ptr::null_mut(),
+                    ss_flags: 0,
+                    ss_size: 0,
+                },
+                gregs: [0; 23],
+            };
+            ctx.gregs[REG_RIP] = 0x100;
+            ctx.gregs[REG_RSP] = base;
+            ctx.gregs[REG_RBP] = base + 2 * word;
+            let mut frames = [0; MAX_DEPTH];
+            let depth = unsafe { walk_stack(&ctx, &mut frames) };
+            assert_eq!(&frames[..depth], &[0x100, 0x1000, 0x2000]);
+
+            // A frame pointer below the stack pointer is not followed.
+            ctx.gregs[REG_RSP] = base + 3 * word;
+            let depth = unsafe { walk_stack(&ctx, &mut frames) };
+            assert_eq!(&frames[..depth], &[0x100]);
+
+            // An unmapped frame ends the chain without crashing.
+            ctx.gregs[REG_RSP] = 0x1000;
+            ctx.gregs[REG_RBP] = 0x2000;
+            let depth = unsafe { walk_stack(&ctx, &mut frames) };
+            assert_eq!(&frames[..depth], &[0x100]);
+        }
+
+        #[test]
+        fn test_fold_stacks() {
+            let samples = vec![vec![1, 2, 3], vec![1, 2, 3], vec![4, 3]];
+            let folded = fold_stacks(samples.iter().map(|s| s.as_slice()));
+            let lines: Vec<_> = folded.lines().collect();
+            assert_eq!(lines.len(), 2);
+            assert!(lines.iter().any(|l| l.ends_with(" 2")));
+            assert!(lines.iter().any(|l| l.ends_with(" 1")));
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+mod cpu {
+    use std::time::Duration;
+
+    use super::Result;
+
+    pub fn profile_cpu(_: Duration, _: u32) -> Result<String> {
+        Err("cpu profiling is only supported on x86_64 linux".to_owned())
+    }
+}
+
+#[cfg(feature = "mem-profiling")]
+mod heap {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io::Read;
+    use std::{env, ptr};
+
+    use jemallocator;
+    use libc::c_char;
+    use tempdir::TempDir;
+
+    use super::Result;
+
+    // c string should end with a '\0'.
+    const PROFILE_ACTIVE: &'static [u8] = b"prof.active\0";
+    const PROFILE_DUMP: &'static [u8] = b"prof.dump\0";
+
+    struct DumpPathGuard(Option<Vec<u8>>);
+
+    impl DumpPathGuard {
+        fn from_cstring(s: Option<CString>) -> DumpPathGuard {
+            DumpPathGuard(s.map(|s| s.into_bytes_with_nul()))
+        }
+
+        /// caller should ensure that the pointer should not be accessed after
+        /// the guard is dropped.
+        #[inline]
+        unsafe fn get_mut_ptr(&mut self) -> *mut c_char {
+            self.0
+                .as_mut()
+                .map_or(ptr::null_mut(), |v| v.as_mut_ptr() as *mut c_char)
+        }
+    }
+
+    /// Dump the heap profile to the `path`.
+    ///
+    /// If `path` is `None`, will dump it in the working directory with a auto-generated name.
+    pub fn dump_heap_profile(path: Option<&str>) -> Result<()> {
+        unsafe {
+            if let Err(e) = jemallocator::mallctl_set(PROFILE_ACTIVE, true) {
+                return Err(format!("failed to activate profiling: {}", e));
+            }
+        }
+        let mut c_path = DumpPathGuard::from_cstring(path.map(|p| CString::new(p).unwrap()));
+        let res = unsafe { jemallocator::mallctl_set(PROFILE_DUMP, c_path.get_mut_ptr()) };
+        if let Err(e) = res {
+            return Err(format!("failed to dump the profile to {:?}: {}", path, e));
+        }
+        match path {
+            Some(p) => info!("dump heap profile to {}", p),
+            None => info!(
+                "dump heap profile to {}",
+                env::current_dir().unwrap().display()
+            ),
+        }
+        Ok(())
+    }
+
+    /// Dumps the heap profile to a temporary file and returns its content.
+    pub fn read_heap_profile() -> Result<Vec<u8>> {
+        let dir = try!(TempDir::new("heap_profile").map_err(|e| format!("{}", e)));
+        let path = dir.path().join("heap.dump");
+        try!(dump_heap_profile(Some(path.to_str().unwrap())));
+        let mut buf = vec![];
+        try!(
+            File::open(&path)
+                .and_then(|mut f| f.read_to_end(&mut buf))
+                .map_err(|e| format!("failed to read heap profile: {}", e))
+        );
+        Ok(buf)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::fs;
+
+        use tempdir::TempDir;
+
+        // Only trigger this test with prof set to true.
+        #[test]
+        #[ignore]
+        fn test_profiling_memory() {
+            let dir = TempDir::new("test_profiling").unwrap();
+            let os_path = dir.path().to_path_buf().join("test1.dump").into_os_string();
+            let path = os_path.into_string().unwrap();
+            super::dump_heap_profile(Some(&path)).unwrap();
+
+            let os_path = dir.path().to_path_buf().join("test2.dump").into_os_string();
+            let path = os_path.into_string().unwrap();
+            super::dump_heap_profile(Some(&path)).unwrap();
+
+            let files = fs::read_dir(dir.path()).unwrap().count();
+            assert_eq!(files, 2);
+
+            assert!(!super::read_heap_profile().unwrap().is_empty());
+        }
+    }
+}
+
+#[cfg(not(feature = "mem-profiling"))]
+mod heap {
+    use super::Result;
+
+    const NOT_ENABLED: &'static str = "heap profiling requires the mem-profiling feature";
+
+    pub fn dump_heap_profile(_: Option<&str>) -> Result<()> {
+        Err(NOT_ENABLED.to_owned())
+    }
+
+    pub fn read_heap_profile() -> Result<Vec<u8>> {
+        Err(NOT_ENABLED.to_owned())
+    }
+}
+
+pub use self::cpu::profile_cpu;
+pub use self::heap::{dump_heap_profile, read_heap_profile};
@@ -0,0 +1,424 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Resource groups share the coprocessor and scheduler worker pools between tenants.
+//!
+//! A request names its group by `resource_group_name` of `kvrpcpb::Context`, requests
+//! of unknown groups belong to the default group. Groups get the worker pools in
+//! proportion to their weights, and a group that has used up its read or write quota
+//! is rejected with server-is-busy until the quota is refilled. Quotas are measured in request units (RU):
+//!
+//! - a read costs 1 RU, plus 1 RU for every `READ_OPS_PER_RU` engine operations it takes;
+//! - a write costs 1 RU, plus 1 RU for every `WRITE_BYTES_PER_RU` bytes it writes.
+
+use std::error::Error;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use util::collections::{HashMap, HashSet};
+use util::time::duration_to_sec;
+use super::metrics::*;
+
+pub const DEFAULT_RESOURCE_GROUP: &'static str = "default";
+
+const READ_OPS_PER_RU: u64 = 64;
+const WRITE_BYTES_PER_RU: u64 = 1024;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct GroupConfig {
+    pub name: String,
+    // Share of the worker pools relative to other groups.
+    pub weight: u64,
+    // Request units per second, 0 means no limit.
+    pub read_quota: u64,
+    pub write_quota: u64,
+}
+
+impl Default for GroupConfig {
+    fn default() -> GroupConfig {
+        GroupConfig {
+            name: "".to_owned(),
+            weight: 1,
+            read_quota: 0,
+            write_quota: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    pub groups: Vec<GroupConfig>,
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), Box<Error>> {
+        let mut names = HashSet::default();
+        for group in &self.groups {
+            if group.name.is_empty() {
+                return Err("resource-group.groups.name should not be empty".into());
+            }
+            if !names.insert(group.name.as_str()) {
+                return Err(format!("resource group {:?} is defined twice", group.name).into());
+            }
+            if group.weight == 0 {
+                return Err(format!(
+                    "weight of resource group {:?} should not be 0",
+                    group.name
+                ).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[inline]
+pub fn read_request_units(ops: usize) -> u64 {
+    1 + ops as u64 / READ_OPS_PER_RU
+}
+
+#[inline]
+pub fn write_request_units(bytes: usize) -> u64 {
+    1 + bytes as u64 / WRITE_BYTES_PER_RU
+}
+
+// A token bucket holding at most one second of quota. It can go into debt because
+// the cost of a request is only known after it's done.
+struct Quota {
+    units_per_sec: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+// The methods take the current time so that tests don't depend on the clock.
+impl Quota {
+    fn new(units_per_sec: u64, now: Instant) -> Quota {
+        Quota {
+            units_per_sec: units_per_sec,
+            tokens: units_per_sec as f64,
+            last_refill: now,
+        }
+    }
+
+    fn set_units_per_sec(&mut self, units_per_sec: u64, now: Instant) {
+        if self.units_per_sec != units_per_sec {
+            *self = Quota::new(units_per_sec, now);
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = duration_to_sec(now.duration_since(self.last_refill));
+        let limit = self.units_per_sec as f64;
+        self.tokens = (self.tokens + elapsed * limit).min(limit);
+        self.last_refill = now;
+    }
+
+    fn is_exhausted(&mut self, now: Instant) -> bool {
+        if self.units_per_sec == 0 {
+            return false;
+        }
+        self.refill(now);
+        self.tokens <= 0.0
+    }
+
+    fn consume(&mut self, units: u64, now: Instant) {
+        if self.units_per_sec == 0 {
+            return;
+        }
+        self.refill(now);
+        self.tokens -= units as f64;
+    }
+}
+
+pub struct ResourceGroup {
+    id: u64,
+    name: String,
+    weight: AtomicUsize,
+    read_quota: Mutex<Quota>,
+    write_quota: Mutex<Quota>,
+}
+
+impl ResourceGroup {
+    fn new(id: u64, cfg: &GroupConfig) -> ResourceGroup {
+        ResourceGroup {
+            id: id,
+            name: cfg.name.clone(),
+            weight: AtomicUsize::new(cfg.weight as usize),
+            read_quota: Mutex::new(Quota::new(cfg.read_quota, Instant::now())),
+            write_quota: Mutex::new(Quota::new(cfg.write_quota, Instant::now())),
+        }
+    }
+
+    fn set_config(&self, cfg: &GroupConfig) {
+        self.weight.store(cfg.weight as usize, Ordering::Relaxed);
+        self.read_quota
+            .lock()
+            .unwrap()
+            .set_units_per_sec(cfg.read_quota, Instant::now());
+        self.write_quota
+            .lock()
+            .unwrap()
+            .set_units_per_sec(cfg.write_quota, Instant::now());
+    }
+
+    /// The id identifies the group in the worker pools.
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub fn weight(&self) -> u64 {
+        self.weight.load(Ordering::Relaxed) as u64
+    }
+
+    /// Checks whether the group has used up its read quota. The rejected request is
+    /// counted in the metrics.
+    pub fn is_read_throttled(&self) -> bool {
+        let throttled = self.read_quota.lock().unwrap().is_exhausted(Instant::now());
+        if throttled {
+            RESOURCE_GROUP_THROTTLED_COUNTER_VEC
+                .with_label_values(&[&self.name, "read"])
+                .inc();
+        }
+        throttled
+    }
+
+    pub fn is_write_throttled(&self) -> bool {
+        let throttled = self.write_quota.lock().unwrap().is_exhausted(Instant::now());
+        if throttled {
+            RESOURCE_GROUP_THROTTLED_COUNTER_VEC
+                .with_label_values(&[&self.name, "write"])
+                .inc();
+        }
+        throttled
+    }
+
+    pub fn consume_read(&self, units: u64) {
+        self.read_quota.lock().unwrap().consume(units, Instant::now());
+        RESOURCE_GROUP_REQUEST_UNITS_VEC
+            .with_label_values(&[&self.name, "read"])
+            .inc_by(units as f64)
+            .unwrap();
+    }
+
+    pub fn consume_write(&self, units: u64) {
+        self.write_quota.lock().unwrap().consume(units, Instant::now());
+        RESOURCE_GROUP_REQUEST_UNITS_VEC
+            .with_label_values(&[&self.name, "write"])
+            .inc_by(units as f64)
+            .unwrap();
+    }
+}
+
+struct Groups {
+    groups: HashMap<String, Arc<ResourceGroup>>,
+    default_group: Arc<ResourceGroup>,
+    next_id: u64,
+}
+
+/// `ResourceGroupManager` maps group names to groups, it's shared by the coprocessor
+/// and the transaction scheduler.
+#[derive(Clone)]
+pub struct ResourceGroupManager {
+    groups: Arc<RwLock<Groups>>,
+}
+
+impl ResourceGroupManager {
+    pub fn new(cfg: &Config) -> ResourceGroupManager {
+        let default_cfg = GroupConfig {
+            name: DEFAULT_RESOURCE_GROUP.to_owned(),
+            ..Default::default()
+        };
+        let mgr = ResourceGroupManager {
+            groups: Arc::new(RwLock::new(Groups {
+                groups: HashMap::default(),
+                // Id 0 is the default group of `ThreadPool::execute`.
+                default_group: Arc::new(ResourceGroup::new(0, &default_cfg)),
+                next_id: 1,
+            })),
+        };
+        mgr.set_config(cfg);
+        mgr
+    }
+
+    /// Gets the group by name, requests of unknown groups belong to the default group.
+    pub fn get(&self, name: &str) -> Arc<ResourceGroup> {
+        let groups = self.groups.read().unwrap();
+        match groups.groups.get(name) {
+            Some(group) => group.clone(),
+            None => groups.default_group.clone(),
+        }
+    }
+
+    /// Applies the new config. Groups not in the config are removed, their running
+    /// requests are still charged to them.
+    pub fn set_config(&self, cfg: &Config) {
+        let mut groups = self.groups.write().unwrap();
+        let mut new_groups = HashMap::default();
+        let mut default_configured = false;
+        for group_cfg in &cfg.groups {
+            if group_cfg.name == DEFAULT_RESOURCE_GROUP {
+                groups.default_group.set_config(group_cfg);
+                default_configured = true;
+                continue;
+            }
+            let group = match groups.groups.remove(&group_cfg.name) {
+                Some(group) => {
+                    group.set_config(group_cfg);
+                    group
+                }
+                None => {
+                    let id = groups.next_id;
+                    groups.next_id += 1;
+                    Arc::new(ResourceGroup::new(id, group_cfg))
+                }
+            };
+            new_groups.insert(group_cfg.name.clone(), group);
+        }
+        if !default_configured {
+            groups.default_group.set_config(&GroupConfig {
+                name: DEFAULT_RESOURCE_GROUP.to_owned(),
+                ..Default::default()
+            });
+        }
+        groups.groups = new_groups;
+    }
+}
+
+impl Default for ResourceGroupManager {
+    fn default() -> ResourceGroupManager {
+        ResourceGroupManager::new(&Config::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn new_group(name: &str, weight: u64, read_quota: u64, write_quota: u64) -> GroupConfig {
+        GroupConfig {
+            name: name.to_owned(),
+            weight: weight,
+            read_quota: read_quota,
+            write_quota: write_quota,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut cfg = Config::default();
+        cfg.validate().unwrap();
+        cfg.groups.push(new_group("a", 1, 0, 0));
+        cfg.validate().unwrap();
+        cfg.groups.push(new_group("a", 2, 0, 0));
+        assert!(cfg.validate().is_err());
+        cfg.groups[1].name = "b".to_owned();
+        cfg.validate().unwrap();
+        cfg.groups[1].weight = 0;
+        assert!(cfg.validate().is_err());
+        cfg.groups[1].weight = 1;
+        cfg.groups[1].name = "".to_owned();
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn test_manager() {
+        let mut cfg = Config {
+            groups: vec![new_group("a", 2, 0, 0), new_group("b", 3, 0, 0)],
+        };
+        let mgr = ResourceGroupManager::new(&cfg);
+        let default_group = mgr.get("");
+        assert_eq!(default_group.name(), DEFAULT_RESOURCE_GROUP);
+        assert_eq!(default_group.id(), 0);
+        assert_eq!(mgr.get("unknown").id(), 0);
+        let a = mgr.get("a");
+        assert_eq!(a.weight(), 2);
+        assert_ne!(a.id(), mgr.get("b").id());
+
+        cfg.groups = vec![
+            new_group("a", 5, 0, 0),
+            new_group(DEFAULT_RESOURCE_GROUP, 4, 0, 0),
+        ];
+        mgr.set_config(&cfg);
+        // The group is updated in place.
+        assert_eq!(a.weight(), 5);
+        assert_eq!(mgr.get("a").id(), a.id());
+        assert_eq!(mgr.get("").weight(), 4);
+        // Removed groups fall back to the default group.
+        assert_eq!(mgr.get("b").id(), 0);
+    }
+
+    #[test]
+    fn test_quota() {
+        let mgr = ResourceGroupManager::new(&Config {
+            groups: vec![new_group("a", 1, 100, 10)],
+        });
+        let a = mgr.get("a");
+        assert!(!a.is_read_throttled());
+        assert!(!a.is_write_throttled());
+        a.consume_read(150);
+        assert!(a.is_read_throttled());
+        assert!(!a.is_write_throttled());
+        a.consume_write(write_request_units(20 * 1024));
+        assert!(a.is_write_throttled());
+
+        // No quota means no limit.
+        let default_group = mgr.get("");
+        default_group.consume_read(u32::max_value() as u64);
+        assert!(!default_group.is_read_throttled());
+    }
+
+    #[test]
+    fn test_quota_refill() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut quota = Quota::new(100, start);
+        quota.consume(150, start);
+        assert!(quota.is_exhausted(start));
+        // 100 RU/s pays the debt of 50 RU in half a second.
+        assert!(quota.is_exhausted(at(400)));
+        assert!(!quota.is_exhausted(at(600)));
+
+        // At most one second of quota is accumulated.
+        quota.consume(100, at(10_000));
+        assert!(quota.is_exhausted(at(10_000)));
+        assert!(!quota.is_exhausted(at(10_100)));
+
+        // Changing the quota starts a new bucket.
+        quota.set_units_per_sec(10, at(10_100));
+        quota.consume(10, at(10_100));
+        assert!(quota.is_exhausted(at(10_100)));
+        quota.set_units_per_sec(10, at(10_100));
+        assert!(quota.is_exhausted(at(10_100)));
+    }
+
+    #[test]
+    fn test_request_units() {
+        assert_eq!(read_request_units(0), 1);
+        assert_eq!(read_request_units(READ_OPS_PER_RU as usize), 2);
+        assert_eq!(write_request_units(100), 1);
+        assert_eq!(write_request_units(3 * WRITE_BYTES_PER_RU as usize), 4);
+    }
+}
@@ -24,7 +24,9 @@ use std::path::Path;
 use std::sync::Arc;
 
 use storage::{ALL_CFS, CF_DEFAULT};
-use rocksdb::{ColumnFamilyOptions, DBCompressionType, DBOptions, SliceTransform, DB};
+use rocksdb::{ColumnFamilyOptions, DBCompressionType, DBOptions, IngestExternalFileOptions,
+              SeekKey, SliceTransform, DB};
+use tempdir::TempDir;
 use rocksdb::rocksdb::supported_compression;
 use util::rocksdb::engine_metrics::{ROCKSDB_CUR_SIZE_ALL_MEM_TABLES, ROCKSDB_TOTAL_SST_FILES_SIZE};
 use util::rocksdb;
@@ -311,12 +313,28 @@ pub fn delete_file_in_range(db: &DB, start_key: &[u8], end_key: &[u8]) -> Result
     Ok(())
 }
 
+/// Returns the smallest and largest keys of the SST file, or `None` if it has no keys.
+/// The file is copied into a temporary db created next to it to be read.
+pub fn get_sst_key_range(path: &str) -> Result<Option<(Vec<u8>, Vec<u8>)>, String> {
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new("."));
+    let tmp = try!(TempDir::new_in(dir, "sst_key_range").map_err(|e| format!("{:?}", e)));
+    let db = try!(new_engine(tmp.path().to_str().unwrap(), &[CF_DEFAULT]));
+    try!(db.ingest_external_file(&IngestExternalFileOptions::new(), &[path]));
+    let mut iter = db.iter();
+    if !iter.seek(SeekKey::Start) {
+        return Ok(None);
+    }
+    let smallest = iter.key().to_vec();
+    iter.seek(SeekKey::End);
+    Ok(Some((smallest, iter.key().to_vec())))
+}
+
 #[cfg(test)]
 mod tests {
-    use rocksdb::{ColumnFamilyOptions, DBOptions, DB};
+    use rocksdb::{ColumnFamilyOptions, DBOptions, EnvOptions, SstFileWriter, DB};
     use tempdir::TempDir;
     use storage::CF_DEFAULT;
-    use super::{check_and_open, CFOptions};
+    use super::{check_and_open, get_sst_key_range, CFOptions};
 
     #[test]
     fn test_check_and_open() {
@@ -357,4 +375,21 @@ mod tests {
         cfs_excepted.sort();
         assert_eq!(cfs_existed, cfs_excepted);
     }
+
+    #[test]
+    fn test_get_sst_key_range() {
+        let dir = TempDir::new("_util_rocksdb_test_get_sst_key_range").unwrap();
+        let path = dir.path().join("test.sst");
+        let path = path.to_str().unwrap();
+        let mut writer = SstFileWriter::new(EnvOptions::new(), ColumnFamilyOptions::new());
+        writer.open(path).unwrap();
+        for key in &[b"k1", b"k3", b"k5"] {
+            writer.put(*key, b"v").unwrap();
+        }
+        writer.finish().unwrap();
+
+        let range = get_sst_key_range(path).unwrap();
+        assert_eq!(range, Some((b"k1".to_vec(), b"k5".to_vec())));
+        assert!(get_sst_key_range(dir.path().join("missing.sst").to_str().unwrap()).is_err());
+    }
 }
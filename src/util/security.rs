@@ -0,0 +1,326 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TLS for the gRPC listeners and the connections to other stores and PD.
+//!
+//! When the CA, certificate and key paths are set, every listener requires clients to
+//! present a certificate signed by the CA, and every outgoing channel presents the
+//! certificate. The files are read again when their modification time changes, new
+//! outgoing channels use the new certificates at once, and the listeners are rebuilt
+//! by their owner when `SecurityManager::generation` changes. The HTTP status server
+//! has no TLS, so it can't be enabled together with TLS.
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Read;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use grpc::{Channel, ChannelBuilder, ChannelCredentialsBuilder, RpcContext, ServerBuilder,
+           ServerCredentialsBuilder};
+
+use util::collections::HashSet;
+
+// The name of the auth property holding the common name of the peer certificate.
+const X509_COMMON_NAME: &'static str = "x509_common_name";
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    pub ca_path: String,
+    pub cert_path: String,
+    pub key_path: String,
+    // Common names a client certificate must have one of, empty means any.
+    pub cert_allowed_cn: Vec<String>,
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), Box<Error>> {
+        let paths = [&self.ca_path, &self.cert_path, &self.key_path];
+        if paths.iter().all(|p| p.is_empty()) {
+            if !self.cert_allowed_cn.is_empty() {
+                return Err("security.cert-allowed-cn requires TLS to be enabled".into());
+            }
+            return Ok(());
+        }
+        for (name, path) in ["ca-path", "cert-path", "key-path"].iter().zip(&paths) {
+            if path.is_empty() {
+                return Err(format!(
+                    "security.{} should be set if any of ca-path, cert-path and \
+                     key-path is set",
+                    name
+                ).into());
+            }
+            if let Err(e) = fs::metadata(path) {
+                return Err(format!("security.{} {:?} is not readable: {}", name, path, e).into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_tls_enabled(&self) -> bool {
+        !self.ca_path.is_empty()
+    }
+}
+
+fn modified_time(path: &str) -> Result<SystemTime, Box<Error>> {
+    let meta = box_try!(fs::metadata(path));
+    Ok(box_try!(meta.modified()))
+}
+
+fn load_file(path: &str) -> Result<Vec<u8>, Box<Error>> {
+    let mut f = box_try!(File::open(path));
+    let mut content = vec![];
+    box_try!(f.read_to_end(&mut content));
+    if content.is_empty() {
+        return Err(format!("{:?} is empty", path).into());
+    }
+    Ok(content)
+}
+
+#[derive(Default)]
+struct Certs {
+    ca: Vec<u8>,
+    cert: Vec<u8>,
+    key: Vec<u8>,
+    modified: Vec<SystemTime>,
+}
+
+impl Certs {
+    fn load(cfg: &Config) -> Result<Certs, Box<Error>> {
+        let paths = [&cfg.ca_path, &cfg.cert_path, &cfg.key_path];
+        // Take the times before reading, so a change during reading is seen next time.
+        let mut modified = Vec::with_capacity(paths.len());
+        for path in &paths {
+            modified.push(try!(modified_time(path)));
+        }
+        Ok(Certs {
+            ca: try!(load_file(&cfg.ca_path)),
+            cert: try!(load_file(&cfg.cert_path)),
+            key: try!(load_file(&cfg.key_path)),
+            modified: modified,
+        })
+    }
+}
+
+// Checks whether any of the common names of a peer is allowed.
+fn match_common_name(allowed: &HashSet<String>, names: &[&str]) -> bool {
+    allowed.is_empty() || names.iter().any(|n| allowed.contains(*n))
+}
+
+/// `SecurityManager` creates the listeners and channels according to the security config.
+pub struct SecurityManager {
+    cfg: Config,
+    allowed_cn: HashSet<String>,
+    certs: RwLock<Certs>,
+    // Increased every time the certificates are reloaded.
+    generation: AtomicUsize,
+}
+
+impl Default for SecurityManager {
+    fn default() -> SecurityManager {
+        SecurityManager::new(&Config::default()).unwrap()
+    }
+}
+
+impl SecurityManager {
+    pub fn new(cfg: &Config) -> Result<SecurityManager, Box<Error>> {
+        let certs = if cfg.is_tls_enabled() {
+            try!(Certs::load(cfg))
+        } else {
+            Certs::default()
+        };
+        Ok(SecurityManager {
+            cfg: cfg.clone(),
+            allowed_cn: cfg.cert_allowed_cn.iter().cloned().collect(),
+            certs: RwLock::new(certs),
+            generation: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn is_tls_enabled(&self) -> bool {
+        self.cfg.is_tls_enabled()
+    }
+
+    /// Reads the certificates again if any of the files has been modified, returns
+    /// whether they are changed. The old certificates are kept if the new ones can't
+    /// be read, e.g. when they are being replaced.
+    pub fn reload(&self) -> bool {
+        if !self.is_tls_enabled() {
+            return false;
+        }
+        let paths = [&self.cfg.ca_path, &self.cfg.cert_path, &self.cfg.key_path];
+        {
+            let certs = self.certs.read().unwrap();
+            let unchanged = paths.iter().zip(&certs.modified).all(|(path, t)| {
+                match modified_time(path) {
+                    Ok(modified) => modified == *t,
+                    Err(_) => true,
+                }
+            });
+            if unchanged {
+                return false;
+            }
+        }
+        match Certs::load(&self.cfg) {
+            Ok(certs) => {
+                info!("certificates are reloaded from {:?}", paths);
+                *self.certs.write().unwrap() = certs;
+                self.generation.fetch_add(1, Ordering::SeqCst);
+                true
+            }
+            Err(e) => {
+                warn!("failed to reload certificates: {:?}", e);
+                false
+            }
+        }
+    }
+
+    /// Returns the number of times the certificates have been reloaded.
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    pub fn connect(&self, cb: ChannelBuilder, addr: &str) -> Channel {
+        if !self.is_tls_enabled() {
+            return cb.connect(addr);
+        }
+        self.reload();
+        let certs = self.certs.read().unwrap();
+        let creds = ChannelCredentialsBuilder::new()
+            .root_cert(certs.ca.clone())
+            .cert(certs.cert.clone(), certs.key.clone())
+            .build();
+        cb.secure_connect(addr, creds)
+    }
+
+    pub fn bind(&self, sb: ServerBuilder, addr: &str, port: u16) -> ServerBuilder {
+        if !self.is_tls_enabled() {
+            return sb.bind(addr, port);
+        }
+        self.reload();
+        let certs = self.certs.read().unwrap();
+        // Clients must present a certificate signed by the CA.
+        let creds = ServerCredentialsBuilder::new()
+            .root_cert(certs.ca.clone(), true)
+            .add_cert(certs.cert.clone(), certs.key.clone())
+            .build();
+        sb.bind_secure(addr, port, creds)
+    }
+
+    /// Checks whether the client certificate of the call has an allowed common name.
+    pub fn check_common_name(&self, ctx: &RpcContext) -> bool {
+        if self.allowed_cn.is_empty() {
+            return true;
+        }
+        let auth = match ctx.auth_context() {
+            Some(auth) => auth,
+            None => return false,
+        };
+        let names: Vec<&str> = (&auth)
+            .into_iter()
+            .filter(|p| p.name() == X509_COMMON_NAME)
+            .filter_map(|p| p.value_str().ok())
+            .collect();
+        if match_common_name(&self.allowed_cn, &names) {
+            return true;
+        }
+        warn!(
+            "reject call from {} with certificate common names {:?}",
+            ctx.peer(),
+            names
+        );
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+    use std::thread;
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_config_validate() {
+        let mut cfg = Config::default();
+        cfg.validate().unwrap();
+        assert!(!cfg.is_tls_enabled());
+
+        cfg.cert_allowed_cn = vec!["tikv".to_owned()];
+        assert!(cfg.validate().is_err());
+
+        let dir = TempDir::new("test_security_config").unwrap();
+        let ca_path = format!("{}", dir.path().join("ca.pem").display());
+        File::create(&ca_path).unwrap();
+        cfg.ca_path = ca_path.clone();
+        assert!(cfg.validate().is_err());
+        cfg.cert_path = ca_path.clone();
+        cfg.key_path = format!("{}", dir.path().join("missing.pem").display());
+        assert!(cfg.validate().is_err());
+        cfg.key_path = ca_path;
+        cfg.validate().unwrap();
+        assert!(cfg.is_tls_enabled());
+    }
+
+    #[test]
+    fn test_match_common_name() {
+        let mut allowed = HashSet::default();
+        assert!(match_common_name(&allowed, &[]));
+        allowed.insert("tikv".to_owned());
+        allowed.insert("pd".to_owned());
+        assert!(!match_common_name(&allowed, &[]));
+        assert!(!match_common_name(&allowed, &["tidb"]));
+        assert!(match_common_name(&allowed, &["tidb", "pd"]));
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = TempDir::new("test_security_reload").unwrap();
+        let mut cfg = Config::default();
+        let mut paths = vec![];
+        for name in &["ca.pem", "cert.pem", "key.pem"] {
+            let path = format!("{}", dir.path().join(name).display());
+            File::create(&path).unwrap().write_all(b"old").unwrap();
+            paths.push(path);
+        }
+        cfg.ca_path = paths[0].clone();
+        cfg.cert_path = paths[1].clone();
+        cfg.key_path = paths[2].clone();
+
+        let mgr = SecurityManager::new(&cfg).unwrap();
+        assert!(!mgr.reload());
+        assert_eq!(mgr.certs.read().unwrap().cert, b"old");
+
+        // Make sure the modification time differs on coarse file systems.
+        thread::sleep(Duration::from_millis(1100));
+        // An empty file is being rewritten, the old certificates are kept.
+        File::create(&paths[1]).unwrap();
+        assert!(!mgr.reload());
+        assert_eq!(mgr.certs.read().unwrap().cert, b"old");
+
+        File::create(&paths[1]).unwrap().write_all(b"new").unwrap();
+        assert!(mgr.reload());
+        assert_eq!(mgr.certs.read().unwrap().cert, b"new");
+        assert_eq!(mgr.generation(), 1);
+        assert!(!mgr.reload());
+
+        assert!(!SecurityManager::default().reload());
+    }
+}
@@ -20,10 +20,14 @@ use std::collections::VecDeque;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
 use std::fmt::Write;
 
+use util::collections::HashMap;
+
 pub const DEFAULT_TASKS_PER_TICK: usize = 10000;
+/// The group of tasks submitted by `execute`.
+pub const DEFAULT_GROUP_ID: u64 = 0;
 const WORKER_WAIT_TIME: u64 = 2000; // ms
-const DEFAULT_QUEUE_CAPACITY: usize = 1000;
-const QUEUE_MAX_CAPACITY: usize = 8 * DEFAULT_QUEUE_CAPACITY;
+// The pass of a group with weight 1 advances by `STRIDE` for every task.
+const STRIDE: u64 = 1 << 20;
 
 pub trait Context: Send {
     fn on_task_started(&mut self);
@@ -50,29 +54,61 @@ impl<C: Context> Task<C> {
     }
 }
 
-// First in first out queue.
-pub struct FifoQueue<C> {
-    queue: VecDeque<Task<C>>,
+struct GroupTasks<C> {
+    tasks: VecDeque<Task<C>>,
+    weight: u64,
+    pass: u64,
+}
+
+// Tasks of a group run in first in first out order, and groups share the pool by
+// their weights with stride scheduling: popping a task of a group advances its pass
+// by `STRIDE / weight`, and the pending group with the smallest pass runs next.
+pub struct GroupQueue<C> {
+    groups: HashMap<u64, GroupTasks<C>>,
+    // The pass of the last popped task. A group becoming pending again starts from
+    // it, so it can't take over the pool with the credit saved while it was idle.
+    pass: u64,
 }
 
-impl<C: Context> FifoQueue<C> {
-    fn new() -> FifoQueue<C> {
-        FifoQueue {
-            queue: VecDeque::with_capacity(DEFAULT_QUEUE_CAPACITY),
+impl<C: Context> GroupQueue<C> {
+    fn new() -> GroupQueue<C> {
+        GroupQueue {
+            groups: HashMap::default(),
+            pass: 0,
         }
     }
 
-    fn push(&mut self, task: Task<C>) {
-        self.queue.push_back(task);
+    fn push(&mut self, group_id: u64, weight: u64, task: Task<C>) {
+        let pass = self.pass;
+        let group = self.groups.entry(group_id).or_insert_with(|| {
+            GroupTasks {
+                tasks: VecDeque::new(),
+                weight: weight,
+                pass: pass,
+            }
+        });
+        group.weight = weight;
+        group.tasks.push_back(task);
     }
 
     fn pop(&mut self) -> Option<Task<C>> {
-        let task = self.queue.pop_front();
-
-        if self.queue.is_empty() && self.queue.capacity() > QUEUE_MAX_CAPACITY {
-            self.queue = VecDeque::with_capacity(DEFAULT_QUEUE_CAPACITY);
+        let group_id = match self.groups
+            .iter()
+            .min_by_key(|&(&id, group)| (group.pass, id))
+        {
+            Some((&id, _)) => id,
+            None => return None,
+        };
+        let (task, is_empty) = {
+            let group = self.groups.get_mut(&group_id).unwrap();
+            let task = group.tasks.pop_front();
+            self.pass = group.pass;
+            group.pass += STRIDE / group.weight.max(1);
+            (task, group.tasks.is_empty())
+        };
+        if is_empty {
+            self.groups.remove(&group_id);
         }
-
         task
     }
 }
@@ -80,54 +116,120 @@ impl<C: Context> FifoQueue<C> {
 /// `ThreadPool` is used to execute tasks in parallel.
 /// Each task would be pushed into the pool, and when a thread
 /// is ready to process a task, it will get a task from the pool
-/// according to the `ScheduleQueue` provided in initialization.
+/// according to the weights of the task groups, see `execute_with_group`.
 pub struct ThreadPool<Ctx> {
+    name: String,
+    tasks_per_tick: usize,
     stop_flag: Arc<AtomicBool>,
-    task_pool: Arc<(Mutex<FifoQueue<Ctx>>, Condvar)>,
-    threads: Vec<JoinHandle<()>>,
+    task_pool: Arc<(Mutex<GroupQueue<Ctx>>, Condvar)>,
+    // Running threads, the flags used to retire them and the flags set when they exit.
+    threads: Vec<(JoinHandle<()>, Arc<AtomicBool>, Arc<AtomicBool>)>,
+    // Retired threads may still be finishing their last task, the flags are set
+    // when they exit.
+    retired_threads: Vec<(JoinHandle<()>, Arc<AtomicBool>)>,
     task_count: Arc<AtomicUsize>,
+    ctx_factory: Box<ContextFactory<Ctx> + Send>,
 }
 
 impl<Ctx> ThreadPool<Ctx>
 where
     Ctx: Context + 'static,
 {
-    pub fn new<C: ContextFactory<Ctx>>(
+    pub fn new<C: ContextFactory<Ctx> + Send + 'static>(
         name: String,
         num_threads: usize,
         tasks_per_tick: usize,
         f: C,
     ) -> ThreadPool<Ctx> {
         assert!(num_threads >= 1);
-        let task_pool = Arc::new((Mutex::new(FifoQueue::new()), Condvar::new()));
-        let mut threads = Vec::with_capacity(num_threads);
-        let task_count = Arc::new(AtomicUsize::new(0));
-        let stop_flag = Arc::new(AtomicBool::new(false));
-        // Threadpool threads
-        for _ in 0..num_threads {
-            let tasks = task_pool.clone();
-            let task_num = task_count.clone();
-            let ctx = f.create();
-            let stop = stop_flag.clone();
-            let thread = Builder::new()
-                .name(name.clone())
-                .spawn(move || {
-                    let mut worker = Worker::new(tasks, task_num, tasks_per_tick, stop, ctx);
-                    worker.run();
-                })
-                .unwrap();
-            threads.push(thread);
-        }
-
-        ThreadPool {
-            task_pool: task_pool,
-            stop_flag: stop_flag,
-            threads: threads,
-            task_count: task_count,
+        let mut pool = ThreadPool {
+            name: name,
+            tasks_per_tick: tasks_per_tick,
+            task_pool: Arc::new((Mutex::new(GroupQueue::new()), Condvar::new())),
+            stop_flag: Arc::new(AtomicBool::new(false)),
+            threads: Vec::with_capacity(num_threads),
+            retired_threads: vec![],
+            task_count: Arc::new(AtomicUsize::new(0)),
+            ctx_factory: box f,
+        };
+        pool.set_num_threads(num_threads);
+        pool
+    }
+
+    fn spawn_thread(&mut self) {
+        let tasks = self.task_pool.clone();
+        let task_num = self.task_count.clone();
+        let ctx = self.ctx_factory.create();
+        let stop = self.stop_flag.clone();
+        let retired = Arc::new(AtomicBool::new(false));
+        let retired_flag = retired.clone();
+        let exited = Arc::new(AtomicBool::new(false));
+        let exited_flag = exited.clone();
+        let tasks_per_tick = self.tasks_per_tick;
+        let thread = Builder::new()
+            .name(self.name.clone())
+            .spawn(move || {
+                let mut worker =
+                    Worker::new(tasks, task_num, tasks_per_tick, stop, retired_flag, ctx);
+                worker.run();
+                exited_flag.store(true, AtomicOrdering::SeqCst);
+            })
+            .unwrap();
+        self.threads.push((thread, retired, exited));
+    }
+
+    // Joins the retired threads that have exited, so resizing the pool repeatedly
+    // doesn't accumulate handles.
+    fn join_exited_threads(&mut self) {
+        let mut i = 0;
+        while i < self.retired_threads.len() {
+            if !self.retired_threads[i].1.load(AtomicOrdering::SeqCst) {
+                i += 1;
+                continue;
+            }
+            let (thread, _) = self.retired_threads.swap_remove(i);
+            if let Err(e) = thread.join() {
+                error!("{} failed to join retired thread: {:?}", self.name, e);
+            }
         }
     }
 
+    /// Resizes the pool. Extra threads exit after finishing their current task.
+    pub fn set_num_threads(&mut self, num_threads: usize) {
+        assert!(num_threads >= 1);
+        if self.stop_flag.load(AtomicOrdering::SeqCst) {
+            return;
+        }
+        self.join_exited_threads();
+        while self.threads.len() < num_threads {
+            self.spawn_thread();
+        }
+        if self.threads.len() > num_threads {
+            for (thread, retired, exited) in self.threads.drain(num_threads..) {
+                retired.store(true, AtomicOrdering::SeqCst);
+                self.retired_threads.push((thread, exited));
+            }
+            let &(_, ref cvar) = &*self.task_pool;
+            cvar.notify_all();
+        }
+    }
+
+    #[inline]
+    pub fn get_num_threads(&self) -> usize {
+        self.threads.len()
+    }
+
     pub fn execute<F>(&mut self, job: F)
+    where
+        F: FnOnce(&mut Ctx) + Send + 'static,
+        Ctx: Context,
+    {
+        self.execute_with_group(DEFAULT_GROUP_ID, 1, job)
+    }
+
+    /// Executes the job as a task of group `group_id`. When the pool is busy, pending
+    /// groups get the threads in proportion to their weights.
+    pub fn execute_with_group<F>(&mut self, group_id: u64, weight: u64, job: F)
     where
         F: FnOnce(&mut Ctx) + Send + 'static,
         Ctx: Context,
@@ -138,7 +240,7 @@ where
         let task = Task::new(job);
         let &(ref lock, ref cvar) = &*self.task_pool;
         let mut queue = lock.lock().unwrap();
-        queue.push(task);
+        queue.push(group_id, weight, task);
         cvar.notify_one();
         self.task_count.fetch_add(1, AtomicOrdering::SeqCst);
     }
@@ -153,7 +255,9 @@ where
         let &(_, ref cvar) = &*self.task_pool;
         cvar.notify_all();
         let mut err_msg = String::new();
-        for t in self.threads.drain(..) {
+        let threads = self.threads.drain(..).map(|(t, _, _)| t);
+        let retired_threads = self.retired_threads.drain(..).map(|(t, _)| t);
+        for t in threads.chain(retired_threads) {
             if let Err(e) = t.join() {
                 write!(&mut err_msg, "Failed to join thread with err: {:?};", e).unwrap();
             }
@@ -168,7 +272,8 @@ where
 // Each thread has a worker.
 struct Worker<C> {
     stop_flag: Arc<AtomicBool>,
-    task_queue: Arc<(Mutex<FifoQueue<C>>, Condvar)>,
+    retired: Arc<AtomicBool>,
+    task_queue: Arc<(Mutex<GroupQueue<C>>, Condvar)>,
     task_count: Arc<AtomicUsize>,
     tasks_per_tick: usize,
     task_counter: usize,
@@ -180,14 +285,16 @@ where
     C: Context,
 {
     fn new(
-        task_queue: Arc<(Mutex<FifoQueue<C>>, Condvar)>,
+        task_queue: Arc<(Mutex<GroupQueue<C>>, Condvar)>,
         task_count: Arc<AtomicUsize>,
         tasks_per_tick: usize,
         stop_flag: Arc<AtomicBool>,
+        retired: Arc<AtomicBool>,
         ctx: C,
     ) -> Worker<C> {
         Worker {
             stop_flag: stop_flag,
+            retired: retired,
             task_queue: task_queue,
             task_count: task_count,
             tasks_per_tick: tasks_per_tick,
@@ -208,7 +315,9 @@ where
     }
 
     fn run(&mut self) {
-        while !self.stop_flag.load(AtomicOrdering::SeqCst) {
+        while !self.stop_flag.load(AtomicOrdering::SeqCst) &&
+            !self.retired.load(AtomicOrdering::SeqCst)
+        {
             if let Some(t) = self.get_task_timeout(time::Duration::from_millis(WORKER_WAIT_TIME)) {
                 self.ctx.on_task_started();
                 (t.task).call_once((&mut self.ctx,));
@@ -229,10 +338,11 @@ where
 
 #[cfg(test)]
 mod test {
-    use super::{Context, ContextFactory, ThreadPool, DEFAULT_TASKS_PER_TICK};
-    use std::time::Duration;
+    use super::{Context, ContextFactory, GroupQueue, Task, ThreadPool, DEFAULT_TASKS_PER_TICK};
+    use std::thread;
+    use std::time::{Duration, Instant};
     use std::sync::mpsc::{channel, Sender};
-    use std::sync::{Arc, Mutex};
+    use std::sync::{Arc, Barrier, Mutex};
     use std::sync::atomic::{AtomicIsize, Ordering};
 
     #[derive(Clone)]
@@ -293,6 +403,86 @@ mod test {
         task_pool.stop().unwrap();
     }
 
+    #[test]
+    fn test_group_queue() {
+        let order = Arc::new(Mutex::new(vec![]));
+        let mut queue = GroupQueue::new();
+        // Group 2 has 4 times the weight of group 1.
+        for &(gid, weight) in &[(1, 1), (2, 4)] {
+            for _ in 0..8 {
+                let order = order.clone();
+                let task = Task::new(move |_: &mut DummyContext| order.lock().unwrap().push(gid));
+                queue.push(gid, weight, task);
+            }
+        }
+        let mut ctx = DummyContext {};
+        for _ in 0..10 {
+            let t = queue.pop().unwrap();
+            (t.task).call_once((&mut ctx,));
+        }
+        assert_eq!(
+            *order.lock().unwrap(),
+            vec![1, 2, 2, 2, 2, 1, 2, 2, 2, 2]
+        );
+
+        // An idle group doesn't get credit for the time it was idle.
+        order.lock().unwrap().clear();
+        let o = order.clone();
+        queue.push(3, 1, Task::new(move |_: &mut DummyContext| o.lock().unwrap().push(3)));
+        while let Some(t) = queue.pop() {
+            (t.task).call_once((&mut ctx,));
+        }
+        let order = order.lock().unwrap();
+        assert_eq!(*order, vec![3, 1, 1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_set_num_threads() {
+        let name = thd_name!("test_set_num_threads");
+        let f = DummyContextFactory {};
+        let mut task_pool = ThreadPool::new(name, 1, DEFAULT_TASKS_PER_TICK, f);
+        task_pool.set_num_threads(3);
+        assert_eq!(task_pool.get_num_threads(), 3);
+
+        // All three tasks must run at the same time to pass the barrier.
+        let barrier = Arc::new(Barrier::new(3));
+        let (tx, rx) = channel();
+        for _ in 0..3 {
+            let barrier = barrier.clone();
+            let tx = tx.clone();
+            task_pool.execute(move |_: &mut DummyContext| {
+                barrier.wait();
+                tx.send(()).unwrap();
+            });
+        }
+        for _ in 0..3 {
+            rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        }
+
+        task_pool.set_num_threads(1);
+        assert_eq!(task_pool.get_num_threads(), 1);
+        for _ in 0..3 {
+            let tx = tx.clone();
+            task_pool.execute(move |_: &mut DummyContext| tx.send(()).unwrap());
+        }
+        for _ in 0..3 {
+            rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        }
+
+        // Retired threads are joined by the next resize once they exit.
+        assert_eq!(task_pool.retired_threads.len(), 2);
+        for &(_, ref exited) in &task_pool.retired_threads {
+            let deadline = Instant::now() + Duration::from_secs(2);
+            while !exited.load(Ordering::SeqCst) {
+                assert!(Instant::now() < deadline);
+                thread::yield_now();
+            }
+        }
+        task_pool.set_num_threads(1);
+        assert!(task_pool.retired_threads.is_empty());
+        task_pool.stop().unwrap();
+    }
+
     #[test]
     fn test_task_context() {
         struct TestContext {
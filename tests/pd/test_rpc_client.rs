@@ -22,6 +22,7 @@ use kvproto::metapb;
 use kvproto::pdpb;
 
 use tikv::pd::{validate_endpoints, Error as PdError, PdClient, RegionStat, RpcClient};
+use tikv::util::security::SecurityManager;
 
 use super::mock::mocker::*;
 use super::mock::Server as MockServer;
@@ -39,7 +40,7 @@ fn test_rpc_client() {
 
     thread::sleep(Duration::from_secs(1));
 
-    let client = RpcClient::new(&eps).unwrap();
+    let client = RpcClient::new(&eps, Arc::new(SecurityManager::default())).unwrap();
     assert_ne!(client.get_cluster_id().unwrap(), 0);
 
     let store_id = client.alloc_id().unwrap();
@@ -71,7 +72,7 @@ fn test_rpc_client() {
 
     let mut prev_id = 0;
     for _ in 0..100 {
-        let client = RpcClient::new(&eps).unwrap();
+        let client = RpcClient::new(&eps, Arc::new(SecurityManager::default())).unwrap();
         let alloc_id = client.alloc_id().unwrap();
         assert!(alloc_id > prev_id);
         prev_id = alloc_id;
@@ -118,7 +119,7 @@ fn test_reboot() {
 
     thread::sleep(Duration::from_secs(1));
 
-    let client = RpcClient::new(&eps).unwrap();
+    let client = RpcClient::new(&eps, Arc::new(SecurityManager::default())).unwrap();
 
     assert!(!client.is_cluster_bootstrapped().unwrap());
 
@@ -150,7 +151,7 @@ fn test_validate_endpoints() {
 
     thread::sleep(Duration::from_secs(1));
 
-    assert!(validate_endpoints(env, &eps).is_err());
+    assert!(validate_endpoints(env, &SecurityManager::default(), &eps).is_err());
 }
 
 #[test]
@@ -168,7 +169,7 @@ fn test_retry_async() {
 
     thread::sleep(Duration::from_secs(1));
 
-    let client = RpcClient::new(&eps).unwrap();
+    let client = RpcClient::new(&eps, Arc::new(SecurityManager::default())).unwrap();
 
     for _ in 0..5 {
         let region = client.get_region_by_id(1);
@@ -191,7 +192,7 @@ fn test_restart_leader() {
 
     thread::sleep(Duration::from_secs(2));
 
-    let client = RpcClient::new(&eps).unwrap();
+    let client = RpcClient::new(&eps, Arc::new(SecurityManager::default())).unwrap();
     // Put a region.
     let store_id = client.alloc_id().unwrap();
     let mut store = metapb::Store::new();
@@ -242,7 +243,7 @@ fn test_change_leader_async() {
 
     thread::sleep(Duration::from_secs(1));
 
-    let client = RpcClient::new(&eps).unwrap();
+    let client = RpcClient::new(&eps, Arc::new(SecurityManager::default())).unwrap();
     let leader = client.get_leader();
 
     for _ in 0..5 {
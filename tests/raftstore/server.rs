@@ -29,6 +29,7 @@ use tikv::server::transport::ServerRaftStoreRouter;
 use tikv::server::transport::RaftStoreRouter;
 use tikv::raftstore::{store, Error, Result};
 use tikv::raftstore::store::{Engines, Msg as StoreMsg, SnapManager};
+use tikv::util::security::SecurityManager;
 use tikv::util::transport::SendCh;
 use tikv::util::worker::Worker;
 use tikv::storage::{CfName, Engine};
@@ -76,7 +77,11 @@ impl ServerCluster {
             pd_client: pd_client,
             storages: HashMap::new(),
             snap_paths: HashMap::new(),
-            raft_client: RaftClient::new(env, Config::default()),
+            raft_client: RaftClient::new(
+                env,
+                Config::default(),
+                Arc::new(SecurityManager::default()),
+            ),
         }
     }
 }
@@ -125,6 +130,8 @@ impl Simulator for ServerCluster {
             snap_status_sender,
             resolver,
             snap_mgr.clone(),
+            Arc::new(SecurityManager::new(&cfg.security).unwrap()),
+            Some(engines.clone()),
         ).unwrap();
         let addr = server.listening_addr();
         cfg.server.addr = format!("{}", addr);
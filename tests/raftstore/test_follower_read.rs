@@ -0,0 +1,154 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A module contains test cases for read index on Raft followers.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use kvproto::eraftpb::MessageType;
+use kvproto::metapb::{Peer, Region};
+use kvproto::raft_cmdpb::{CmdType, RaftCmdResponse, Request};
+use tikv::raftstore::Result;
+
+use super::cluster::{Cluster, Simulator};
+use super::node::new_node_cluster;
+use super::server::new_server_cluster;
+use super::transport_simulate::*;
+use super::util::*;
+
+fn call_on_follower<T: Simulator>(
+    cluster: &mut Cluster<T>,
+    peer: Peer,
+    region: &Region,
+    cmd: Request,
+    allow_follower_read: bool,
+    timeout: Duration,
+) -> Result<RaftCmdResponse> {
+    let mut request = new_request(
+        region.get_id(),
+        region.get_region_epoch().clone(),
+        vec![cmd],
+        false,
+    );
+    request.mut_header().set_peer(peer);
+    if allow_follower_read {
+        request.mut_header().set_follower_read(true);
+    }
+    cluster.call_command(request, timeout)
+}
+
+fn must_follower_read<T: Simulator>(
+    cluster: &mut Cluster<T>,
+    peer: Peer,
+    region: &Region,
+    key: &[u8],
+    value: &[u8],
+) {
+    let timeout = Duration::from_secs(3);
+    let resp = call_on_follower(cluster, peer, region, new_get_cmd(key), true, timeout).unwrap();
+    assert!(!resp.get_header().has_error(), "{:?}", resp);
+    assert_eq!(resp.get_responses().len(), 1);
+    assert_eq!(resp.get_responses()[0].get_cmd_type(), CmdType::Get);
+    assert_eq!(resp.get_responses()[0].get_get().get_value(), value);
+}
+
+fn test_follower_read<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_transfer_leader(1, new_peer(1, 1));
+    let region = cluster.get_region(b"");
+
+    cluster.must_put(b"k1", b"v1");
+    must_follower_read(cluster, new_peer(2, 2), &region, b"k1", b"v1");
+    must_follower_read(cluster, new_peer(3, 3), &region, b"k1", b"v1");
+
+    // Reads without the flag are still rejected by followers.
+    let timeout = Duration::from_secs(3);
+    let cmd = new_get_cmd(b"k1");
+    let resp = call_on_follower(cluster, new_peer(2, 2), &region, cmd, false, timeout).unwrap();
+    assert!(resp.get_header().get_error().has_not_leader(), "{:?}", resp);
+
+    // So are writes, even with the flag.
+    let cmd = new_put_cmd(b"k2", b"v2");
+    let resp = call_on_follower(cluster, new_peer(2, 2), &region, cmd, true, timeout).unwrap();
+    assert!(resp.get_header().get_error().has_not_leader(), "{:?}", resp);
+}
+
+#[test]
+fn test_node_follower_read() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_follower_read(&mut cluster);
+}
+
+#[test]
+fn test_server_follower_read() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_follower_read(&mut cluster);
+}
+
+// A follower must wait until it applies to the read index before serving the read.
+#[test]
+fn test_node_follower_read_wait_apply() {
+    let mut cluster = new_node_cluster(0, 3);
+    cluster.run();
+    cluster.must_transfer_leader(1, new_peer(1, 1));
+    let region = cluster.get_region(b"");
+    cluster.must_put(b"k1", b"v1");
+    must_follower_read(&mut cluster, new_peer(3, 3), &region, b"k1", b"v1");
+
+    // Store 3 can't receive new entries, but it can still get the read index.
+    cluster.add_send_filter(CloneFilterFactory(
+        RegionPacketFilter::new(1, 3)
+            .msg_type(MessageType::MsgAppend)
+            .direction(Direction::Recv),
+    ));
+    cluster.must_put(b"k1", b"v2");
+
+    let timeout = Duration::from_millis(500);
+    let cmd = new_get_cmd(b"k1");
+    let res = call_on_follower(&mut cluster, new_peer(3, 3), &region, cmd, true, timeout);
+    assert!(res.is_err(), "{:?}", res);
+
+    cluster.clear_send_filters();
+    must_follower_read(&mut cluster, new_peer(3, 3), &region, b"k1", b"v2");
+}
+
+// A follower sends read index again if the leader doesn't answer it in time.
+#[test]
+fn test_node_follower_read_retry() {
+    let mut cluster = new_node_cluster(0, 3);
+    cluster.run();
+    cluster.must_transfer_leader(1, new_peer(1, 1));
+    let region = cluster.get_region(b"");
+    cluster.must_put(b"k1", b"v1");
+    must_follower_read(&mut cluster, new_peer(3, 3), &region, b"k1", b"v1");
+
+    // Drop the read index sent by store 3 before the lease expires, so only the
+    // retried one reaches the leader.
+    let dropping = Arc::new(AtomicBool::new(true));
+    cluster.add_send_filter(CloneFilterFactory(
+        RegionPacketFilter::new(1, 3)
+            .msg_type(MessageType::MsgReadIndex)
+            .direction(Direction::Send)
+            .when(dropping.clone()),
+    ));
+    let d = dropping.clone();
+    let handle = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(MAX_LEADER_LEASE / 2));
+        d.store(false, Ordering::SeqCst);
+    });
+    must_follower_read(&mut cluster, new_peer(3, 3), &region, b"k1", b"v1");
+    handle.join().unwrap();
+}
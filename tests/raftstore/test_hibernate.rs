@@ -0,0 +1,105 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::thread;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use kvproto::eraftpb::MessageType;
+use kvproto::raft_serverpb::RaftMessage;
+use tikv::raftstore::Result;
+use tikv::util::config::ReadableDuration;
+
+use super::cluster::{Cluster, Simulator};
+use super::node::new_node_cluster;
+use super::transport_simulate::*;
+use super::util::*;
+
+#[derive(Clone)]
+struct MessageCountFilter {
+    msg_type: MessageType,
+    count: Arc<AtomicUsize>,
+}
+
+impl Filter<RaftMessage> for MessageCountFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        for m in msgs.iter() {
+            if m.get_message().get_msg_type() == self.msg_type {
+                self.count.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn election_timeout<T: Simulator>(cluster: &Cluster<T>) -> Duration {
+    let cfg = &cluster.cfg.raft_store;
+    cfg.raft_base_tick_interval.0 * cfg.raft_election_timeout_ticks as u32
+}
+
+#[test]
+fn test_node_hibernate_idle_region() {
+    let mut cluster = new_node_cluster(0, 3);
+    cluster.cfg.raft_store.hibernate_regions = true;
+    cluster.run();
+    cluster.must_transfer_leader(1, new_peer(1, 1));
+    cluster.must_put(b"k1", b"v1");
+    must_get_equal(&cluster.get_engine(3), b"k1", b"v1");
+
+    // Wait for the region to be idle and hibernate.
+    let timeout = election_timeout(&cluster);
+    thread::sleep(timeout * 3);
+
+    let heartbeats = Arc::new(AtomicUsize::new(0));
+    cluster.add_send_filter(CloneFilterFactory(MessageCountFilter {
+        msg_type: MessageType::MsgHeartbeat,
+        count: heartbeats.clone(),
+    }));
+    thread::sleep(timeout * 2);
+    assert_eq!(heartbeats.load(Ordering::SeqCst), 0);
+
+    // A proposal wakes the region up.
+    cluster.must_put(b"k2", b"v2");
+    must_get_equal(&cluster.get_engine(3), b"k2", b"v2");
+    thread::sleep(timeout / 2);
+    assert!(heartbeats.load(Ordering::SeqCst) > 0);
+    assert_eq!(cluster.leader_of_region(1), Some(new_peer(1, 1)));
+}
+
+#[test]
+fn test_node_hibernate_leader_down() {
+    let mut cluster = new_node_cluster(0, 3);
+    cluster.cfg.raft_store.hibernate_regions = true;
+    cluster.cfg.raft_store.hibernate_leader_check_interval = ReadableDuration::millis(500);
+    cluster.run();
+    cluster.must_transfer_leader(1, new_peer(1, 1));
+    cluster.must_put(b"k1", b"v1");
+    must_get_equal(&cluster.get_engine(3), b"k1", b"v1");
+
+    let timeout = election_timeout(&cluster);
+    thread::sleep(timeout * 3);
+
+    // Followers should find the leader missing after they wake up.
+    cluster.stop_node(1);
+    thread::sleep(cluster.cfg.raft_store.hibernate_leader_check_interval.0 + timeout * 3);
+    cluster.reset_leader_of_region(1);
+    let new_leader = cluster
+        .leader_of_region(1)
+        .expect("leader should be elected.");
+    assert_ne!(new_leader.get_store_id(), 1);
+
+    cluster.must_put(b"k2", b"v2");
+    cluster.run_node(1);
+    must_get_equal(&cluster.get_engine(1), b"k2", b"v2");
+}